@@ -1,25 +1,14 @@
 use std::time::Duration;
 use tokio_blocked::TokioBlockedLayer;
-use tracing_subscriber::{
-    EnvFilter, Layer as _, layer::SubscriberExt as _, util::SubscriberInitExt as _,
-};
 
 #[tokio::main]
 async fn main() {
-    // Prepare the tracing-subscriber logger with both a regular format logger
-    // and the TokioBlockedLayer.
-
-    {
-        let fmt = tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env());
-
-        let blocked =
-            TokioBlockedLayer::new().with_warn_busy_single_poll(Some(Duration::from_millis(1)));
-
-        tracing_subscriber::registry()
-            .with(fmt)
-            .with(blocked)
-            .init();
-    }
+    // Install a fmt logger, filtered by `RUST_LOG`, together with the
+    // TokioBlockedLayer.
+    tokio_blocked::init_with(
+        TokioBlockedLayer::new().with_warn_busy_single_poll(Some(Duration::from_millis(1))),
+    )
+    .expect("a global subscriber is already set");
 
     tokio::task::spawn(async {
         // BAD!
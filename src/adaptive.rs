@@ -0,0 +1,188 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::time::Instant;
+
+/// Scales the single-poll threshold with the load of the runtime, see
+/// [`with_adaptive_thresholds`](crate::TokioBlockedLayer::with_adaptive_thresholds).
+///
+/// The load is the fraction of wall time the workers spent polling tracked
+/// tasks during the last completed window: the busy time of all task polls
+/// divided by the window length times [`workers`](Self::workers). `0.0` is
+/// idle, `1.0` means every worker was busy the whole window.
+///
+/// The effective threshold is the configured one multiplied by a factor
+/// derived from the load, either linearly ([`linear`](Self::linear)) or by a
+/// custom curve ([`curve`](Self::curve)).
+#[derive(Clone)]
+pub struct AdaptiveThresholds {
+    pub(crate) scale: Scale,
+    pub(crate) window: Duration,
+    pub(crate) workers: usize,
+}
+
+#[derive(Clone)]
+pub(crate) enum Scale {
+    Linear {
+        low: f64,
+        high: f64,
+        min_factor: f64,
+    },
+    Curve(Arc<dyn Fn(f64) -> f64 + Send + Sync>),
+}
+
+impl AdaptiveThresholds {
+    /// Keeps the threshold at load `low` and below, and tightens it linearly
+    /// to `min_factor` times the threshold at load `high` and above.
+    ///
+    /// E.g. `linear(0.3, 0.9, 0.25)` keeps a 1ms threshold up to 30% load
+    /// and lowers it to 250µs from 90% load.
+    pub fn linear(low: f64, high: f64, min_factor: f64) -> Self {
+        Self::with_scale(Scale::Linear {
+            low,
+            high,
+            min_factor,
+        })
+    }
+
+    /// Uses `curve` to map the load to the factor the threshold is
+    /// multiplied with. Negative and non-finite factors are treated as `1.0`.
+    pub fn curve(curve: impl Fn(f64) -> f64 + Send + Sync + 'static) -> Self {
+        Self::with_scale(Scale::Curve(Arc::new(curve)))
+    }
+
+    fn with_scale(scale: Scale) -> Self {
+        Self {
+            scale,
+            window: Duration::from_secs(1),
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Length of the window the load is measured over. Defaults to 1s.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Number of threads polling tasks, the capacity the busy time is
+    /// compared against. Defaults to [`std::thread::available_parallelism`].
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// The factor the threshold is multiplied with at `load`.
+    pub fn factor(&self, load: f64) -> f64 {
+        let factor = match &self.scale {
+            Scale::Linear {
+                low,
+                high,
+                min_factor,
+            } => {
+                if load <= *low {
+                    1.0
+                } else if load >= *high {
+                    *min_factor
+                } else {
+                    1.0 - (1.0 - min_factor) * (load - low) / (high - low)
+                }
+            }
+            Scale::Curve(curve) => curve(load),
+        };
+        if factor.is_finite() && factor >= 0.0 {
+            factor
+        } else {
+            1.0
+        }
+    }
+}
+
+impl fmt::Debug for AdaptiveThresholds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("AdaptiveThresholds");
+        match &self.scale {
+            Scale::Linear {
+                low,
+                high,
+                min_factor,
+            } => s
+                .field("low", low)
+                .field("high", high)
+                .field("min_factor", min_factor),
+            Scale::Curve(_) => s.field("curve", &"<fn>"),
+        };
+        s.field("window", &self.window)
+            .field("workers", &self.workers)
+            .finish()
+    }
+}
+
+/// Measures the load of the runtime over fixed windows, updated lock-free on
+/// every task poll.
+pub(crate) struct LoadTracker {
+    config: AdaptiveThresholds,
+    // End of the first recorded poll, so the tracker doesn't depend on the
+    // clock it was created with.
+    epoch: OnceLock<Instant>,
+    // Start of the current window, in nanoseconds since `epoch`.
+    window_start_ns: AtomicU64,
+    // Busy time of the task polls that ended in the current window.
+    busy_ns: AtomicU64,
+    // Load of the last completed window, as `f64` bits.
+    load: AtomicU64,
+}
+
+impl LoadTracker {
+    pub(crate) fn new(config: AdaptiveThresholds) -> Self {
+        Self {
+            config,
+            epoch: OnceLock::new(),
+            window_start_ns: AtomicU64::new(0),
+            busy_ns: AtomicU64::new(0),
+            load: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    /// Load of the last completed window.
+    pub(crate) fn load(&self) -> f64 {
+        f64::from_bits(self.load.load(Ordering::Relaxed))
+    }
+
+    /// The effective threshold for `threshold` at the current load.
+    pub(crate) fn scale(&self, threshold: Duration, load: f64) -> Duration {
+        threshold.mul_f64(self.config.factor(load))
+    }
+
+    /// Adds a task poll that ended at `end`, completing the window if it has
+    /// elapsed.
+    pub(crate) fn record(&self, busy: Duration, end: Instant) {
+        self.busy_ns
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+        let epoch = *self.epoch.get_or_init(|| end);
+        let now_ns = end.saturating_duration_since(epoch).as_nanos() as u64;
+        let start_ns = self.window_start_ns.load(Ordering::Relaxed);
+        let elapsed = now_ns.saturating_sub(start_ns);
+        if elapsed < self.config.window.as_nanos() as u64 {
+            return;
+        }
+        // Only one thread completes the window.
+        if self
+            .window_start_ns
+            .compare_exchange(start_ns, now_ns, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        let busy = self.busy_ns.swap(0, Ordering::Relaxed);
+        let capacity = elapsed as f64 * self.config.workers.max(1) as f64;
+        let load = (busy as f64 / capacity).min(1.0);
+        self.load.store(load.to_bits(), Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,83 @@
+//! Prints snapshots saved with `Snapshot::save` as a table, or the changes
+//! between two of them.
+//!
+//! Install with `cargo install tokio-blocked --features cli`.
+
+use std::process::ExitCode;
+
+use tokio_blocked::{Snapshot, TableOptions, Tolerances};
+
+const USAGE: &str = "\
+Usage: tokio-blocked-report [OPTIONS] <SNAPSHOT> [<NEW_SNAPSHOT>]
+
+Prints the callsites of a JSON snapshot. With two snapshots, prints how the
+callsites changed from the first to the second.
+
+Options:
+  --top <N>        Only show the first N rows
+  --sort <KEY>     Sort by total_busy (default), max_poll, polls or spans
+  --filter <TEXT>  Only show callsites whose location, name or target
+                   contain TEXT
+  -h, --help       Print this help
+";
+
+fn main() -> ExitCode {
+    let (opts, files) = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("error: {error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut snapshots = Vec::new();
+    for file in &files {
+        match Snapshot::load(file) {
+            Ok(snapshot) => snapshots.push(snapshot),
+            Err(error) => {
+                eprintln!("error: failed to read {file}: {error}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    match &snapshots[..] {
+        [snapshot] => print!("{}", snapshot.render_table(&opts)),
+        [baseline, snapshot] => print!(
+            "{}",
+            snapshot.render_diff(baseline, Tolerances::default(), &opts)
+        ),
+        _ => unreachable!("parse_args accepts one or two files"),
+    }
+    ExitCode::SUCCESS
+}
+
+/// Returns `None` if help was requested.
+fn parse_args(
+    mut args: impl Iterator<Item = String>,
+) -> Result<Option<(TableOptions, Vec<String>)>, String> {
+    let mut opts = TableOptions::default();
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--top" => {
+                let top = value()?;
+                opts.top = Some(top.parse().map_err(|_| format!("invalid --top: {top}"))?);
+            }
+            "--sort" => opts.sort = value()?.parse()?,
+            "--filter" => opts.filter = Some(value()?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            _ => files.push(arg),
+        }
+    }
+    match files.len() {
+        1 | 2 => Ok(Some((opts, files))),
+        0 => Err("missing snapshot file".to_string()),
+        _ => Err("expected at most two snapshot files".to_string()),
+    }
+}
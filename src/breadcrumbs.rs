@@ -0,0 +1,130 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write as _},
+};
+
+use tracing::{field::Field, Event, Level};
+
+use crate::time::SystemTime;
+
+/// Longest message kept per breadcrumb, in bytes. Longer ones are cut off.
+const MAX_MESSAGE_LEN: usize = 256;
+
+/// An event emitted inside a tracked span, kept by
+/// [`with_breadcrumbs`](crate::TokioBlockedLayer::with_breadcrumbs).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Breadcrumb {
+    pub target: &'static str,
+    #[cfg_attr(feature = "serde", serde(with = "crate::events::ser::level"))]
+    pub level: Level,
+    /// The message of the event followed by its other fields as
+    /// `name=value`, at most 256 bytes.
+    pub message: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "timestamp_unix_ns", with = "crate::events::ser::unix_nanos")
+    )]
+    pub timestamp: SystemTime,
+}
+
+impl Breadcrumb {
+    pub(crate) fn of(event: &Event<'_>) -> Self {
+        let mut visitor = MessageVisitor {
+            message: String::new(),
+            fields: String::new(),
+        };
+        event.record(&mut visitor);
+        let mut message = visitor.message;
+        message.push_str(&visitor.fields);
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        let meta = event.metadata();
+        Self {
+            target: meta.target(),
+            level: *meta.level(),
+            message,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+impl fmt::Display for Breadcrumb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.level, self.target, self.message)
+    }
+}
+
+/// The newest breadcrumbs of a span, oldest first. Older ones are dropped
+/// once `capacity` is reached.
+#[derive(Debug, Default)]
+pub(crate) struct Breadcrumbs {
+    capacity: usize,
+    crumbs: VecDeque<Breadcrumb>,
+}
+
+impl Breadcrumbs {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            crumbs: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, crumb: Breadcrumb) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.crumbs.len() >= self.capacity {
+            self.crumbs.pop_front();
+        }
+        self.crumbs.push_back(crumb);
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<Breadcrumb> {
+        self.crumbs.iter().cloned().collect()
+    }
+}
+
+/// The breadcrumbs as one field value, oldest first, separated by ` | `.
+pub(crate) fn join(crumbs: &[Breadcrumb]) -> Option<String> {
+    if crumbs.is_empty() {
+        return None;
+    }
+    let mut joined = String::new();
+    for (i, crumb) in crumbs.iter().enumerate() {
+        if i > 0 {
+            joined.push_str(" | ");
+        }
+        let _ = write!(joined, "{crumb}");
+    }
+    Some(joined)
+}
+
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
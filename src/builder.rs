@@ -0,0 +1,465 @@
+use std::{sync::Arc, time::Duration};
+
+use tracing::Level;
+
+use crate::{
+    adaptive::Scale, AdaptiveThresholds, BlockedObserver, CallsiteSelector, Clock, EvictionPolicy,
+    Granularity, GroupBy, LocFieldNames, MetricNaming, TokioBlockedLayer, ViolationMode,
+};
+
+/// Validating builder for [`TokioBlockedLayer`], created with
+/// [`TokioBlockedLayer::builder`].
+///
+/// Options mirror the `with_*` methods of the layer, but nonsensical values
+/// are rejected by [`build`](Self::build) instead of being accepted or
+/// clamped silently.
+pub struct Builder {
+    layer: TokioBlockedLayer,
+    warn_busy_single_poll: Option<Duration>,
+    warn_busy_total: Option<Duration>,
+    escalation: bool,
+    escalation_quiet_period: bool,
+    error: Option<ConfigError>,
+}
+
+/// An invalid configuration, returned by [`Builder::build`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// A duration that must be positive is zero.
+    ZeroDuration { option: &'static str },
+    /// A rate or factor is out of its valid range.
+    InvalidRate { option: &'static str, value: f64 },
+    /// Options that contradict each other.
+    ConflictingOptions { reason: &'static str },
+    /// Histogram bucket bounds that are empty or not increasing.
+    InvalidBuckets { reason: &'static str },
+    /// Severity tiers that are empty or not increasing.
+    InvalidTiers { reason: &'static str },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroDuration { option } => write!(f, "{option} must not be zero"),
+            Self::InvalidRate { option, value } => {
+                write!(f, "{option} is out of range: {value}")
+            }
+            Self::ConflictingOptions { reason } => write!(f, "conflicting options: {reason}"),
+            Self::InvalidBuckets { reason } => write!(f, "invalid histogram buckets: {reason}"),
+            Self::InvalidTiers { reason } => write!(f, "invalid severity tiers: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl TokioBlockedLayer {
+    /// Returns a [`Builder`] that validates the configuration.
+    pub fn builder() -> Builder {
+        Builder {
+            layer: TokioBlockedLayer::new(),
+            warn_busy_single_poll: Some(Duration::from_micros(150)),
+            warn_busy_total: None,
+            escalation: false,
+            escalation_quiet_period: false,
+            error: None,
+        }
+    }
+}
+
+impl Builder {
+    /// See [`TokioBlockedLayer::with_warn_busy_single_poll`].
+    pub fn warn_busy_single_poll(mut self, duration: Option<Duration>) -> Self {
+        self.check_duration("warn_busy_single_poll", duration);
+        self.warn_busy_single_poll = duration;
+        self.map(|l| l.with_warn_busy_single_poll(duration))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_busy_total`].
+    pub fn warn_busy_total(mut self, duration: Option<Duration>) -> Self {
+        self.check_duration("warn_busy_total", duration);
+        self.warn_busy_total = duration;
+        self.map(|l| l.with_warn_busy_total(duration))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_busy_total_eager`].
+    pub fn warn_busy_total_eager(self, enabled: bool) -> Self {
+        self.map(|l| l.with_warn_busy_total_eager(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_busy_total_final`].
+    pub fn warn_busy_total_final(self, enabled: bool) -> Self {
+        self.map(|l| l.with_warn_busy_total_final(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_poll_severity_tiers`]. Tiers must be
+    /// non-empty with strictly increasing, non-zero thresholds.
+    pub fn poll_severity_tiers(mut self, tiers: &[(Duration, Level)]) -> Self {
+        self.check_tiers("poll_severity_tiers", tiers);
+        self.warn_busy_single_poll = tiers.first().map(|&(t, _)| t);
+        self.map(|l| l.with_poll_severity_tiers(tiers))
+    }
+
+    /// See [`TokioBlockedLayer::with_total_severity_tiers`], with the same
+    /// requirements as [`poll_severity_tiers`](Self::poll_severity_tiers).
+    pub fn total_severity_tiers(mut self, tiers: &[(Duration, Level)]) -> Self {
+        self.check_tiers("total_severity_tiers", tiers);
+        self.warn_busy_total = tiers.first().map(|&(t, _)| t);
+        self.map(|l| l.with_total_severity_tiers(tiers))
+    }
+
+    /// See [`TokioBlockedLayer::with_adaptive_thresholds`].
+    ///
+    /// Linear loads must satisfy `0 <= low < high`, and the factor must be
+    /// in `(0, 1]`.
+    pub fn adaptive_thresholds(mut self, config: AdaptiveThresholds) -> Self {
+        self.check_duration("adaptive_thresholds.window", Some(config.window));
+        if config.workers == 0 {
+            self.fail(ConfigError::ConflictingOptions {
+                reason: "adaptive thresholds with zero workers",
+            });
+        }
+        if let Scale::Linear {
+            low,
+            high,
+            min_factor,
+        } = config.scale
+        {
+            if !(low >= 0.0 && low < high) {
+                self.fail(ConfigError::InvalidRate {
+                    option: "adaptive_thresholds.low",
+                    value: low,
+                });
+            }
+            if !(min_factor > 0.0 && min_factor <= 1.0) {
+                self.fail(ConfigError::InvalidRate {
+                    option: "adaptive_thresholds.min_factor",
+                    value: min_factor,
+                });
+            }
+        }
+        self.map(|l| l.with_adaptive_thresholds(config))
+    }
+
+    /// See [`TokioBlockedLayer::with_health_window`].
+    pub fn health_window(mut self, retention: Duration) -> Self {
+        self.check_duration("health_window", Some(retention));
+        self.map(|l| l.with_health_window(retention))
+    }
+
+    /// See [`TokioBlockedLayer::with_group_by`].
+    pub fn group_by(self, group_by: GroupBy) -> Self {
+        self.map(|l| l.with_group_by(group_by))
+    }
+
+    /// See [`TokioBlockedLayer::with_loc_fields`].
+    pub fn loc_fields(self, names: LocFieldNames) -> Self {
+        self.map(|l| l.with_loc_fields(names))
+    }
+
+    /// See [`TokioBlockedLayer::with_per_task_summary`].
+    pub fn per_task_summary(self, enabled: bool) -> Self {
+        self.map(|l| l.with_per_task_summary(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_on_violation`].
+    pub fn on_violation(self, mode: ViolationMode) -> Self {
+        self.map(|l| l.with_on_violation(mode))
+    }
+
+    /// See [`TokioBlockedLayer::with_trace_recorder`].
+    pub fn trace_recorder(mut self, limit: usize, min_duration: Duration) -> Self {
+        if limit == 0 {
+            self.fail(ConfigError::ConflictingOptions {
+                reason: "trace recorder limit is zero",
+            });
+        }
+        self.map(|l| l.with_trace_recorder(limit, min_duration))
+    }
+
+    /// See [`TokioBlockedLayer::with_escalation`].
+    pub fn escalation(mut self, after: u64, level: Level) -> Self {
+        self.escalation = true;
+        self.map(|l| l.with_escalation(after, level))
+    }
+
+    /// See [`TokioBlockedLayer::with_escalation_quiet_period`].
+    pub fn escalation_quiet_period(mut self, period: Duration) -> Self {
+        self.check_duration("escalation_quiet_period", Some(period));
+        self.escalation_quiet_period = true;
+        self.map(|l| l.with_escalation_quiet_period(period))
+    }
+
+    /// See [`TokioBlockedLayer::with_dedup_window`].
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.check_duration("dedup_window", Some(window));
+        self.map(|l| l.with_dedup_window(window))
+    }
+
+    /// See [`TokioBlockedLayer::with_group_warnings_by`].
+    pub fn group_warnings_by(self, granularity: Granularity) -> Self {
+        self.map(|l| l.with_group_warnings_by(granularity))
+    }
+
+    /// See [`TokioBlockedLayer::with_ewma_alpha`]. Must be in `(0, 1]`.
+    pub fn ewma_alpha(mut self, alpha: f64) -> Self {
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            self.fail(ConfigError::InvalidRate {
+                option: "ewma_alpha",
+                value: alpha,
+            });
+        }
+        self.map(|l| l.with_ewma_alpha(alpha))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_poll_rate`]. Must be positive and
+    /// finite.
+    pub fn warn_poll_rate(mut self, polls_per_sec: f64) -> Self {
+        if !(polls_per_sec > 0.0 && polls_per_sec.is_finite()) {
+            self.fail(ConfigError::InvalidRate {
+                option: "warn_poll_rate",
+                value: polls_per_sec,
+            });
+        }
+        self.map(|l| l.with_warn_poll_rate(polls_per_sec))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_first_poll_latency`].
+    pub fn warn_first_poll_latency(mut self, latency: Option<Duration>) -> Self {
+        self.check_duration("warn_first_poll_latency", latency);
+        self.map(|l| l.with_warn_first_poll_latency(latency))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_lock_wait`].
+    pub fn warn_lock_wait(mut self, wait: Duration) -> Self {
+        self.check_duration("warn_lock_wait", Some(wait));
+        self.map(|l| l.with_warn_lock_wait(wait))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_blocking_pool_op`].
+    pub fn warn_blocking_pool_op(mut self, duration: Duration) -> Self {
+        self.check_duration("warn_blocking_pool_op", Some(duration));
+        self.map(|l| l.with_warn_blocking_pool_op(duration))
+    }
+
+    /// See [`TokioBlockedLayer::with_recovery_events`]. Must be positive.
+    pub fn recovery_events(mut self, quiet_polls: u64) -> Self {
+        if quiet_polls == 0 {
+            self.fail(ConfigError::InvalidRate {
+                option: "recovery_events",
+                value: 0.0,
+            });
+        }
+        self.map(|l| l.with_recovery_events(quiet_polls))
+    }
+
+    /// See [`TokioBlockedLayer::with_warn_migrations`].
+    pub fn warn_migrations(self, migrations: u64) -> Self {
+        self.map(|l| l.with_warn_migrations(migrations))
+    }
+
+    /// See [`TokioBlockedLayer::with_busy_budget`].
+    pub fn busy_budget(
+        mut self,
+        selector: CallsiteSelector,
+        budget: Duration,
+        per: Duration,
+    ) -> Self {
+        self.check_duration("busy_budget.per", Some(per));
+        self.map(|l| l.with_busy_budget(selector, budget, per))
+    }
+
+    /// See [`TokioBlockedLayer::with_scope_span`].
+    pub fn scope_span(self, selector: CallsiteSelector) -> Self {
+        self.map(|l| l.with_scope_span(selector))
+    }
+
+    /// See [`TokioBlockedLayer::with_allow_blocking_at`].
+    pub fn allow_blocking_at(self, locations: &[(&str, Option<u32>)]) -> Self {
+        self.map(|l| l.with_allow_blocking_at(locations))
+    }
+
+    /// See [`TokioBlockedLayer::with_track_resources`].
+    pub fn track_resources(self, enabled: bool) -> Self {
+        self.map(|l| l.with_track_resources(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_callsite_eviction`].
+    pub fn callsite_eviction(mut self, policy: EvictionPolicy) -> Self {
+        if policy == (EvictionPolicy::Lru { max_entries: 0 }) {
+            self.fail(ConfigError::ConflictingOptions {
+                reason: "LRU eviction with max_entries of zero",
+            });
+        }
+        self.map(|l| l.with_callsite_eviction(policy))
+    }
+
+    /// See [`TokioBlockedLayer::with_live_flush`].
+    pub fn live_flush(mut self, interval: Duration) -> Self {
+        self.check_duration("live_flush", Some(interval));
+        self.map(|l| l.with_live_flush(interval))
+    }
+
+    /// See [`TokioBlockedLayer::with_live_snapshots`].
+    pub fn live_snapshots(self, enabled: bool) -> Self {
+        self.map(|l| l.with_live_snapshots(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_poll_histogram`].
+    pub fn poll_histogram(self, enabled: bool) -> Self {
+        self.map(|l| l.with_poll_histogram(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_anomaly_threshold`]. The multiplier must
+    /// be finite and at least 1, the percentile in `(0, 100]` and `min_samples` positive.
+    pub fn anomaly_threshold(mut self, multiplier: f64, percentile: f64, min_samples: u64) -> Self {
+        if !multiplier.is_finite() || multiplier < 1.0 {
+            self.fail(ConfigError::InvalidRate {
+                option: "anomaly_threshold.multiplier",
+                value: multiplier,
+            });
+        }
+        if !(percentile > 0.0 && percentile <= 100.0) {
+            self.fail(ConfigError::InvalidRate {
+                option: "anomaly_threshold.percentile",
+                value: percentile,
+            });
+        }
+        if min_samples == 0 {
+            self.fail(ConfigError::InvalidRate {
+                option: "anomaly_threshold.min_samples",
+                value: 0.0,
+            });
+        }
+        self.map(|l| l.with_anomaly_threshold(multiplier, percentile, min_samples))
+    }
+
+    /// See [`TokioBlockedLayer::with_histogram_buckets`]. Bounds must be
+    /// non-empty and strictly increasing.
+    pub fn histogram_buckets(mut self, bounds: &[Duration]) -> Self {
+        if bounds.is_empty() {
+            self.fail(ConfigError::InvalidBuckets {
+                reason: "no histogram buckets",
+            });
+        } else if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            self.fail(ConfigError::InvalidBuckets {
+                reason: "histogram buckets are not strictly increasing",
+            });
+        }
+        self.map(|l| l.with_histogram_buckets(bounds))
+    }
+
+    /// See [`TokioBlockedLayer::with_report_percentiles`]. Quantiles must be
+    /// in `(0, 1]`.
+    pub fn report_percentiles(mut self, quantiles: &[f64]) -> Self {
+        if let Some(&q) = quantiles.iter().find(|q| !(**q > 0.0 && **q <= 1.0)) {
+            self.fail(ConfigError::InvalidRate {
+                option: "report_percentiles",
+                value: q,
+            });
+        }
+        self.map(|l| l.with_report_percentiles(quantiles))
+    }
+
+    /// See [`TokioBlockedLayer::with_recent_polls`].
+    pub fn recent_polls(self, n: usize) -> Self {
+        self.map(|l| l.with_recent_polls(n))
+    }
+
+    /// See [`TokioBlockedLayer::with_warning_history`].
+    pub fn warning_history(self, n: usize) -> Self {
+        self.map(|l| l.with_warning_history(n))
+    }
+
+    /// See [`TokioBlockedLayer::with_warning_history_suppressed`].
+    pub fn warning_history_suppressed(self, enabled: bool) -> Self {
+        self.map(|l| l.with_warning_history_suppressed(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_recent_polls_floor`].
+    pub fn recent_polls_floor(self, floor: Duration) -> Self {
+        self.map(|l| l.with_recent_polls_floor(floor))
+    }
+
+    /// See [`TokioBlockedLayer::with_metric_events`].
+    pub fn metric_events(self, enabled: bool) -> Self {
+        self.map(|l| l.with_metric_events(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_metric_naming`].
+    pub fn metric_naming(self, naming: MetricNaming) -> Self {
+        self.map(|l| l.with_metric_naming(naming))
+    }
+
+    /// See [`TokioBlockedLayer::with_observer`].
+    pub fn observer(self, observer: Arc<dyn BlockedObserver>) -> Self {
+        self.map(|l| l.with_observer(observer))
+    }
+
+    /// See [`TokioBlockedLayer::with_report_interval`].
+    pub fn report_interval(mut self, interval: Duration) -> Self {
+        self.check_duration("report_interval", Some(interval));
+        self.map(|l| l.with_report_interval(interval))
+    }
+
+    /// See [`TokioBlockedLayer::with_warnings_enabled`].
+    pub fn warnings_enabled(self, enabled: bool) -> Self {
+        self.map(|l| l.with_warnings_enabled(enabled))
+    }
+
+    /// See [`TokioBlockedLayer::with_clock`].
+    pub fn clock(self, clock: impl Clock) -> Self {
+        self.map(|l| l.with_clock(clock))
+    }
+
+    /// Validates the configuration and creates the layer.
+    ///
+    /// Returns the first invalid option, if any.
+    pub fn build(self) -> Result<TokioBlockedLayer, ConfigError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if let (Some(single), Some(total)) = (self.warn_busy_single_poll, self.warn_busy_total) {
+            if total < single {
+                return Err(ConfigError::ConflictingOptions {
+                    reason: "warn_busy_total is smaller than warn_busy_single_poll",
+                });
+            }
+        }
+        if self.escalation_quiet_period && !self.escalation {
+            return Err(ConfigError::ConflictingOptions {
+                reason: "escalation_quiet_period without escalation",
+            });
+        }
+        Ok(self.layer)
+    }
+
+    fn map(mut self, f: impl FnOnce(TokioBlockedLayer) -> TokioBlockedLayer) -> Self {
+        self.layer = f(self.layer);
+        self
+    }
+
+    fn check_duration(&mut self, option: &'static str, duration: Option<Duration>) {
+        if duration == Some(Duration::ZERO) {
+            self.fail(ConfigError::ZeroDuration { option });
+        }
+    }
+
+    fn check_tiers(&mut self, option: &'static str, tiers: &[(Duration, Level)]) {
+        self.check_duration(option, tiers.first().map(|&(t, _)| t));
+        if tiers.is_empty() {
+            self.fail(ConfigError::InvalidTiers {
+                reason: "no severity tiers",
+            });
+        } else if tiers.windows(2).any(|w| w[0].0 >= w[1].0) {
+            self.fail(ConfigError::InvalidTiers {
+                reason: "severity tiers are not strictly increasing",
+            });
+        }
+    }
+
+    fn fail(&mut self, error: ConfigError) {
+        self.error.get_or_insert(error);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::time::Instant;
+
+/// Bounded buffer of completed polls, exportable in the Chrome trace event
+/// format.
+pub(crate) struct TraceRecorder {
+    limit: usize,
+    min_duration: Duration,
+    slices: VecDeque<TraceSlice>,
+}
+
+/// A single recorded poll.
+struct TraceSlice {
+    start: Instant,
+    duration: Duration,
+    tid: u64,
+    thread_name: Option<String>,
+    name: &'static str,
+    target: &'static str,
+    file: String,
+    line: u32,
+    col: u32,
+}
+
+impl TraceRecorder {
+    pub(crate) fn new(limit: usize, min_duration: Duration) -> Self {
+        Self {
+            limit,
+            min_duration,
+            slices: VecDeque::with_capacity(limit.min(1024)),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slices.len()
+    }
+
+    /// Estimated heap memory of the recorded slices.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        let strings: usize = self
+            .slices
+            .iter()
+            .map(|s| s.file.capacity() + s.thread_name.as_ref().map_or(0, String::capacity))
+            .sum();
+        self.slices.capacity() * std::mem::size_of::<TraceSlice>() + strings
+    }
+
+    /// Records a completed poll, evicting the oldest slice if the buffer is
+    /// full.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn record(
+        &mut self,
+        start: Instant,
+        duration: Duration,
+        name: &'static str,
+        target: &'static str,
+        file: String,
+        line: u32,
+        col: u32,
+    ) {
+        if self.limit == 0 || duration < self.min_duration {
+            return;
+        }
+        if self.slices.len() == self.limit {
+            self.slices.pop_front();
+        }
+        let thread = std::thread::current();
+        self.slices.push_back(TraceSlice {
+            start,
+            duration,
+            tid: current_tid(),
+            thread_name: thread.name().map(|n| n.to_string()),
+            name,
+            target,
+            file,
+            line,
+            col,
+        });
+    }
+
+    /// Writes the recorded slices as a JSON array of trace events.
+    ///
+    /// Each poll becomes a `"ph": "X"` complete event on the timeline of the
+    /// thread it ran on. Timestamps are microseconds relative to `epoch`.
+    pub(crate) fn export(&self, epoch: Instant, mut w: impl Write) -> io::Result<()> {
+        // Thread name metadata events, so viewers label the tracks.
+        let mut threads = BTreeMap::new();
+        for slice in &self.slices {
+            if let Some(name) = &slice.thread_name {
+                threads.entry(slice.tid).or_insert(name.as_str());
+            }
+        }
+
+        w.write_all(b"[")?;
+        let mut first = true;
+        for (tid, name) in threads {
+            if !first {
+                w.write_all(b",")?;
+            }
+            first = false;
+            write!(
+                w,
+                r#"{{"ph":"M","name":"thread_name","pid":1,"tid":{tid},"args":{{"name":"{}"}}}}"#,
+                JsonEscape(name),
+            )?;
+        }
+        for slice in &self.slices {
+            if !first {
+                w.write_all(b",")?;
+            }
+            first = false;
+            let ts = slice.start.saturating_duration_since(epoch).as_nanos() as f64 / 1000.0;
+            let dur = slice.duration.as_nanos() as f64 / 1000.0;
+            write!(
+                w,
+                r#"{{"ph":"X","name":"{}:{}","cat":"{}","pid":1,"tid":{},"ts":{ts:.3},"dur":{dur:.3},"args":{{"callsite.name":"{}","callsite.target":"{}","callsite.file":"{}","callsite.line":{},"callsite.col":{}}}}}"#,
+                JsonEscape(&slice.file),
+                slice.line,
+                JsonEscape(slice.target),
+                slice.tid,
+                JsonEscape(slice.name),
+                JsonEscape(slice.target),
+                JsonEscape(&slice.file),
+                slice.line,
+                slice.col,
+            )?;
+        }
+        w.write_all(b"]")?;
+        w.flush()
+    }
+}
+
+/// Small sequential per-thread id, used as the trace event `tid`.
+///
+/// `ThreadId::as_u64` is unstable, so assign our own.
+pub(crate) fn current_tid() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static TID: u64 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    TID.with(|tid| *tid)
+}
+
+/// Escapes a string for use inside a JSON string literal.
+struct JsonEscape<'a>(&'a str);
+
+impl std::fmt::Display for JsonEscape<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use std::fmt::Write as _;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
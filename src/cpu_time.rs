@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+/// Whether [`thread_cpu_time`] is available on this platform.
+pub(crate) const SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    windows,
+));
+
+/// CPU time the current thread has consumed, in user and kernel mode, or
+/// `None` if the platform doesn't support measuring it.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+))]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid, writable timespec.
+    let result = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    (result == 0).then(|| Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(windows)]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    use windows_sys::Win32::{
+        Foundation::FILETIME,
+        System::Threading::{GetCurrentThread, GetThreadTimes},
+    };
+
+    let [mut creation, mut exit, mut kernel, mut user] = [FILETIME::default(); 4];
+    // SAFETY: the pseudo handle of the current thread is always valid, and
+    // the out parameters are valid, writable FILETIMEs.
+    let ok = unsafe {
+        GetThreadTimes(
+            GetCurrentThread(),
+            &mut creation,
+            &mut exit,
+            &mut kernel,
+            &mut user,
+        )
+    };
+    // In units of 100ns.
+    let ticks = |t: FILETIME| (u64::from(t.dwHighDateTime) << 32) | u64::from(t.dwLowDateTime);
+    (ok != 0).then(|| Duration::from_nanos((ticks(kernel) + ticks(user)) * 100))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    windows,
+)))]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// `cpu` as a percentage of `wall`, at most `100.0`, or `None` for a zero
+/// wall time.
+pub(crate) fn on_cpu_percent(cpu: Duration, wall: Duration) -> Option<f64> {
+    (!wall.is_zero()).then(|| (cpu.as_secs_f64() / wall.as_secs_f64() * 100.0).min(100.0))
+}
@@ -0,0 +1,134 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::{time::Instant, Granularity};
+
+/// Distinct lines listed in the summary of a file group.
+const MAX_GROUPED_LINES: usize = 16;
+
+/// Groups blocked poll warnings by origin location, or only its file,
+/// within a time window.
+///
+/// The first warning of a group is emitted right away, later ones inside the
+/// window are only counted and reported as a single summary once the window
+/// has closed.
+pub(crate) struct Dedup {
+    window: Duration,
+    // The line is `None` for groups of whole files.
+    groups: HashMap<(String, Option<u32>), DedupGroup>,
+}
+
+struct DedupGroup {
+    started: Instant,
+    occurrences: u64,
+    max_duration: Duration,
+    total_duration: Duration,
+    // Sorted distinct lines of a file group.
+    lines: Vec<u32>,
+}
+
+/// Aggregate of the warnings of a closed window.
+pub(crate) struct DedupSummary {
+    pub(crate) file: String,
+    pub(crate) line: Option<u32>,
+    /// Distinct lines of a file group, at most `MAX_GROUPED_LINES`.
+    pub(crate) lines: Vec<u32>,
+    /// All occurrences in the window, including the emitted first one.
+    pub(crate) occurrences: u64,
+    pub(crate) max_duration: Duration,
+    pub(crate) total_duration: Duration,
+}
+
+impl Dedup {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Registers a warning for the given location, grouped by
+    /// `granularity`.
+    ///
+    /// Returns whether the warning should be emitted. Summaries of windows
+    /// that have closed by `now` are appended to `closed`.
+    pub(crate) fn observe(
+        &mut self,
+        file: &str,
+        line: u32,
+        granularity: Granularity,
+        duration: Duration,
+        now: Instant,
+        closed: &mut Vec<DedupSummary>,
+    ) -> bool {
+        self.drain_expired(now, closed);
+
+        let key = match granularity {
+            Granularity::Callsite => (file.to_string(), Some(line)),
+            Granularity::File => (file.to_string(), None),
+        };
+        let grouped = key.1.is_none();
+        if let Some(group) = self.groups.get_mut(&key) {
+            group.occurrences += 1;
+            group.max_duration = group.max_duration.max(duration);
+            group.total_duration += duration;
+            if grouped && group.lines.len() < MAX_GROUPED_LINES {
+                if let Err(i) = group.lines.binary_search(&line) {
+                    group.lines.insert(i, line);
+                }
+            }
+            return false;
+        }
+        self.groups.insert(
+            key,
+            DedupGroup {
+                started: now,
+                occurrences: 1,
+                max_duration: duration,
+                total_duration: duration,
+                lines: if grouped { vec![line] } else { Vec::new() },
+            },
+        );
+        true
+    }
+
+    /// Closes all windows that ended by `now`.
+    pub(crate) fn drain_expired(&mut self, now: Instant, closed: &mut Vec<DedupSummary>) {
+        let window = self.window;
+        self.drain_where(closed, |g| {
+            now.saturating_duration_since(g.started) >= window
+        });
+    }
+
+    /// Closes all windows, regardless of their age.
+    pub(crate) fn drain_all(&mut self, closed: &mut Vec<DedupSummary>) {
+        self.drain_where(closed, |_| true);
+    }
+
+    fn drain_where(
+        &mut self,
+        closed: &mut Vec<DedupSummary>,
+        mut pred: impl FnMut(&DedupGroup) -> bool,
+    ) {
+        let keys: Vec<_> = self
+            .groups
+            .iter()
+            .filter(|(_, g)| pred(g))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            let group = self.groups.remove(&key).unwrap();
+            // Nothing was suppressed, so there is nothing to summarize.
+            if group.occurrences < 2 {
+                continue;
+            }
+            closed.push(DedupSummary {
+                file: key.0,
+                line: key.1,
+                lines: group.lines,
+                occurrences: group.occurrences,
+                max_duration: group.max_duration,
+                total_duration: group.total_duration,
+            });
+        }
+    }
+}
@@ -0,0 +1,483 @@
+//! Compact binary log of blocked polls, see
+//! [`with_event_log`](crate::TokioBlockedLayer::with_event_log).
+//!
+//! The log consists of two files. `<path>` holds one record per blocked
+//! poll: a fixed header of the record kind, the flags and the length of the
+//! body, followed by the varint-encoded time in nanoseconds since the Unix
+//! epoch, the poll duration in nanoseconds, the callsite hash and the
+//! thread number. `<path>.strings` maps callsite hashes to their location
+//! and thread numbers to thread names, and is written as new callsites and
+//! threads show up. Once `<path>` reaches its size limit, it is renamed to
+//! `<path>.1` and a new file is started.
+//!
+//! [`Reader`] reads the records of both files, oldest first, and resolves
+//! the callsites and threads they refer to.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    io::{self, BufWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TryRecvError},
+    },
+    thread::Thread,
+    time::Duration,
+};
+
+use crate::time::{SystemTime, UNIX_EPOCH};
+
+const RECORDS_MAGIC: &[u8; 5] = b"TBEL\x01";
+const STRINGS_MAGIC: &[u8; 5] = b"TBES\x01";
+
+const KIND_POLL: u8 = 1;
+const KIND_CALLSITE: u8 = 1;
+const KIND_THREAD: u8 = 2;
+
+const FLAG_FIRST_POLL: u8 = 1;
+const FLAG_ESCALATED: u8 = 2;
+
+/// Records queued for the writer thread; more are dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// A blocked poll read from the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Record {
+    /// When the poll ended, as the `timestamp` of its warning.
+    pub timestamp: SystemTime,
+    pub duration: Duration,
+    /// Hash of the callsite, see [`Reader::callsite`].
+    pub callsite: u64,
+    /// Number of the thread the poll ran on, see [`Reader::thread_name`].
+    /// Numbers are assigned per process in the order threads first record
+    /// a poll.
+    pub thread: u64,
+    /// Whether this was the first poll of a task.
+    pub first_poll: bool,
+    /// Whether the warning was
+    /// [escalated](crate::TokioBlockedLayer::with_escalation).
+    pub escalated: bool,
+}
+
+/// The location of a callsite in the log's string table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Callsite {
+    pub target: String,
+    pub name: String,
+    /// Spawn location of tasks, or the span's own location.
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+/// Reads an event log written by
+/// [`with_event_log`](crate::TokioBlockedLayer::with_event_log).
+///
+/// ```rust,no_run
+/// use tokio_blocked::event_log::Reader;
+///
+/// let reader = Reader::open("blocked.log")?;
+/// for record in reader.records() {
+///     let record = record?;
+///     if let Some(callsite) = reader.callsite(record.callsite) {
+///         println!("{}:{} blocked for {:?}", callsite.file, callsite.line, record.duration);
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Reader {
+    // Contents of `<path>.1` and `<path>`, without their headers.
+    files: Vec<Vec<u8>>,
+    callsites: HashMap<u64, Callsite>,
+    threads: HashMap<u64, String>,
+}
+
+impl Reader {
+    /// Reads the log at `path`, its rotated predecessor `<path>.1` if there
+    /// is one, and its string table.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        match std::fs::read(with_suffix(path, ".1")) {
+            Ok(data) => files.push(strip_magic(data, RECORDS_MAGIC)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        files.push(strip_magic(std::fs::read(path)?, RECORDS_MAGIC)?);
+
+        let strings = strip_magic(std::fs::read(with_suffix(path, ".strings"))?, STRINGS_MAGIC)?;
+        let mut callsites = HashMap::new();
+        let mut threads = HashMap::new();
+        let mut input = &strings[..];
+        // A truncated last entry was not completely written, skip it.
+        while let Ok((kind, mut body)) = entry(&mut input) {
+            match kind {
+                KIND_CALLSITE => {
+                    let hash = varint(&mut body)?;
+                    let callsite = Callsite {
+                        target: string(&mut body)?,
+                        name: string(&mut body)?,
+                        file: string(&mut body)?,
+                        line: varint(&mut body)? as u32,
+                        col: varint(&mut body)? as u32,
+                    };
+                    callsites.insert(hash, callsite);
+                }
+                KIND_THREAD => {
+                    let thread = varint(&mut body)?;
+                    threads.insert(thread, string(&mut body)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            files,
+            callsites,
+            threads,
+        })
+    }
+
+    /// Iterates the records, oldest first.
+    ///
+    /// A record cut short, e.g. by a crash while writing it, yields an
+    /// [`UnexpectedEof`](io::ErrorKind::UnexpectedEof) error and ends its
+    /// file.
+    pub fn records(&self) -> impl Iterator<Item = io::Result<Record>> + '_ {
+        self.files.iter().flat_map(|data| {
+            let mut input = &data[..];
+            std::iter::from_fn(move || {
+                while !input.is_empty() {
+                    let (kind, flags, mut body) = match record(&mut input) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            input = &[];
+                            return Some(Err(e));
+                        }
+                    };
+                    if kind != KIND_POLL {
+                        continue;
+                    }
+                    return Some(poll_record(flags, &mut body));
+                }
+                None
+            })
+        })
+    }
+
+    /// The callsite with the `hash` of a [`Record::callsite`].
+    pub fn callsite(&self, hash: u64) -> Option<&Callsite> {
+        self.callsites.get(&hash)
+    }
+
+    /// The name of the thread with the number of a [`Record::thread`], if
+    /// it is named.
+    pub fn thread_name(&self, thread: u64) -> Option<&str> {
+        self.threads.get(&thread).map(String::as_str)
+    }
+}
+
+/// A blocked poll queued for the writer thread.
+pub(crate) struct PollEntry {
+    pub(crate) timestamp: SystemTime,
+    pub(crate) duration: Duration,
+    pub(crate) target: &'static str,
+    pub(crate) name: &'static str,
+    pub(crate) file: &'static str,
+    pub(crate) line: u32,
+    pub(crate) col: u32,
+    // Number and handle of the polling thread, for its name.
+    pub(crate) tid: u64,
+    pub(crate) thread: Thread,
+    pub(crate) first_poll: bool,
+    pub(crate) escalated: bool,
+}
+
+enum Message {
+    Poll(PollEntry),
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+
+/// Queues records for a dedicated writer thread, which stops once this is
+/// dropped.
+pub(crate) struct EventLog {
+    sender: SyncSender<Message>,
+    dropped: AtomicU64,
+}
+
+impl EventLog {
+    /// Creates the log files, replacing existing ones, and starts the writer
+    /// thread.
+    pub(crate) fn create(path: &Path, max_size: u64) -> io::Result<Self> {
+        match std::fs::remove_file(with_suffix(path, ".1")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut writer = Writer {
+            path: path.to_path_buf(),
+            max_size,
+            records: create(path, RECORDS_MAGIC)?,
+            written: RECORDS_MAGIC.len() as u64,
+            strings: create(&with_suffix(path, ".strings"), STRINGS_MAGIC)?,
+            callsites: HashSet::new(),
+            threads: HashSet::new(),
+            buf: Vec::new(),
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        std::thread::Builder::new()
+            .name("tokio-blocked-event-log".to_string())
+            .spawn(move || {
+                let mut failed = false;
+                loop {
+                    let message = match receiver.try_recv() {
+                        Ok(message) => message,
+                        Err(TryRecvError::Empty) => {
+                            // Idle, so make the queued records durable.
+                            let _ = writer.flush();
+                            match receiver.recv() {
+                                Ok(message) => message,
+                                Err(_) => break,
+                            }
+                        }
+                        Err(TryRecvError::Disconnected) => break,
+                    };
+                    match message {
+                        Message::Poll(entry) => {
+                            if let Err(error) = writer.write(&entry) {
+                                if !failed {
+                                    failed = true;
+                                    tracing::warn!(
+                                        target: "tokio_blocked::event_log",
+                                        path = %writer.path.display(),
+                                        %error,
+                                        "failed to write the event log",
+                                    );
+                                }
+                            }
+                        }
+                        Message::Flush(done) => {
+                            let _ = done.send(writer.flush());
+                        }
+                    }
+                }
+                let _ = writer.flush();
+            })?;
+        Ok(Self {
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queues `entry` without blocking, dropping it if the queue is full.
+    pub(crate) fn record(&self, entry: PollEntry) {
+        if self.sender.try_send(Message::Poll(entry)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the records queued so far are written and flushed.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let (done, wait) = mpsc::channel();
+        let stopped = || io::Error::other("event log writer stopped");
+        self.sender
+            .send(Message::Flush(done))
+            .map_err(|_| stopped())?;
+        wait.recv().map_err(|_| stopped())?
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    records: BufWriter<File>,
+    // Bytes in the current records file.
+    written: u64,
+    strings: BufWriter<File>,
+    // Callsites and threads already in the string table.
+    callsites: HashSet<u64>,
+    threads: HashSet<u64>,
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn write(&mut self, entry: &PollEntry) -> io::Result<()> {
+        let hash = callsite_hash(entry);
+        if self.callsites.insert(hash) {
+            self.buf.clear();
+            put_varint(&mut self.buf, hash);
+            put_string(&mut self.buf, entry.target);
+            put_string(&mut self.buf, entry.name);
+            put_string(&mut self.buf, entry.file);
+            put_varint(&mut self.buf, entry.line.into());
+            put_varint(&mut self.buf, entry.col.into());
+            write_entry(&mut self.strings, KIND_CALLSITE, &self.buf)?;
+        }
+        let thread = entry.tid;
+        if self.threads.insert(thread) {
+            if let Some(name) = entry.thread.name() {
+                self.buf.clear();
+                put_varint(&mut self.buf, thread);
+                put_string(&mut self.buf, name);
+                write_entry(&mut self.strings, KIND_THREAD, &self.buf)?;
+            }
+        }
+        // The string table is flushed first, so records never refer to
+        // entries missing after a crash.
+        self.strings.flush()?;
+
+        self.buf.clear();
+        let timestamp = entry
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        put_varint(&mut self.buf, timestamp);
+        put_varint(&mut self.buf, entry.duration.as_nanos() as u64);
+        put_varint(&mut self.buf, hash);
+        put_varint(&mut self.buf, thread);
+        let mut flags = 0;
+        if entry.first_poll {
+            flags |= FLAG_FIRST_POLL;
+        }
+        if entry.escalated {
+            flags |= FLAG_ESCALATED;
+        }
+        let len = 3 + self.buf.len() as u64;
+        if self.written + len > self.max_size && self.written > RECORDS_MAGIC.len() as u64 {
+            self.rotate()?;
+        }
+        // The body is at most four 10-byte varints.
+        self.records
+            .write_all(&[KIND_POLL, flags, self.buf.len() as u8])?;
+        self.records.write_all(&self.buf)?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.records.flush()?;
+        std::fs::rename(&self.path, with_suffix(&self.path, ".1"))?;
+        self.records = create(&self.path, RECORDS_MAGIC)?;
+        self.written = RECORDS_MAGIC.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.strings.flush()?;
+        self.records.flush()
+    }
+}
+
+fn callsite_hash(entry: &PollEntry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (entry.target, entry.name, entry.file, entry.line, entry.col).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn create(path: &Path, magic: &[u8]) -> io::Result<BufWriter<File>> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(magic)?;
+    out.flush()?;
+    Ok(out)
+}
+
+fn write_entry(out: &mut impl io::Write, kind: u8, body: &[u8]) -> io::Result<()> {
+    let mut header = vec![kind];
+    put_varint(&mut header, body.len() as u64);
+    out.write_all(&header)?;
+    out.write_all(body)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn strip_magic(mut data: Vec<u8>, magic: &[u8]) -> io::Result<Vec<u8>> {
+    if !data.starts_with(magic) {
+        return Err(invalid("not a tokio-blocked event log"));
+    }
+    data.drain(..magic.len());
+    Ok(data)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated event log")
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(eof());
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn varint(input: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = take(input, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn string(input: &mut &[u8]) -> io::Result<String> {
+    let len = varint(input)? as usize;
+    String::from_utf8(take(input, len)?.to_vec()).map_err(|_| invalid("invalid UTF-8"))
+}
+
+/// A string table entry: kind and body.
+fn entry<'a>(input: &mut &'a [u8]) -> io::Result<(u8, &'a [u8])> {
+    let kind = take(input, 1)?[0];
+    let len = varint(input)? as usize;
+    Ok((kind, take(input, len)?))
+}
+
+/// A record: kind, flags and body.
+fn record<'a>(input: &mut &'a [u8]) -> io::Result<(u8, u8, &'a [u8])> {
+    let &[kind, flags, len] = take(input, 3)? else {
+        unreachable!()
+    };
+    Ok((kind, flags, take(input, len.into())?))
+}
+
+fn poll_record(flags: u8, body: &mut &[u8]) -> io::Result<Record> {
+    Ok(Record {
+        timestamp: UNIX_EPOCH + Duration::from_nanos(varint(body)?),
+        duration: Duration::from_nanos(varint(body)?),
+        callsite: varint(body)?,
+        thread: varint(body)?,
+        first_poll: flags & FLAG_FIRST_POLL != 0,
+        escalated: flags & FLAG_ESCALATED != 0,
+    })
+}
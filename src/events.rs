@@ -0,0 +1,529 @@
+//! Structured descriptions of blocked polls and spans.
+//!
+//! The layer builds one of these per warning and passes the same value to the
+//! [`TracingObserver`](crate::TracingObserver), custom
+//! [observers](crate::BlockedObserver) and callbacks, so all of them see
+//! consistent data.
+
+use std::{borrow::Cow, thread::ThreadId, time::Duration};
+
+use tracing::Level;
+
+use crate::{breadcrumbs::Breadcrumb, pattern::BlockingPattern, time::SystemTime};
+
+/// Details of a single poll that exceeded the single-poll threshold.
+///
+/// Passed to the [`with_on_blocked`](crate::TokioBlockedLayer::with_on_blocked)
+/// callback.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct BlockedPollInfo<'a> {
+    /// 1-based number of the warning among the poll and total warnings the
+    /// layer emitted, see
+    /// [`events_emitted`](crate::BlockedStatsHandle::events_emitted). Zero
+    /// if it is not emitted, and in the
+    /// [`with_on_blocked`](crate::TokioBlockedLayer::with_on_blocked)
+    /// callback, which runs before that is decided.
+    pub seq: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "poll_duration_ns", with = "ser::nanos")
+    )]
+    pub poll_duration: Duration,
+    /// The configured single-poll threshold.
+    #[cfg_attr(feature = "serde", serde(rename = "threshold_ns", with = "ser::nanos"))]
+    pub threshold: Duration,
+    /// The threshold the poll was compared against: `threshold` scaled by
+    /// [`with_adaptive_thresholds`](crate::TokioBlockedLayer::with_adaptive_thresholds),
+    /// or `threshold` itself.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "effective_threshold_ns", with = "ser::nanos")
+    )]
+    pub effective_threshold: Duration,
+    /// Measured load the threshold was scaled with, if adaptive thresholds
+    /// are enabled.
+    pub load: Option<f64>,
+    /// 1-based number of this poll within the span.
+    pub poll_seq: u64,
+    /// Number of exceeded polls of the callsite so far, including this one.
+    pub exceed_count: u64,
+    /// Busy time of the span so far, including this poll.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "span_busy_ns_so_far", with = "ser::nanos")
+    )]
+    pub span_busy: Duration,
+    /// Time since the span was created.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "span_lifetime_ns_so_far", with = "ser::nanos")
+    )]
+    pub span_lifetime: Duration,
+    /// `span_busy` as a fraction of `span_lifetime`, `0.0` for a zero
+    /// lifetime. Tells a task that is busy most of the time apart from a
+    /// mostly idle one that blocked once.
+    pub span_busy_ratio: f64,
+    pub callsite_name: &'static str,
+    pub callsite_target: &'static str,
+    /// Location of the span callsite.
+    pub callsite_file: Option<&'static str>,
+    pub callsite_line: Option<u32>,
+    /// Spawn location recorded on the span (`loc.*` fields), if any.
+    pub origin_file: Option<&'static str>,
+    pub origin_line: Option<u32>,
+    pub origin_col: Option<u32>,
+    /// `task.name` of tokio task spans.
+    pub task_name: Option<Cow<'a, str>>,
+    /// `task.id` of tokio task spans.
+    pub task_id: Option<u64>,
+    /// The thread that ran the poll.
+    #[cfg_attr(feature = "serde", serde(with = "ser::debug"))]
+    pub thread_id: ThreadId,
+    pub thread_name: Option<Cow<'a, str>>,
+    /// Wall-clock time at the end of the poll.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "timestamp_unix_ns", with = "ser::unix_nanos")
+    )]
+    pub timestamp: SystemTime,
+    /// Level of the warning event, raised by
+    /// [`with_escalation`](crate::TokioBlockedLayer::with_escalation).
+    #[cfg_attr(feature = "serde", serde(with = "ser::level"))]
+    pub level: Level,
+    /// Threshold of the severity tier that determined `level`, see
+    /// [`with_poll_severity_tiers`](crate::TokioBlockedLayer::with_poll_severity_tiers).
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "tier_threshold_ns", with = "ser::opt_nanos")
+    )]
+    pub tier_threshold: Option<Duration>,
+    pub escalated: bool,
+    /// Resource of async op spans, see [`CallsiteId`](crate::CallsiteId).
+    pub resource_concrete_type: Option<&'static str>,
+    pub resource_kind: Option<&'static str>,
+    pub resource_source: Option<&'static str>,
+    /// Task the async op was created in, see
+    /// [`ParentTask`](crate::ParentTask).
+    pub parent_task_file: Option<&'static str>,
+    pub parent_task_line: Option<u32>,
+    pub parent_task_name: Option<&'static str>,
+    /// Span id of the parent task span.
+    pub parent_task_span_id: Option<u64>,
+    /// Id of the span, as reported by other layers of the subscriber, e.g.
+    /// to join the warning with other logs of the task.
+    pub span_id: u64,
+    /// Id of the parent span, `None` for tokio task spans, which are created
+    /// without a parent.
+    pub span_parent_id: Option<u64>,
+    /// CPU time of the polling thread during the poll, see
+    /// [`with_cpu_time`](crate::TokioBlockedLayer::with_cpu_time).
+    #[cfg_attr(feature = "serde", serde(rename = "cpu_ns", with = "ser::opt_nanos"))]
+    pub cpu: Option<Duration>,
+    /// `cpu` as a percentage of `poll_duration`. Low for a poll waiting on a
+    /// syscall or lock, high for one that is computing.
+    pub on_cpu_percent: Option<f64>,
+    /// Label of the runtime, see
+    /// [`with_runtime_label`](crate::TokioBlockedLayer::with_runtime_label).
+    pub runtime: Option<&'static str>,
+    /// The spans entered on the thread when the poll ended, formatted as a
+    /// `tracing_error::SpanTrace`.
+    ///
+    /// Requires the `span-trace` feature and a
+    /// `tracing_error::ErrorLayer` in the subscriber, `None` otherwise or if
+    /// no span was entered. Tokio polls tasks outside of any span, so this
+    /// is mostly useful for async ops, where it holds the task and the user
+    /// spans the op was polled in.
+    pub span_trace: Option<String>,
+    /// The last events emitted inside the span, oldest first, see
+    /// [`with_breadcrumbs`](crate::TokioBlockedLayer::with_breadcrumbs).
+    pub breadcrumbs: Vec<Breadcrumb>,
+}
+
+impl BlockedPollInfo<'_> {
+    /// Copies the borrowed strings, e.g. to send the info to another thread.
+    pub fn into_owned(self) -> BlockedPollInfo<'static> {
+        BlockedPollInfo {
+            seq: self.seq,
+            poll_duration: self.poll_duration,
+            threshold: self.threshold,
+            effective_threshold: self.effective_threshold,
+            load: self.load,
+            poll_seq: self.poll_seq,
+            exceed_count: self.exceed_count,
+            span_busy: self.span_busy,
+            span_lifetime: self.span_lifetime,
+            span_busy_ratio: self.span_busy_ratio,
+            callsite_name: self.callsite_name,
+            callsite_target: self.callsite_target,
+            callsite_file: self.callsite_file,
+            callsite_line: self.callsite_line,
+            origin_file: self.origin_file,
+            origin_line: self.origin_line,
+            origin_col: self.origin_col,
+            task_name: self.task_name.map(|n| Cow::Owned(n.into_owned())),
+            task_id: self.task_id,
+            thread_id: self.thread_id,
+            thread_name: self.thread_name.map(|n| Cow::Owned(n.into_owned())),
+            timestamp: self.timestamp,
+            level: self.level,
+            tier_threshold: self.tier_threshold,
+            escalated: self.escalated,
+            resource_concrete_type: self.resource_concrete_type,
+            resource_kind: self.resource_kind,
+            resource_source: self.resource_source,
+            parent_task_file: self.parent_task_file,
+            parent_task_line: self.parent_task_line,
+            parent_task_name: self.parent_task_name,
+            parent_task_span_id: self.parent_task_span_id,
+            span_id: self.span_id,
+            span_parent_id: self.span_parent_id,
+            cpu: self.cpu,
+            on_cpu_percent: self.on_cpu_percent,
+            runtime: self.runtime,
+            span_trace: self.span_trace,
+            breadcrumbs: self.breadcrumbs,
+        }
+    }
+
+    /// Whether this was the first poll of a task, which blocks typically due
+    /// to synchronous initialization such as reading config files or
+    /// connecting with blocking clients.
+    pub fn is_first_task_poll(&self) -> bool {
+        self.poll_seq == 1 && self.callsite_name == "runtime.spawn"
+    }
+
+    /// `task.name` of the task, or of the task an async op was created in.
+    pub(crate) fn owning_task_name(&self) -> Option<&str> {
+        self.task_name.as_deref().or(self.parent_task_name)
+    }
+
+    /// Spawn location if known, or the span callsite location otherwise.
+    pub(crate) fn location(&self) -> (&'static str, u32, u32) {
+        location(
+            self.origin_file.or(self.callsite_file),
+            self.origin_line.or(self.callsite_line),
+            self.origin_col,
+        )
+    }
+}
+
+/// Details of a closed span whose total busy time exceeded the total
+/// threshold.
+///
+/// Passed to the
+/// [`with_on_blocked_total`](crate::TokioBlockedLayer::with_on_blocked_total)
+/// callback.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct BlockedTotalInfo<'a> {
+    /// See [`BlockedPollInfo::seq`].
+    pub seq: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "busy_ns", with = "ser::nanos"))]
+    pub busy: Duration,
+    /// Time from span creation to close.
+    #[cfg_attr(feature = "serde", serde(rename = "lifetime_ns", with = "ser::nanos"))]
+    pub lifetime: Duration,
+    /// The total threshold, or `None` if the span is only reported as a
+    /// [per-task summary](crate::TokioBlockedLayer::with_per_task_summary).
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "threshold_ns", with = "ser::opt_nanos")
+    )]
+    pub threshold: Option<Duration>,
+    /// Level of the warning event, see
+    /// [`with_total_severity_tiers`](crate::TokioBlockedLayer::with_total_severity_tiers).
+    #[cfg_attr(feature = "serde", serde(with = "ser::level"))]
+    pub level: Level,
+    /// Threshold of the severity tier that determined `level`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "tier_threshold_ns", with = "ser::opt_nanos")
+    )]
+    pub tier_threshold: Option<Duration>,
+    /// `busy` as a percentage of `lifetime`.
+    pub blocked_percent: f64,
+    /// Number of polls of the span.
+    pub polls: u64,
+    /// Longest poll of the span.
+    #[cfg_attr(feature = "serde", serde(rename = "max_poll_ns", with = "ser::nanos"))]
+    pub max_poll: Duration,
+    /// 1-based number of the longest poll within the span, or `0` if the
+    /// span was never polled.
+    pub max_poll_seq: u64,
+    /// Number of polls that exceeded the single-poll threshold.
+    pub polls_over_threshold: u64,
+    /// How the busy time is spread over the polls, `None` if the span was
+    /// never polled.
+    pub pattern: Option<BlockingPattern>,
+    /// Polls on a different thread than the previous poll of the span, only
+    /// counted with
+    /// [`with_warn_migrations`](crate::TokioBlockedLayer::with_warn_migrations).
+    pub migrations: u64,
+    pub callsite_name: &'static str,
+    pub callsite_target: &'static str,
+    /// Location of the span callsite.
+    pub callsite_file: Option<&'static str>,
+    pub callsite_line: Option<u32>,
+    /// Spawn location recorded on the span (`loc.*` fields), if any.
+    pub origin_file: Option<&'static str>,
+    pub origin_line: Option<u32>,
+    pub origin_col: Option<u32>,
+    /// `task.name` of tokio task spans.
+    pub task_name: Option<Cow<'a, str>>,
+    /// `task.id` of tokio task spans.
+    pub task_id: Option<u64>,
+    /// Resource of async op spans, see [`CallsiteId`](crate::CallsiteId).
+    pub resource_concrete_type: Option<&'static str>,
+    pub resource_kind: Option<&'static str>,
+    pub resource_source: Option<&'static str>,
+    /// Task the async op was created in, see
+    /// [`ParentTask`](crate::ParentTask).
+    pub parent_task_file: Option<&'static str>,
+    pub parent_task_line: Option<u32>,
+    pub parent_task_name: Option<&'static str>,
+    /// Span id of the parent task span.
+    pub parent_task_span_id: Option<u64>,
+    /// Id of the span, as reported by other layers of the subscriber, e.g.
+    /// to join the warning with other logs of the task.
+    pub span_id: u64,
+    /// Id of the parent span, `None` for tokio task spans, which are created
+    /// without a parent.
+    pub span_parent_id: Option<u64>,
+    /// CPU time of the polling thread during all polls of the span, see
+    /// [`with_cpu_time`](crate::TokioBlockedLayer::with_cpu_time).
+    #[cfg_attr(feature = "serde", serde(rename = "cpu_ns", with = "ser::opt_nanos"))]
+    pub cpu: Option<Duration>,
+    /// `cpu` as a percentage of `busy`.
+    pub on_cpu_percent: Option<f64>,
+    /// Label of the runtime, see
+    /// [`with_runtime_label`](crate::TokioBlockedLayer::with_runtime_label).
+    pub runtime: Option<&'static str>,
+    /// Whether the span is still open, i.e. reported when it crossed the
+    /// threshold with
+    /// [`with_warn_busy_total_eager`](crate::TokioBlockedLayer::with_warn_busy_total_eager).
+    pub span_still_open: bool,
+    /// Wall-clock time at which the span was closed, or crossed the
+    /// threshold if it is still open.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "timestamp_unix_ns", with = "ser::unix_nanos")
+    )]
+    pub timestamp: SystemTime,
+}
+
+impl BlockedTotalInfo<'_> {
+    /// Copies the borrowed strings, e.g. to send the info to another thread.
+    pub fn into_owned(self) -> BlockedTotalInfo<'static> {
+        BlockedTotalInfo {
+            seq: self.seq,
+            busy: self.busy,
+            lifetime: self.lifetime,
+            threshold: self.threshold,
+            level: self.level,
+            tier_threshold: self.tier_threshold,
+            blocked_percent: self.blocked_percent,
+            polls: self.polls,
+            max_poll: self.max_poll,
+            max_poll_seq: self.max_poll_seq,
+            polls_over_threshold: self.polls_over_threshold,
+            pattern: self.pattern,
+            migrations: self.migrations,
+            callsite_name: self.callsite_name,
+            callsite_target: self.callsite_target,
+            callsite_file: self.callsite_file,
+            callsite_line: self.callsite_line,
+            origin_file: self.origin_file,
+            origin_line: self.origin_line,
+            origin_col: self.origin_col,
+            task_name: self.task_name.map(|n| Cow::Owned(n.into_owned())),
+            task_id: self.task_id,
+            resource_concrete_type: self.resource_concrete_type,
+            resource_kind: self.resource_kind,
+            resource_source: self.resource_source,
+            parent_task_file: self.parent_task_file,
+            parent_task_line: self.parent_task_line,
+            parent_task_name: self.parent_task_name,
+            parent_task_span_id: self.parent_task_span_id,
+            span_id: self.span_id,
+            span_parent_id: self.span_parent_id,
+            cpu: self.cpu,
+            on_cpu_percent: self.on_cpu_percent,
+            runtime: self.runtime,
+            span_still_open: self.span_still_open,
+            timestamp: self.timestamp,
+        }
+    }
+
+    /// Mean poll duration, `busy` divided by `polls`, or `None` if the span
+    /// was never polled. Tells one long poll from many short ones.
+    pub fn mean_poll(&self) -> Option<Duration> {
+        (self.polls > 0)
+            .then(|| Duration::from_nanos((self.busy.as_nanos() / self.polls as u128) as u64))
+    }
+
+    /// `task.name` of the task, or of the task an async op was created in.
+    pub(crate) fn owning_task_name(&self) -> Option<&str> {
+        self.task_name.as_deref().or(self.parent_task_name)
+    }
+
+    /// Spawn location if known, or the span callsite location otherwise.
+    pub(crate) fn location(&self) -> (&'static str, u32, u32) {
+        location(
+            self.origin_file.or(self.callsite_file),
+            self.origin_line.or(self.callsite_line),
+            self.origin_col,
+        )
+    }
+}
+
+/// A callsite whose spans reached the total threshold in a row, passed to
+/// the [`with_consecutive_alert`](crate::TokioBlockedLayer::with_consecutive_alert)
+/// callback.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct SustainedBlockingInfo<'a> {
+    /// Number of consecutive spans over the threshold, the configured count.
+    pub streak: u64,
+    /// The total threshold.
+    #[cfg_attr(feature = "serde", serde(rename = "threshold_ns", with = "ser::nanos"))]
+    pub threshold: Duration,
+    /// Busy time of the span that completed the streak.
+    #[cfg_attr(feature = "serde", serde(rename = "busy_ns", with = "ser::nanos"))]
+    pub busy: Duration,
+    pub callsite_name: &'static str,
+    pub callsite_target: &'static str,
+    /// Spawn location if known, or the span callsite location otherwise.
+    pub file: &'static str,
+    pub line: u32,
+    pub col: u32,
+    /// `task.name` of tokio task spans.
+    pub task_name: Option<&'a str>,
+    /// Wall-clock time at which the span that completed the streak closed.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "timestamp_unix_ns", with = "ser::unix_nanos")
+    )]
+    pub timestamp: SystemTime,
+}
+
+/// An event received from [`BlockedStatsHandle::subscribe`](crate::BlockedStatsHandle::subscribe).
+///
+/// Requires the `channel` feature.
+#[cfg(feature = "channel")]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub enum BlockedEvent {
+    PollBlocked(BlockedPollInfo<'static>),
+    TotalBlocked(BlockedTotalInfo<'static>),
+}
+
+/// A warning kept by
+/// [`with_warning_history`](crate::TokioBlockedLayer::with_warning_history),
+/// see [`BlockedStatsHandle::recent_warnings`](crate::BlockedStatsHandle::recent_warnings).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HistoricalWarning {
+    pub warning: WarningInfo,
+    /// Whether no event was emitted, because warnings were disabled, the
+    /// location is muted or exempt, or the dedup window folded it into a
+    /// summary. Only kept with
+    /// [`with_warning_history_suppressed`](crate::TokioBlockedLayer::with_warning_history_suppressed).
+    pub suppressed: bool,
+}
+
+impl HistoricalWarning {
+    /// Wall-clock time of the warning.
+    pub fn timestamp(&self) -> SystemTime {
+        match &self.warning {
+            WarningInfo::PollBlocked(info) => info.timestamp,
+            WarningInfo::TotalBlocked(info) => info.timestamp,
+        }
+    }
+}
+
+/// The details of a [`HistoricalWarning`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub enum WarningInfo {
+    PollBlocked(BlockedPollInfo<'static>),
+    TotalBlocked(BlockedTotalInfo<'static>),
+}
+
+fn location(
+    file: Option<&'static str>,
+    line: Option<u32>,
+    col: Option<u32>,
+) -> (&'static str, u32, u32) {
+    (
+        file.unwrap_or("<unknown>"),
+        line.unwrap_or(0),
+        col.unwrap_or(0),
+    )
+}
+
+/// Serializers for fields without a suitable `Serialize` impl.
+#[cfg(feature = "serde")]
+pub(crate) mod ser {
+    pub(super) mod nanos {
+        use std::time::Duration;
+
+        pub(crate) fn serialize<S: serde::Serializer>(
+            d: &Duration,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            s.serialize_u64(d.as_nanos() as u64)
+        }
+    }
+
+    pub(super) mod opt_nanos {
+        use std::time::Duration;
+
+        pub(crate) fn serialize<S: serde::Serializer>(
+            d: &Option<Duration>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            match d {
+                Some(d) => s.serialize_some(&(d.as_nanos() as u64)),
+                None => s.serialize_none(),
+            }
+        }
+    }
+
+    pub(crate) mod unix_nanos {
+        use crate::time::{SystemTime, UNIX_EPOCH};
+
+        pub(crate) fn serialize<S: serde::Serializer>(
+            t: &SystemTime,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            let nanos = t
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            s.serialize_u64(nanos)
+        }
+    }
+
+    pub(crate) mod level {
+        pub(crate) fn serialize<S: serde::Serializer>(
+            l: &tracing::Level,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            s.serialize_str(l.as_str())
+        }
+    }
+
+    pub(super) mod debug {
+        pub(crate) fn serialize<T: std::fmt::Debug, S: serde::Serializer>(
+            value: &T,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            s.collect_str(&format_args!("{value:?}"))
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::OnceLock;
+
+use crate::{BlockedStatsHandle, CallsiteStatsSnapshot, TokioBlockedLayer};
+
+static GLOBAL: OnceLock<BlockedStatsHandle> = OnceLock::new();
+
+/// Returned by the global accessors, see
+/// [`TokioBlockedLayer::install_global`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GlobalError {
+    /// A layer was already installed globally.
+    AlreadyInstalled,
+    /// No layer was installed globally.
+    NotInstalled,
+}
+
+impl std::fmt::Display for GlobalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AlreadyInstalled => {
+                f.write_str("a global tokio-blocked layer is already installed")
+            }
+            Self::NotInstalled => f.write_str("no global tokio-blocked layer is installed"),
+        }
+    }
+}
+
+impl std::error::Error for GlobalError {}
+
+impl TokioBlockedLayer {
+    /// Makes the statistics of this layer available through [`stats`],
+    /// [`snapshot`] and [`reset`], so small binaries don't need to pass a
+    /// [`BlockedStatsHandle`] around.
+    ///
+    /// Returns the layer to add to the subscriber as usual. Only one layer
+    /// can be installed per process, later calls fail with
+    /// [`GlobalError::AlreadyInstalled`].
+    ///
+    /// ```rust
+    /// use tokio_blocked::TokioBlockedLayer;
+    /// use tracing_subscriber::layer::SubscriberExt as _;
+    ///
+    /// let layer = TokioBlockedLayer::new().install_global()?;
+    /// let _subscriber = tracing_subscriber::registry().with(layer);
+    ///
+    /// let callsites = tokio_blocked::snapshot();
+    /// # Ok::<(), tokio_blocked::GlobalError>(())
+    /// ```
+    pub fn install_global(self) -> Result<Self, GlobalError> {
+        GLOBAL
+            .set(self.handle())
+            .map_err(|_| GlobalError::AlreadyInstalled)?;
+        Ok(self)
+    }
+}
+
+/// The handle of the layer installed with
+/// [`TokioBlockedLayer::install_global`], if any.
+pub fn stats() -> Option<BlockedStatsHandle> {
+    GLOBAL.get().cloned()
+}
+
+/// [`BlockedStatsHandle::snapshot`] of the globally installed layer, empty
+/// if none is installed.
+pub fn snapshot() -> Vec<CallsiteStatsSnapshot> {
+    GLOBAL.get().map(|h| h.snapshot()).unwrap_or_default()
+}
+
+/// [`BlockedStatsHandle::reset`] of the globally installed layer.
+pub fn reset() -> Result<(), GlobalError> {
+    GLOBAL.get().ok_or(GlobalError::NotInstalled)?.reset();
+    Ok(())
+}
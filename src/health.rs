@@ -0,0 +1,136 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::time::Instant;
+
+/// Number of buckets the retention of the health window is split into.
+const BUCKETS: usize = 60;
+
+/// How much a runtime was blocked recently, returned by
+/// [`BlockedStatsHandle::health`](crate::BlockedStatsHandle::health).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockedHealth {
+    /// The window that was covered, the requested one rounded up to whole
+    /// buckets and capped at the retention.
+    pub window: Duration,
+    /// Sum of the task polls that reached the single-poll threshold.
+    pub blocked_time: Duration,
+    /// Number of task polls that reached the single-poll threshold.
+    pub blocked_polls: u64,
+    /// Longest task poll, blocking or not.
+    pub max_poll: Duration,
+}
+
+/// Limits for [`BlockedHealth::is_healthy`]. Unset limits always pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HealthThresholds {
+    /// Maximum [`blocked_time`](BlockedHealth::blocked_time).
+    pub blocked_time: Option<Duration>,
+    /// Maximum [`max_poll`](BlockedHealth::max_poll).
+    pub max_poll: Option<Duration>,
+}
+
+impl BlockedHealth {
+    /// Whether the window stayed within `thresholds`.
+    pub fn is_healthy(&self, thresholds: &HealthThresholds) -> bool {
+        thresholds
+            .blocked_time
+            .is_none_or(|max| self.blocked_time <= max)
+            && thresholds.max_poll.is_none_or(|max| self.max_poll <= max)
+    }
+}
+
+/// Blocked time per time bucket, as a ring of the last [`BUCKETS`] buckets,
+/// updated lock-free on every task poll.
+///
+/// A bucket is reset by the first poll that ends in a newer bucket mapping to
+/// the same slot. Polls racing with the reset may be lost, which is fine for
+/// a health indicator.
+pub(crate) struct HealthTracker {
+    bucket_len: Duration,
+    // End of the first recorded poll, so the tracker doesn't depend on the
+    // clock it was created with.
+    epoch: OnceLock<Instant>,
+    buckets: Box<[Bucket]>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    // Index of the bucket plus one, zero if unused.
+    index: AtomicU64,
+    blocked_ns: AtomicU64,
+    blocked_polls: AtomicU64,
+    max_poll_ns: AtomicU64,
+}
+
+impl HealthTracker {
+    pub(crate) fn new(retention: Duration) -> Self {
+        Self {
+            bucket_len: (retention / BUCKETS as u32).max(Duration::from_nanos(1)),
+            epoch: OnceLock::new(),
+            buckets: (0..BUCKETS).map(|_| Bucket::default()).collect(),
+        }
+    }
+
+    fn index(&self, epoch: Instant, at: Instant) -> u64 {
+        (at.saturating_duration_since(epoch).as_nanos() / self.bucket_len.as_nanos()) as u64
+    }
+
+    /// Adds a task poll that ended at `end`.
+    pub(crate) fn record(&self, poll: Duration, end: Instant, blocked: bool) {
+        let epoch = *self.epoch.get_or_init(|| end);
+        let index = self.index(epoch, end);
+        let bucket = &self.buckets[index as usize % BUCKETS];
+        let seen = bucket.index.load(Ordering::Relaxed);
+        if seen != index + 1
+            && bucket
+                .index
+                .compare_exchange(seen, index + 1, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            bucket.blocked_ns.store(0, Ordering::Relaxed);
+            bucket.blocked_polls.store(0, Ordering::Relaxed);
+            bucket.max_poll_ns.store(0, Ordering::Relaxed);
+        }
+        let poll_ns = poll.as_nanos() as u64;
+        if blocked {
+            bucket.blocked_ns.fetch_add(poll_ns, Ordering::Relaxed);
+            bucket.blocked_polls.fetch_add(1, Ordering::Relaxed);
+        }
+        bucket.max_poll_ns.fetch_max(poll_ns, Ordering::Relaxed);
+    }
+
+    /// Sums the buckets of the last `window` before `now`.
+    pub(crate) fn health(&self, window: Duration, now: Instant) -> BlockedHealth {
+        let count = window
+            .as_nanos()
+            .div_ceil(self.bucket_len.as_nanos())
+            .clamp(1, BUCKETS as u128) as u64;
+        let mut health = BlockedHealth {
+            window: self.bucket_len * count as u32,
+            ..Default::default()
+        };
+        let Some(&epoch) = self.epoch.get() else {
+            return health;
+        };
+        let current = self.index(epoch, now);
+        let oldest = (current + 1).saturating_sub(count);
+        for bucket in self.buckets.iter() {
+            let index = bucket.index.load(Ordering::Relaxed);
+            if index == 0 || !(oldest..=current).contains(&(index - 1)) {
+                continue;
+            }
+            health.blocked_time += Duration::from_nanos(bucket.blocked_ns.load(Ordering::Relaxed));
+            health.blocked_polls += bucket.blocked_polls.load(Ordering::Relaxed);
+            health.max_poll = health.max_poll.max(Duration::from_nanos(
+                bucket.max_poll_ns.load(Ordering::Relaxed),
+            ));
+        }
+        health
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Number of default bucket boundaries: powers of two from 1µs to ~8.4s.
+const DEFAULT_BUCKETS: u32 = 24;
+
+/// Distribution of poll durations of a callsite.
+///
+/// Bucket `i` counts polls up to and including `bounds()[i]` (and longer than
+/// the previous bound); the last bucket counts polls longer than the last
+/// bound.
+///
+/// Only available with
+/// [`with_poll_histogram`](crate::TokioBlockedLayer::with_poll_histogram).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Histogram {
+    #[cfg_attr(feature = "serde", serde(rename = "bounds_ns", with = "nanos_vec"))]
+    bounds: Vec<Duration>,
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Upper bounds of the buckets, in increasing order.
+    pub fn bounds(&self) -> &[Duration] {
+        &self.bounds
+    }
+
+    /// Number of polls per bucket, one more than there are bounds.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Total number of recorded polls.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Estimates the `percentile` (`0.0..=100.0`) poll duration.
+    ///
+    /// The polls of a bucket are assumed to be spread evenly between the
+    /// previous bound (or zero) and its own bound, so the k-th of n polls in
+    /// a bucket is estimated at k/n of the way. Polls longer than the last
+    /// bound are reported as the last bound.
+    ///
+    /// Returns `None` if nothing was recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = (((percentile.clamp(0.0, 100.0) / 100.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            if seen + count < rank {
+                seen += count;
+                continue;
+            }
+            let Some(&upper) = self.bounds.get(i) else {
+                break;
+            };
+            let lower = i.checked_sub(1).map_or(Duration::ZERO, |i| self.bounds[i]);
+            let fraction = (rank - seen) as f64 / count as f64;
+            return Some(lower + (upper - lower).mul_f64(fraction));
+        }
+        self.bounds.last().copied()
+    }
+}
+
+/// The default bucket bounds, see [`DEFAULT_BUCKETS`].
+pub(crate) fn default_bounds() -> Arc<[Duration]> {
+    (0..DEFAULT_BUCKETS)
+        .map(|i| Duration::from_micros(1 << i))
+        .collect()
+}
+
+/// Lock-free histogram updated on every poll.
+#[derive(Debug)]
+pub(crate) struct AtomicHistogram {
+    // Shared by the histograms of all callsites.
+    bounds: Arc<[Duration]>,
+    counts: Box<[AtomicU64]>,
+}
+
+impl AtomicHistogram {
+    pub(crate) fn new(bounds: Arc<[Duration]>) -> Self {
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self { bounds, counts }
+    }
+
+    /// Heap memory of the counters. The shared bounds are not included.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.counts.len() * std::mem::size_of::<AtomicU64>()
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let bucket = self.bounds.partition_point(|bound| *bound < duration);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn load(&self) -> Histogram {
+        Histogram {
+            bounds: self.bounds.to_vec(),
+            counts: self
+                .counts
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Serializes a list of [`Duration`]s as integer nanoseconds.
+#[cfg(feature = "serde")]
+mod nanos_vec {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(d: &[Duration], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(d.iter().map(|d| d.as_nanos() as u64))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Duration>, D::Error> {
+        Vec::<u64>::deserialize(d).map(|v| v.into_iter().map(Duration::from_nanos).collect())
+    }
+}
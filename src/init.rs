@@ -0,0 +1,55 @@
+use tracing::{level_filters::LevelFilter, Subscriber};
+use tracing_subscriber::{
+    layer::SubscriberExt as _,
+    util::{SubscriberInitExt as _, TryInitError},
+    EnvFilter, Layer as _,
+};
+
+use crate::{BlockedStatsHandle, TokioBlockedLayer};
+
+/// Installs a global subscriber with a fmt layer and a [`TokioBlockedLayer`]
+/// with the default configuration.
+///
+/// See [`init_with`].
+pub fn init() -> Result<BlockedStatsHandle, TryInitError> {
+    init_with(TokioBlockedLayer::new())
+}
+
+/// Installs a global subscriber with a fmt layer and `layer`, returning the
+/// handle to its statistics.
+///
+/// The fmt layer is filtered by `RUST_LOG`, showing warnings if it is not
+/// set; `layer` sees all spans either way. Fails if a global subscriber is
+/// already set.
+///
+/// Requires the `init` feature.
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use tokio_blocked::TokioBlockedLayer;
+///
+/// let handle = tokio_blocked::init_with(
+///     TokioBlockedLayer::new().with_warn_busy_single_poll(Some(Duration::from_micros(150))),
+/// )?;
+/// # Ok::<(), tracing_subscriber::util::TryInitError>(())
+/// ```
+pub fn init_with(layer: TokioBlockedLayer) -> Result<BlockedStatsHandle, TryInitError> {
+    let (subscriber, handle) = subscriber_with(layer);
+    subscriber.try_init()?;
+    Ok(handle)
+}
+
+/// The subscriber installed by [`init_with`], e.g. to install it only for a
+/// scope with [`tracing::subscriber::set_default`].
+pub fn subscriber_with(
+    layer: TokioBlockedLayer,
+) -> (impl Subscriber + Send + Sync + 'static, BlockedStatsHandle) {
+    let handle = layer.handle();
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::WARN.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(filter))
+        .with(layer);
+    (subscriber, handle)
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use crate::snapshot::{CallsiteStatsSnapshot, ParentTask};
+
+/// Identifies the row of a callsite across snapshots, for the per-interval
+/// sinks.
+pub(crate) type RowKey = (
+    &'static str,
+    &'static str,
+    Option<&'static str>,
+    Option<u32>,
+    Option<&'static str>,
+    Option<&'static str>,
+    Option<&'static str>,
+    bool,
+    Option<ParentTask>,
+    Option<&'static str>,
+);
+
+/// The cumulative totals of a row, to compute the activity of an interval.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Totals {
+    pub(crate) busy: Duration,
+    pub(crate) polls: u64,
+    pub(crate) spans: u64,
+}
+
+impl Totals {
+    pub(crate) fn of(row: &CallsiteStatsSnapshot) -> Self {
+        Self {
+            busy: row.total_busy,
+            polls: row.polls,
+            spans: row.count,
+        }
+    }
+
+    /// Activity since `previous`. After a reset of the statistics, the totals
+    /// are smaller than before and are taken as the delta themselves.
+    pub(crate) fn since(self, previous: Totals) -> Totals {
+        if self.spans < previous.spans || self.polls < previous.polls {
+            return self;
+        }
+        Totals {
+            busy: self.busy.saturating_sub(previous.busy),
+            polls: self.polls - previous.polls,
+            spans: self.spans - previous.spans,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.polls == 0 && self.spans == 0 && self.busy.is_zero()
+    }
+}
+
+pub(crate) fn key(row: &CallsiteStatsSnapshot) -> RowKey {
+    (
+        row.target,
+        row.name,
+        row.file,
+        row.line,
+        row.resource_type,
+        row.async_op_source,
+        row.task_name,
+        row.lock_wait,
+        row.parent_task,
+        row.runtime,
+    )
+}
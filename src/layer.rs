@@ -1,187 +1,4584 @@
 use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    borrow::Cow,
+    cell::Cell,
     collections::{HashMap, HashSet},
-    sync::Mutex,
-    time::{Duration, Instant},
+    fmt::Write as _,
+    num::{NonZeroU32, NonZeroU64},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+    },
+    thread::ThreadId,
+    time::Duration,
 };
 
 use tracing::{self, Level};
 use tracing_core::{callsite::Identifier, field::Visit, span, subscriber, Field, Metadata};
-use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use tracing_subscriber::{
+    filter::FilterFn,
+    layer::Context,
+    registry::{LookupSpan, SpanRef},
+    Layer,
+};
+
+#[cfg(feature = "channel")]
+use crate::events::BlockedEvent;
+use crate::{
+    adaptive::{AdaptiveThresholds, LoadTracker},
+    breadcrumbs::{Breadcrumb, Breadcrumbs},
+    chrome_trace::{self, TraceRecorder},
+    clock::{Clock, SystemClock},
+    cpu_time,
+    dedup::{Dedup, DedupSummary},
+    event_log::{EventLog, PollEntry},
+    events::{
+        BlockedPollInfo, BlockedTotalInfo, HistoricalWarning, SustainedBlockingInfo, WarningInfo,
+    },
+    health::{BlockedHealth, HealthTracker},
+    histogram::{self, AtomicHistogram},
+    observer::{
+        self, BlockedObserver, MetricNaming, OnBlocked, OnBlockedTotal, OnSustained,
+        TracingObserver,
+    },
+    pattern::{BlockingPattern, PatternCounts, PatternThresholds},
+    recent::{PollSample, RecentPolls, WarningHistory},
+    report::MarkdownOptions,
+    saturation::{Saturated, SaturationThresholds, SaturationTracker},
+    selector::CallsiteSelector,
+    snapshot::{
+        CallsiteId, CallsiteStatsSnapshot, Category, GroupKey, GroupStats, OwnedCallsiteStats,
+        ParentTask, Snapshot, TaskOps,
+    },
+    time::{Instant, SystemTime},
+};
+
+/// A standalone layer that measures "busy" time per callsite (span metadata),
+/// and records each measured duration as a tracing event.
+///
+/// Busy time is measured as the wall-clock time between a span's enter and the
+/// matching exit, counting only the outermost enter/exit pairs per span
+/// instance (nested enters are ignored to avoid double-counting).
+///
+/// With several layers on the same registry, each span is tracked by the
+/// first layer that sees it. Spans entered while a layer handles a span,
+/// e.g. by another layer reacting to its warnings, are not tracked.
+pub struct TokioBlockedLayer {
+    // State shared with [`BlockedStatsHandle`]s.
+    shared: Arc<Shared>,
+    // Locally cached set of callsites to consider.
+    // Caching speeds up performance.
+    allowed_callsites: Mutex<HashSet<Identifier>>,
+    // Warn if a single outermost poll exceeds this duration.
+    warn_busy_single_poll: Option<Duration>,
+    // Scales `warn_busy_single_poll` with the measured load, if enabled.
+    adaptive: Option<LoadTracker>,
+    health: Option<Arc<HealthTracker>>,
+    fraction_gauge: Option<Duration>,
+    saturation: Option<Arc<SaturationTracker>>,
+    // Warn on close if total busy time across the span exceeds this duration.
+    warn_busy_total: Option<Duration>,
+    // Check the total threshold after every poll, and warn again on close.
+    warn_busy_total_eager: bool,
+    warn_busy_total_final: bool,
+    // What to do when a single poll exceeds `warn_busy_single_poll`.
+    on_violation: ViolationMode,
+    // What dedup groups warnings by.
+    warning_granularity: Granularity,
+    // Minimum duration of polls captured by the trace recorder, if enabled.
+    trace_min_duration: Option<Duration>,
+    // Raise the level of warnings for callsites that keep blocking.
+    escalation: Option<Escalation>,
+    // De-escalate once a callsite has not exceeded the threshold for this long.
+    escalation_quiet_period: Option<Duration>,
+    // Sorted `(threshold, level)` tiers of poll and total warnings.
+    poll_tiers: Vec<(Duration, Level)>,
+    total_tiers: Vec<(Duration, Level)>,
+    // Clean polls after which a blocking callsite counts as recovered.
+    recovery_quiet_polls: Option<u64>,
+    clock: Arc<dyn Clock>,
+    // Smoothing factor of the per-callsite poll duration EWMA, if enabled.
+    ewma_alpha: Option<f64>,
+    poll_histogram: bool,
+    // Per-callsite thresholds derived from the poll histograms.
+    anomaly: Option<AnomalyThreshold>,
+    budgets: Vec<BusyBudget>,
+    // Only spans beneath one of these are tracked, if any.
+    scope_spans: Vec<CallsiteSelector>,
+    // Names of spans that total the blocked time of their tasks.
+    request_spans: Vec<String>,
+    // Field of request spans their time is aggregated by.
+    route_field: Option<String>,
+    histogram_bounds: Arc<[Duration]>,
+    recent_polls: usize,
+    recent_polls_floor: Duration,
+    // Capacity of the warning history, zero if disabled.
+    warning_history: usize,
+    warning_history_suppressed: bool,
+    warn_poll_rate: Option<f64>,
+    warn_first_poll_latency: Option<Duration>,
+    warn_lock_wait: Option<Duration>,
+    warn_blocking_pool_op: Option<Duration>,
+    warn_migrations: Option<u64>,
+    track_resources: bool,
+    eviction: EvictionPolicy,
+    live_flush: Option<Duration>,
+    // Keep open spans in the live index of `Shared`.
+    live_snapshots: bool,
+    per_task_summary: bool,
+    // Sample the thread CPU time around polls.
+    cpu_time: bool,
+    // Sorted thresholds of the per-callsite SLO counters.
+    slo_thresholds: Vec<Duration>,
+    // Events kept per tracked span, for the warnings of its polls.
+    breadcrumbs: usize,
+    group_by: GroupBy,
+    loc_fields: LocFieldNames,
+    runtime_label: Option<RuntimeLabel>,
+    on_blocked: Option<Arc<OnBlocked>>,
+    on_blocked_total: Option<Arc<OnBlockedTotal>>,
+    // Streak length and callback of `with_consecutive_alert`.
+    consecutive_alert: Option<(u64, Arc<OnSustained>)>,
+    patterns: PatternThresholds,
+    // Field names of the metric events, if enabled.
+    metric_events: Option<MetricNaming>,
+    report_interval: Option<Duration>,
+}
+
+/// Consecutive blocked polls of a callsite, until it recovers.
+#[derive(Copy, Clone, Debug)]
+struct Episode {
+    started: Instant,
+    peak: Duration,
+    blocked_polls: u64,
+    // Polls under the threshold since the last blocked one.
+    clean_polls: u64,
+}
+
+/// What a callsite's warnings depend on, kept apart from its statistics.
+#[derive(Debug, Default)]
+struct WarningState {
+    // Blocked polls since the callsite last recovered.
+    episode: Option<Episode>,
+    // Exceeded polls counted towards escalation; reset after a quiet period.
+    escalation_count: u64,
+    last_exceeded: Option<Instant>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Escalation {
+    // Number of exceeded polls from which on warnings are escalated.
+    after: u64,
+    level: Level,
+}
+
+/// How the runtime a span is polled on is labeled, see
+/// [`TokioBlockedLayer::with_runtime_label`].
+enum RuntimeLabel {
+    Fn(fn() -> Option<&'static str>),
+    // `(thread name prefix, label)`.
+    ThreadPrefixes(Vec<(String, &'static str)>),
+}
+
+impl RuntimeLabel {
+    /// The label of the current thread.
+    fn resolve(&self) -> Option<&'static str> {
+        match self {
+            Self::Fn(f) => f(),
+            Self::ThreadPrefixes(prefixes) => {
+                let thread = std::thread::current();
+                let name = thread.name()?;
+                prefixes
+                    .iter()
+                    .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+                    .map(|&(_, label)| label)
+            }
+        }
+    }
+}
+
+/// State shared between the layer and its [`BlockedStatsHandle`]s.
+struct Shared {
+    // When the layer was created; the epoch for exported traces.
+    created_at: Instant,
+    callsites: Mutex<HashMap<CallsiteKey, CallsiteStats>>,
+    // Warning state of callsites without a row that exceeded the threshold
+    // while statistics were paused, until their row is created.
+    paused_warnings: Mutex<HashMap<CallsiteKey, WarningState>>,
+    // Per-callsite values updated lock-free on every poll.
+    cells: Mutex<HashMap<CallsiteKey, Arc<CallsiteCell>>>,
+    // Tracked spans that have not been closed yet, with
+    // `TokioBlockedLayer::with_live_snapshots`.
+    live: LiveIndex,
+    // Open request spans of the tower middleware.
+    requests: Mutex<HashMap<span::Id, Arc<RequestBusy>>>,
+    // Length of `requests`, to skip its lock when spawning tasks without
+    // open requests.
+    open_requests: AtomicUsize,
+    // Closed request spans by route, see `TokioBlockedLayer::with_route_field`.
+    routes: Mutex<HashMap<String, RouteBlockedStats>>,
+    violations: Mutex<Vec<Violation>>,
+    trace: Mutex<Option<TraceRecorder>>,
+    health: Mutex<Option<Arc<HealthTracker>>>,
+    saturation: Mutex<Option<Arc<SaturationTracker>>>,
+    dedup: Mutex<Option<Dedup>>,
+    // Whether warning events are emitted; statistics are kept regardless.
+    warnings_enabled: AtomicBool,
+    // Whether closing spans are merged into `callsites`.
+    stats_paused: AtomicBool,
+    activity: Mutex<Activity>,
+    // Same as the layer's clock, for use by handles.
+    clock: Mutex<Arc<dyn Clock>>,
+    // Interned spawn location file names and resource descriptions.
+    strings: Mutex<HashSet<&'static str>>,
+    // Symbols of the interned spawn location file names.
+    files: RwLock<FileSymbols>,
+    // Callsites whose warnings are suppressed.
+    muted: Mutex<Vec<CallsiteSelector>>,
+    // Callsites traced in detail, see `BlockedStatsHandle::focus`.
+    focused: Mutex<Vec<Focus>>,
+    any_focused: AtomicBool,
+    // Whether `muted` is non-empty, to skip the lock in the common case.
+    any_muted: AtomicBool,
+    // `(file substring, line)` of locations exempt from warnings.
+    allow_blocking_at: Mutex<Vec<(String, Option<u32>)>>,
+    // Current period of each callsite and matching busy budget.
+    budget_windows: Mutex<HashMap<(CallsiteKey, usize), BudgetWindow>>,
+    warning_history: Mutex<WarningHistory>,
+    // Longest task poll, with its nanoseconds to skip the lock for shorter
+    // polls.
+    worst_poll_ns: AtomicU64,
+    worst_poll: Mutex<Option<WorstPoll>>,
+    // Sequence number of the last emitted poll or total warning.
+    events_emitted: AtomicU64,
+    // Number of live layers sharing this state, see `TokioBlockedLayer::from_handle`.
+    layers: AtomicUsize,
+    evicted_callsites: AtomicU64,
+    // Counters of `BlockedStatsHandle::diagnostics`.
+    reentrant_spans: AtomicU64,
+    uninterned_strings: AtomicU64,
+    missing_extensions: AtomicU64,
+    poisoned_locks: AtomicU64,
+    // Tracked spans that are currently open, see `BlockedStatsHandle::live_spans`.
+    live_tasks: AtomicUsize,
+    live_async_ops: AtomicUsize,
+    live_resources: AtomicUsize,
+    live_high_water: AtomicUsize,
+    // Single-poll and total thresholds of the installed layer, for reports.
+    thresholds: Mutex<(Option<Duration>, Option<Duration>)>,
+    // Window of the blocked fraction gauge and the metric naming of the
+    // installed layer, see `TokioBlockedLayer::with_blocked_fraction_gauge`.
+    fraction_gauge: Mutex<Option<(Duration, MetricNaming)>>,
+    // Quantiles estimated from the poll histograms in snapshots.
+    report_percentiles: Mutex<Vec<f64>>,
+    observers: RwLock<Vec<Arc<dyn BlockedObserver>>>,
+    event_log: RwLock<Option<Arc<EventLog>>>,
+    #[cfg(feature = "channel")]
+    subscribers: Mutex<Vec<tokio::sync::mpsc::Sender<BlockedEvent>>>,
+    #[cfg(feature = "channel")]
+    dropped_events: AtomicU64,
+    #[cfg(feature = "channel")]
+    snapshot_watch: tokio::sync::watch::Sender<Arc<Vec<CallsiteStatsSnapshot>>>,
+}
+
+// Upper bound for interned strings; they are leaked, so guard against
+// instrumentation recording dynamic strings as the location or resource.
+const MAX_INTERNED_STRINGS: usize = 4096;
+
+/// Capacity of the channels returned by `BlockedStatsHandle::subscribe`.
+#[cfg(feature = "channel")]
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Minimum time over which a span's poll rate is measured, so short bursts
+/// of legitimate polls don't count as a storm.
+const POLL_RATE_MIN_WINDOW: Duration = Duration::from_millis(100);
+
+/// How long statistics accumulation has been active (not paused).
+struct Activity {
+    accumulated: Duration,
+    // Set while not paused.
+    active_since: Option<Instant>,
+    // When the layer was created or last reset.
+    since: Instant,
+}
+
+impl Activity {
+    fn active_duration(&self, now: Instant) -> Duration {
+        self.accumulated
+            + self
+                .active_since
+                .map_or(Duration::ZERO, |since| now.saturating_duration_since(since))
+    }
+}
+
+impl Shared {
+    fn new() -> Self {
+        Self {
+            created_at: Instant::now(),
+            callsites: Mutex::new(HashMap::new()),
+            paused_warnings: Mutex::new(HashMap::new()),
+            cells: Mutex::new(HashMap::new()),
+            live: LiveIndex::default(),
+            requests: Mutex::new(HashMap::new()),
+            open_requests: AtomicUsize::new(0),
+            routes: Mutex::new(HashMap::new()),
+            violations: Mutex::new(Vec::new()),
+            trace: Mutex::new(None),
+            health: Mutex::new(None),
+            saturation: Mutex::new(None),
+            dedup: Mutex::new(None),
+            warnings_enabled: AtomicBool::new(true),
+            stats_paused: AtomicBool::new(false),
+            activity: Mutex::new(Activity {
+                accumulated: Duration::ZERO,
+                active_since: Some(Instant::now()),
+                since: Instant::now(),
+            }),
+            clock: Mutex::new(Arc::new(SystemClock)),
+            strings: Mutex::new(HashSet::new()),
+            files: RwLock::new(FileSymbols::default()),
+            muted: Mutex::new(Vec::new()),
+            focused: Mutex::new(Vec::new()),
+            any_focused: AtomicBool::new(false),
+            any_muted: AtomicBool::new(false),
+            allow_blocking_at: Mutex::new(Vec::new()),
+            budget_windows: Mutex::new(HashMap::new()),
+            warning_history: Mutex::new(WarningHistory::default()),
+            worst_poll_ns: AtomicU64::new(0),
+            worst_poll: Mutex::new(None),
+            events_emitted: AtomicU64::new(0),
+            layers: AtomicUsize::new(0),
+            evicted_callsites: AtomicU64::new(0),
+            reentrant_spans: AtomicU64::new(0),
+            uninterned_strings: AtomicU64::new(0),
+            missing_extensions: AtomicU64::new(0),
+            poisoned_locks: AtomicU64::new(0),
+            live_tasks: AtomicUsize::new(0),
+            live_async_ops: AtomicUsize::new(0),
+            live_resources: AtomicUsize::new(0),
+            live_high_water: AtomicUsize::new(0),
+            thresholds: Mutex::new((None, None)),
+            fraction_gauge: Mutex::new(None),
+            report_percentiles: Mutex::new(vec![0.5, 0.95, 0.99]),
+            observers: RwLock::new(vec![Arc::new(TracingObserver)]),
+            event_log: RwLock::new(None),
+            #[cfg(feature = "channel")]
+            subscribers: Mutex::new(Vec::new()),
+            #[cfg(feature = "channel")]
+            dropped_events: AtomicU64::new(0),
+            #[cfg(feature = "channel")]
+            snapshot_watch: tokio::sync::watch::Sender::new(Arc::new(Vec::new())),
+        }
+    }
+
+    fn is_muted(&self, meta: &Metadata<'_>, file: &str, line: u32) -> bool {
+        if !self.any_muted.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.lock(&self.muted)
+            .iter()
+            .any(|sel| sel.matches(meta.target(), meta.name(), Some(file), Some(line)))
+    }
+
+    /// Keeps a poll of `file:line` in the rings of the matching focused
+    /// selectors, returns whether there were any.
+    fn record_focused(&self, meta: &Metadata<'_>, file: &str, line: u32, poll: PollSample) -> bool {
+        let mut matched = false;
+        for focus in self.lock(&self.focused).iter() {
+            if focus
+                .selector
+                .matches(meta.target(), meta.name(), Some(file), Some(line))
+            {
+                focus.recent.push(poll);
+                matched = true;
+            }
+        }
+        matched
+    }
+
+    /// Whether warnings about `file:line` are suppressed, either by muting or
+    /// by the allowlist.
+    fn is_exempt(&self, meta: &Metadata<'_>, file: &str, line: u32) -> bool {
+        is_allowed_at(&self.lock(&self.allow_blocking_at), file, line)
+            || self.is_muted(meta, file, line)
+    }
+
+    /// Returns a `'static` copy of `value` shared by all spans recording the
+    /// same string, or `None` once the interner is full.
+    fn intern(&self, value: &str) -> Option<&'static str> {
+        let mut strings = self.lock(&self.strings);
+        if let Some(interned) = strings.get(value) {
+            return Some(interned);
+        }
+        if strings.len() >= MAX_INTERNED_STRINGS {
+            self.uninterned_strings.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let interned: &'static str = Box::leak(value.to_string().into_boxed_str());
+        strings.insert(interned);
+        Some(interned)
+    }
+
+    /// Interns a spawn location file name as a symbol, which is resolved with
+    /// [`file`](Self::file).
+    fn intern_file(&self, file: &str) -> Option<FileSymbol> {
+        if let Some(symbol) = self.read(&self.files).symbols.get(file) {
+            return Some(*symbol);
+        }
+        let file = self.intern(file)?;
+        let mut files = self.write(&self.files);
+        let FileSymbols { symbols, names } = &mut *files;
+        Some(*symbols.entry(file).or_insert_with(|| {
+            names.push(file);
+            // Bounded by `MAX_INTERNED_STRINGS`.
+            FileSymbol(NonZeroU32::new(names.len() as u32).unwrap())
+        }))
+    }
+
+    fn file(&self, symbol: FileSymbol) -> &'static str {
+        self.read(&self.files).names[symbol.0.get() as usize - 1]
+    }
+
+    fn now(&self) -> Instant {
+        self.clock().now()
+    }
+
+    /// Locks the clock, recovering from a panic of [`Clock::now`].
+    fn clock(&self) -> MutexGuard<'_, Arc<dyn Clock>> {
+        self.lock(&self.clock)
+    }
+
+    /// Locks `mutex` of the shared state. A panic while it was held, e.g. of a
+    /// user callback or a bug, leaves the state consistent enough to go on,
+    /// so the lock is recovered rather than failing every later span.
+    fn lock<'a, T: ?Sized>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        recover(mutex, &self.poisoned_locks)
+    }
+
+    /// Like [`lock`](Self::lock), for reading.
+    fn read<'a, T: ?Sized>(&self, lock: &'a RwLock<T>) -> RwLockReadGuard<'a, T> {
+        lock.read().unwrap_or_else(|poisoned| {
+            self.poisoned_locks.fetch_add(1, Ordering::Relaxed);
+            lock.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    /// Like [`lock`](Self::lock), for writing.
+    fn write<'a, T: ?Sized>(&self, lock: &'a RwLock<T>) -> RwLockWriteGuard<'a, T> {
+        lock.write().unwrap_or_else(|poisoned| {
+            self.poisoned_locks.fetch_add(1, Ordering::Relaxed);
+            lock.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    fn set_clock(&self, clock: Arc<dyn Clock>) {
+        let now = clock.now();
+        *self.clock() = clock;
+        let mut activity = self.lock(&self.activity);
+        activity.since = now;
+        if activity.active_since.is_some() {
+            activity.active_since = Some(now);
+        }
+    }
+
+    fn stats_paused(&self) -> bool {
+        self.stats_paused.load(Ordering::Relaxed)
+    }
+
+    fn set_stats_paused(&self, paused: bool) {
+        let now = self.now();
+        let mut activity = self.lock(&self.activity);
+        self.stats_paused.store(paused, Ordering::Relaxed);
+        match (paused, activity.active_since) {
+            (true, Some(since)) => {
+                activity.accumulated += now.saturating_duration_since(since);
+                activity.active_since = None;
+            }
+            (false, None) => activity.active_since = Some(now),
+            _ => {}
+        }
+    }
+
+    fn stats_active_duration(&self) -> Duration {
+        let now = self.now();
+        self.lock(&self.activity).active_duration(now)
+    }
+
+    /// Sends an event to all subscribers without blocking, dropping it for
+    /// subscribers whose channel is full.
+    #[cfg(feature = "channel")]
+    fn publish(&self, event: impl FnOnce() -> BlockedEvent) {
+        use tokio::sync::mpsc::error::TrySendError;
+
+        let mut subscribers = self.lock(&self.subscribers);
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = event();
+        subscribers.retain(|tx| match tx.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped_events.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+    }
+
+    /// Adds a closed request span to the statistics of its route.
+    fn record_route(&self, route: Option<&str>, busy: &RequestBusy) {
+        let route = route.unwrap_or(UNKNOWN_ROUTE);
+        let busy_time = Duration::from_nanos(busy.busy_ns.load(Ordering::Relaxed));
+        let blocked = Duration::from_nanos(busy.blocked_ns.load(Ordering::Relaxed));
+        let mut routes = self.lock(&self.routes);
+        if !routes.contains_key(route) {
+            routes.insert(route.to_string(), RouteBlockedStats::new(route));
+        }
+        let stats = routes.get_mut(route).expect("just inserted");
+        stats.requests += 1;
+        stats.total_busy += busy_time;
+        stats.total_blocked += blocked;
+        stats.max_blocked = stats.max_blocked.max(blocked);
+    }
+
+    /// Counts a tracked span whose extension was gone or inconsistent.
+    fn missing_extension(&self) {
+        self.missing_extensions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn warnings_enabled(&self) -> bool {
+        self.warnings_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Emits the summaries of all open dedup windows.
+    fn flush_dedup(&self) {
+        let mut closed = Vec::new();
+        if let Some(dedup) = &mut *self.lock(&self.dedup) {
+            dedup.drain_all(&mut closed);
+        }
+        closed.iter().for_each(emit_dedup_summary);
+    }
+
+    /// Summarizes the dedup windows that have closed, so that a location
+    /// that stopped warning is not only summarized on drop.
+    fn flush_expired_dedup(&self) {
+        let mut closed = Vec::new();
+        if let Some(dedup) = &mut *self.lock(&self.dedup) {
+            dedup.drain_expired(self.now(), &mut closed);
+        }
+        closed.iter().for_each(emit_dedup_summary);
+    }
+
+    fn export_chrome_trace(&self, w: impl std::io::Write) -> std::io::Result<()> {
+        match &*self.lock(&self.trace) {
+            Some(trace) => trace.export(self.created_at, w),
+            None => TraceRecorder::new(0, Duration::ZERO).export(self.created_at, w),
+        }
+    }
+
+    fn blocked_fraction(&self, window: Duration) -> f64 {
+        let tracker = self.lock(&self.health).clone();
+        tracker.map_or(0.0, |tracker| {
+            let health = tracker.health(window, self.now());
+            health.blocked_time.as_secs_f64() / health.window.as_secs_f64()
+        })
+    }
+
+    fn observers(&self) -> RwLockReadGuard<'_, Vec<Arc<dyn BlockedObserver>>> {
+        self.read(&self.observers)
+    }
+
+    fn report(&self) -> Vec<CallsiteStatsSnapshot> {
+        self.flush_expired_dedup();
+        let snapshot = self.snapshot(false);
+        for observer in self.observers().iter() {
+            observer.on_snapshot(&snapshot);
+        }
+        if let Some((window, naming)) = *self.lock(&self.fraction_gauge) {
+            observer::emit_fraction_metrics(naming, self.blocked_fraction(window), window);
+        }
+        #[cfg(feature = "channel")]
+        if self.snapshot_watch.receiver_count() > 0 {
+            self.snapshot_watch.send_replace(Arc::new(snapshot.clone()));
+        }
+        snapshot
+    }
+
+    /// Publishes a snapshot to the watch channel, unless nobody watches.
+    #[cfg(feature = "channel")]
+    fn publish_snapshot(&self) -> bool {
+        if self.snapshot_watch.receiver_count() == 0 {
+            return false;
+        }
+        self.snapshot_watch
+            .send_replace(Arc::new(self.snapshot(false)));
+        true
+    }
+
+    fn snapshot(&self, includes_live: bool) -> Vec<CallsiteStatsSnapshot> {
+        let mut rows = self.snapshot_rows(includes_live);
+        let allowed = self.lock(&self.allow_blocking_at);
+        if !allowed.is_empty() {
+            for row in &mut rows {
+                row.allowed = match (row.file, row.line) {
+                    (Some(file), Some(line)) => is_allowed_at(&allowed, file, line),
+                    _ => false,
+                };
+            }
+        }
+        rows
+    }
+
+    fn snapshot_rows(&self, includes_live: bool) -> Vec<CallsiteStatsSnapshot> {
+        let map = self.lock(&self.callsites);
+        let cells = self.lock(&self.cells);
+        let quantiles = self.lock(&self.report_percentiles).clone();
+        let cell = |key: &CallsiteKey| cells.get(key).map(|c| &**c);
+        if !includes_live {
+            return map
+                .iter()
+                .map(|(key, s)| s.to_snapshot(cell(key), &quantiles))
+                .collect();
+        }
+
+        let mut rows: HashMap<_, _> = map
+            .iter()
+            .map(|(key, s)| (*key, s.to_snapshot(cell(key), &quantiles)))
+            .collect();
+        self.live.for_each(&self.poisoned_locks, |live| {
+            let pending = *recover(&live.pending, &self.poisoned_locks);
+            let row = rows.entry(live.key).or_insert_with(|| {
+                CallsiteStats::new(live.meta, live.key).to_snapshot(cell(&live.key), &quantiles)
+            });
+            row.total_busy += pending.total_busy;
+            row.max_poll = row.max_poll.max(pending.max_poll);
+            row.polls += pending.polls;
+            row.poll_sum_squares_ns += pending.poll_sum_squares_ns;
+        });
+        rows.into_values().collect()
+    }
+
+    fn summary(&self) -> BlockedSummary {
+        let map = self.lock(&self.callsites);
+        let (mut total_busy, mut polls, mut spans) = (Duration::ZERO, 0, 0);
+        for stats in map.values() {
+            total_busy += stats.total_busy;
+            polls += stats.polls;
+            spans += stats.count;
+        }
+        let callsites = map.len();
+        drop(map);
+
+        let now = self.now();
+        let activity = self.lock(&self.activity);
+        BlockedSummary {
+            total_busy,
+            polls,
+            spans,
+            callsites,
+            worst_poll: self.lock(&self.worst_poll).clone(),
+            since: activity.since,
+            until: now,
+            active_duration: activity.active_duration(now),
+        }
+    }
+
+    /// Sequence number of a poll or total warning that is about to be emitted.
+    fn next_event_seq(&self) -> u64 {
+        self.events_emitted.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Keeps the task poll if it is the longest so far.
+    fn record_worst_poll(&self, poll: Duration, worst: impl FnOnce() -> WorstPoll) {
+        let poll_ns = poll.as_nanos() as u64;
+        if self.worst_poll_ns.fetch_max(poll_ns, Ordering::Relaxed) >= poll_ns {
+            return;
+        }
+        let mut current = self.lock(&self.worst_poll);
+        // Another thread may have stored a longer poll in the meantime.
+        if current.as_ref().is_none_or(|w| w.duration < poll) {
+            *current = Some(worst());
+        }
+    }
+
+    /// The live span counter of spans named `name`.
+    fn live_counter(&self, name: &str) -> &AtomicUsize {
+        match name {
+            "runtime.spawn" => &self.live_tasks,
+            "runtime.resource" => &self.live_resources,
+            _ => &self.live_async_ops,
+        }
+    }
+
+    fn live_span_opened(&self, name: &str) {
+        self.live_counter(name).fetch_add(1, Ordering::Relaxed);
+        let live = self.live_spans();
+        self.live_high_water
+            .fetch_max(live.total(), Ordering::Relaxed);
+    }
+
+    fn live_span_closed(&self, name: &str) {
+        self.live_counter(name).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn live_spans(&self) -> LiveSpans {
+        LiveSpans {
+            tasks: self.live_tasks.load(Ordering::Relaxed),
+            async_ops: self.live_async_ops.load(Ordering::Relaxed),
+            resources: self.live_resources.load(Ordering::Relaxed),
+        }
+    }
+
+    fn memory_stats(&self) -> MemoryStats {
+        use std::mem::size_of;
+
+        let callsites = self.lock(&self.callsites);
+        let cells = self.lock(&self.cells);
+        let live_spans = self.live_spans().total();
+        let mut stats = MemoryStats {
+            callsites: callsites.len(),
+            callsite_cells: cells.len(),
+            live_spans,
+            ..Default::default()
+        };
+        // Hash maps allocate their capacity, plus a control byte per bucket.
+        let mut bytes = callsites.capacity()
+            * (size_of::<CallsiteKey>() + size_of::<CallsiteStats>() + 1)
+            + cells.capacity() * (size_of::<CallsiteKey>() + size_of::<Arc<CallsiteCell>>() + 1)
+            + self.live.capacity(&self.poisoned_locks)
+                * (size_of::<span::Id>() + size_of::<Arc<LiveSpan>>() + 1);
+        for cell in cells.values() {
+            bytes += size_of::<CallsiteCell>();
+            bytes += cell
+                .histogram
+                .as_ref()
+                .map_or(0, AtomicHistogram::heap_bytes);
+            if let Some(recent) = &cell.recent {
+                stats.recent_poll_samples += recent.len();
+                bytes += recent.heap_bytes();
+            }
+        }
+        // Each live span also carries its extension in the registry.
+        bytes += live_spans * (size_of::<LiveSpan>() + size_of::<SpanBusyExt>());
+        drop((callsites, cells));
+
+        if let Some(trace) = &*self.lock(&self.trace) {
+            stats.trace_slices = trace.len();
+            bytes += trace.heap_bytes();
+        }
+        let strings = self.lock(&self.strings);
+        stats.interned_strings = strings.len();
+        bytes += strings.capacity() * (size_of::<&str>() + 1)
+            + strings.iter().map(|s| s.len()).sum::<usize>();
+        let files = self.read(&self.files);
+        bytes += files.symbols.capacity() * (size_of::<&str>() + size_of::<FileSymbol>() + 1)
+            + files.names.capacity() * size_of::<&str>();
+        stats.estimated_bytes = bytes;
+        stats
+    }
+
+    /// Returns the cell of a callsite, creating it with `new` if needed.
+    fn cell(&self, key: CallsiteKey, new: impl FnOnce() -> CallsiteCell) -> Arc<CallsiteCell> {
+        self.lock(&self.cells)
+            .entry(key)
+            .or_insert_with(|| Arc::new(new()))
+            .clone()
+    }
+
+    fn recent_polls(&self, selector: &CallsiteSelector) -> Vec<PollSample> {
+        if let Some(focus) = self
+            .lock(&self.focused)
+            .iter()
+            .find(|f| f.selector == *selector)
+        {
+            return focus.recent.samples();
+        }
+        let cells = self.lock(&self.cells);
+        let mut samples: Vec<_> = cells
+            .iter()
+            .filter(|(key, cell)| {
+                let (file, line) = key.location(cell.meta);
+                selector.matches(cell.meta.target(), cell.meta.name(), file, line)
+            })
+            .filter_map(|(_, cell)| cell.recent.as_ref())
+            .flat_map(|recent| recent.samples())
+            .collect();
+        samples.sort_by_key(|s| s.start);
+        samples
+    }
+}
+
+thread_local! {
+    static IN_LAYER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks a thread as running a span callback of any [`TokioBlockedLayer`].
+///
+/// The warnings emitted from the callbacks flow through the whole subscriber
+/// again, and other layers may react to them with spans of their own.
+/// Callbacks running meanwhile are skipped, so the layers never recurse into
+/// themselves or each other, and never account the spans of those reactions.
+/// The same goes for events: `on_event` skips them while a callback runs, so
+/// the layer's own warnings never become breadcrumbs.
+struct InLayer(());
+
+impl InLayer {
+    fn enter() -> Option<Self> {
+        IN_LAYER.with(|in_layer| (!in_layer.replace(true)).then_some(Self(())))
+    }
+}
+
+impl Drop for InLayer {
+    // Also reached when unwinding from a `ViolationMode::Panic` panic.
+    fn drop(&mut self) {
+        IN_LAYER.with(|in_layer| in_layer.set(false));
+    }
+}
+
+/// The [`SpanBusyExt`] of a span, taken out of its extensions while warnings
+/// are emitted: other layers may lock the extensions of the span from
+/// `on_event`, as tracing-opentelemetry does. Put back when dropped, also
+/// when unwinding.
+struct TakenExt<'s, 'a, R: LookupSpan<'a>> {
+    span: &'s SpanRef<'a, R>,
+    ext: Option<SpanBusyExt>,
+}
+
+impl<'s, 'a, R: LookupSpan<'a>> TakenExt<'s, 'a, R> {
+    fn take(span: &'s SpanRef<'a, R>) -> Option<Self> {
+        let ext = span.extensions_mut().remove::<SpanBusyExt>()?;
+        Some(Self {
+            span,
+            ext: Some(ext),
+        })
+    }
+}
+
+impl<'a, R: LookupSpan<'a>> std::ops::Deref for TakenExt<'_, 'a, R> {
+    type Target = SpanBusyExt;
+
+    fn deref(&self) -> &SpanBusyExt {
+        self.ext.as_ref().expect("taken until dropped")
+    }
+}
+
+impl<'a, R: LookupSpan<'a>> std::ops::DerefMut for TakenExt<'_, 'a, R> {
+    fn deref_mut(&mut self) -> &mut SpanBusyExt {
+        self.ext.as_mut().expect("taken until dropped")
+    }
+}
+
+impl<'a, R: LookupSpan<'a>> Drop for TakenExt<'_, 'a, R> {
+    fn drop(&mut self) {
+        if let Some(ext) = self.ext.take() {
+            self.span.extensions_mut().insert(ext);
+        }
+    }
+}
+
+/// Busy time of a live span that is not in its callsite stats yet, so
+/// snapshots can include it.
+#[derive(Debug)]
+struct LiveSpan {
+    meta: &'static Metadata<'static>,
+    key: CallsiteKey,
+    pending: Mutex<PendingBusy>,
+}
+
+/// Locks `mutex`, counting it in `poisoned` if it has to be recovered. See
+/// [`Shared::lock`].
+fn recover<'a, T: ?Sized>(mutex: &'a Mutex<T>, poisoned: &AtomicU64) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|error| {
+        poisoned.fetch_add(1, Ordering::Relaxed);
+        mutex.clear_poison();
+        error.into_inner()
+    })
+}
+
+/// Shards of the [`LiveIndex`].
+const LIVE_SHARDS: usize = 32;
+
+/// The live spans by id, for snapshots including them. Sharded by id, so
+/// that workers opening and closing spans rarely wait on each other or on a
+/// snapshot, which locks one shard at a time.
+#[derive(Default)]
+struct LiveIndex {
+    shards: [Mutex<HashMap<span::Id, Arc<LiveSpan>>>; LIVE_SHARDS],
+}
+
+impl LiveIndex {
+    fn shard(&self, id: &span::Id) -> &Mutex<HashMap<span::Id, Arc<LiveSpan>>> {
+        &self.shards[id.into_u64() as usize % LIVE_SHARDS]
+    }
+
+    fn insert(&self, id: span::Id, span: Arc<LiveSpan>, poisoned: &AtomicU64) {
+        recover(self.shard(&id), poisoned).insert(id, span);
+    }
+
+    fn remove(&self, id: &span::Id, poisoned: &AtomicU64) {
+        recover(self.shard(id), poisoned).remove(id);
+    }
+
+    fn for_each(&self, poisoned: &AtomicU64, mut f: impl FnMut(&LiveSpan)) {
+        for shard in &self.shards {
+            for span in recover(shard, poisoned).values() {
+                f(span);
+            }
+        }
+    }
+
+    fn capacity(&self, poisoned: &AtomicU64) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| recover(shard, poisoned).capacity())
+            .sum()
+    }
+}
+
+/// Target and name of the request spans created by the tower middleware.
+const REQUEST_TARGET: &str = "tokio_blocked::request";
+const REQUEST_NAME: &str = "tokio_blocked.request";
+
+/// Busy time attributed to a request span: its own polls and the polls of
+/// the tasks spawned while handling it.
+#[derive(Debug, Default)]
+pub(crate) struct RequestBusy {
+    pub(crate) busy_ns: AtomicU64,
+    // Polls that reached the single-poll threshold.
+    pub(crate) blocked_ns: AtomicU64,
+}
+
+impl RequestBusy {
+    fn record(&self, poll: Duration, threshold: Option<Duration>) {
+        let nanos = poll.as_nanos() as u64;
+        self.busy_ns.fetch_add(nanos, Ordering::Relaxed);
+        if threshold.is_some_and(|t| poll >= t) {
+            self.blocked_ns.fetch_add(nanos, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Extension of request spans, which are not tracked as callsites.
+struct RequestExt {
+    // See `TokioBlockedLayer::owner`.
+    owner: usize,
+    busy: Arc<RequestBusy>,
+    in_count: usize,
+    start: Option<Instant>,
+    // Value of the route field, see `TokioBlockedLayer::with_route_field`.
+    route: Option<String>,
+    // Whether to emit `tokio_blocked::request_total` on close.
+    report: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PendingBusy {
+    total_busy: Duration,
+    max_poll: Duration,
+    polls: u64,
+    poll_sum_squares_ns: u128,
+}
+
+/// Per-callsite values that are updated on every poll without locking.
+///
+/// Spans hold an `Arc` to the cell of their callsite.
+#[derive(Debug)]
+struct CallsiteCell {
+    meta: &'static Metadata<'static>,
+    // Exponentially weighted moving average of poll durations, as f64
+    // nanoseconds bits. `EWMA_UNSET` until the first poll.
+    ewma_bits: AtomicU64,
+    histogram: Option<AtomicHistogram>,
+    recent: Option<RecentPolls>,
+}
+
+// A NaN bit pattern that is never produced by the EWMA computation.
+const EWMA_UNSET: u64 = u64::MAX;
+
+impl CallsiteCell {
+    fn new(
+        meta: &'static Metadata<'static>,
+        histogram: Option<Arc<[Duration]>>,
+        recent_polls: usize,
+    ) -> Self {
+        Self {
+            meta,
+            ewma_bits: AtomicU64::new(EWMA_UNSET),
+            histogram: histogram.map(AtomicHistogram::new),
+            recent: (recent_polls > 0).then(|| RecentPolls::new(recent_polls)),
+        }
+    }
+
+    fn ewma(&self) -> Option<Duration> {
+        let bits = self.ewma_bits.load(Ordering::Relaxed);
+        (bits != EWMA_UNSET)
+            .then(|| Duration::try_from_secs_f64(f64::from_bits(bits) / 1e9).ok())
+            .flatten()
+    }
+
+    fn update_ewma(&self, alpha: f64, sample: Duration) {
+        let sample = sample.as_nanos() as f64;
+        // Failing to update just means another poll raced us; retry with the
+        // latest value.
+        let _ = self
+            .ewma_bits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let next = if bits == EWMA_UNSET {
+                    sample
+                } else {
+                    alpha * sample + (1.0 - alpha) * f64::from_bits(bits)
+                };
+                Some(next.to_bits())
+            });
+    }
+
+    fn reset(&self) {
+        self.ewma_bits.store(EWMA_UNSET, Ordering::Relaxed);
+        if let Some(histogram) = &self.histogram {
+            histogram.reset();
+        }
+        if let Some(recent) = &self.recent {
+            recent.clear();
+        }
+    }
+}
+
+/// What the layer does when a single poll exceeds the configured
+/// single-poll threshold.
+///
+/// See [`TokioBlockedLayer::with_on_violation`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum ViolationMode {
+    /// Emit a `tokio_blocked::task_poll_blocked` warning event.
+    #[default]
+    Warn,
+    /// Panic with a message containing the spawn location and the poll
+    /// duration.
+    ///
+    /// The panic is raised from the span exit of the poll, so it propagates
+    /// into the task and surfaces as a failed `JoinHandle` (or a failed test).
+    Panic,
+    /// Record the violation without emitting anything.
+    ///
+    /// Recorded violations can be retrieved with
+    /// [`BlockedStatsHandle::take_violations`].
+    Record,
+}
+
+/// Bounds the number of callsites kept in the statistics.
+///
+/// See [`TokioBlockedLayer::with_callsite_eviction`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum EvictionPolicy {
+    /// Keep all callsites.
+    #[default]
+    Unbounded,
+    /// Keep at most `max_entries` callsites, evicting the least recently
+    /// updated ones first.
+    Lru { max_entries: usize },
+}
+
+/// How spans are aggregated into rows of the statistics.
+///
+/// See [`TokioBlockedLayer::with_group_by`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum GroupBy {
+    /// One row per spawn location (or span callsite).
+    #[default]
+    Location,
+    /// One row per `task.name`, e.g. as set with `tokio::task::Builder::name`.
+    /// Unnamed tasks are grouped by location.
+    TaskName,
+    /// Both: every named task is counted in its location row and in its name
+    /// row.
+    Both,
+}
+
+/// What deduplicated warnings are grouped by, see
+/// [`TokioBlockedLayer::with_group_warnings_by`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Granularity {
+    /// The origin location, file and line.
+    #[default]
+    Callsite,
+    /// The file of the origin location, so all lines of a file share one
+    /// group.
+    File,
+}
+
+/// Names of the span fields the spawn location is read from, see
+/// [`TokioBlockedLayer::with_loc_fields`].
+///
+/// Each part accepts several names, so spans following different
+/// conventions can be mixed. If a span has more than one of them, the first
+/// one it records is used.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct LocFieldNames {
+    pub file: Vec<&'static str>,
+    pub line: Vec<&'static str>,
+    pub col: Vec<&'static str>,
+}
+
+impl LocFieldNames {
+    /// The fields tokio records on task spans: `loc.file`, `loc.line` and
+    /// `loc.col`.
+    pub fn tokio() -> Self {
+        Self {
+            file: vec!["loc.file"],
+            line: vec!["loc.line"],
+            col: vec!["loc.col"],
+        }
+    }
+
+    /// The OpenTelemetry semantic conventions: `code.filepath`,
+    /// `code.lineno` and `code.column`.
+    pub fn otel() -> Self {
+        Self {
+            file: vec!["code.filepath"],
+            line: vec!["code.lineno"],
+            col: vec!["code.column"],
+        }
+    }
+
+    /// Also accepts the names of `other`, after the own ones.
+    pub fn and(mut self, other: Self) -> Self {
+        self.file.extend(other.file);
+        self.line.extend(other.line);
+        self.col.extend(other.col);
+        self
+    }
+}
+
+impl Default for LocFieldNames {
+    fn default() -> Self {
+        Self::tokio()
+    }
+}
+
+/// Number of tracked spans that are currently open, see
+/// [`BlockedStatsHandle::live_spans_by_kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LiveSpans {
+    /// Task spans (`runtime.spawn`), including blocking pool tasks.
+    pub tasks: usize,
+    /// Async op spans and their per-poll spans.
+    pub async_ops: usize,
+    /// Resource spans, with
+    /// [`with_track_resources`](TokioBlockedLayer::with_track_resources).
+    pub resources: usize,
+}
+
+impl LiveSpans {
+    pub fn total(&self) -> usize {
+        self.tasks + self.async_ops + self.resources
+    }
+}
+
+/// Memory held by the layer, see [`BlockedStatsHandle::memory_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryStats {
+    /// Entries of the per-callsite statistics.
+    pub callsites: usize,
+    /// Per-callsite EWMAs, histograms and recent poll rings.
+    pub callsite_cells: usize,
+    /// Tracked spans that are open, each with a span extension.
+    pub live_spans: usize,
+    /// Polls kept by [`with_recent_polls`](TokioBlockedLayer::with_recent_polls).
+    pub recent_poll_samples: usize,
+    /// Polls kept by [`with_trace_recorder`](TokioBlockedLayer::with_trace_recorder).
+    pub trace_slices: usize,
+    /// Interned spawn location file names and resource descriptions, which
+    /// are never freed.
+    pub interned_strings: usize,
+    /// Estimate of the bytes held, from the sizes of the stored structs,
+    /// allocated capacities and string lengths. Allocator overhead and the
+    /// dedup and escalation state are not included.
+    pub estimated_bytes: usize,
+}
+
+/// Counters of data the layer dropped, see [`BlockedStatsHandle::diagnostics`].
+///
+/// All counters only grow; they are not cleared by a
+/// [reset](BlockedStatsHandle::reset).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct Diagnostics {
+    /// Tokio spans created while the layer handled another callback on the
+    /// same thread, e.g. by an [observer](BlockedObserver), which are not
+    /// tracked.
+    pub reentrant_spans: u64,
+    /// Spawn locations, task names and resource descriptions that were
+    /// dropped because 4096 distinct strings were already interned.
+    pub uninterned_strings: u64,
+    /// Callsites removed by the
+    /// [eviction policy](TokioBlockedLayer::with_callsite_eviction).
+    pub evicted_callsites: u64,
+    /// Events dropped because a [subscriber](BlockedStatsHandle::subscribe)'s
+    /// channel was full. Always zero without the `channel` feature.
+    pub dropped_events: u64,
+    /// Polls dropped because the
+    /// [event log](TokioBlockedLayer::with_event_log) writer fell behind.
+    pub event_log_dropped: u64,
+    /// Polls of tracked spans that were not accounted because the span's
+    /// extension was missing or inconsistent, which indicates a bug.
+    pub missing_extensions: u64,
+    /// Locks of the layer's state recovered after a panic poisoned them,
+    /// e.g. of a [`Clock`] or a callback.
+    pub poisoned_locks: u64,
+}
+
+/// Route of requests without the route field, see
+/// [`TokioBlockedLayer::with_route_field`].
+const UNKNOWN_ROUTE: &str = "unknown";
+
+/// Time spent on behalf of the requests of a route, see
+/// [`BlockedStatsHandle::route_snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RouteBlockedStats {
+    /// Value of the [route field](TokioBlockedLayer::with_route_field), or
+    /// `unknown`.
+    pub route: String,
+    /// Number of closed requests.
+    pub requests: u64,
+    /// Busy time of the requests and the tasks spawned for them.
+    pub total_busy: Duration,
+    /// Time of the polls that reached the single-poll threshold.
+    pub total_blocked: Duration,
+    /// Most blocked time of a single request.
+    pub max_blocked: Duration,
+}
+
+impl RouteBlockedStats {
+    fn new(route: &str) -> Self {
+        Self {
+            route: route.to_string(),
+            requests: 0,
+            total_busy: Duration::ZERO,
+            total_blocked: Duration::ZERO,
+            max_blocked: Duration::ZERO,
+        }
+    }
+}
+
+/// Grand totals over all callsites, see [`BlockedStatsHandle::summary`].
+///
+/// The totals are the sums of the [snapshot](BlockedStatsHandle::snapshot)
+/// rows, so with [`GroupBy::Both`] named tasks are counted twice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockedSummary {
+    /// Busy time of all closed spans.
+    pub total_busy: Duration,
+    /// Polls of all closed spans.
+    pub polls: u64,
+    /// Number of closed spans.
+    pub spans: u64,
+    /// Number of distinct callsites.
+    pub callsites: usize,
+    /// Longest task poll, including polls of tasks that are still open, see
+    /// [`BlockedStatsHandle::worst_poll`].
+    pub worst_poll: Option<WorstPoll>,
+    /// When the layer was created or last [reset](BlockedStatsHandle::reset).
+    pub since: Instant,
+    /// When the summary was taken.
+    pub until: Instant,
+    /// Time between `since` and `until` that statistics were accumulated,
+    /// excluding paused periods.
+    pub active_duration: Duration,
+}
+
+/// The longest task poll seen, see [`BlockedStatsHandle::worst_poll`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorstPoll {
+    /// How long the poll took.
+    pub duration: Duration,
+    /// The statistics row of the task.
+    pub callsite: CallsiteId,
+    /// Spawn location if known, or the span callsite location otherwise.
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+    pub task_name: Option<String>,
+    /// Wall-clock time at which the poll ended.
+    pub timestamp: SystemTime,
+    /// The thread that ran the poll.
+    pub thread_id: ThreadId,
+    pub thread_name: Option<String>,
+}
+
+/// A single poll that exceeded the single-poll threshold.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    /// How long the poll took.
+    pub poll_duration: Duration,
+    /// The threshold that was exceeded.
+    pub threshold: Duration,
+    pub callsite_name: &'static str,
+    pub callsite_target: &'static str,
+    /// Spawn location if known, or the span callsite location otherwise.
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tokio task blocked for {:?} (threshold {:?}) at {}:{}:{} ({} {})",
+            self.poll_duration,
+            self.threshold,
+            self.file,
+            self.line,
+            self.col,
+            self.callsite_target,
+            self.callsite_name,
+        )
+    }
+}
+
+/// A cloneable handle to the statistics collected by a [`TokioBlockedLayer`].
+///
+/// The layer is moved into the subscriber when it is installed, so obtain a
+/// handle with [`TokioBlockedLayer::handle`] beforehand.
+#[derive(Clone)]
+pub struct BlockedStatsHandle {
+    shared: Arc<Shared>,
+}
+
+/// A [`BlockedStatsHandle`] that doesn't keep the statistics alive, for
+/// background threads that should stop with the layer.
+#[cfg(feature = "otlp")]
+pub(crate) struct WeakStatsHandle(Weak<Shared>);
+
+#[cfg(feature = "otlp")]
+impl WeakStatsHandle {
+    pub(crate) fn upgrade(&self) -> Option<BlockedStatsHandle> {
+        self.0.upgrade().map(|shared| BlockedStatsHandle { shared })
+    }
+}
+
+impl BlockedStatsHandle {
+    #[cfg(feature = "otlp")]
+    pub(crate) fn downgrade(&self) -> WeakStatsHandle {
+        WeakStatsHandle(Arc::downgrade(&self.shared))
+    }
+
+    /// Returns a snapshot of totals per callsite.
+    pub fn snapshot(&self) -> Vec<CallsiteStatsSnapshot> {
+        self.shared.snapshot(false)
+    }
+
+    /// Like [`snapshot`](Self::snapshot), but with `includes_live` also adds
+    /// the busy time and polls of spans that have not been closed yet.
+    ///
+    /// Live spans are not included in `count`, and callsites that only have
+    /// live spans so far appear with a `count` of zero. Requires
+    /// [`with_live_snapshots`](TokioBlockedLayer::with_live_snapshots), or
+    /// [`with_live_flush`](TokioBlockedLayer::with_live_flush), on the layer.
+    pub fn snapshot_with_live(&self, includes_live: bool) -> Vec<CallsiteStatsSnapshot> {
+        self.shared.snapshot(includes_live)
+    }
+
+    /// Like [`snapshot`](Self::snapshot), but with the async op rows grouped
+    /// under the task they were created in, e.g. to see which op made a task
+    /// slow.
+    pub fn snapshot_by_task(&self) -> Vec<TaskOps> {
+        TaskOps::group(self.snapshot())
+    }
+
+    /// Returns the totals over all callsites since the layer was created or
+    /// last [`reset`](Self::reset), e.g. for a dashboard.
+    ///
+    /// Cheaper than summing a [`snapshot`](Self::snapshot), as no rows are
+    /// copied.
+    pub fn summary(&self) -> BlockedSummary {
+        self.shared.summary()
+    }
+
+    /// Returns the warnings kept by
+    /// [`with_warning_history`](TokioBlockedLayer::with_warning_history),
+    /// newest first.
+    pub fn recent_warnings(&self) -> Vec<HistoricalWarning> {
+        self.shared.lock(&self.shared.warning_history).warnings()
+    }
+
+    /// Returns the longest task poll seen since the layer was created or last
+    /// [`reset`](Self::reset), and where it happened.
+    ///
+    /// Unlike the per-callsite [`max_poll`](CallsiteStatsSnapshot::max_poll),
+    /// this includes tasks that are still open and survives
+    /// [`clear_callsite`](Self::clear_callsite).
+    pub fn worst_poll(&self) -> Option<WorstPoll> {
+        self.shared.lock(&self.shared.worst_poll).clone()
+    }
+
+    /// Returns the number of blocked poll and blocked total warnings emitted
+    /// so far, which is also the [`seq`](BlockedPollInfo::seq) of the last
+    /// one. Not affected by [`reset`](Self::reset).
+    pub fn events_emitted(&self) -> u64 {
+        self.shared.events_emitted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the totals per crate, module or directory, see
+    /// [`Snapshot::grouped`].
+    pub fn snapshot_grouped(&self, key: GroupKey) -> Vec<GroupStats> {
+        self.owned_snapshot().grouped(key)
+    }
+
+    /// Takes a snapshot and passes it to the
+    /// [`on_snapshot`](BlockedObserver::on_snapshot) hook of all observers.
+    pub fn report(&self) -> Vec<CallsiteStatsSnapshot> {
+        self.shared.report()
+    }
+
+    /// Returns an owned snapshot of totals per callsite, e.g. to save as a
+    /// baseline.
+    pub fn owned_snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::from(self.snapshot());
+        snapshot.active_duration = self.stats_active_duration();
+        snapshot
+    }
+
+    /// Stops merging closed spans into the per-callsite statistics, e.g. during
+    /// a noisy startup phase.
+    ///
+    /// Spans that close while paused do not contribute at all. Warnings are
+    /// not affected.
+    pub fn pause_stats(&self) {
+        self.shared.set_stats_paused(true);
+    }
+
+    /// Resumes statistics accumulation after [`pause_stats`](Self::pause_stats).
+    pub fn resume_stats(&self) {
+        self.shared.set_stats_paused(false);
+    }
+
+    /// Returns how long statistics have been accumulated, excluding paused
+    /// periods, since the layer was created or last [`reset`](Self::reset).
+    ///
+    /// Use this rather than wall time to compute rates.
+    pub fn stats_active_duration(&self) -> Duration {
+        self.shared.stats_active_duration()
+    }
+
+    /// Emits pending `tokio_blocked::task_poll_blocked_summary` events of
+    /// the dedup window, without waiting for the window to close.
+    ///
+    /// This also happens when the layer is dropped.
+    ///
+    /// See [`TokioBlockedLayer::with_dedup_window`].
+    pub fn flush(&self) {
+        self.shared.flush_dedup();
+    }
+
+    /// Merges the statistics of the tasks named `task_name` with the
+    /// scheduling metrics of `monitor`, which should instrument the same
+    /// tasks, see [`CombinedTaskReport`](crate::CombinedTaskReport).
+    ///
+    /// ```rust,no_run
+    /// # async fn example(handle: tokio_blocked::BlockedStatsHandle) {
+    /// let monitor = tokio_metrics::TaskMonitor::new();
+    /// // Spawned with `tokio::task::Builder::new().name("worker")`.
+    /// # let worker = async {};
+    /// tokio::spawn(monitor.instrument(worker)).await.unwrap();
+    /// println!("{}", handle.combined_report("worker", &monitor));
+    /// # }
+    /// ```
+    ///
+    /// Requires the `tokio-metrics` feature.
+    #[cfg(feature = "tokio-metrics")]
+    pub fn combined_report(
+        &self,
+        task_name: &str,
+        monitor: &tokio_metrics::TaskMonitor,
+    ) -> crate::CombinedTaskReport {
+        crate::CombinedTaskReport::new(
+            task_name,
+            &self.snapshot_with_live(true),
+            monitor.cumulative(),
+        )
+    }
+
+    /// Waits until the polls logged so far by
+    /// [`with_event_log`](TokioBlockedLayer::with_event_log) are written to
+    /// the file. Does nothing without an event log.
+    pub fn flush_event_log(&self) -> std::io::Result<()> {
+        let log = self.shared.read(&self.shared.event_log).clone();
+        log.map_or(Ok(()), |log| log.flush())
+    }
+
+    /// Number of polls dropped from the event log because its writer fell
+    /// behind, see [`TokioBlockedLayer::with_event_log`].
+    pub fn event_log_dropped(&self) -> u64 {
+        let log = self.shared.read(&self.shared.event_log);
+        log.as_ref().map_or(0, |log| log.dropped())
+    }
+
+    /// Removes the statistics of all callsites matching `selector`, e.g. after
+    /// fixing an offender.
+    ///
+    /// Returns the number of removed callsites.
+    pub fn clear_callsite(&self, selector: &CallsiteSelector) -> usize {
+        let mut map = self.shared.lock(&self.shared.callsites);
+        let keys: Vec<_> = map
+            .iter()
+            .filter(|(_, s)| selector.matches(s.target, s.name, s.file, s.line))
+            .map(|(k, _)| *k)
+            .collect();
+        let cells = self.shared.lock(&self.shared.cells);
+        for key in &keys {
+            map.remove(key);
+            if let Some(cell) = cells.get(key) {
+                cell.reset();
+            }
+        }
+        keys.len()
+    }
+
+    /// Returns the polls kept by
+    /// [`with_recent_polls`](TokioBlockedLayer::with_recent_polls) for all
+    /// callsites matching `selector`, oldest first.
+    pub fn recent_polls(&self, selector: &CallsiteSelector) -> Vec<PollSample> {
+        self.shared.recent_polls(selector)
+    }
+
+    /// Suppresses future warnings of callsites matching `selector`.
+    ///
+    /// Statistics keep being collected.
+    pub fn mute_callsite(&self, selector: CallsiteSelector) {
+        let mut muted = self.shared.lock(&self.shared.muted);
+        if !muted.contains(&selector) {
+            muted.push(selector);
+        }
+        self.shared.any_muted.store(true, Ordering::Relaxed);
+    }
+
+    /// Reverts a previous [`mute_callsite`](Self::mute_callsite) with an equal
+    /// selector.
+    ///
+    /// Returns whether the selector was muted.
+    pub fn unmute_callsite(&self, selector: &CallsiteSelector) -> bool {
+        let mut muted = self.shared.lock(&self.shared.muted);
+        let len = muted.len();
+        muted.retain(|s| s != selector);
+        self.shared
+            .any_muted
+            .store(!muted.is_empty(), Ordering::Relaxed);
+        muted.len() != len
+    }
+
+    /// Traces the polls of tasks and async ops matching `selector` in detail,
+    /// regardless of the layer's configuration, e.g. to chase a single
+    /// offender in production.
+    ///
+    /// Every poll of a matching span emits a `TRACE` event with target
+    /// `tokio_blocked::focus_poll`, carrying the poll's start and duration,
+    /// its sequence number within the span, the thread, and the span's
+    /// location. With `RUST_LIB_BACKTRACE=1` (or `RUST_BACKTRACE=1`), the
+    /// event also carries a backtrace captured as the poll ends. The events
+    /// are not subject to muting, dedup or
+    /// [`set_warnings_enabled`](Self::set_warnings_enabled), but need to pass
+    /// the subscriber's filters like any other event.
+    ///
+    /// The last 64 polls are kept, and returned by
+    /// [`recent_polls`](Self::recent_polls) with an equal selector instead of
+    /// the samples of [`with_recent_polls`](TokioBlockedLayer::with_recent_polls).
+    ///
+    /// At most 4 selectors can be focused at once. Returns `false` if the
+    /// selector could not be added because of that; focusing a selector
+    /// again keeps its polls.
+    pub fn focus(&self, selector: CallsiteSelector) -> bool {
+        let mut focused = self.shared.lock(&self.shared.focused);
+        if !focused.iter().any(|f| f.selector == selector) {
+            if focused.len() >= MAX_FOCUSED {
+                return false;
+            }
+            focused.push(Focus {
+                selector,
+                recent: RecentPolls::new(FOCUS_RECENT_POLLS),
+            });
+        }
+        self.shared.any_focused.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Reverts a previous [`focus`](Self::focus) with an equal selector,
+    /// dropping its polls.
+    ///
+    /// Returns whether the selector was focused.
+    pub fn unfocus(&self, selector: &CallsiteSelector) -> bool {
+        let mut focused = self.shared.lock(&self.shared.focused);
+        let len = focused.len();
+        focused.retain(|f| f.selector != *selector);
+        self.shared
+            .any_focused
+            .store(!focused.is_empty(), Ordering::Relaxed);
+        focused.len() != len
+    }
+
+    /// Enables or disables emission of warning events at runtime, e.g. to
+    /// silence planned blocking during maintenance.
+    ///
+    /// Statistics keep being collected while warnings are disabled. Does not
+    /// affect [`ViolationMode::Panic`] and [`ViolationMode::Record`].
+    pub fn set_warnings_enabled(&self, enabled: bool) {
+        self.shared
+            .warnings_enabled
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns whether warning events are currently emitted.
+    pub fn warnings_enabled(&self) -> bool {
+        self.shared.warnings_enabled()
+    }
+
+    /// Clears all per-callsite and [per-route](Self::route_snapshot) statistics,
+    /// including escalation state, and restarts
+    /// [`stats_active_duration`](Self::stats_active_duration).
+    pub fn reset(&self) {
+        self.shared.lock(&self.shared.callsites).clear();
+        self.shared.lock(&self.shared.paused_warnings).clear();
+        self.shared.lock(&self.shared.routes).clear();
+        let now = self.shared.now();
+        let mut activity = self.shared.lock(&self.shared.activity);
+        activity.accumulated = Duration::ZERO;
+        activity.since = now;
+        if activity.active_since.is_some() {
+            activity.active_since = Some(now);
+        }
+        drop(activity);
+        self.shared.worst_poll_ns.store(0, Ordering::Relaxed);
+        *self.shared.lock(&self.shared.worst_poll) = None;
+        // Live spans still reference the cells, so reset them in place.
+        for cell in self.shared.lock(&self.shared.cells).values() {
+            cell.reset();
+        }
+    }
+
+    /// Renders the current statistics as Markdown, see
+    /// [`Snapshot::render_markdown`].
+    ///
+    /// Thresholds and capture time that are not set in `opts` are filled in
+    /// from the installed layer and the current time.
+    pub fn render_markdown(&self, opts: &MarkdownOptions) -> String {
+        let (single_poll, total) = *self.shared.lock(&self.shared.thresholds);
+        let opts = MarkdownOptions {
+            warn_busy_single_poll: opts.warn_busy_single_poll.or(single_poll),
+            warn_busy_total: opts.warn_busy_total.or(total),
+            worst_poll: opts.worst_poll.clone().or_else(|| self.worst_poll()),
+            captured_at: opts.captured_at.or_else(|| Some(SystemTime::now())),
+            ..opts.clone()
+        };
+        self.owned_snapshot().render_markdown(&opts)
+    }
+
+    /// Renders the current statistics, including spans that are still open
+    /// with [`with_live_snapshots`](TokioBlockedLayer::with_live_snapshots),
+    /// in the Prometheus text exposition format, see
+    /// [`Snapshot::render_prometheus`].
+    ///
+    /// With [`with_blocked_fraction_gauge`](TokioBlockedLayer::with_blocked_fraction_gauge),
+    /// the [`blocked_fraction`](Self::blocked_fraction) is appended as the
+    /// `tokio_blocked_blocked_fraction` gauge.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = Snapshot::from(self.shared.snapshot(true)).render_prometheus();
+        if let Some((window, _)) = *self.shared.lock(&self.shared.fraction_gauge) {
+            let metric = "tokio_blocked_blocked_fraction";
+            let _ = writeln!(
+                out,
+                "# HELP {metric} Fraction of the window spent in polls over the single-poll threshold."
+            );
+            let _ = writeln!(out, "# TYPE {metric} gauge");
+            let _ = writeln!(
+                out,
+                "{metric}{{window_seconds=\"{}\"}} {}",
+                window.as_secs_f64(),
+                self.shared.blocked_fraction(window)
+            );
+        }
+        out
+    }
+
+    /// Returns a receiver for blocked polls and blocked spans.
+    ///
+    /// Receives the same events as the [observers](BlockedObserver). The
+    /// channel holds up to 1024 events; while it is full, new events are
+    /// dropped for this subscriber and counted in
+    /// [`dropped_events`](Self::dropped_events), so a slow subscriber never
+    /// blocks the polled task. Requires the `channel` feature.
+    #[cfg(feature = "channel")]
+    pub fn subscribe(&self) -> tokio::sync::mpsc::Receiver<BlockedEvent> {
+        let (tx, rx) = tokio::sync::mpsc::channel(SUBSCRIBER_CAPACITY);
+        self.shared.lock(&self.shared.subscribers).push(tx);
+        rx
+    }
+
+    /// Returns a receiver that always holds the latest published snapshot,
+    /// for dashboards and other consumers that only need the current state.
+    ///
+    /// A snapshot is published on every [`report`](Self::report), i.e. every
+    /// [report interval](TokioBlockedLayer::with_report_interval) if one is
+    /// configured, and on [`publish_snapshot`](Self::publish_snapshot). The
+    /// receiver starts out with an empty snapshot. Requires the `channel`
+    /// feature.
+    #[cfg(feature = "channel")]
+    pub fn watch_snapshot(&self) -> tokio::sync::watch::Receiver<Arc<Vec<CallsiteStatsSnapshot>>> {
+        self.shared.snapshot_watch.subscribe()
+    }
+
+    /// Publishes a snapshot to all [`watch_snapshot`](Self::watch_snapshot)
+    /// receivers.
+    ///
+    /// Returns `false` without taking a snapshot if there are no receivers.
+    #[cfg(feature = "channel")]
+    pub fn publish_snapshot(&self) -> bool {
+        self.shared.publish_snapshot()
+    }
+
+    /// Number of events dropped because a subscriber's channel was full, see
+    /// [`subscribe`](Self::subscribe).
+    #[cfg(feature = "channel")]
+    pub fn dropped_events(&self) -> u64 {
+        self.shared.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Number of callsites removed by the
+    /// [eviction policy](TokioBlockedLayer::with_callsite_eviction).
+    pub fn evicted_callsites(&self) -> u64 {
+        self.shared.evicted_callsites.load(Ordering::Relaxed)
+    }
+
+    /// Reports how much the runtime was blocked during the last `window`,
+    /// e.g. for a liveness probe:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tokio_blocked::{HealthThresholds, TokioBlockedLayer};
+    /// let layer = TokioBlockedLayer::new().with_health_window(Duration::from_secs(60));
+    /// let handle = layer.handle();
+    /// // ... install the layer ...
+    /// let healthy = handle.health(Duration::from_secs(30)).is_healthy(&HealthThresholds {
+    ///     blocked_time: Some(Duration::from_secs(1)),
+    ///     ..Default::default()
+    /// });
+    /// # assert!(healthy);
+    /// ```
+    ///
+    /// Only reads a few atomics, so it is cheap enough to be called on every
+    /// request. Reports nothing blocked without
+    /// [`with_health_window`](TokioBlockedLayer::with_health_window).
+    pub fn health(&self, window: Duration) -> BlockedHealth {
+        let tracker = self.shared.lock(&self.shared.health).clone();
+        match tracker {
+            Some(tracker) => tracker.health(window, self.shared.now()),
+            None => BlockedHealth::default(),
+        }
+    }
+
+    /// The fraction of the last `window` spent in task polls that reached the
+    /// single-poll threshold, process-wide, e.g. for a dashboard gauge.
+    ///
+    /// This is the blocked time of [`health`](Self::health) divided by the
+    /// window it covered, so it exceeds `1.0` if several workers blocked at
+    /// the same time. Only reads a few atomics, so it can be called every
+    /// second. `0.0` if nothing was recorded yet or without
+    /// [`with_health_window`](TokioBlockedLayer::with_health_window).
+    pub fn blocked_fraction(&self, window: Duration) -> f64 {
+        self.shared.blocked_fraction(window)
+    }
+
+    /// The fraction of the runtime's capacity the task polls of the last
+    /// window were busy for, from `0.0` to `1.0`.
+    ///
+    /// Only reads a few atomics. Always `0.0` without
+    /// [`with_saturation_detection`](TokioBlockedLayer::with_saturation_detection).
+    pub fn blocked_capacity_fraction(&self) -> f64 {
+        let tracker = self.shared.lock(&self.shared.saturation).clone();
+        tracker.map_or(0.0, |tracker| tracker.fraction(self.shared.now()))
+    }
+
+    /// Returns counters of data the layer dropped or failed to record, which
+    /// otherwise make the statistics incomplete without notice.
+    pub fn diagnostics(&self) -> Diagnostics {
+        let shared = &*self.shared;
+        Diagnostics {
+            reentrant_spans: shared.reentrant_spans.load(Ordering::Relaxed),
+            uninterned_strings: shared.uninterned_strings.load(Ordering::Relaxed),
+            evicted_callsites: shared.evicted_callsites.load(Ordering::Relaxed),
+            #[cfg(feature = "channel")]
+            dropped_events: shared.dropped_events.load(Ordering::Relaxed),
+            #[cfg(not(feature = "channel"))]
+            dropped_events: 0,
+            event_log_dropped: self.event_log_dropped(),
+            missing_extensions: shared.missing_extensions.load(Ordering::Relaxed),
+            poisoned_locks: shared.poisoned_locks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reports how much memory the collected statistics take, e.g. to size
+    /// the [callsite eviction](TokioBlockedLayer::with_callsite_eviction).
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.shared.memory_stats()
+    }
+
+    /// Number of tracked spans that are currently open.
+    ///
+    /// A steadily growing number hints at leaked spans, e.g. tasks that never
+    /// complete.
+    pub fn live_spans(&self) -> usize {
+        self.shared.live_spans().total()
+    }
+
+    /// Like [`live_spans`](Self::live_spans), split into tasks and async ops.
+    pub fn live_spans_by_kind(&self) -> LiveSpans {
+        self.shared.live_spans()
+    }
+
+    /// Returns the time spent on behalf of closed requests by route, most
+    /// blocked first. Empty unless
+    /// [`with_route_field`](TokioBlockedLayer::with_route_field) is set.
+    pub fn route_snapshot(&self) -> Vec<RouteBlockedStats> {
+        let mut routes: Vec<_> = self
+            .shared
+            .lock(&self.shared.routes)
+            .values()
+            .cloned()
+            .collect();
+        routes.sort_by(|a, b| {
+            b.total_blocked
+                .cmp(&a.total_blocked)
+                .then_with(|| a.route.cmp(&b.route))
+        });
+        routes
+    }
+
+    /// The highest number of [live spans](Self::live_spans) seen at once
+    /// since the layer was created.
+    pub fn live_spans_high_water(&self) -> usize {
+        self.shared.live_high_water.load(Ordering::Relaxed)
+    }
+
+    /// Busy and blocked time attributed to an open request span.
+    #[cfg(feature = "tower")]
+    pub(crate) fn request_busy(&self, id: &span::Id) -> Option<(Duration, Duration)> {
+        let requests = self.shared.lock(&self.shared.requests);
+        let busy = requests.get(id)?;
+        Some((
+            Duration::from_nanos(busy.busy_ns.load(Ordering::Relaxed)),
+            Duration::from_nanos(busy.blocked_ns.load(Ordering::Relaxed)),
+        ))
+    }
+
+    /// Removes and returns all violations recorded so far.
+    ///
+    /// Only populated with [`ViolationMode::Record`].
+    pub fn take_violations(&self) -> Vec<Violation> {
+        std::mem::take(&mut *self.shared.lock(&self.shared.violations))
+    }
+
+    /// Writes the polls captured by the trace recorder in the Chrome trace
+    /// event format (a JSON array), viewable in `chrome://tracing` or
+    /// Perfetto.
+    ///
+    /// Each poll is a complete (`"ph": "X"`) event on the track of the worker
+    /// thread it ran on. Timestamps are microseconds since layer creation.
+    /// Writes an empty array if no recorder is configured.
+    ///
+    /// See [`TokioBlockedLayer::with_trace_recorder`].
+    pub fn export_chrome_trace(&self, w: impl std::io::Write) -> std::io::Result<()> {
+        self.shared.export_chrome_trace(w)
+    }
+}
+
+impl Default for TokioBlockedLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokioBlockedLayer {
+    pub fn new() -> Self {
+        Self::with_shared(Arc::new(Shared::new()), Arc::new(SystemClock))
+    }
+
+    /// A per-layer filter enabling exactly the spans the layer needs: tokio
+    /// task and async op spans, resource spans, block zones, and the spans of
+    /// [`spawn`](fn@crate::spawn), [`scope`](fn@crate::scope) and the tower
+    /// middleware. Events are disabled, so they must be enabled in addition
+    /// for [`with_breadcrumbs`](Self::with_breadcrumbs), see there.
+    ///
+    /// Attach it with [`Layer::with_filter`] to keep the layer working when the
+    /// rest of the subscriber only enables warnings, or to spare the layer from
+    /// seeing unrelated spans. Other filters like an `EnvFilter` of `warn`
+    /// usually disable tokio's `TRACE` spans and must not be applied to this
+    /// layer, nor globally.
+    ///
+    /// The layer itself enables every callsite, as disabling one without a
+    /// per-layer filter would disable it for all layers of the subscriber.
+    ///
+    /// Spans selected with [`with_scope_span`](Self::with_scope_span) other
+    /// than [`CallsiteSelector::scope`], and the spans of
+    /// [`with_request_span`](Self::with_request_span), must be enabled in
+    /// addition, for example with
+    /// [`FilterExt::or`](tracing_subscriber::filter::FilterExt::or).
+    ///
+    /// ```rust
+    /// use tokio_blocked::TokioBlockedLayer;
+    /// use tracing_subscriber::{layer::SubscriberExt as _, Layer as _};
+    ///
+    /// let subscriber = tracing_subscriber::registry().with(
+    ///     TokioBlockedLayer::new().with_filter(TokioBlockedLayer::recommended_filter()),
+    /// );
+    /// ```
+    pub fn recommended_filter() -> FilterFn<fn(&Metadata<'_>) -> bool> {
+        FilterFn::new(is_needed_span)
+    }
+
+    /// Creates a layer with default configuration that records into the same
+    /// statistics as the layer `handle` was obtained from.
+    ///
+    /// Intended for [`tracing_subscriber::reload`]: build the replacement
+    /// layer with this constructor and pass it to `reload::Handle::reload`.
+    /// Accumulated statistics, dedup windows, the trace buffer and the clock
+    /// are kept, and spans created before the swap continue to be accounted.
+    /// Dedup summaries are only flushed once the last sharing layer is
+    /// dropped.
+    ///
+    /// Per-layer settings such as thresholds, violation mode and escalation
+    /// are not inherited and can be set with the usual builder methods.
+    pub fn from_handle(handle: &BlockedStatsHandle) -> Self {
+        let clock = handle.shared.clock().clone();
+        Self::with_shared(handle.shared.clone(), clock)
+    }
+
+    fn with_shared(shared: Arc<Shared>, clock: Arc<dyn Clock>) -> Self {
+        shared.layers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared,
+            allowed_callsites: Mutex::new(HashSet::new()),
+            warn_busy_single_poll: Some(Duration::from_micros(150)),
+            adaptive: None,
+            health: None,
+            fraction_gauge: None,
+            saturation: None,
+            warn_busy_total: None,
+            warn_busy_total_eager: false,
+            warn_busy_total_final: false,
+            on_violation: ViolationMode::Warn,
+            warning_granularity: Granularity::Callsite,
+            trace_min_duration: None,
+            escalation: None,
+            escalation_quiet_period: None,
+            recovery_quiet_polls: None,
+            clock,
+            ewma_alpha: None,
+            poll_histogram: false,
+            anomaly: None,
+            budgets: Vec::new(),
+            scope_spans: Vec::new(),
+            request_spans: Vec::new(),
+            route_field: None,
+            histogram_bounds: histogram::default_bounds(),
+            poll_tiers: Vec::new(),
+            total_tiers: Vec::new(),
+            recent_polls: 0,
+            recent_polls_floor: Duration::ZERO,
+            warning_history: 0,
+            warning_history_suppressed: false,
+            warn_poll_rate: None,
+            warn_first_poll_latency: None,
+            warn_lock_wait: None,
+            warn_blocking_pool_op: None,
+            warn_migrations: None,
+            track_resources: false,
+            eviction: EvictionPolicy::Unbounded,
+            live_flush: None,
+            live_snapshots: false,
+            on_blocked: None,
+            on_blocked_total: None,
+            consecutive_alert: None,
+            patterns: PatternThresholds::default(),
+            metric_events: None,
+            report_interval: None,
+            per_task_summary: false,
+            cpu_time: false,
+            slo_thresholds: Vec::new(),
+            breadcrumbs: 0,
+            group_by: GroupBy::Location,
+            loc_fields: LocFieldNames::default(),
+            runtime_label: None,
+        }
+    }
+
+    /// Creates a layer for use in tests: panics whenever a single poll exceeds
+    /// the single-poll threshold.
+    ///
+    /// Equivalent to `TokioBlockedLayer::new().with_on_violation(ViolationMode::Panic)`.
+    pub fn strict() -> Self {
+        Self::new().with_on_violation(ViolationMode::Panic)
+    }
+
+    pub fn with_warn_busy_single_poll(mut self, duration: Option<Duration>) -> Self {
+        self.warn_busy_single_poll = duration;
+        self
+    }
+
+    /// Scales the single-poll threshold with the load of the runtime,
+    /// tightening it as the workers get busier.
+    ///
+    /// The load is measured by the layer itself from the busy time of task
+    /// polls, see [`AdaptiveThresholds`]. Warnings then carry the configured
+    /// threshold as `threshold_ns`, plus `effective_threshold_ns` and the
+    /// `load` it was derived from.
+    pub fn with_adaptive_thresholds(mut self, config: AdaptiveThresholds) -> Self {
+        self.adaptive = Some(LoadTracker::new(config));
+        self
+    }
+
+    /// Keeps the blocked time of the last `retention` in time buckets, for
+    /// [`BlockedStatsHandle::health`], e.g. for liveness probes.
+    ///
+    /// The retention is split into 60 buckets, which is the granularity of
+    /// the queried windows. Blocked time counts task polls that reached the
+    /// single-poll threshold, so with
+    /// [`with_warn_busy_single_poll(None)`](Self::with_warn_busy_single_poll)
+    /// only the longest poll is tracked.
+    pub fn with_health_window(mut self, retention: Duration) -> Self {
+        let tracker = Arc::new(HealthTracker::new(retention));
+        *self.shared.lock(&self.shared.health) = Some(tracker.clone());
+        self.health = Some(tracker);
+        self
+    }
+
+    /// Exports the [`BlockedStatsHandle::blocked_fraction`] of the last
+    /// `window` as a gauge: in [`BlockedStatsHandle::render_prometheus`], and
+    /// on every [report](BlockedStatsHandle::report) as a
+    /// `tokio_blocked::metrics` event with `blocked_fraction` and
+    /// `window_ms`, named per [`with_metric_naming`](Self::with_metric_naming).
+    ///
+    /// Enables [`with_health_window`](Self::with_health_window) with a
+    /// retention of `window` unless it is already configured.
+    pub fn with_blocked_fraction_gauge(mut self, window: Duration) -> Self {
+        if self.health.is_none() {
+            self = self.with_health_window(window);
+        }
+        self.fraction_gauge = Some(window);
+        self
+    }
+
+    /// Emits a `tokio_blocked::runtime_saturated` WARN event when the busy
+    /// time of task polls during a sliding window reaches a percentage of
+    /// the runtime's capacity, see [`SaturationThresholds`].
+    ///
+    /// The event has the `fraction`, `busy_ns`, `capacity_ns`, `workers` and
+    /// `window_ns`. It is emitted once when the threshold is crossed, the
+    /// next one only after the fraction dropped below the threshold minus the
+    /// hysteresis. The current fraction is available from
+    /// [`BlockedStatsHandle::blocked_capacity_fraction`].
+    pub fn with_saturation_detection(mut self, config: SaturationThresholds) -> Self {
+        let tracker = Arc::new(SaturationTracker::new(config));
+        *self.shared.lock(&self.shared.saturation) = Some(tracker.clone());
+        self.saturation = Some(tracker);
+        self
+    }
+
+    pub fn with_warn_busy_total(mut self, duration: Option<Duration>) -> Self {
+        self.warn_busy_total = duration;
+        self
+    }
+
+    /// Checks the [total threshold](Self::with_warn_busy_total) of tasks
+    /// after every poll instead of only on close, so long-lived tasks such as
+    /// connections are reported when they cross it rather than days later.
+    ///
+    /// The first crossing emits `task_blocked_total` with
+    /// `span_still_open = true`. The warning on close is then skipped, unless
+    /// enabled with [`with_warn_busy_total_final`](Self::with_warn_busy_total_final).
+    pub fn with_warn_busy_total_eager(mut self, enabled: bool) -> Self {
+        self.warn_busy_total_eager = enabled;
+        self
+    }
+
+    /// Also warns on close about tasks already reported by
+    /// [`with_warn_busy_total_eager`](Self::with_warn_busy_total_eager), with
+    /// their final totals.
+    pub fn with_warn_busy_total_final(mut self, enabled: bool) -> Self {
+        self.warn_busy_total_final = enabled;
+        self
+    }
+
+    /// Sets how spans are aggregated in the statistics. Defaults to
+    /// [`GroupBy::Location`].
+    ///
+    /// Rows grouped by task name have the name in
+    /// [`CallsiteStatsSnapshot::task_name`] and no location. With
+    /// [`GroupBy::Both`], name rows are only updated when a span closes, and
+    /// their [`exceed_count`](CallsiteStatsSnapshot::exceed_count) is not
+    /// used for escalation.
+    pub fn with_group_by(mut self, group_by: GroupBy) -> Self {
+        self.group_by = group_by;
+        self
+    }
+
+    /// Sets the span fields the spawn location is read from. Defaults to
+    /// [`LocFieldNames::tokio`].
+    ///
+    /// E.g. `LocFieldNames::tokio().and(LocFieldNames::otel())` accepts
+    /// tokio's fields as well as `code.filepath` and `code.lineno`. Fields
+    /// recorded after the span was created fill in what it did not have yet.
+    pub fn with_loc_fields(mut self, names: LocFieldNames) -> Self {
+        self.loc_fields = names;
+        self
+    }
+
+    /// Labels every span with the runtime it is polled on, as returned by `f`
+    /// on the thread of its first poll.
+    ///
+    /// The label is a separate dimension of the statistics, so every runtime
+    /// of a process gets its own rows, and is added as the `runtime` field to
+    /// warnings. Spans that are never polled are not labeled.
+    pub fn with_runtime_label(mut self, f: fn() -> Option<&'static str>) -> Self {
+        self.runtime_label = Some(RuntimeLabel::Fn(f));
+        self
+    }
+
+    /// Like [`with_runtime_label`](Self::with_runtime_label), labeling spans
+    /// first polled on threads whose name starts with one of the prefixes,
+    /// e.g. as set with `tokio::runtime::Builder::thread_name`:
+    ///
+    /// ```rust
+    /// # use tokio_blocked::TokioBlockedLayer;
+    /// let layer = TokioBlockedLayer::new()
+    ///     .with_runtime_label_from_thread_prefixes(&[("ingest-", "ingest"), ("compute-", "compute")]);
+    /// ```
+    ///
+    /// The first matching prefix wins.
+    pub fn with_runtime_label_from_thread_prefixes(
+        mut self,
+        prefixes: &[(&str, &'static str)],
+    ) -> Self {
+        let prefixes = prefixes
+            .iter()
+            .map(|&(prefix, label)| (prefix.to_string(), label))
+            .collect();
+        self.runtime_label = Some(RuntimeLabel::ThreadPrefixes(prefixes));
+        self
+    }
+
+    /// Reports blocking tasks with a single event when they close, instead of
+    /// one `tokio_blocked::task_poll_blocked` event per blocked poll.
+    ///
+    /// The `tokio_blocked::task_blocked_total` event is then emitted for every
+    /// span with at least one poll over the single-poll threshold, even
+    /// without [`with_warn_busy_total`](Self::with_warn_busy_total). Its
+    /// `max_poll_ns`, `max_poll_seq`, `polls_over_threshold` and
+    /// `polls_total` fields summarize the polls. Defaults to `false`.
+    pub fn with_per_task_summary(mut self, enabled: bool) -> Self {
+        self.per_task_summary = enabled;
+        self
+    }
+
+    /// Measures the CPU time of the polling thread next to the wall time of
+    /// polls, to tell a poll waiting on a syscall or lock from one that is
+    /// computing. Defaults to `false`.
+    ///
+    /// Warnings then carry `cpu_ns` and `on_cpu_percent`, the CPU time as a
+    /// percentage of the wall time, and closed spans add their CPU time to
+    /// [`CallsiteStatsSnapshot::total_cpu`]. Costs a system call at the
+    /// start and end of every poll.
+    ///
+    /// Supported on Linux, Android, macOS, iOS, FreeBSD and Windows. Elsewhere
+    /// the setting has no effect and the fields are left out.
+    pub fn with_cpu_time(mut self, enabled: bool) -> Self {
+        self.cpu_time = enabled;
+        self
+    }
+
+    /// Counts the polls of at least each of `thresholds` per callsite, e.g.
+    /// 1ms, 10ms and 100ms for SLO reporting, without the cost of a full
+    /// [poll histogram](Self::with_poll_histogram).
+    ///
+    /// The counts are kept per span and added to the callsite when the span
+    /// closes, see [`CallsiteStatsSnapshot::over_threshold`]. They are
+    /// exported by [`render_prometheus`](Snapshot::render_prometheus) as
+    /// `tokio_blocked_polls_over_threshold_total` and by the OTLP exporter as
+    /// `tokio_blocked.polls_over_threshold`, labeled with the threshold.
+    pub fn with_slo_thresholds(mut self, thresholds: &[Duration]) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort();
+        thresholds.dedup();
+        self.slo_thresholds = thresholds;
+        self
+    }
+
+    /// Keeps the last `n` events emitted inside every tracked span, and
+    /// attaches them to the warnings of its polls as
+    /// [`BlockedPollInfo::breadcrumbs`] and the `breadcrumbs` field. `0`, the
+    /// default, disables it.
+    ///
+    /// The log lines a task emitted before and during a blocked poll usually
+    /// tell which operation blocked. Events count for the innermost tracked
+    /// span entered on the thread, messages are cut off at 256 bytes, so
+    /// memory stays bounded by `n` short strings per span.
+    ///
+    /// The layer only sees the events its filter enables: with the
+    /// [`recommended_filter`](Self::recommended_filter), which disables
+    /// events, enable them in addition, for example with
+    /// `recommended_filter().or(filter_fn(|meta| meta.is_event()))`.
+    pub fn with_breadcrumbs(mut self, n: usize) -> Self {
+        self.breadcrumbs = n;
+        self
+    }
+
+    /// Configures what happens when a single poll exceeds the single-poll
+    /// threshold. Defaults to [`ViolationMode::Warn`].
+    pub fn with_on_violation(mut self, mode: ViolationMode) -> Self {
+        self.on_violation = mode;
+        self
+    }
+
+    /// Records polls that take at least `min_duration` into a bounded buffer
+    /// of at most `limit` entries (the oldest are dropped first), for export
+    /// with [`BlockedStatsHandle::export_chrome_trace`].
+    pub fn with_trace_recorder(mut self, limit: usize, min_duration: Duration) -> Self {
+        *self.shared.lock(&self.shared.trace) = Some(TraceRecorder::new(limit, min_duration));
+        self.trace_min_duration = Some(min_duration);
+        self
+    }
+
+    /// Emits `task_poll_blocked` warnings at the level of the highest tier
+    /// the poll reached, e.g. DEBUG from 200µs, WARN from 2ms and ERROR from
+    /// 50ms, with the tier's threshold as `tier_threshold_ns`.
+    ///
+    /// Replaces the single-poll threshold with the lowest tier. Tiers are
+    /// sorted and duplicate thresholds removed;
+    /// [`Builder::poll_severity_tiers`](crate::Builder::poll_severity_tiers)
+    /// rejects such lists instead. An empty list removes the tiers.
+    /// [Escalated](Self::with_escalation) warnings keep the escalation level.
+    pub fn with_poll_severity_tiers(mut self, tiers: &[(Duration, Level)]) -> Self {
+        self.poll_tiers = sorted_tiers(tiers);
+        if let Some(&(lowest, _)) = self.poll_tiers.first() {
+            self.warn_busy_single_poll = Some(lowest);
+        }
+        self
+    }
+
+    /// Like [`with_poll_severity_tiers`](Self::with_poll_severity_tiers), for
+    /// the `task_blocked_total` warnings, replacing the
+    /// [total threshold](Self::with_warn_busy_total).
+    pub fn with_total_severity_tiers(mut self, tiers: &[(Duration, Level)]) -> Self {
+        self.total_tiers = sorted_tiers(tiers);
+        if let Some(&(lowest, _)) = self.total_tiers.first() {
+            self.warn_busy_total = Some(lowest);
+        }
+        self
+    }
+
+    /// Escalates `task_poll_blocked` warnings of a callsite to `level` once it
+    /// has exceeded the single-poll threshold `after` times.
+    ///
+    /// The `after`-th warning and all following ones are emitted at `level`,
+    /// with an `escalated = true` field. The count is kept for the lifetime of
+    /// the layer, until [`BlockedStatsHandle::reset`], or until the callsite
+    /// has been quiet for the period set with
+    /// [`with_escalation_quiet_period`](Self::with_escalation_quiet_period).
+    pub fn with_escalation(mut self, after: u64, level: Level) -> Self {
+        self.escalation = Some(Escalation { after, level });
+        self
+    }
+
+    /// De-escalate a callsite (and restart its count) once it has not exceeded
+    /// the single-poll threshold for `period`.
+    ///
+    /// Only has an effect together with [`with_escalation`](Self::with_escalation),
+    /// which may be called before or after this.
+    pub fn with_escalation_quiet_period(mut self, period: Duration) -> Self {
+        self.escalation_quiet_period = Some(period);
+        self
+    }
+
+    /// Emits a single `tokio_blocked::callsite_recovered` INFO event once a
+    /// callsite that exceeded the single-poll threshold completes
+    /// `quiet_polls` consecutive polls under it, e.g. to resolve alerts.
+    ///
+    /// The event has the callsite fields, `episode_ns` from the start of the
+    /// first blocked poll to the recovery, `peak_poll_ns` and `blocked_polls`.
+    /// A later blocked poll starts a new episode. Every clean poll of a
+    /// blocked callsite takes the statistics lock, so keep `quiet_polls` low.
+    pub fn with_recovery_events(mut self, quiet_polls: u64) -> Self {
+        self.recovery_quiet_polls = Some(quiet_polls.max(1));
+        self
+    }
+
+    /// Deduplicates `task_poll_blocked` warnings from the same origin location
+    /// (spawn location, or span callsite if unknown) within `window`.
+    ///
+    /// The first warning of a location is emitted immediately, later ones
+    /// within the window are only counted. Once the window has closed, a
+    /// single `tokio_blocked::task_poll_blocked_summary` event is emitted with
+    /// `occurrences`, `max_duration_ns` and `total_duration_ns`, if anything
+    /// was suppressed.
+    ///
+    /// Closed windows are flushed on the next warning, on each report (see
+    /// [`with_report_interval`](Self::with_report_interval) and
+    /// [`BlockedStatsHandle::report`]), on [`BlockedStatsHandle::flush`] and
+    /// when the layer is dropped.
+    pub fn with_dedup_window(self, window: Duration) -> Self {
+        *self.shared.lock(&self.shared.dedup) = Some(Dedup::new(window));
+        self
+    }
+
+    /// Groups the warnings deduplicated by
+    /// [`with_dedup_window`](Self::with_dedup_window) at `granularity`.
+    /// Defaults to [`Granularity::Callsite`].
+    ///
+    /// With [`Granularity::File`], the first warning of any line of a file
+    /// is emitted and the warnings of all its lines within the window are
+    /// summarized together. The summary has no `callsite.line`, but lists
+    /// up to 16 distinct lines, sorted, in `lines`, e.g. `"12,40"`. The
+    /// statistics are still kept per callsite.
+    pub fn with_group_warnings_by(mut self, granularity: Granularity) -> Self {
+        self.warning_granularity = granularity;
+        self
+    }
+
+    /// Maintains an exponentially weighted moving average of poll durations per
+    /// callsite, exposed as [`CallsiteStatsSnapshot::ewma_poll`].
+    ///
+    /// Every completed poll updates the average as
+    /// `ewma = alpha * poll + (1 - alpha) * ewma`, so higher values of `alpha`
+    /// (clamped to `(0, 1]`, NaN is taken as 1) react faster. The average does
+    /// not decay with time: when a callsite goes quiet, it keeps the value of
+    /// its last poll.
+    pub fn with_ewma_alpha(mut self, alpha: f64) -> Self {
+        let alpha = if alpha.is_nan() { 1.0 } else { alpha };
+        self.ewma_alpha = Some(alpha.clamp(f64::MIN_POSITIVE, 1.0));
+        self
+    }
+
+    /// Warns when a single task is polled more than `polls_per_sec` times per
+    /// second, which usually means a future keeps waking itself without making
+    /// progress. Such tasks burn a whole core while every individual poll stays
+    /// below the duration thresholds.
+    ///
+    /// The rate is measured per span over windows of at least 100ms. A
+    /// `tokio_blocked::task_poll_storm` event is emitted with `polls_per_sec`,
+    /// `window_polls` and `total_polls` for every window that exceeds the
+    /// limit. Non-positive rates are clamped to the smallest positive value.
+    pub fn with_warn_poll_rate(mut self, polls_per_sec: f64) -> Self {
+        self.warn_poll_rate = Some(polls_per_sec.max(f64::MIN_POSITIVE));
+        self
+    }
+
+    /// Warns when a task is first polled more than `latency` after it was
+    /// spawned, which indicates a backed up scheduler.
+    ///
+    /// Emits `tokio_blocked::task_first_poll_delayed` with `delay_ns` and the
+    /// task name, if any. The delay is also accumulated per callsite, see
+    /// [`CallsiteStatsSnapshot::spawn_to_first_poll`].
+    pub fn with_warn_first_poll_latency(mut self, latency: Option<Duration>) -> Self {
+        self.warn_first_poll_latency = latency;
+        self
+    }
+
+    /// Warns when an operation on tokio's blocking pool, e.g. a
+    /// `spawn_blocking` closure or a `tokio::fs` call, runs for at least
+    /// `duration`.
+    ///
+    /// Blocking pool spans (task spans with `kind = "blocking"`) never get
+    /// the single-poll and total warnings, as they don't block the workers.
+    /// Emits `tokio_blocked::blocking_pool_op` with `busy_ns`; their rows have
+    /// [`Category::BlockingPool`]. Needs `tokio_unstable` for tokio to
+    /// instrument them.
+    pub fn with_warn_blocking_pool_op(mut self, duration: Duration) -> Self {
+        self.warn_blocking_pool_op = Some(duration);
+        self
+    }
+
+    /// Warns when a span moved between threads more than `migrations` times
+    /// over its lifetime, which often comes with blocking, as idle workers
+    /// steal the tasks queued behind a blocked one.
+    ///
+    /// A migration is an outermost poll on a different thread than the
+    /// previous one. Emits `tokio_blocked::task_migrations` with `migrations`
+    /// and `polls_total` when the span closes. Migrations are only counted
+    /// with this, see [`CallsiteStatsSnapshot::migrations`].
+    pub fn with_warn_migrations(mut self, migrations: u64) -> Self {
+        self.warn_migrations = Some(migrations);
+        self
+    }
+
+    /// Warns when the callsites matching `selector` are busy for more than
+    /// `budget` within a period of `per`, e.g. 2ms per second.
+    ///
+    /// Each callsite is accounted separately, in consecutive periods starting
+    /// with its first poll after the previous period ended. The first poll
+    /// over budget emits `tokio_blocked::budget_exceeded` with the busy time
+    /// so far, `over_budget_ns` and the `polls` of the period; later polls of
+    /// the period don't warn again. Can be called several times, `per` is
+    /// clamped to at least 1ns.
+    pub fn with_busy_budget(
+        mut self,
+        selector: CallsiteSelector,
+        budget: Duration,
+        per: Duration,
+    ) -> Self {
+        self.budgets.push(BusyBudget {
+            selector,
+            budget,
+            per: per.max(Duration::from_nanos(1)),
+        });
+        self
+    }
+
+    /// Only tracks spans beneath a span matching `selector`, e.g. a
+    /// `load_test` span, and ignores all others. Can be called several times.
+    ///
+    /// A span is beneath a scope if one of its ancestors matches, or one of
+    /// the ancestors of the span that is current while it is created. As the
+    /// tasks spawned by tracked tasks are tracked as well, whole task trees
+    /// are scoped, even though tokio's task spans have no parent. See
+    /// [`scope`](fn@crate::scope) to scope a future without a span of its own.
+    pub fn with_scope_span(mut self, selector: CallsiteSelector) -> Self {
+        self.scope_spans.push(selector);
+        self
+    }
+
+    /// Totals the time spent on behalf of spans named `name`, e.g. the
+    /// `request` span of an HTTP handler, of any target. Can be called
+    /// several times.
+    ///
+    /// Like the spans of the tower middleware, such a span accumulates the
+    /// time it was entered itself and the polls of the tasks spawned beneath
+    /// it, which are attributed to the nearest such span when they are
+    /// created. When it closes, which is only after the spans beneath it
+    /// closed, it emits an INFO `tokio_blocked::request_total` event with
+    /// `busy_ns` and `blocked_ns`, the time of polls that reached the
+    /// single-poll threshold, as a child of the span.
+    ///
+    /// Tasks are only attributed through tokio's task spans (`--cfg
+    /// tokio_unstable`) or [`spawn`](fn@crate::spawn). Work handed to tasks
+    /// that already exist, e.g. over a channel, is not attributed.
+    pub fn with_request_span(mut self, name: impl Into<String>) -> Self {
+        self.request_spans.push(name.into());
+        self
+    }
+
+    /// Aggregates the time of closed request spans by the value of their
+    /// field `name`, e.g. `http.route`, see
+    /// [`BlockedStatsHandle::route_snapshot`].
+    ///
+    /// Request spans are the spans of the tower middleware and those named
+    /// with [`with_request_span`](Self::with_request_span). The field may be
+    /// recorded after the span was created. Requests without it are counted
+    /// under the route `unknown`.
+    pub fn with_route_field(mut self, name: impl Into<String>) -> Self {
+        self.route_field = Some(name.into());
+        self
+    }
+
+    /// Never warns about blocking at the given `(file, line)` spawn locations,
+    /// e.g. a startup task that is allowed to block. A location matches if
+    /// its file contains `file` and, if set, its line equals `line`.
+    ///
+    /// Unlike [`BlockedStatsHandle::mute_callsite`], the list is meant to be
+    /// kept in code. Statistics are still collected, and the rows of allowed
+    /// locations are marked with [`CallsiteStatsSnapshot::allowed`].
+    /// Replaces the previous list.
+    pub fn with_allow_blocking_at(self, locations: &[(&str, Option<u32>)]) -> Self {
+        *self.shared.lock(&self.shared.allow_blocking_at) = locations
+            .iter()
+            .map(|&(file, line)| (file.to_string(), line))
+            .collect();
+        self
+    }
+
+    /// Also tracks tokio's `runtime.resource` spans, e.g. of timers and
+    /// sockets, to find resource churn.
+    ///
+    /// Resource spans live as long as the resource, so only their number and
+    /// lifetimes are aggregated, in rows with [`Category::Resource`] and the
+    /// concrete type of the resource. They never get warnings. Off by default,
+    /// as it adds a tracked span per resource. Needs `tokio_unstable` and
+    /// tokio's `tracing` feature for tokio to instrument resources.
+    pub fn with_track_resources(mut self, enabled: bool) -> Self {
+        self.track_resources = enabled;
+        self
+    }
+
+    /// Warns when acquiring a tokio sync primitive such as a `Mutex`,
+    /// `RwLock` or `Semaphore` takes at least `wait`.
+    ///
+    /// The wait is the lifetime of the async op span, e.g. `Mutex::lock`, so
+    /// this needs tokio's resource instrumentation (`tokio_unstable` and the
+    /// `tracing` feature). Emits `tokio_blocked::lock_wait` with `wait_ns`
+    /// and the resource fields instead of the generic async op warnings.
+    /// Lock waits have their own rows in the statistics, see
+    /// [`CallsiteStatsSnapshot::lock_wait`].
+    pub fn with_warn_lock_wait(mut self, wait: Duration) -> Self {
+        self.warn_lock_wait = Some(wait);
+        self
+    }
+
+    /// Bounds the number of tracked callsites, e.g. for long running services
+    /// with many spawn locations that only run once.
+    ///
+    /// Evicted callsites are counted in
+    /// [`BlockedStatsHandle::evicted_callsites`].
+    pub fn with_callsite_eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction = policy;
+        self
+    }
+
+    /// Moves the busy time of live spans into the callsite statistics at most
+    /// once per `interval`, instead of only when the span closes.
+    ///
+    /// Without this, tasks that live as long as the process never show up in
+    /// [`BlockedStatsHandle::snapshot`]. Flushing happens at the end of a poll,
+    /// so idle spans are flushed on their next poll. The span `count`, lifetime
+    /// and first poll delay are still only accounted on close.
+    ///
+    /// Enables [`with_live_snapshots`](Self::with_live_snapshots) as well, so
+    /// live snapshots include the busy time since the last flush.
+    pub fn with_live_flush(mut self, interval: Duration) -> Self {
+        self.live_flush = Some(interval);
+        self
+    }
+
+    /// Keeps an index of the tracked spans that are open, so that
+    /// [`BlockedStatsHandle::snapshot_with_live`],
+    /// [`render_prometheus`](BlockedStatsHandle::render_prometheus) and the
+    /// OTLP exporter include the busy time of spans that have not been
+    /// closed yet.
+    ///
+    /// Off by default, as the index is updated whenever a tracked span opens
+    /// or closes, including the per-poll spans of async ops. Without it, live
+    /// snapshots only include closed spans.
+    pub fn with_live_snapshots(mut self, enabled: bool) -> Self {
+        self.live_snapshots = enabled;
+        self
+    }
+
+    /// Maintains a histogram of poll durations per callsite, exposed as
+    /// [`CallsiteStatsSnapshot::poll_histogram`], e.g. for percentiles.
+    ///
+    /// Buckets are powers of two from 1µs to ~8.4s, unless configured with
+    /// [`with_histogram_buckets`](Self::with_histogram_buckets).
+    pub fn with_poll_histogram(mut self, enabled: bool) -> Self {
+        self.poll_histogram = enabled;
+        self
+    }
+
+    /// Warns about polls of more than `multiplier` times the `percentile`
+    /// (e.g. `95.0`) of their callsite's own poll durations, once the callsite
+    /// has at least `min_samples` polls, e.g. to find the outliers of
+    /// callsites that are always somewhat slow.
+    ///
+    /// Such callsites emit `tokio_blocked::poll_anomaly` with the baseline
+    /// percentile as `baseline_ns` instead of comparing against the
+    /// single-poll threshold, which still applies before `min_samples`. The
+    /// baseline is estimated from poll histograms, which are kept as with
+    /// [`with_poll_histogram`](Self::with_poll_histogram). Anomalies are not
+    /// counted in [`exceed_count`](CallsiteStatsSnapshot::exceed_count).
+    ///
+    /// The multiplier is clamped to at least 1, the percentile to
+    /// `(0, 100]` and `min_samples` to at least 1.
+    pub fn with_anomaly_threshold(
+        mut self,
+        multiplier: f64,
+        percentile: f64,
+        min_samples: u64,
+    ) -> Self {
+        self.anomaly = Some(AnomalyThreshold {
+            multiplier: if multiplier >= 1.0 { multiplier } else { 1.0 },
+            percentile: if percentile > 0.0 {
+                percentile.min(100.0)
+            } else {
+                100.0
+            },
+            min_samples: min_samples.max(1),
+        });
+        self
+    }
+
+    /// Replaces the default bucket bounds of
+    /// [`with_poll_histogram`](Self::with_poll_histogram), e.g. with the
+    /// buckets of an existing Prometheus histogram so they line up exactly.
+    ///
+    /// Bounds are sorted and duplicates removed. An empty list keeps the
+    /// default buckets; [`Builder::histogram_buckets`](crate::Builder::histogram_buckets)
+    /// rejects such lists instead.
+    pub fn with_histogram_buckets(mut self, bounds: &[Duration]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.sort();
+        bounds.dedup();
+        if !bounds.is_empty() {
+            self.histogram_bounds = bounds.into();
+        }
+        self
+    }
+
+    /// Sets the quantiles estimated from the
+    /// [poll histograms](Self::with_poll_histogram) in snapshots and reports,
+    /// see [`CallsiteStatsSnapshot::percentiles`]. Defaults to 0.5, 0.95 and
+    /// 0.99.
+    ///
+    /// Quantiles outside of `(0, 1]` are ignored;
+    /// [`Builder::report_percentiles`](crate::Builder::report_percentiles)
+    /// rejects them instead.
+    pub fn with_report_percentiles(self, quantiles: &[f64]) -> Self {
+        let mut quantiles: Vec<_> = quantiles
+            .iter()
+            .copied()
+            .filter(|q| *q > 0.0 && *q <= 1.0)
+            .collect();
+        quantiles.sort_by(f64::total_cmp);
+        quantiles.dedup();
+        *self.shared.lock(&self.shared.report_percentiles) = quantiles;
+        self
+    }
+
+    /// Keeps the last `n` polls of every callsite with their start time,
+    /// returned by [`BlockedStatsHandle::recent_polls`]. `0` disables it.
+    ///
+    /// Older polls are dropped, so memory stays bounded by `n` samples per
+    /// callsite; combine with [`with_callsite_eviction`](Self::with_callsite_eviction)
+    /// to bound the number of callsites. Polls are grouped by location, like
+    /// [`GroupBy::Location`].
+    pub fn with_recent_polls(mut self, n: usize) -> Self {
+        self.recent_polls = n;
+        self
+    }
+
+    /// Keeps the last `n` emitted warnings, returned by
+    /// [`BlockedStatsHandle::recent_warnings`], e.g. for an admin endpoint.
+    /// `0` disables it.
+    ///
+    /// Keeps `task_poll_blocked` and `task_blocked_total` warnings with
+    /// [`ViolationMode::Warn`].
+    pub fn with_warning_history(mut self, n: usize) -> Self {
+        self.warning_history = n;
+        self.shared.lock(&self.shared.warning_history).capacity = n;
+        self
+    }
+
+    /// Also keeps warnings in the [history](Self::with_warning_history) that
+    /// were not emitted, flagged as
+    /// [`suppressed`](crate::events::HistoricalWarning::suppressed).
+    /// Defaults to `false`.
+    pub fn with_warning_history_suppressed(mut self, enabled: bool) -> Self {
+        self.warning_history_suppressed = enabled;
+        self
+    }
+
+    /// Only keeps polls of at least `floor` for
+    /// [`with_recent_polls`](Self::with_recent_polls), so short polls don't
+    /// push out the interesting ones. Defaults to zero.
+    pub fn with_recent_polls_floor(mut self, floor: Duration) -> Self {
+        self.recent_polls_floor = floor;
+        self
+    }
+
+    /// Calls `f` for every poll that exceeds the single-poll threshold, e.g. to
+    /// update custom metrics.
+    ///
+    /// The callback runs on the thread that ran the poll (usually a runtime
+    /// worker), right before the warning is emitted, so it must be fast and
+    /// must not block. It is called regardless of the violation mode, muting
+    /// and [`BlockedStatsHandle::set_warnings_enabled`].
+    pub fn with_on_blocked(
+        mut self,
+        f: impl Fn(&BlockedPollInfo<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_blocked = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` for every closed span whose total busy time exceeds the
+    /// total threshold, see [`with_warn_busy_total`](Self::with_warn_busy_total),
+    /// and for every [per-task summary](Self::with_per_task_summary).
+    ///
+    /// Like [`with_on_blocked`](Self::with_on_blocked), the callback runs
+    /// inline and must be fast.
+    pub fn with_on_blocked_total(
+        mut self,
+        f: impl Fn(&BlockedTotalInfo<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_blocked_total = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` and emits a `tokio_blocked::sustained_blocking` ERROR event
+    /// once `n` consecutive spans of a callsite closed with a total busy
+    /// time over the total threshold, see
+    /// [`with_warn_busy_total`](Self::with_warn_busy_total).
+    ///
+    /// A single blocking span is often noise, the same spawn location
+    /// blocking in every task is an incident. The alert fires once per
+    /// streak; a span under the threshold ends the streak and re-arms it.
+    /// Streaks are kept in the per-callsite statistics, so they are lost on
+    /// [reset](BlockedStatsHandle::reset) and eviction, and not counted while
+    /// [paused](BlockedStatsHandle::pause_stats).
+    ///
+    /// Like [`with_on_blocked`](Self::with_on_blocked), the callback runs
+    /// inline, regardless of muting, and must be fast.
+    pub fn with_consecutive_alert(
+        mut self,
+        n: u64,
+        f: impl Fn(&SustainedBlockingInfo<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.consecutive_alert = Some((n.max(1), Arc::new(f)));
+        self
+    }
+
+    /// Overrides the thresholds of the [`BlockingPattern`] classification,
+    /// which are derived from the warning thresholds by default.
+    ///
+    /// The pattern of a blocked span is reported as the `pattern` field of
+    /// `tokio_blocked::task_blocked_total`, and counted per callsite in
+    /// [`CallsiteStatsSnapshot::patterns`].
+    pub fn with_blocking_patterns(mut self, thresholds: PatternThresholds) -> Self {
+        self.patterns = thresholds;
+        self
+    }
+
+    /// Emits a `tokio_blocked::metrics` event for every blocked poll and
+    /// blocked span, with fields named after the
+    /// [`MetricNaming::OpenTelemetry`] convention, e.g.
+    /// `histogram.tokio_blocked.poll_duration_ms = 12.3` and
+    /// `monotonic_counter.tokio_blocked.blocked_polls = 1`, plus
+    /// `callsite.file`, `callsite.line`, `callsite.name` and `task.name`
+    /// attributes. Blocked spans emit `blocked_total_ms` and `blocked_spans`.
+    ///
+    /// With the `tracing-opentelemetry` metrics layer installed, these become
+    /// metrics without any further exporter. Like the callbacks, they are
+    /// emitted before muting and deduplication, so every blocked poll is
+    /// counted. They come in addition to the warnings; use
+    /// [`with_observers`](Self::with_observers) with an empty list to only
+    /// emit the metrics.
+    pub fn with_metric_events(mut self, enabled: bool) -> Self {
+        self.metric_events = enabled.then(|| self.metric_events.unwrap_or_default());
+        self
+    }
+
+    /// Enables [`with_metric_events`](Self::with_metric_events) with the
+    /// given field names.
+    pub fn with_metric_naming(mut self, naming: MetricNaming) -> Self {
+        self.metric_events = Some(naming);
+        self
+    }
+
+    /// Adds an observer that receives blocked polls, blocked spans and
+    /// snapshots, in addition to the existing ones.
+    ///
+    /// Observers are shared with layers created with
+    /// [`from_handle`](Self::from_handle), so they don't need to be added
+    /// again after a reload.
+    pub fn with_observer(self, observer: Arc<dyn BlockedObserver>) -> Self {
+        self.shared.write(&self.shared.observers).push(observer);
+        self
+    }
+
+    /// Additionally emits blocked polls and spans as `log` records, see
+    /// [`LogObserver`](crate::LogObserver), e.g. for binaries logging with
+    /// `env_logger`. Other events of the layer are only emitted to tracing.
+    ///
+    /// Opt-in so that warnings aren't printed twice when tracing events are
+    /// already forwarded to `log`. Requires the `log` feature.
+    #[cfg(feature = "log")]
+    pub fn with_log_fallback(self, enabled: bool) -> Self {
+        if enabled {
+            self.with_observer(Arc::new(crate::LogObserver))
+        } else {
+            self
+        }
+    }
+
+    /// Replaces all observers, including the default [`TracingObserver`].
+    ///
+    /// Pass an empty list to disable the warning events entirely, or include
+    /// `Arc::new(TracingObserver)` to keep them.
+    pub fn with_observers(
+        self,
+        observers: impl IntoIterator<Item = Arc<dyn BlockedObserver>>,
+    ) -> Self {
+        *self.shared.write(&self.shared.observers) = observers.into_iter().collect();
+        self
+    }
+
+    /// Calls [`BlockedStatsHandle::report`] every `interval` from a
+    /// background thread, which is started when the layer is installed and
+    /// stops once the layer and all handles are dropped.
+    pub fn with_report_interval(mut self, interval: Duration) -> Self {
+        self.report_interval = Some(interval.max(Duration::from_millis(1)));
+        self
+    }
+
+    /// Appends per-callsite samples to the file at `path` every `interval`,
+    /// see [`TimeseriesWriter`](crate::TimeseriesWriter).
+    ///
+    /// Registers the writer as an observer and sets the
+    /// [report interval](Self::with_report_interval). Fails if the file
+    /// cannot be opened. Requires the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn with_timeseries_file(
+        self,
+        path: impl AsRef<std::path::Path>,
+        interval: Duration,
+    ) -> std::io::Result<Self> {
+        let writer = crate::TimeseriesWriter::create(path)?;
+        Ok(self
+            .with_observer(Arc::new(writer))
+            .with_report_interval(interval))
+    }
+
+    /// Stores per-callsite samples in the SQLite database at `path` every
+    /// `interval`, see [`SqliteSink`](crate::SqliteSink).
+    ///
+    /// Registers the sink as an observer and sets the
+    /// [report interval](Self::with_report_interval), so the samples are
+    /// written from the reporter thread. Fails if the database cannot be
+    /// opened or migrated. Requires the `sqlite` feature.
+    #[cfg(feature = "sqlite")]
+    pub fn with_sqlite_sink(
+        self,
+        path: impl AsRef<std::path::Path>,
+        interval: Duration,
+    ) -> rusqlite::Result<Self> {
+        let sink = crate::SqliteSink::open(path)?;
+        Ok(self
+            .with_observer(Arc::new(sink))
+            .with_report_interval(interval))
+    }
+
+    /// Appends every poll over the single-poll threshold to a compact binary
+    /// log at `path`, see [`event_log`](crate::event_log) for the format
+    /// and the reader.
+    ///
+    /// Polls are logged whether or not their warning is emitted, e.g. when
+    /// it is deduplicated or [muted](BlockedStatsHandle::mute_callsite). A dedicated
+    /// thread writes the records; if it falls behind, records are dropped
+    /// rather than blocking the polled task, see
+    /// [`BlockedStatsHandle::event_log_dropped`]. Once the log exceeds
+    /// `max_size` bytes it is rotated to `<path>.1`, so the newest records
+    /// take up to twice `max_size`.
+    ///
+    /// Existing log files at `path` are replaced. Fails if they cannot be
+    /// created.
+    pub fn with_event_log(
+        self,
+        path: impl AsRef<std::path::Path>,
+        max_size: u64,
+    ) -> std::io::Result<Self> {
+        let log = EventLog::create(path.as_ref(), max_size)?;
+        *self.shared.write(&self.shared.event_log) = Some(Arc::new(log));
+        Ok(self)
+    }
+
+    /// Sets whether warning events are emitted initially. Defaults to `true`.
+    ///
+    /// See [`BlockedStatsHandle::set_warnings_enabled`].
+    pub fn with_warnings_enabled(self, enabled: bool) -> Self {
+        self.handle().set_warnings_enabled(enabled);
+        self
+    }
+
+    /// Sets the clock used to measure polls. Defaults to [`SystemClock`].
+    ///
+    /// See [`ManualClock`](crate::ManualClock) for deterministic tests.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self.shared.set_clock(self.clock.clone());
+        self
+    }
+
+    /// Returns a handle to the collected statistics that stays valid after the
+    /// layer has been installed.
+    pub fn handle(&self) -> BlockedStatsHandle {
+        BlockedStatsHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Returns a snapshot of totals per callsite.
+    pub fn snapshot(&self) -> Vec<CallsiteStatsSnapshot> {
+        self.shared.snapshot(false)
+    }
+
+    /// See [`BlockedStatsHandle::summary`].
+    pub fn summary(&self) -> BlockedSummary {
+        self.shared.summary()
+    }
+
+    /// See [`BlockedStatsHandle::worst_poll`].
+    pub fn worst_poll(&self) -> Option<WorstPoll> {
+        self.handle().worst_poll()
+    }
+
+    /// See [`BlockedStatsHandle::events_emitted`].
+    pub fn events_emitted(&self) -> u64 {
+        self.shared.events_emitted.load(Ordering::Relaxed)
+    }
+
+    /// See [`BlockedStatsHandle::recent_polls`].
+    pub fn recent_polls(&self, selector: &CallsiteSelector) -> Vec<PollSample> {
+        self.shared.recent_polls(selector)
+    }
+
+    /// Removes and returns all violations recorded so far.
+    ///
+    /// Only populated with [`ViolationMode::Record`].
+    pub fn take_violations(&self) -> Vec<Violation> {
+        self.handle().take_violations()
+    }
+
+    /// See [`BlockedStatsHandle::export_chrome_trace`].
+    pub fn export_chrome_trace(&self, w: impl std::io::Write) -> std::io::Result<()> {
+        self.shared.export_chrome_trace(w)
+    }
+}
+
+/// Aggregation key of the per-callsite statistics: the span callsite plus
+/// the spawn location, if known.
+///
+/// All tokio tasks share the same span callsite, so the spawn location is what
+/// tells them apart.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+struct CallsiteKey {
+    meta: usize,
+    origin_file: Option<&'static str>,
+    origin_line: Option<u32>,
+    resource: Resource,
+    // Set instead of the origin for rows grouped by task name.
+    task_name: Option<&'static str>,
+    // Task that async op spans were created in.
+    parent_task: Option<ParentTask>,
+    category: Category,
+    // Label of the runtime, see `TokioBlockedLayer::with_runtime_label`.
+    runtime: Option<&'static str>,
+}
+
+impl CallsiteKey {
+    fn new(
+        meta: &'static Metadata<'static>,
+        origin_file: Option<&'static str>,
+        origin_line: Option<u32>,
+        resource: Resource,
+        parent_task: Option<ParentTask>,
+        category: Category,
+    ) -> Self {
+        Self {
+            meta: meta as *const _ as usize,
+            origin_file,
+            origin_line,
+            resource,
+            task_name: None,
+            parent_task,
+            category,
+            runtime: None,
+        }
+    }
+
+    /// The reported location: the spawn location if known, the span
+    /// callsite otherwise. Rows grouped by task name have none.
+    fn location(&self, meta: &'static Metadata<'static>) -> (Option<&'static str>, Option<u32>) {
+        match (self.origin_file, self.task_name) {
+            (_, Some(_)) => (None, None),
+            (Some(file), None) => (Some(file), self.origin_line),
+            (None, None) => (meta.file(), meta.line()),
+        }
+    }
+
+    /// The key of the row grouped by task name `name`.
+    fn for_task_name(self, name: &'static str) -> Self {
+        Self {
+            origin_file: None,
+            origin_line: None,
+            task_name: Some(name),
+            ..self
+        }
+    }
+}
+
+/// What a tokio resource or async op span refers to, e.g. a `Sleep` timer.
+///
+/// Async ops of different resources share span callsites, so this is part of
+/// the aggregation key.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+struct Resource {
+    /// `concrete_type` of the `runtime.resource` span, e.g. `Sleep`.
+    concrete_type: Option<&'static str>,
+    /// `kind` of the `runtime.resource` span, e.g. `timer`.
+    kind: Option<&'static str>,
+    /// `source` of the `runtime.resource.async_op` span, e.g.
+    /// `Sleep::new_timeout`.
+    source: Option<&'static str>,
+    /// Used internally by another resource, like the semaphore of a `Mutex`.
+    internal: bool,
+}
+
+impl Resource {
+    /// Whether the resource is a tokio sync primitive, whose async ops wait
+    /// for a lock or permit. Primitives used internally by another one are
+    /// not, so waits are only reported once.
+    ///
+    /// Channels have the `Sync` kind as well, but waiting on a receiver is
+    /// expected to take long, so the concrete type decides.
+    fn is_sync(&self) -> bool {
+        let sync = matches!(
+            self.concrete_type,
+            Some("Mutex" | "RwLock" | "Semaphore" | "Barrier" | "Notify")
+        );
+        sync && !self.internal
+    }
+}
+
+#[derive(Debug, Default)]
+struct CallsiteStats {
+    name: &'static str,
+    target: &'static str,
+    file: Option<&'static str>,
+    line: Option<u32>,
+    resource: Resource,
+    task_name: Option<&'static str>,
+    parent_task: Option<ParentTask>,
+    category: Category,
+    runtime: Option<&'static str>,
+    total_busy: Duration,
+    // Thread CPU time of closed spans, with `with_cpu_time`.
+    total_cpu: Duration,
+    count: u64,
+    max_poll: Duration,
+    polls: u64,
+    // Sum of squared poll durations in nanoseconds, for the variance.
+    poll_sum_squares_ns: u128,
+    // Number of polls that exceeded the single-poll threshold.
+    exceed_count: u64,
+    // Of those, first polls of tasks.
+    first_poll_blocked: u64,
+    // Sum of the delays between span creation and first poll.
+    spawn_to_first_poll: Duration,
+    // Spans closed without ever being polled.
+    never_polled: u64,
+    // Polls on a different thread than the previous poll of the span.
+    migrations: u64,
+    // Time from span creation to close.
+    min_lifetime: Duration,
+    total_lifetime: Duration,
+    max_lifetime: Duration,
+    warning: WarningState,
+    // Wall-clock time of the first exceeded poll.
+    first_exceeded_at: Option<SystemTime>,
+    // Consecutive closed spans over the total threshold, and whether the
+    // streak was alerted, see `with_consecutive_alert`.
+    blocked_streak: u64,
+    streak_alerted: bool,
+    // Blocked spans by pattern.
+    patterns: PatternCounts,
+    // Polls of closed spans of at least each SLO threshold.
+    over_threshold: Vec<(Duration, u64)>,
+    // Last update, for LRU eviction.
+    last_seen: Option<Instant>,
+}
+
+impl CallsiteStats {
+    fn to_snapshot(&self, cell: Option<&CallsiteCell>, quantiles: &[f64]) -> CallsiteStatsSnapshot {
+        let poll_histogram = cell.and_then(|c| c.histogram.as_ref()).map(|h| h.load());
+        let percentiles = poll_histogram.as_ref().map_or_else(Vec::new, |h| {
+            quantiles
+                .iter()
+                .filter_map(|&q| Some((q, h.percentile(q * 100.0)?)))
+                .collect()
+        });
+        CallsiteStatsSnapshot {
+            name: self.name,
+            target: self.target,
+            file: self.file,
+            line: self.line,
+            resource_type: self.resource.concrete_type,
+            async_op_source: self.resource.source,
+            lock_wait: self.is_lock_wait(),
+            allowed: false,
+            task_name: self.task_name,
+            parent_task: self.parent_task,
+            category: self.category,
+            runtime: self.runtime,
+            total_busy: self.total_busy,
+            total_cpu: self.total_cpu,
+            count: self.count,
+            exceed_count: self.exceed_count,
+            first_poll_blocked: self.first_poll_blocked,
+            max_poll: self.max_poll,
+            ewma_poll: cell.and_then(|c| c.ewma()),
+            poll_histogram,
+            percentiles,
+            polls: self.polls,
+            poll_sum_squares_ns: self.poll_sum_squares_ns,
+            spawn_to_first_poll: self.spawn_to_first_poll,
+            never_polled: self.never_polled,
+            migrations: self.migrations,
+            min_lifetime: self.min_lifetime,
+            total_lifetime: self.total_lifetime,
+            max_lifetime: self.max_lifetime,
+            first_exceeded_at: self.first_exceeded_at,
+            patterns: self.patterns,
+            over_threshold: self.over_threshold.clone(),
+        }
+    }
+
+    fn new(meta: &'static Metadata<'static>, key: CallsiteKey) -> Self {
+        let (file, line) = key.location(meta);
+        Self {
+            name: meta.name(),
+            target: meta.target(),
+            file,
+            line,
+            resource: key.resource,
+            task_name: key.task_name,
+            parent_task: key.parent_task,
+            category: key.category,
+            runtime: key.runtime,
+            ..Default::default()
+        }
+    }
+
+    fn is_lock_wait(&self) -> bool {
+        self.name == "runtime.resource.async_op" && self.resource.is_sync()
+    }
+
+    /// Adds the polls of a closed span over each of the SLO `thresholds`.
+    fn record_over_threshold(&mut self, thresholds: &[Duration], counts: &[u64]) {
+        if counts.is_empty() {
+            return;
+        }
+        if self.over_threshold.is_empty() {
+            self.over_threshold = thresholds.iter().map(|&t| (t, 0)).collect();
+        }
+        for ((_, total), count) in self.over_threshold.iter_mut().zip(counts) {
+            *total += count;
+        }
+    }
+
+    /// Extends or ends the streak of spans over the total threshold, returning
+    /// the streak if it reached `n` for the first time.
+    fn record_streak(&mut self, over: bool, n: u64) -> Option<u64> {
+        if !over {
+            self.blocked_streak = 0;
+            self.streak_alerted = false;
+            return None;
+        }
+        self.blocked_streak += 1;
+        if self.blocked_streak < n || self.streak_alerted {
+            return None;
+        }
+        self.streak_alerted = true;
+        Some(self.blocked_streak)
+    }
+
+    /// Accounts the lifetime and first poll of a closed span.
+    fn record_close(
+        &mut self,
+        ext: &SpanBusyExt,
+        lifetime: Duration,
+        pattern: Option<BlockingPattern>,
+    ) {
+        self.min_lifetime = match self.count {
+            0 => lifetime,
+            _ => self.min_lifetime.min(lifetime),
+        };
+        self.total_lifetime += lifetime;
+        self.max_lifetime = self.max_lifetime.max(lifetime);
+        self.total_cpu += ext.total_cpu().unwrap_or_default();
+        self.count += 1;
+        self.migrations += ext.migrations();
+        match ext.first_polled_at {
+            Some(at) => self.spawn_to_first_poll += at.since(ext.created),
+            None => self.never_polled += 1,
+        }
+        if let Some(pattern) = pattern {
+            self.patterns.add(pattern);
+        }
+    }
+}
+
+/// Per-span state, kept in the extensions of every tracked span.
+///
+/// Kept small since there is one per live task: times are stored relative to
+/// [`Shared::created_at`], durations as nanoseconds, the spawn file as a
+/// symbol of the interner in [`Shared`], and the state of opt-in features is
+/// boxed. The callsite key lives in `live`.
+///
+/// Like the other extensions of this layer it is a private type, so it can't
+/// collide with the extensions of layers consuming the same tokio spans,
+/// e.g. console-subscriber. Only extensions of these types are ever removed.
+#[derive(Debug)]
+struct SpanBusyExt {
+    // See `TokioBlockedLayer::owner`.
+    owner: usize,
+    in_count: u32,
+    start: Option<SpanTime>,
+    // Additional row grouped by task name, with `GroupBy::Both`.
+    name_key: Option<Box<CallsiteKey>>,
+    // Original spawn/call location if provided via span fields (e.g.
+    // loc.file/line/col), `NO_LOCATION` if absent.
+    origin_file: Option<FileSymbol>,
+    origin_line: u32,
+    origin_col: u32,
+    total_busy_ns: u64,
+    max_poll_ns: u64,
+    // 1-based number of the longest poll.
+    max_poll_seq: u64,
+    polls: u64,
+    poll_sum_squares_ns: u128,
+    // Polls that exceeded the single-poll threshold.
+    polls_over_threshold: u64,
+    // Only set if one of the features in `SpanExtra` needs it.
+    extra: Option<Box<SpanExtra>>,
+    // Whether the total threshold was already reported while open.
+    total_warned: bool,
+    // Only set if a feature needs per-poll callsite updates.
+    cell: Option<Arc<CallsiteCell>>,
+    // When the span instance was created, to compute total lifetime.
+    created: SpanTime,
+    first_polled_at: Option<SpanTime>,
+    // The `task.name` and `task.id` fields of tokio task spans.
+    task_name: Option<Box<str>>,
+    task_id: Option<u64>,
+    // Time spent in block zones during the current poll.
+    acknowledged_ns: u64,
+    // Entry in the live span index.
+    live: Arc<LiveSpan>,
+    // Whether the runtime label was resolved, on the first poll.
+    runtime_resolved: bool,
+}
+
+/// Per-span state of opt-in features, boxed since it is rarely used.
+#[derive(Debug)]
+struct SpanExtra {
+    // Thread CPU time at the start of the current poll, and of all polls if
+    // measured, see `TokioBlockedLayer::with_cpu_time`.
+    cpu_start: Option<Duration>,
+    total_cpu: Option<Duration>,
+    // Polls of at least each of the SLO thresholds, merged into the callsite
+    // stats on close.
+    over_threshold: Box<[u64]>,
+    breadcrumbs: Breadcrumbs,
+    // Start of the current window of `with_warn_poll_rate` and the polls in
+    // it.
+    rate_window_start: Option<SpanTime>,
+    rate_window_polls: u32,
+    // Thread of the previous poll, and how often it changed, see
+    // `with_warn_migrations`.
+    last_thread: Option<ThreadId>,
+    migrations: u64,
+    // Busy time already moved into the callsite stats by `with_live_flush`.
+    flushed: Flushed,
+    // Span of the task async op spans were created in.
+    parent_task_span: Option<span::Id>,
+    // Request of the tower middleware the task was spawned for.
+    request: Option<Arc<RequestBusy>>,
+    // Indices of the busy budgets matching the span's callsite.
+    budgets: Box<[usize]>,
+}
+
+/// The part of a span's busy time that was merged into the callsite stats.
+#[derive(Debug, Clone, Copy)]
+struct Flushed {
+    at: SpanTime,
+    busy_ns: u64,
+    polls: u64,
+    sum_squares_ns: u128,
+}
+
+// Thousands of tasks are common, so the size must not grow unnoticed.
+#[cfg(target_pointer_width = "64")]
+const _: () = assert!(std::mem::size_of::<SpanBusyExt>() == 192);
+
+/// Line or column of a `SpanBusyExt` without a spawn location.
+const NO_LOCATION: u32 = u32::MAX;
+
+/// A time as nanoseconds since [`Shared::created_at`], negative for a clock
+/// that is behind it, e.g. a [`ManualClock`](crate::ManualClock) created
+/// before the layer. Stored with the sign bit flipped, which is never zero,
+/// so that `Option<SpanTime>` is no larger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SpanTime(NonZeroU64);
+
+impl SpanTime {
+    const SIGN: u64 = 1 << 63;
+
+    fn new(epoch: Instant, at: Instant) -> Self {
+        let nanos = match at.checked_duration_since(epoch) {
+            Some(after) => i64::try_from(after.as_nanos()).unwrap_or(i64::MAX),
+            None => -i64::try_from((epoch - at).as_nanos()).unwrap_or(i64::MAX),
+        };
+        // At least `-i64::MAX`, which does not map to zero.
+        Self(NonZeroU64::new(nanos as u64 ^ Self::SIGN).unwrap())
+    }
+
+    fn nanos(self) -> i64 {
+        (self.0.get() ^ Self::SIGN) as i64
+    }
+
+    fn instant(self, epoch: Instant) -> Instant {
+        let offset = Duration::from_nanos(self.nanos().unsigned_abs());
+        if self.nanos() >= 0 {
+            epoch + offset
+        } else {
+            epoch.checked_sub(offset).unwrap_or(epoch)
+        }
+    }
+
+    /// Time from `earlier` to this, zero if it is later.
+    fn since(self, earlier: SpanTime) -> Duration {
+        Duration::from_nanos(self.nanos().saturating_sub(earlier.nanos()).max(0) as u64)
+    }
+}
+
+/// Spawn location file name interned by [`Shared::intern_file`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileSymbol(NonZeroU32);
+
+#[derive(Default)]
+struct FileSymbols {
+    symbols: HashMap<&'static str, FileSymbol>,
+    // Indexed by symbol minus one.
+    names: Vec<&'static str>,
+}
+
+impl TokioBlockedLayer {
+    /// Returns the stats of a callsite, creating them if needed and evicting
+    /// the least recently updated callsites according to the eviction policy.
+    fn callsite_stats<'a>(
+        &self,
+        map: &'a mut HashMap<CallsiteKey, CallsiteStats>,
+        key: CallsiteKey,
+        meta: &'static Metadata<'static>,
+        now: Instant,
+    ) -> &'a mut CallsiteStats {
+        if let EvictionPolicy::Lru { max_entries } = self.eviction {
+            if !map.contains_key(&key) {
+                while map.len() >= max_entries.max(1) {
+                    let Some(coldest) =
+                        map.iter().min_by_key(|(_, s)| s.last_seen).map(|(k, _)| *k)
+                    else {
+                        break;
+                    };
+                    map.remove(&coldest);
+                    self.shared.lock(&self.shared.cells).remove(&coldest);
+                    self.shared
+                        .evicted_callsites
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        let stats = map.entry(key).or_insert_with(|| {
+            let mut stats = CallsiteStats::new(meta, key);
+            if let Some(warning) = self.shared.lock(&self.shared.paused_warnings).remove(&key) {
+                stats.warning = warning;
+            }
+            stats
+        });
+        stats.last_seen = Some(now);
+        stats
+    }
 
-/// A standalone layer that measures "busy" time per callsite (span metadata),
-/// and records each measured duration as a tracing event.
-///
-/// Busy time is measured as the wall-clock time between a span's enter and the
-/// matching exit, counting only the outermost enter/exit pairs per span
-/// instance (nested enters are ignored to avoid double-counting).
-pub struct TokioBlockedLayer {
-    callsites: Mutex<HashMap<CallsiteKey, CallsiteStats>>,
-    // Locally cached set of callsites to consider.
-    // Caching speeds up performance.
-    allowed_callsites: Mutex<HashSet<Identifier>>,
-    // Warn if a single outermost poll exceeds this duration.
-    warn_busy_single_poll: Option<Duration>,
-    // Warn on close if total busy time across the span exceeds this duration.
-    warn_busy_total: Option<Duration>,
+    /// Counts an exceeded single-poll threshold for the callsite.
+    ///
+    /// Returns the callsite's total exceed count and whether the warning
+    /// should be escalated.
+    fn record_exceeded(
+        &self,
+        key: CallsiteKey,
+        meta: &'static Metadata<'static>,
+        now: Instant,
+        poll: Duration,
+        first_poll: bool,
+    ) -> (u64, bool) {
+        let mut map = self.shared.lock(&self.shared.callsites);
+        let mut paused_warnings;
+        let (exceed_count, warning) = if self.shared.stats_paused() {
+            // Spans closing while paused do not contribute, so no row is
+            // created or touched, but the warnings go on.
+            match map.get_mut(&key) {
+                Some(stats) => (stats.exceed_count, &mut stats.warning),
+                None => {
+                    paused_warnings = self.shared.lock(&self.shared.paused_warnings);
+                    (0, paused_warnings.entry(key).or_default())
+                }
+            }
+        } else {
+            let stats = self.callsite_stats(&mut map, key, meta, now);
+            stats.exceed_count += 1;
+            stats.first_poll_blocked += u64::from(first_poll);
+            stats.first_exceeded_at.get_or_insert_with(SystemTime::now);
+            (stats.exceed_count, &mut stats.warning)
+        };
+        if self.recovery_quiet_polls.is_some() {
+            let episode = warning.episode.get_or_insert(Episode {
+                started: now.checked_sub(poll).unwrap_or(now),
+                peak: Duration::ZERO,
+                blocked_polls: 0,
+                clean_polls: 0,
+            });
+            episode.peak = episode.peak.max(poll);
+            episode.blocked_polls += 1;
+            episode.clean_polls = 0;
+        }
+
+        let Some(escalation) = self.escalation else {
+            warning.last_exceeded = Some(now);
+            return (exceed_count, false);
+        };
+        let quiet = match (self.escalation_quiet_period, warning.last_exceeded) {
+            (Some(period), Some(last)) => now.saturating_duration_since(last) >= period,
+            _ => false,
+        };
+        if quiet {
+            warning.escalation_count = 0;
+        }
+        warning.escalation_count += 1;
+        warning.last_exceeded = Some(now);
+        (exceed_count, warning.escalation_count >= escalation.after)
+    }
 }
 
-impl Default for TokioBlockedLayer {
-    fn default() -> Self {
-        Self::new()
+impl TokioBlockedLayer {
+    /// Warns if the first poll at `now` came more than `latency` after the
+    /// span was created.
+    fn check_first_poll(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        now: Instant,
+        latency: Duration,
+    ) {
+        let delay = ext.lifetime(self.shared.created_at, now);
+        if delay < latency || !self.shared.warnings_enabled() {
+            return;
+        }
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::task_first_poll_delayed",
+            Level::WARN,
+            delay_ns = delay.as_nanos() as u64,
+            task.name = ext.task_name.as_deref(),
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            "tokio task was first polled late",
+        );
+    }
+
+    /// Emits the detailed event of a poll of a focused span.
+    fn emit_focus_poll(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        elapsed: Duration,
+    ) {
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        let thread = std::thread::current();
+        let start = SystemTime::now()
+            .checked_sub(elapsed)
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok());
+        let backtrace = Backtrace::capture();
+        tracing::event!(
+            target: "tokio_blocked::focus_poll",
+            Level::TRACE,
+            poll_start_unix_ns = start.map(|t| t.as_nanos() as u64),
+            poll_duration_ns = elapsed.as_nanos() as u64,
+            poll_seq = ext.polls,
+            thread.id = ?thread.id(),
+            thread.name = thread.name(),
+            task.name = ext.task_name.as_deref(),
+            task.id = ext.task_id,
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            backtrace = (backtrace.status() == BacktraceStatus::Captured)
+                .then(|| tracing::field::display(&backtrace)),
+            "focused tokio task poll",
+        );
+    }
+
+    /// Counts a poll under the threshold towards the recovery of a blocking
+    /// callsite, and emits the recovery event after `quiet_polls` of them.
+    fn check_recovery(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        now: Instant,
+        quiet_polls: u64,
+    ) {
+        let episode = {
+            let mut map = self.shared.lock(&self.shared.callsites);
+            let mut paused_warnings;
+            let warning = match map.get_mut(&ext.callsite()) {
+                Some(stats) => &mut stats.warning,
+                None => {
+                    paused_warnings = self.shared.lock(&self.shared.paused_warnings);
+                    let Some(warning) = paused_warnings.get_mut(&ext.callsite()) else {
+                        return;
+                    };
+                    warning
+                }
+            };
+            let Some(episode) = &mut warning.episode else {
+                return;
+            };
+            episode.clean_polls += 1;
+            if episode.clean_polls < quiet_polls {
+                return;
+            }
+            let episode = *episode;
+            warning.episode = None;
+            episode
+        };
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if !self.shared.warnings_enabled() || self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::callsite_recovered",
+            Level::INFO,
+            episode_ns = now.saturating_duration_since(episode.started).as_nanos() as u64,
+            peak_poll_ns = episode.peak.as_nanos() as u64,
+            blocked_polls = episode.blocked_polls,
+            clean_polls = episode.clean_polls,
+            task.name = ext.task_name.as_deref(),
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            "tokio callsite stopped blocking",
+        );
+    }
+
+    fn warn_saturated(&self, saturated: &Saturated) {
+        if !self.shared.warnings_enabled() {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::runtime_saturated",
+            Level::WARN,
+            fraction = saturated.fraction,
+            busy_ns = saturated.busy.as_nanos() as u64,
+            capacity_ns = saturated.capacity.as_nanos() as u64,
+            workers = saturated.workers as u64,
+            window_ns = saturated.window.as_nanos() as u64,
+            "tokio runtime saturated by task polls",
+        );
+    }
+
+    fn alert_sustained(&self, ext: &SpanBusyExt, meta: &'static Metadata<'static>, streak: u64) {
+        let Some(((_, on_sustained), threshold)) =
+            self.consecutive_alert.as_ref().zip(self.warn_busy_total)
+        else {
+            return;
+        };
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        on_sustained(&SustainedBlockingInfo {
+            streak,
+            threshold,
+            busy: ext.total_busy(),
+            callsite_name: meta.name(),
+            callsite_target: meta.target(),
+            file,
+            line,
+            col,
+            task_name: ext.task_name.as_deref(),
+            timestamp: SystemTime::now(),
+        });
+        if !self.shared.warnings_enabled() || self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::sustained_blocking",
+            Level::ERROR,
+            streak,
+            threshold_ns = threshold.as_nanos() as u64,
+            busy_ns = ext.total_busy().as_nanos() as u64,
+            task.name = ext.task_name.as_deref(),
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            "tokio callsite blocked in consecutive spans",
+        );
+    }
+
+    fn check_migrations(&self, ext: &SpanBusyExt, meta: &'static Metadata<'static>, max: u64) {
+        if ext.migrations() <= max || !self.shared.warnings_enabled() {
+            return;
+        }
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::task_migrations",
+            Level::WARN,
+            migrations = ext.migrations(),
+            polls_total = ext.polls,
+            task.name = ext.task_name.as_deref(),
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            "tokio task migrated between threads too often",
+        );
+    }
+
+    /// Adds a poll that ended at `end` to the periods of the span's busy
+    /// budgets.
+    fn check_budgets(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        end: Instant,
+        elapsed: Duration,
+    ) {
+        let mut exceeded = Vec::new();
+        {
+            let mut windows = self.shared.lock(&self.shared.budget_windows);
+            for &index in ext.budgets() {
+                let budget = &self.budgets[index];
+                let window = windows
+                    .entry((ext.callsite(), index))
+                    .or_insert_with(|| BudgetWindow::new(end));
+                if end.saturating_duration_since(window.start) >= budget.per {
+                    *window = BudgetWindow::new(end);
+                }
+                window.busy += elapsed;
+                window.polls += 1;
+                if !window.warned && window.busy > budget.budget {
+                    window.warned = true;
+                    exceeded.push((budget, window.busy, window.polls));
+                }
+            }
+        }
+        if exceeded.is_empty() || !self.shared.warnings_enabled() {
+            return;
+        }
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        for (budget, busy, polls) in exceeded {
+            tracing::event!(
+                target: "tokio_blocked::budget_exceeded",
+                Level::WARN,
+                busy_ns = busy.as_nanos() as u64,
+                budget_ns = budget.budget.as_nanos() as u64,
+                period_ns = budget.per.as_nanos() as u64,
+                over_budget_ns = (busy - budget.budget).as_nanos() as u64,
+                polls,
+                task.name = ext.task_name.as_deref(),
+                callsite.name = meta.name(),
+                callsite.target = meta.target(),
+                callsite.file = file,
+                callsite.line = line,
+                callsite.col = col,
+                "tokio callsite exceeded its busy budget",
+            );
+        }
+    }
+
+    fn check_anomaly(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        elapsed: Duration,
+        anomaly: &AnomalyThreshold,
+        baseline: Duration,
+    ) {
+        // Saturates for large or infinite multipliers.
+        let threshold = Duration::try_from_secs_f64(baseline.as_secs_f64() * anomaly.multiplier)
+            .unwrap_or(Duration::MAX);
+        if elapsed < threshold || !self.shared.warnings_enabled() {
+            return;
+        }
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::poll_anomaly",
+            Level::WARN,
+            poll_duration_ns = elapsed.as_nanos() as u64,
+            baseline_ns = baseline.as_nanos() as u64,
+            percentile = anomaly.percentile,
+            multiplier = anomaly.multiplier,
+            threshold_ns = threshold.as_nanos() as u64,
+            poll_seq = ext.polls,
+            task.name = ext.task_name.as_deref(),
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            "tokio task poll was much slower than usual for its callsite",
+        );
+    }
+
+    /// Counts a poll that ran from `start` to `end` towards the span's poll
+    /// rate, and warns once a window of at least [`POLL_RATE_MIN_WINDOW`]
+    /// exceeds `max_rate`.
+    fn check_poll_rate(
+        &self,
+        ext: &mut SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        start: Instant,
+        end: Instant,
+        max_rate: f64,
+    ) {
+        let epoch = self.shared.created_at;
+        let Some(extra) = &mut ext.extra else {
+            return;
+        };
+        let window_start = match extra.rate_window_start {
+            Some(window_start) => window_start.instant(epoch),
+            None => {
+                extra.rate_window_start = Some(SpanTime::new(epoch, start));
+                start
+            }
+        };
+        extra.rate_window_polls += 1;
+        let window = end.saturating_duration_since(window_start);
+        if window < POLL_RATE_MIN_WINDOW {
+            return;
+        }
+        let window_polls = extra.rate_window_polls;
+        extra.rate_window_start = None;
+        extra.rate_window_polls = 0;
+
+        let rate = window_polls as f64 / window.as_secs_f64();
+        if rate < max_rate || !self.shared.warnings_enabled() {
+            return;
+        }
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::task_poll_storm",
+            Level::WARN,
+            polls_per_sec = rate,
+            window_polls,
+            total_polls = ext.polls,
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            "tokio task is polled in a busy loop",
+        );
     }
 }
 
 impl TokioBlockedLayer {
-    pub fn new() -> Self {
-        Self {
-            callsites: Mutex::new(HashMap::new()),
-            allowed_callsites: Mutex::new(HashSet::new()),
-            warn_busy_single_poll: Some(Duration::from_micros(150)),
-            warn_busy_total: None,
+    fn check_lock_wait(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        wait: Duration,
+        threshold: Duration,
+    ) {
+        if wait < threshold || !self.shared.warnings_enabled() {
+            return;
+        }
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if self.shared.is_exempt(meta, file, line) {
+            return;
         }
+        tracing::event!(
+            target: "tokio_blocked::lock_wait",
+            Level::WARN,
+            wait_ns = wait.as_nanos() as u64,
+            threshold_ns = threshold.as_nanos() as u64,
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            resource.concrete_type = ext.callsite().resource.concrete_type,
+            resource.kind = ext.callsite().resource.kind,
+            resource.source = ext.callsite().resource.source,
+            parent_task.file = ext.callsite().parent_task.and_then(|p| p.file),
+            parent_task.line = ext.callsite().parent_task.and_then(|p| p.line),
+            parent_task.name = ext.callsite().parent_task.and_then(|p| p.name),
+            "waited too long to acquire a tokio sync primitive",
+        );
     }
 
-    pub fn with_warn_busy_single_poll(mut self, duration: Option<Duration>) -> Self {
-        self.warn_busy_single_poll = duration;
-        self
+    /// Warns about a blocking pool operation that ran for at least
+    /// `threshold`. The closure runs in a single poll of its span.
+    fn check_blocking_pool_op(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        elapsed: Duration,
+        threshold: Duration,
+    ) {
+        if elapsed < threshold || !self.shared.warnings_enabled() {
+            return;
+        }
+        let (file, line, col) = ext.origin(&self.shared, meta);
+        if self.shared.is_exempt(meta, file, line) {
+            return;
+        }
+        tracing::event!(
+            target: "tokio_blocked::blocking_pool_op",
+            Level::WARN,
+            busy_ns = elapsed.as_nanos() as u64,
+            threshold_ns = threshold.as_nanos() as u64,
+            callsite.name = meta.name(),
+            callsite.target = meta.target(),
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            task.name = ext.task_name.as_deref(),
+            task.id = ext.task_id,
+            "blocking pool operation took too long",
+        );
     }
 
-    pub fn with_warn_busy_total(mut self, duration: Option<Duration>) -> Self {
-        self.warn_busy_total = duration;
-        self
+    /// Returns whether a warning for the location should be emitted, and
+    /// emits the summaries of closed dedup windows.
+    fn dedup_observe(&self, file: &str, line: u32, duration: Duration, now: Instant) -> bool {
+        let mut closed = Vec::new();
+        let emit = match &mut *self.shared.lock(&self.shared.dedup) {
+            Some(dedup) => dedup.observe(
+                file,
+                line,
+                self.warning_granularity,
+                duration,
+                now,
+                &mut closed,
+            ),
+            None => true,
+        };
+        closed.iter().for_each(emit_dedup_summary);
+        emit
     }
+}
 
-    /// Returns a snapshot of totals per callsite.
-    pub fn snapshot(&self) -> Vec<CallsiteStatsSnapshot> {
-        let map = self.callsites.lock().unwrap();
-        map.values()
-            .map(|s| CallsiteStatsSnapshot {
-                name: s.name,
-                target: s.target,
-                file: s.file,
-                line: s.line,
-                total_busy: s.total_busy,
-                count: s.count,
-            })
-            .collect()
+impl Drop for TokioBlockedLayer {
+    fn drop(&mut self) {
+        if self.shared.layers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.flush_dedup();
+        }
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
-struct CallsiteKey(usize);
+fn spawn_reporter(shared: Weak<Shared>, interval: Duration) {
+    std::thread::Builder::new()
+        .name("tokio-blocked-reporter".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            shared.report();
+        })
+        .expect("failed to spawn reporter thread");
+}
 
-impl CallsiteKey {
-    fn from_meta(meta: &'static Metadata<'static>) -> Self {
-        Self(meta as *const _ as usize)
+fn emit_dedup_summary(summary: &DedupSummary) {
+    macro_rules! summary_event {
+        ($lines:expr, $message:literal) => {
+            tracing::event!(
+                target: "tokio_blocked::task_poll_blocked_summary",
+                Level::WARN,
+                occurrences = summary.occurrences,
+                max_duration_ns = summary.max_duration.as_nanos() as u64,
+                total_duration_ns = summary.total_duration.as_nanos() as u64,
+                callsite.file = &summary.file[..],
+                callsite.line = summary.line,
+                lines = $lines,
+                $message,
+            )
+        };
+    }
+    if summary.line.is_some() {
+        summary_event!(
+            tracing::field::Empty,
+            "tokio task blocked repeatedly at the same location"
+        );
+    } else {
+        let lines: Vec<_> = summary.lines.iter().map(u32::to_string).collect();
+        summary_event!(
+            lines.join(","),
+            "tokio tasks blocked repeatedly in the same file"
+        );
     }
 }
 
-#[derive(Debug, Default)]
-struct CallsiteStats {
-    name: &'static str,
-    target: &'static str,
-    file: Option<&'static str>,
-    line: Option<u32>,
-    total_busy: Duration,
-    count: u64,
+/// The id of `span` and of its parent, as reported by other layers.
+fn span_ids<'a, R: LookupSpan<'a>>(span: &SpanRef<'a, R>) -> (u64, Option<u64>) {
+    (
+        span.id().into_u64(),
+        span.parent().map(|parent| parent.id().into_u64()),
+    )
 }
 
-/// A serializable snapshot of per-callsite totals.
-#[derive(Debug, Clone)]
-pub struct CallsiteStatsSnapshot {
-    pub name: &'static str,
-    pub target: &'static str,
-    pub file: Option<&'static str>,
-    pub line: Option<u32>,
-    pub total_busy: Duration,
-    pub count: u64,
+/// Finds the request a task is spawned for: the nearest request span or task
+/// attributed to a request among its ancestors, or else among the spans
+/// entered while spawning it, as tokio creates task spans without a parent.
+fn enclosing_request<S>(cx: &Context<'_, S>, id: &span::Id) -> Option<Arc<RequestBusy>>
+where
+    S: tracing_core::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let request = |span: SpanRef<'_, S>| {
+        let exts = span.extensions();
+        exts.get::<RequestExt>()
+            .map(|r| r.busy.clone())
+            .or_else(|| exts.get::<SpanBusyExt>()?.request().cloned())
+    };
+    cx.span_scope(id)
+        .and_then(|scope| scope.skip(1).find_map(request))
+        .or_else(|| cx.lookup_current()?.scope().find_map(request))
 }
 
-#[derive(Debug)]
-struct SpanBusyExt {
-    in_count: usize,
-    start: Option<Instant>,
-    callsite: CallsiteKey,
-    // Original spawn/call location if provided via span fields (e.g. loc.file/line/col).
-    origin_file: Option<String>,
-    origin_line: Option<u32>,
-    origin_col: Option<u32>,
-    total_busy: Duration,
-    // When the span instance was created, to compute total lifetime.
-    created_at: Instant,
+impl TokioBlockedLayer {
+    /// Whether a new span is beneath one of the
+    /// [scope spans](Self::with_scope_span), directly or through the span it
+    /// is created in. Tracked spans are in scope themselves.
+    fn in_scope<S>(&self, cx: &Context<'_, S>, id: &span::Id) -> bool
+    where
+        S: tracing_core::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let matches = |span: SpanRef<'_, S>| {
+            let meta = span.metadata();
+            span.extensions().get::<SpanBusyExt>().is_some()
+                || self
+                    .scope_spans
+                    .iter()
+                    .any(|s| s.matches(meta.target(), meta.name(), meta.file(), meta.line()))
+        };
+        cx.span_scope(id)
+            .is_some_and(|scope| scope.skip(1).any(matches))
+            || cx
+                .lookup_current()
+                .is_some_and(|span| span.scope().any(matches))
+    }
+
+    /// Finds the task an async op span is created in: the nearest
+    /// `runtime.spawn` ancestor, or else the task being polled, as tokio
+    /// creates async ops as children of their resource.
+    fn parent_task<S>(&self, cx: &Context<'_, S>, id: &span::Id) -> Option<(span::Id, ParentTask)>
+    where
+        S: tracing_core::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let is_task = |span: &SpanRef<'_, S>| span.name() == "runtime.spawn";
+        let task = cx
+            .span_scope(id)
+            .and_then(|mut scope| scope.find(is_task))
+            .or_else(|| cx.lookup_current()?.scope().find(is_task))?;
+        let meta = task.metadata();
+        let exts = task.extensions();
+        let ext = exts.get::<SpanBusyExt>();
+        let (file, line) = match ext.and_then(|e| e.origin_file(&self.shared)) {
+            Some(file) => (Some(file), ext.and_then(|e| e.origin_line())),
+            None => (meta.file(), meta.line()),
+        };
+        let name = ext
+            .and_then(|e| e.task_name.as_deref())
+            .and_then(|name| self.shared.intern(name));
+        Some((task.id(), ParentTask { file, line, name }))
+    }
+
+    /// Resolves the resource of an async op span from its own `source` field
+    /// and the enclosing `runtime.resource` span. Async op poll spans inherit
+    /// the resource of their async op.
+    fn resource<S>(&self, span: &SpanRef<'_, S>, fields: &LocVisitor) -> Resource
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        if span.name() == "runtime.resource" {
+            return span
+                .extensions()
+                .get::<Resource>()
+                .copied()
+                .unwrap_or_default();
+        }
+        if !span.name().starts_with("runtime.resource.async_op") {
+            return Resource::default();
+        }
+        let mut resource = span
+            .parent()
+            .and_then(|parent| {
+                let exts = parent.extensions();
+                exts.get::<Resource>()
+                    .copied()
+                    .or_else(|| exts.get::<SpanBusyExt>().map(|ext| ext.callsite().resource))
+            })
+            .unwrap_or_default();
+        if let Some(source) = fields.source.as_deref() {
+            resource.source = self.shared.intern(source);
+        }
+        resource
+    }
+}
+
+impl SpanBusyExt {
+    /// Time from the creation of the span to `at`.
+    fn lifetime(&self, epoch: Instant, at: Instant) -> Duration {
+        at.saturating_duration_since(self.created.instant(epoch))
+    }
+
+    fn flushed(&self) -> Flushed {
+        self.extra.as_ref().map_or(
+            Flushed {
+                at: self.created,
+                busy_ns: 0,
+                polls: 0,
+                sum_squares_ns: 0,
+            },
+            |extra| extra.flushed,
+        )
+    }
+
+    fn migrations(&self) -> u64 {
+        self.extra.as_ref().map_or(0, |extra| extra.migrations)
+    }
+
+    fn parent_task_span(&self) -> Option<&span::Id> {
+        self.extra.as_ref()?.parent_task_span.as_ref()
+    }
+
+    fn request(&self) -> Option<&Arc<RequestBusy>> {
+        self.extra.as_ref()?.request.as_ref()
+    }
+
+    fn budgets(&self) -> &[usize] {
+        self.extra.as_ref().map_or(&[], |extra| &extra.budgets)
+    }
+
+    fn total_busy(&self) -> Duration {
+        Duration::from_nanos(self.total_busy_ns)
+    }
+
+    fn max_poll(&self) -> Duration {
+        Duration::from_nanos(self.max_poll_ns)
+    }
+
+    fn total_cpu(&self) -> Option<Duration> {
+        self.extra.as_ref()?.total_cpu
+    }
+
+    fn over_threshold(&self) -> &[u64] {
+        self.extra
+            .as_ref()
+            .map_or(&[], |extra| &extra.over_threshold)
+    }
+
+    /// Accounts the CPU time of the poll that just ended, if it was sampled
+    /// at its start.
+    fn end_cpu(&mut self) -> Option<Duration> {
+        let extra = self.extra.as_mut()?;
+        let start = extra.cpu_start.take()?;
+        let elapsed = cpu_time::thread_cpu_time()?.saturating_sub(start);
+        *extra.total_cpu.as_mut()? += elapsed;
+        Some(elapsed)
+    }
+
+    /// Breadcrumbs of [`TokioBlockedLayer::with_breadcrumbs`], oldest first.
+    fn breadcrumbs(&self) -> Vec<Breadcrumb> {
+        self.extra
+            .as_ref()
+            .map_or_else(Vec::new, |extra| extra.breadcrumbs.to_vec())
+    }
+
+    /// Counts a poll of `elapsed` against the sorted SLO `thresholds`.
+    fn record_over_threshold(&mut self, elapsed: Duration, thresholds: &[Duration]) {
+        let Some(extra) = &mut self.extra else {
+            return;
+        };
+        let exceeded = thresholds.partition_point(|&t| t <= elapsed);
+        for count in &mut extra.over_threshold[..exceeded] {
+            *count += 1;
+        }
+    }
+
+    fn callsite(&self) -> CallsiteKey {
+        self.live.key
+    }
+
+    fn origin_file(&self, shared: &Shared) -> Option<&'static str> {
+        self.origin_file.map(|symbol| shared.file(symbol))
+    }
+
+    fn origin_line(&self) -> Option<u32> {
+        (self.origin_line != NO_LOCATION).then_some(self.origin_line)
+    }
+
+    fn origin_col(&self) -> Option<u32> {
+        (self.origin_col != NO_LOCATION).then_some(self.origin_col)
+    }
+
+    /// Accounts a completed outermost poll.
+    fn record_poll(&mut self, elapsed: Duration) {
+        let elapsed_ns = elapsed.as_nanos() as u64;
+        self.total_busy_ns += elapsed_ns;
+        self.polls += 1;
+        if self.polls == 1 || elapsed_ns > self.max_poll_ns {
+            self.max_poll_ns = elapsed_ns;
+            self.max_poll_seq = self.polls;
+        }
+        let nanos = elapsed.as_nanos();
+        self.poll_sum_squares_ns += nanos * nanos;
+    }
+
+    /// Merges the busy time that was not flushed yet into `stats`, or only
+    /// marks it as flushed while statistics are paused.
+    fn flush_into(&mut self, stats: Option<&mut CallsiteStats>, epoch: Instant, now: Instant) {
+        let flushed = self.flushed();
+        if let Some(stats) = stats {
+            stats.total_busy += Duration::from_nanos(self.total_busy_ns - flushed.busy_ns);
+            stats.max_poll = stats.max_poll.max(self.max_poll());
+            stats.polls += self.polls - flushed.polls;
+            stats.poll_sum_squares_ns += self.poll_sum_squares_ns - flushed.sum_squares_ns;
+        }
+        // Without a box, nothing flushes again before the span is gone.
+        if let Some(extra) = &mut self.extra {
+            extra.flushed = Flushed {
+                at: SpanTime::new(epoch, now),
+                busy_ns: self.total_busy_ns,
+                polls: self.polls,
+                sum_squares_ns: self.poll_sum_squares_ns,
+            };
+        }
+        self.update_live();
+    }
+
+    /// Publishes the busy time that was not flushed yet to the live index.
+    fn update_live(&self) {
+        let flushed = self.flushed();
+        let pending = PendingBusy {
+            total_busy: Duration::from_nanos(self.total_busy_ns - flushed.busy_ns),
+            max_poll: self.max_poll(),
+            polls: self.polls - flushed.polls,
+            poll_sum_squares_ns: self.poll_sum_squares_ns - flushed.sum_squares_ns,
+        };
+        // Only ever held to copy the value in or out, so never left half written.
+        *self
+            .live
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = pending;
+    }
+
+    /// Resolves the spawn location, falling back to the span's callsite.
+    fn origin(
+        &self,
+        shared: &Shared,
+        meta: &'static Metadata<'static>,
+    ) -> (&'static str, u32, u32) {
+        let file = self
+            .origin_file(shared)
+            .or(meta.file())
+            .unwrap_or("<unknown>");
+        let line = self.origin_line().or(meta.line()).unwrap_or(0u32);
+        let col = self.origin_col().unwrap_or(0u32);
+        (file, line, col)
+    }
 }
 
 impl<S> Layer<S> for TokioBlockedLayer
 where
     S: tracing_core::Subscriber + for<'a> LookupSpan<'a>,
 {
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        *self.shared.lock(&self.shared.thresholds) =
+            (self.warn_busy_single_poll, self.warn_busy_total);
+        *self.shared.lock(&self.shared.fraction_gauge) = self
+            .fraction_gauge
+            .map(|window| (window, self.metric_events.unwrap_or_default()));
+        if let Some(interval) = self.report_interval {
+            spawn_reporter(Arc::downgrade(&self.shared), interval);
+        }
+    }
+
     fn register_callsite(&self, meta: &'static Metadata<'static>) -> subscriber::Interest {
         if matches_tokio_poll(meta) {
-            self.allowed_callsites
-                .lock()
-                .unwrap()
+            self.shared
+                .lock(&self.allowed_callsites)
                 .insert(meta.callsite());
         }
         subscriber::Interest::always()
     }
 
-    // fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
-    //     true
-    // }
+    // Without a per-layer filter, `Layered` ANDs this with the other layers,
+    // so returning false for events or unrelated spans would disable them for
+    // the whole subscriber, e.g. hide every `debug!` from a fmt layer. Events
+    // cost the layer nothing beyond this call unless `with_breadcrumbs` is
+    // set, then `on_event` records them for the innermost tracked span, and
+    // skips them while a callback of the layer runs. `recommended_filter`
+    // narrows the callsites per layer instead.
+    fn enabled(&self, _meta: &Metadata<'_>, _cx: Context<'_, S>) -> bool {
+        true
+    }
 
     fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, cx: Context<'_, S>) {
+        let Some(_guard) = InLayer::enter() else {
+            if matches_tokio_poll(attrs.metadata()) {
+                self.shared.reentrant_spans.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        };
         let Some(span) = cx.span(id) else { return };
+        // Layers on the same registry share the extensions, so a span is
+        // tracked by the first of them only.
+        {
+            let exts = span.extensions();
+            if exts.get::<SpanBusyExt>().is_some()
+                || exts.get::<RequestExt>().is_some()
+                || exts.get::<Resource>().is_some()
+            {
+                return;
+            }
+        }
 
         let meta = attrs.metadata();
+        let middleware = meta.target() == REQUEST_TARGET && meta.name() == REQUEST_NAME;
+        if middleware || self.request_spans.iter().any(|name| name == meta.name()) {
+            let busy = Arc::<RequestBusy>::default();
+            let mut requests = self.shared.lock(&self.shared.requests);
+            if requests.insert(id.clone(), busy.clone()).is_none() {
+                self.shared.open_requests.fetch_add(1, Ordering::Relaxed);
+            }
+            drop(requests);
+            let route = self.route_field.as_deref().and_then(|name| {
+                let mut visitor = RouteVisitor { name, route: None };
+                attrs.record(&mut visitor);
+                visitor.route
+            });
+            span.extensions_mut().insert(RequestExt {
+                owner: self.owner(),
+                busy,
+                in_count: 0,
+                start: None,
+                route,
+                // The middleware reports on its own once the response is ready.
+                report: !middleware,
+            });
+            return;
+        }
+        if meta.name() == "runtime.resource" {
+            // Not tracked itself, but describes the async ops created inside.
+            let mut fields = LocVisitor::new(&self.loc_fields);
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(Resource {
+                concrete_type: fields
+                    .concrete_type
+                    .as_deref()
+                    .and_then(|t| self.shared.intern(t)),
+                kind: fields.kind.as_deref().and_then(|k| self.shared.intern(k)),
+                source: None,
+                internal: fields.is_internal,
+            });
+            if !self.track_resources {
+                return;
+            }
+        }
         // Only track busy time for spans that correspond to Tokio poll spans.
-        let is_allowed = {
-            let allowed = self.allowed_callsites.lock().unwrap();
-            allowed.contains(&meta.callsite())
-        } || matches_tokio_poll(meta);
+        let is_allowed = meta.name() == "runtime.resource"
+            || {
+                let allowed = self.shared.lock(&self.allowed_callsites);
+                allowed.contains(&meta.callsite())
+            }
+            || matches_tokio_poll(meta);
 
         if !is_allowed {
             return;
         }
-
-        let key = CallsiteKey::from_meta(meta);
+        if !self.scope_spans.is_empty() && !self.in_scope(&cx, id) {
+            return;
+        }
 
         // Try to extract an original source code location from attributes, if present.
-        let mut loc = LocVisitor::default();
+        let mut loc = LocVisitor::new(&self.loc_fields);
         attrs.record(&mut loc);
+        let file_symbol = loc.file.as_deref().and_then(|f| self.shared.intern_file(f));
+        let origin_file = file_symbol.map(|symbol| self.shared.file(symbol));
+        let origin_line = origin_file.and(loc.line);
+        let resource = self.resource(&span, &loc);
+        let parent_task = meta
+            .name()
+            .starts_with("runtime.resource.async_op")
+            .then(|| self.parent_task(&cx, id))
+            .flatten();
+        let location_key = CallsiteKey::new(
+            meta,
+            origin_file,
+            origin_line,
+            resource,
+            parent_task.as_ref().map(|(_, task)| *task),
+            // Tokio tags its task spans with `kind`, e.g. `task` or `blocking`.
+            match (meta.name(), loc.kind.as_deref()) {
+                ("runtime.spawn", Some("blocking")) => Category::BlockingPool,
+                ("runtime.resource", _) => Category::Resource,
+                ("tokio_blocked.block_zone", _) => Category::Acknowledged,
+                _ => Category::Async,
+            },
+        );
+        let name_key = loc
+            .task_name
+            .as_deref()
+            .and_then(|name| self.shared.intern(name))
+            .map(|name| location_key.for_task_name(name));
+        let (key, name_key) = match self.group_by {
+            GroupBy::Location => (location_key, None),
+            GroupBy::TaskName => (name_key.unwrap_or(location_key), None),
+            GroupBy::Both => (location_key, name_key),
+        };
+        let cell = self.callsite_cell(meta, key);
+        let request = (location_key.category == Category::Async
+            && meta.name() == "runtime.spawn"
+            && self.shared.open_requests.load(Ordering::Relaxed) > 0)
+            .then(|| enclosing_request(&cx, id))
+            .flatten();
+        let budgets: Box<[usize]> = self
+            .budgets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| {
+                b.selector.matches(
+                    meta.target(),
+                    meta.name(),
+                    origin_file.or(meta.file()),
+                    origin_line.or(meta.line()),
+                )
+            })
+            .map(|(i, _)| i)
+            .collect();
+        let created = SpanTime::new(self.shared.created_at, self.clock.now());
+        let parent_task_span = parent_task.map(|(id, _)| id);
+        let extra = (self.measures_cpu()
+            || !self.slo_thresholds.is_empty()
+            || self.breadcrumbs > 0
+            || self.warn_poll_rate.is_some()
+            || self.warn_migrations.is_some()
+            || self.live_flush.is_some()
+            || parent_task_span.is_some()
+            || request.is_some()
+            || !budgets.is_empty())
+        .then(|| {
+            Box::new(SpanExtra {
+                cpu_start: None,
+                total_cpu: self.measures_cpu().then_some(Duration::ZERO),
+                over_threshold: vec![0; self.slo_thresholds.len()].into(),
+                breadcrumbs: Breadcrumbs::new(self.breadcrumbs),
+                rate_window_start: None,
+                rate_window_polls: 0,
+                last_thread: None,
+                migrations: 0,
+                flushed: Flushed {
+                    at: created,
+                    busy_ns: 0,
+                    polls: 0,
+                    sum_squares_ns: 0,
+                },
+                parent_task_span,
+                request,
+                budgets,
+            })
+        });
+        let live = Arc::new(LiveSpan {
+            meta,
+            key,
+            pending: Mutex::new(PendingBusy::default()),
+        });
+        if self.indexes_live() {
+            self.shared
+                .live
+                .insert(id.clone(), live.clone(), &self.shared.poisoned_locks);
+        }
+        self.shared.live_span_opened(meta.name());
         let mut exts = span.extensions_mut();
         exts.insert(SpanBusyExt {
+            owner: self.owner(),
             in_count: 0,
             start: None,
-            callsite: key,
-            origin_file: loc.file,
-            origin_line: loc.line,
-            origin_col: loc.column,
-            total_busy: Duration::new(0, 0),
-            created_at: Instant::now(),
+            name_key: name_key.map(Box::new),
+            origin_file: file_symbol,
+            origin_line: origin_line.unwrap_or(NO_LOCATION),
+            origin_col: loc.column.unwrap_or(NO_LOCATION),
+            total_busy_ns: 0,
+            max_poll_ns: 0,
+            max_poll_seq: 0,
+            polls: 0,
+            poll_sum_squares_ns: 0,
+            polls_over_threshold: 0,
+            extra,
+            total_warned: false,
+            cell,
+            created,
+            first_polled_at: None,
+            task_name: loc.task_name.map(String::into_boxed_str),
+            task_id: loc.task_id,
+            acknowledged_ns: 0,
+            live,
+            runtime_resolved: false,
         });
     }
 
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, cx: Context<'_, S>) {
+        let Some(_guard) = InLayer::enter() else {
+            return;
+        };
+        let Some(span) = cx.span(id) else { return };
+        let owner = self.owner();
+        let mut exts = span.extensions_mut();
+        if let Some(request) = exts.get_mut::<RequestExt>().filter(|r| r.owner == owner) {
+            let Some(name) = self.route_field.as_deref() else {
+                return;
+            };
+            let mut visitor = RouteVisitor { name, route: None };
+            values.record(&mut visitor);
+            if let Some(route) = visitor.route {
+                request.route = Some(route);
+            }
+            return;
+        }
+        let Some(ext) = exts.get_mut::<SpanBusyExt>().filter(|e| e.owner == owner) else {
+            return;
+        };
+        if ext.origin_line().is_some() && ext.origin_col().is_some() {
+            return;
+        }
+        let mut loc = LocVisitor::new(&self.loc_fields);
+        values.record(&mut loc);
+        self.record_location(id, ext, span.metadata(), &loc);
+    }
+
     fn on_enter(&self, id: &span::Id, cx: Context<'_, S>) {
+        let Some(_guard) = InLayer::enter() else {
+            return;
+        };
         let Some(span) = cx.span(id) else { return };
 
         let mut exts = span.extensions_mut();
-        let Some(ext) = exts.get_mut::<SpanBusyExt>() else {
+        let owner = self.owner();
+        if let Some(request) = exts.get_mut::<RequestExt>().filter(|r| r.owner == owner) {
+            if request.in_count == 0 {
+                request.start = Some(self.clock.now());
+            }
+            request.in_count += 1;
+            return;
+        }
+        let Some(ext) = exts.get_mut::<SpanBusyExt>().filter(|e| e.owner == owner) else {
             return;
         };
+        // Entered to create async ops, which is not polling the resource.
+        if ext.callsite().category == Category::Resource {
+            return;
+        }
+        if !ext.runtime_resolved {
+            self.resolve_runtime(id, ext, span.metadata());
+        }
 
+        let mut first_poll = None;
         if ext.in_count == 0 {
-            ext.start = Some(Instant::now());
+            let now = self.clock.now();
+            ext.start = Some(SpanTime::new(self.shared.created_at, now));
+            if let Some(extra) = &mut ext.extra {
+                if extra.total_cpu.is_some() {
+                    extra.cpu_start = cpu_time::thread_cpu_time();
+                }
+                if self.warn_migrations.is_some() {
+                    let thread = std::thread::current().id();
+                    if extra
+                        .last_thread
+                        .replace(thread)
+                        .is_some_and(|t| t != thread)
+                    {
+                        extra.migrations += 1;
+                    }
+                }
+            }
+            if ext.first_polled_at.is_none() {
+                ext.first_polled_at = ext.start;
+                first_poll = Some(now);
+            }
         }
         ext.in_count += 1;
+
+        if let (Some(now), Some(latency)) = (first_poll, self.warn_first_poll_latency) {
+            drop(exts);
+            match TakenExt::take(&span) {
+                Some(ext) => self.check_first_poll(&ext, span.metadata(), now, latency),
+                None => self.shared.missing_extension(),
+            }
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, cx: Context<'_, S>) {
+        if self.breadcrumbs == 0 {
+            return;
+        }
+        // Also skips the warnings of this layer.
+        let Some(_guard) = InLayer::enter() else {
+            return;
+        };
+        let Some(scope) = cx.event_scope(event) else {
+            return;
+        };
+        let owner = self.owner();
+        for span in scope {
+            let mut exts = span.extensions_mut();
+            // Spans entered without being polled, like async ops entered to
+            // create them, leave the event to their parent.
+            let Some(ext) = exts
+                .get_mut::<SpanBusyExt>()
+                .filter(|e| e.owner == owner && e.in_count > 0)
+            else {
+                continue;
+            };
+            if let Some(extra) = &mut ext.extra {
+                extra.breadcrumbs.push(Breadcrumb::of(event));
+            }
+            return;
+        }
     }
 
     fn on_exit(&self, id: &span::Id, cx: Context<'_, S>) {
+        let Some(_guard) = InLayer::enter() else {
+            return;
+        };
         let Some(span) = cx.span(id) else { return };
 
         // Update span-local counters; if exiting the outermost enter,
-        // accumulate into total_busy. Do not lock our own mutex here.
+        // accumulate into total_busy. The shared state is only locked once
+        // `TakenExt` released the extensions.
         let mut exts = span.extensions_mut();
-        let Some(ext) = exts.get_mut::<SpanBusyExt>() else {
+        let owner = self.owner();
+        if let Some(request) = exts.get_mut::<RequestExt>().filter(|r| r.owner == owner) {
+            request.in_count = request.in_count.saturating_sub(1);
+            if request.in_count == 0 {
+                if let Some(start) = request.start.take() {
+                    let elapsed = self.clock.now().saturating_duration_since(start);
+                    request.busy.record(elapsed, self.warn_busy_single_poll);
+                }
+            }
+            return;
+        }
+        let Some(ext) = exts.get_mut::<SpanBusyExt>().filter(|e| e.owner == owner) else {
             return;
         };
 
@@ -193,147 +4590,829 @@ where
         if ext.in_count != 0 {
             return;
         }
-        let Some(start) = ext.start.take() else {
+        let Some(start) = ext
+            .start
+            .take()
+            .map(|start| start.instant(self.shared.created_at))
+        else {
+            self.shared.missing_extension();
+            return;
+        };
+        // The warnings below are events, which other layers may handle by
+        // locking the extensions of this span.
+        drop(exts);
+        let Some(mut taken) = TakenExt::take(&span) else {
+            self.shared.missing_extension();
             return;
         };
+        let ext = &mut *taken;
+
+        let end = self.clock.now();
+        let elapsed = end
+            .saturating_duration_since(start)
+            .saturating_sub(Duration::from_nanos(std::mem::take(
+                &mut ext.acknowledged_ns,
+            )));
+        ext.record_poll(elapsed);
+        ext.record_over_threshold(elapsed, &self.slo_thresholds);
+        let cpu = ext.end_cpu();
+        if let Some(request) = ext.request() {
+            request.record(elapsed, self.warn_busy_single_poll);
+        }
+        // Of the polls before this one, so an outlier doesn't raise its own
+        // baseline.
+        let mut baseline = None;
+        if let Some(cell) = &ext.cell {
+            if let Some(alpha) = self.ewma_alpha {
+                cell.update_ewma(alpha, elapsed);
+            }
+            if let Some(histogram) = &cell.histogram {
+                if let Some(anomaly) = &self.anomaly {
+                    baseline = anomaly.baseline(histogram);
+                }
+                histogram.record(elapsed);
+            }
+            if let Some(recent) = &cell.recent {
+                if elapsed >= self.recent_polls_floor {
+                    recent.push(PollSample {
+                        start,
+                        duration: elapsed,
+                    });
+                }
+            }
+        }
+        if self.shared.any_focused.load(Ordering::Relaxed) {
+            let meta = span.metadata();
+            let (file, line, _) = ext.origin(&self.shared, meta);
+            let poll = PollSample {
+                start,
+                duration: elapsed,
+            };
+            if self.shared.record_focused(meta, file, line, poll) {
+                self.emit_focus_poll(ext, meta, elapsed);
+            }
+        }
+        if self.live_flush.is_some_and(|interval| {
+            let flushed_at = ext.flushed().at.instant(self.shared.created_at);
+            end.saturating_duration_since(flushed_at) >= interval
+        }) {
+            let mut map = self.shared.lock(&self.shared.callsites);
+            let stats = (!self.shared.stats_paused())
+                .then(|| self.callsite_stats(&mut map, ext.callsite(), span.metadata(), end));
+            ext.flush_into(stats, self.shared.created_at, end);
+        } else {
+            ext.update_live();
+        }
+
+        if self.trace_min_duration.is_some_and(|min| elapsed >= min) {
+            let meta = span.metadata();
+            let (file, line, col) = ext.origin(&self.shared, meta);
+            if let Some(trace) = &mut *self.shared.lock(&self.shared.trace) {
+                trace.record(
+                    start,
+                    elapsed,
+                    meta.name(),
+                    meta.target(),
+                    file.to_string(),
+                    line,
+                    col,
+                );
+            }
+        }
+
+        if ext.callsite().category == Category::Acknowledged {
+            for ancestor in span.scope().skip(1) {
+                let mut exts = ancestor.extensions_mut();
+                if let Some(ext) = exts
+                    .get_mut::<SpanBusyExt>()
+                    .filter(|e| e.owner == taken.owner && e.in_count > 0)
+                {
+                    ext.acknowledged_ns += elapsed.as_nanos() as u64;
+                }
+            }
+            return;
+        }
+
+        if ext.callsite().category == Category::BlockingPool {
+            // Runs on the blocking pool, so it doesn't block the workers.
+            // Checked here rather than on close: the closure runs in a single
+            // poll, while closing the span can happen much later.
+            if let Some(threshold) = self.warn_blocking_pool_op {
+                self.check_blocking_pool_op(ext, span.metadata(), elapsed, threshold);
+            }
+            return;
+        }
 
-        let end = Instant::now();
-        let elapsed = end.saturating_duration_since(start);
-        ext.total_busy += elapsed;
+        if let Some(max_rate) = self.warn_poll_rate {
+            self.check_poll_rate(ext, span.metadata(), start, end, max_rate);
+        }
+        if !ext.budgets().is_empty() {
+            self.check_budgets(ext, span.metadata(), end, elapsed);
+        }
+        if self.warn_busy_total_eager
+            && !ext.total_warned
+            && span.metadata().name() == "runtime.spawn"
+            && self.warn_busy_total.is_some_and(|t| ext.total_busy() >= t)
+        {
+            ext.total_warned = true;
+            let lifetime = ext.lifetime(self.shared.created_at, end);
+            self.report_total(
+                ext,
+                span.metadata(),
+                span_ids(&span),
+                lifetime,
+                self.warn_busy_total,
+                true,
+            );
+        }
+
+        // Async ops are polled inside their task, so only tasks count.
+        if span.metadata().name() == "runtime.spawn" {
+            self.shared.record_worst_poll(elapsed, || {
+                let meta = span.metadata();
+                let (file, line, col) = ext.origin(&self.shared, meta);
+                let row = CallsiteStats::new(meta, ext.callsite()).to_snapshot(None, &[]);
+                let thread = std::thread::current();
+                WorstPoll {
+                    duration: elapsed,
+                    callsite: OwnedCallsiteStats::from(&row).id,
+                    file: file.to_string(),
+                    line,
+                    col,
+                    task_name: ext.task_name.as_deref().map(str::to_string),
+                    timestamp: SystemTime::now(),
+                    thread_id: thread.id(),
+                    thread_name: thread.name().map(str::to_string),
+                }
+            });
+            if let Some(tracker) = &self.adaptive {
+                tracker.record(elapsed, end);
+            }
+            if let Some(health) = &self.health {
+                let blocked = self.warn_busy_single_poll.is_some_and(|t| elapsed >= t);
+                health.record(elapsed, end, blocked);
+            }
+            if let Some(saturated) = self
+                .saturation
+                .as_ref()
+                .and_then(|t| t.record(elapsed, end))
+            {
+                self.warn_saturated(&saturated);
+            }
+        }
 
-        let Some(threshold) = self.warn_busy_single_poll else {
+        if let (Some(anomaly), Some(baseline)) = (&self.anomaly, baseline) {
+            self.check_anomaly(ext, span.metadata(), elapsed, anomaly, baseline);
+            return;
+        }
+
+        let Some(configured) = self.warn_busy_single_poll else {
             return; // No threshold configured, skip warning
         };
+        let (threshold, load) = match &self.adaptive {
+            Some(tracker) => {
+                let load = tracker.load();
+                (tracker.scale(configured, load), Some(load))
+            }
+            None => (configured, None),
+        };
 
         // Warn if a single poll exceeded threshold.
         if elapsed >= threshold {
-            // Emit a warning event for this poll occurrence.
             let meta = span.metadata();
-            let file = ext
-                .origin_file
-                .as_deref()
-                .or_else(|| meta.file())
-                .unwrap_or("<unknown>")
-                .to_string();
-            let line = ext.origin_line.or(meta.line()).unwrap_or(0u32);
-            tracing::event!(
-                target: "tokio_blocked::task_poll_blocked",
-                Level::WARN,
-                poll_duration_ns = elapsed.as_nanos() as u64,
-                callsite.name = meta.name(),
-                callsite.target = meta.target(),
-                callsite.file = &file[..],
-                callsite.line = line,
-                callsite.col = ext.origin_col.unwrap_or(0u32),
-            );
+            let (file, line, col) = ext.origin(&self.shared, meta);
+            let first_poll = ext.polls == 1 && meta.name() == "runtime.spawn";
+            let (exceed_count, escalated) =
+                self.record_exceeded(ext.callsite(), meta, end, elapsed, first_poll);
+            ext.polls_over_threshold += 1;
+            let tier = severity_tier(&self.poll_tiers, elapsed);
+            let span_lifetime = ext.lifetime(self.shared.created_at, end);
+            let thread = std::thread::current();
+            let mut info = BlockedPollInfo {
+                seq: 0,
+                poll_duration: elapsed,
+                threshold: configured,
+                effective_threshold: threshold,
+                load,
+                poll_seq: ext.polls,
+                exceed_count,
+                span_busy: ext.total_busy(),
+                span_lifetime,
+                span_busy_ratio: if span_lifetime.is_zero() {
+                    0.0
+                } else {
+                    ext.total_busy().as_secs_f64() / span_lifetime.as_secs_f64()
+                },
+                callsite_name: meta.name(),
+                callsite_target: meta.target(),
+                callsite_file: meta.file(),
+                callsite_line: meta.line(),
+                origin_file: ext.origin_file(&self.shared),
+                origin_line: ext.origin_line(),
+                origin_col: ext.origin_col(),
+                task_name: ext.task_name.as_deref().map(Cow::Borrowed),
+                task_id: ext.task_id,
+                thread_id: thread.id(),
+                thread_name: thread.name().map(Cow::Borrowed),
+                timestamp: SystemTime::now(),
+                level: match (self.escalation, tier) {
+                    (Some(escalation), _) if escalated => escalation.level,
+                    (_, Some((_, level))) => level,
+                    _ => Level::WARN,
+                },
+                tier_threshold: tier.map(|(threshold, _)| threshold),
+                escalated,
+                resource_concrete_type: ext.callsite().resource.concrete_type,
+                resource_kind: ext.callsite().resource.kind,
+                resource_source: ext.callsite().resource.source,
+                parent_task_file: ext.callsite().parent_task.and_then(|p| p.file),
+                parent_task_line: ext.callsite().parent_task.and_then(|p| p.line),
+                parent_task_name: ext.callsite().parent_task.and_then(|p| p.name),
+                parent_task_span_id: ext.parent_task_span().map(span::Id::into_u64),
+                span_id: span.id().into_u64(),
+                span_parent_id: span.parent().map(|parent| parent.id().into_u64()),
+                cpu,
+                on_cpu_percent: cpu.and_then(|cpu| cpu_time::on_cpu_percent(cpu, elapsed)),
+                runtime: ext.callsite().runtime,
+                span_trace: span_trace(),
+                breadcrumbs: ext.breadcrumbs(),
+            };
+            // Logged before dedup and sampling decide about the warning.
+            if let Some(log) = &*self.shared.read(&self.shared.event_log) {
+                log.record(PollEntry {
+                    timestamp: info.timestamp,
+                    duration: elapsed,
+                    target: meta.target(),
+                    name: meta.name(),
+                    file,
+                    line,
+                    col,
+                    tid: chrome_trace::current_tid(),
+                    thread: thread.clone(),
+                    first_poll,
+                    escalated,
+                });
+            }
+            if let Some(on_blocked) = &self.on_blocked {
+                on_blocked(&info);
+            }
+            if let Some(naming) = self.metric_events {
+                observer::emit_poll_metrics(naming, &info);
+            }
+
+            match self.on_violation {
+                ViolationMode::Warn => {
+                    if self.per_task_summary {
+                        return;
+                    }
+                    let emit = self.shared.warnings_enabled()
+                        && !self.shared.is_exempt(meta, file, line)
+                        && self.dedup_observe(file, line, elapsed, end);
+                    if emit {
+                        info.seq = self.shared.next_event_seq();
+                    }
+                    self.keep_warning(emit, || WarningInfo::PollBlocked(info.clone().into_owned()));
+                    if !emit {
+                        return;
+                    }
+                    for observer in self.shared.observers().iter() {
+                        observer.on_poll_blocked(&info);
+                    }
+                    #[cfg(feature = "channel")]
+                    self.shared
+                        .publish(|| BlockedEvent::PollBlocked(info.clone().into_owned()));
+                }
+                ViolationMode::Panic | ViolationMode::Record => {
+                    let violation = Violation {
+                        poll_duration: elapsed,
+                        threshold,
+                        callsite_name: meta.name(),
+                        callsite_target: meta.target(),
+                        file: file.to_string(),
+                        line,
+                        col,
+                    };
+                    if self.on_violation == ViolationMode::Record {
+                        self.shared.lock(&self.shared.violations).push(violation);
+                    } else if !std::thread::panicking() {
+                        // `taken` puts the extension back while unwinding, so
+                        // the span can still be closed cleanly.
+                        panic!("{violation}");
+                    }
+                }
+            }
+        } else if let Some(quiet_polls) = self.recovery_quiet_polls {
+            self.check_recovery(ext, span.metadata(), end, quiet_polls);
         }
     }
 
     fn on_close(&self, id: span::Id, cx: Context<'_, S>) {
+        let Some(_guard) = InLayer::enter() else {
+            return;
+        };
         let Some(span) = cx.span(&id) else { return };
 
+        let owner = self.owner();
         let mut extensions = span.extensions_mut();
-        let Some(mut ext) = extensions.remove::<SpanBusyExt>() else {
+        if extensions
+            .get_mut::<RequestExt>()
+            .is_some_and(|r| r.owner == owner)
+        {
+            let request = extensions.remove::<RequestExt>();
+            if self
+                .shared
+                .lock(&self.shared.requests)
+                .remove(&id)
+                .is_some()
+            {
+                self.shared.open_requests.fetch_sub(1, Ordering::Relaxed);
+            }
+            drop(extensions);
+            if let Some(request) = request {
+                if self.route_field.is_some() && !self.shared.stats_paused() {
+                    self.shared
+                        .record_route(request.route.as_deref(), &request.busy);
+                }
+                if request.report && self.shared.warnings_enabled() {
+                    emit_request_total(&id, span.metadata(), &request.busy);
+                }
+            }
+            return;
+        }
+        if extensions
+            .get_mut::<SpanBusyExt>()
+            .is_none_or(|e| e.owner != owner)
+        {
             return; // No busy time tracking for this span
+        }
+        let Some(mut ext) = extensions.remove::<SpanBusyExt>() else {
+            return;
         };
 
         let meta = span.metadata();
-        // Finish any in-progress busy interval and copy accumulated totals.
-        let (callsite_key, total_busy, origin_file, origin_line, created_at) = {
-            if ext.in_count > 0 {
-                if let Some(start) = ext.start.take() {
-                    let end = Instant::now();
-                    let elapsed = end.saturating_duration_since(start);
-                    ext.total_busy += elapsed;
-                    ext.in_count = 0;
-                }
+        // Finish any in-progress busy interval.
+        if ext.in_count > 0 {
+            if let Some(start) = ext.start.take() {
+                let end = self.clock.now();
+                let elapsed = end.saturating_duration_since(start.instant(self.shared.created_at));
+                ext.record_poll(elapsed);
+                ext.record_over_threshold(elapsed, &self.slo_thresholds);
+                ext.end_cpu();
+                ext.in_count = 0;
             }
-            (
-                ext.callsite,
-                ext.total_busy,
-                ext.origin_file.clone(),
-                ext.origin_line,
-                ext.created_at,
-            )
-        };
+        }
+        let total_busy = ext.total_busy();
+        let now = self.clock.now();
+        let lifetime = ext.lifetime(self.shared.created_at, now);
+        // Recording calls into the other layers, which lock the extensions.
+        drop(extensions);
+        record_span_fields(&id, meta, &ext);
+
+        let counted = !matches!(
+            ext.callsite().category,
+            Category::BlockingPool | Category::Resource | Category::Acknowledged
+        );
+        let mut streak = None;
+        let threshold = self.warn_busy_total;
+        let summary = self.per_task_summary && ext.polls_over_threshold > 0;
+        let blocked = threshold.is_some_and(|t| total_busy >= t) || ext.polls_over_threshold > 0;
+        let pattern = self.pattern(&ext).filter(|_| blocked && counted);
 
         // Update per-callsite totals once per span instance.
-        {
-            let mut map = self.callsites.lock().unwrap();
-            let stats = map.entry(callsite_key).or_insert_with(|| CallsiteStats {
-                name: meta.name(),
-                target: meta.target(),
-                file: meta.file(),
-                line: meta.line(),
-                ..Default::default()
-            });
-            stats.total_busy += total_busy;
-            stats.count += 1;
+        let mut map = self.shared.lock(&self.shared.callsites);
+        if !self.shared.stats_paused() {
+            let stats = self.callsite_stats(&mut map, ext.callsite(), meta, now);
+            ext.flush_into(Some(&mut *stats), self.shared.created_at, now);
+            stats.record_close(&ext, lifetime, pattern);
+            stats.record_over_threshold(&self.slo_thresholds, ext.over_threshold());
+            if let (Some((n, _)), Some(threshold), true) =
+                (&self.consecutive_alert, self.warn_busy_total, counted)
+            {
+                streak = stats.record_streak(total_busy >= threshold, *n);
+            }
+            if let Some(key) = ext.name_key.as_deref().copied() {
+                // Not live flushed, so the whole span is added at once.
+                let stats = self.callsite_stats(&mut map, key, meta, now);
+                stats.total_busy += ext.total_busy();
+                stats.max_poll = stats.max_poll.max(ext.max_poll());
+                stats.polls += ext.polls;
+                stats.poll_sum_squares_ns += ext.poll_sum_squares_ns;
+                stats.exceed_count += ext.polls_over_threshold;
+                stats.record_close(&ext, lifetime, pattern);
+                stats.record_over_threshold(&self.slo_thresholds, ext.over_threshold());
+            }
+        }
+        // Still under the callsites lock, so snapshots never count the span
+        // twice or not at all.
+        if self.indexes_live() {
+            self.shared.live.remove(&id, &self.shared.poisoned_locks);
         }
+        drop(map);
+        self.shared.live_span_closed(meta.name());
 
-        let Some(threshold) = self.warn_busy_total else {
-            return; // No total busy time threshold configured
-        };
+        if !counted {
+            return;
+        }
+
+        if let Some(streak) = streak {
+            self.alert_sustained(&ext, meta, streak);
+        }
+
+        if let Some(max) = self.warn_migrations {
+            self.check_migrations(&ext, meta, max);
+        }
+
+        if let Some(threshold) = self.warn_lock_wait {
+            if meta.name() == "runtime.resource.async_op" && ext.callsite().resource.is_sync() {
+                self.check_lock_wait(&ext, meta, lifetime, threshold);
+                return;
+            }
+        }
 
         // Emit a warning for the span's total busy time and total lifetime only
-        // if the configured threshold is exceeded.
-        if total_busy >= threshold {
-            let total_span = Instant::now().saturating_duration_since(created_at);
-            let file = origin_file
-                .as_deref()
-                .or_else(|| meta.file())
-                .unwrap_or("<unknown>")
-                .to_string();
-            let line = origin_line.or(meta.line()).unwrap_or(0u32);
-            tracing::event!(
-                target: "tokio_blocked::task_blocked_total",
-                Level::WARN,
-                busy_ns = total_busy.as_nanos() as u64,
-                duration_ns = total_span.as_nanos() as u64,
-                blocked_percent = (total_busy.as_secs_f64() / total_span.as_secs_f64()) * 100.0,
-                callsite.name = meta.name(),
-                callsite.target = meta.target(),
-                callsite.file = &file[..],
-                callsite.line = line,
-                callsite.col = ext.origin_col.unwrap_or(0u32),
-                "tokio task blocked for too long",
-            );
+        // if the configured threshold is exceeded, or as a per-task summary if
+        // any poll exceeded the single-poll threshold.
+        let reported = ext.total_warned && !self.warn_busy_total_final;
+        if (threshold.is_some_and(|t| total_busy >= t) || summary) && !reported {
+            // The span is still in the registry while it closes.
+            self.report_total(&ext, meta, span_ids(&span), lifetime, threshold, false);
         }
     }
 }
 
-// A simple visitor to extract `loc.file`, `loc.line`, and `loc.col` if present
-// on a span's attributes. Tokio and other instrumentations often include these
-// fields to indicate the original user code location.
-#[derive(Default)]
-struct LocVisitor {
+impl TokioBlockedLayer {
+    /// Identifies the extensions of the layers recording into the same
+    /// statistics, of which there is one at a time, or a replacement loaded
+    /// with [`from_handle`](Self::from_handle).
+    fn owner(&self) -> usize {
+        Arc::as_ptr(&self.shared) as usize
+    }
+
+    /// The per-callsite cell of `key`, if a feature needs one.
+    fn callsite_cell(
+        &self,
+        meta: &'static Metadata<'static>,
+        key: CallsiteKey,
+    ) -> Option<Arc<CallsiteCell>> {
+        let histogram = self.poll_histogram || self.anomaly.is_some();
+        (self.ewma_alpha.is_some() || histogram || self.recent_polls > 0).then(|| {
+            self.shared.cell(key, || {
+                CallsiteCell::new(
+                    meta,
+                    histogram.then(|| self.histogram_bounds.clone()),
+                    self.recent_polls,
+                )
+            })
+        })
+    }
+
+    /// Moves a span to the rows of the runtime it is first polled on.
+    fn resolve_runtime(
+        &self,
+        id: &span::Id,
+        ext: &mut SpanBusyExt,
+        meta: &'static Metadata<'static>,
+    ) {
+        ext.runtime_resolved = true;
+        let Some(runtime) = self.runtime_label.as_ref().and_then(RuntimeLabel::resolve) else {
+            return;
+        };
+        let key = CallsiteKey {
+            runtime: Some(runtime),
+            ..ext.callsite()
+        };
+        if let Some(key) = &mut ext.name_key {
+            key.runtime = Some(runtime);
+        }
+        self.rekey(id, ext, meta, key);
+    }
+
+    /// Fills the parts of the spawn location a span did not have on creation
+    /// from the fields it recorded later, moving it to the row of the new
+    /// location. Busy time already merged by a live flush stays in the old
+    /// row.
+    fn record_location(
+        &self,
+        id: &span::Id,
+        ext: &mut SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        loc: &LocVisitor<'_>,
+    ) {
+        if ext.origin_col().is_none() {
+            if let Some(col) = loc.column {
+                ext.origin_col = col;
+            }
+        }
+        let file = match ext.origin_file {
+            Some(symbol) => symbol,
+            None => match loc.file.as_deref().and_then(|f| self.shared.intern_file(f)) {
+                Some(symbol) => symbol,
+                None => return,
+            },
+        };
+        let line = ext.origin_line().or(loc.line);
+        if ext.origin_file == Some(file) && ext.origin_line() == line {
+            return;
+        }
+        ext.origin_file = Some(file);
+        ext.origin_line = line.unwrap_or(NO_LOCATION);
+        let key = ext.callsite();
+        // Rows grouped by task name don't depend on the location.
+        if key.task_name.is_some() {
+            return;
+        }
+        let key = CallsiteKey {
+            origin_file: Some(self.shared.file(file)),
+            origin_line: line,
+            ..key
+        };
+        self.rekey(id, ext, meta, key);
+    }
+
+    /// Moves the span to the row of `key`.
+    fn rekey(
+        &self,
+        id: &span::Id,
+        ext: &mut SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        key: CallsiteKey,
+    ) {
+        ext.cell = self.callsite_cell(meta, key);
+        ext.live = Arc::new(LiveSpan {
+            meta,
+            key,
+            pending: Mutex::new(PendingBusy::default()),
+        });
+        if self.indexes_live() {
+            self.shared
+                .live
+                .insert(id.clone(), ext.live.clone(), &self.shared.poisoned_locks);
+        }
+    }
+
+    /// Whether open spans are kept in the live index, see
+    /// [`with_live_snapshots`](Self::with_live_snapshots).
+    fn indexes_live(&self) -> bool {
+        self.live_snapshots || self.live_flush.is_some()
+    }
+
+    /// Whether polls are sampled for their CPU time, see
+    /// [`with_cpu_time`](Self::with_cpu_time).
+    fn measures_cpu(&self) -> bool {
+        self.cpu_time && cpu_time::SUPPORTED
+    }
+
+    /// The blocking pattern of a span, from the thresholds it was tracked
+    /// with.
+    fn pattern(&self, ext: &SpanBusyExt) -> Option<BlockingPattern> {
+        self.patterns.classify(
+            ext.total_busy(),
+            ext.polls,
+            ext.max_poll(),
+            self.warn_busy_single_poll,
+            self.warn_busy_total,
+        )
+    }
+
+    /// Adds a warning to the history, if enabled.
+    fn keep_warning(&self, emitted: bool, warning: impl FnOnce() -> WarningInfo) {
+        if self.warning_history == 0 || !(emitted || self.warning_history_suppressed) {
+            return;
+        }
+        self.shared
+            .lock(&self.shared.warning_history)
+            .push(HistoricalWarning {
+                warning: warning(),
+                suppressed: !emitted,
+            });
+    }
+
+    /// Emits the total busy time of a span that reached `threshold`, or of a
+    /// per-task summary.
+    fn report_total(
+        &self,
+        ext: &SpanBusyExt,
+        meta: &'static Metadata<'static>,
+        (span_id, span_parent_id): (u64, Option<u64>),
+        lifetime: Duration,
+        threshold: Option<Duration>,
+        still_open: bool,
+    ) {
+        let total_busy = ext.total_busy();
+        let (file, line, _) = ext.origin(&self.shared, meta);
+        let tier = threshold
+            .is_some_and(|t| total_busy >= t)
+            .then(|| severity_tier(&self.total_tiers, total_busy))
+            .flatten();
+        let mut info = BlockedTotalInfo {
+            seq: 0,
+            busy: total_busy,
+            lifetime,
+            threshold,
+            level: tier.map_or(Level::WARN, |(_, level)| level),
+            tier_threshold: tier.map(|(threshold, _)| threshold),
+            blocked_percent: (total_busy.as_secs_f64() / lifetime.as_secs_f64()) * 100.0,
+            polls: ext.polls,
+            max_poll: ext.max_poll(),
+            max_poll_seq: ext.max_poll_seq,
+            polls_over_threshold: ext.polls_over_threshold,
+            pattern: self.pattern(ext),
+            migrations: ext.migrations(),
+            callsite_name: meta.name(),
+            callsite_target: meta.target(),
+            callsite_file: meta.file(),
+            callsite_line: meta.line(),
+            origin_file: ext.origin_file(&self.shared),
+            origin_line: ext.origin_line(),
+            origin_col: ext.origin_col(),
+            task_name: ext.task_name.as_deref().map(Cow::Borrowed),
+            task_id: ext.task_id,
+            resource_concrete_type: ext.callsite().resource.concrete_type,
+            resource_kind: ext.callsite().resource.kind,
+            resource_source: ext.callsite().resource.source,
+            parent_task_file: ext.callsite().parent_task.and_then(|p| p.file),
+            parent_task_line: ext.callsite().parent_task.and_then(|p| p.line),
+            parent_task_name: ext.callsite().parent_task.and_then(|p| p.name),
+            parent_task_span_id: ext.parent_task_span().map(span::Id::into_u64),
+            span_id,
+            span_parent_id,
+            cpu: ext.total_cpu(),
+            on_cpu_percent: ext
+                .total_cpu()
+                .and_then(|cpu| cpu_time::on_cpu_percent(cpu, total_busy)),
+            runtime: ext.callsite().runtime,
+            span_still_open: still_open,
+            timestamp: SystemTime::now(),
+        };
+        if let Some(on_blocked_total) = &self.on_blocked_total {
+            on_blocked_total(&info);
+        }
+        if let Some(naming) = self.metric_events {
+            observer::emit_total_metrics(naming, &info);
+        }
+        let emit = self.shared.warnings_enabled() && !self.shared.is_exempt(meta, file, line);
+        if emit {
+            info.seq = self.shared.next_event_seq();
+        }
+        self.keep_warning(emit, || {
+            WarningInfo::TotalBlocked(info.clone().into_owned())
+        });
+        if !emit {
+            return;
+        }
+        for observer in self.shared.observers().iter() {
+            observer.on_span_closed(&info);
+        }
+        #[cfg(feature = "channel")]
+        self.shared
+            .publish(|| BlockedEvent::TotalBlocked(info.clone().into_owned()));
+    }
+}
+
+// A simple visitor to extract the location fields (`loc.file`, `loc.line`, and
+// `loc.col` by default, see `LocFieldNames`) if present on a span's attributes.
+// Tokio and other instrumentations often include these fields to indicate the
+// original user code location.
+struct LocVisitor<'a> {
+    names: &'a LocFieldNames,
     file: Option<String>,
     line: Option<u32>,
     column: Option<u32>,
+    task_name: Option<String>,
+    task_id: Option<u64>,
+    // Resource fields of `runtime.resource` and async op spans.
+    concrete_type: Option<String>,
+    kind: Option<String>,
+    source: Option<String>,
+    is_internal: bool,
+}
+
+impl<'a> LocVisitor<'a> {
+    fn new(names: &'a LocFieldNames) -> Self {
+        Self {
+            names,
+            file: None,
+            line: None,
+            column: None,
+            task_name: None,
+            task_id: None,
+            concrete_type: None,
+            kind: None,
+            source: None,
+            is_internal: false,
+        }
+    }
+
+    fn record_position(&mut self, field: &Field, value: u32) {
+        // The first matching field wins if a span has several.
+        let name = field.name();
+        if self.names.line.contains(&name) {
+            self.line.get_or_insert(value);
+        } else if self.names.col.contains(&name) {
+            self.column.get_or_insert(value);
+        }
+    }
+}
+
+/// Reads the route field of a request span, see
+/// `TokioBlockedLayer::with_route_field`.
+struct RouteVisitor<'a> {
+    name: &'a str,
+    route: Option<String>,
 }
 
-impl Visit for LocVisitor {
-    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+impl Visit for RouteVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == self.name {
+            self.route = Some(format!("{value:?}"));
+        }
+    }
 
     fn record_str(&mut self, field: &Field, value: &str) {
-        if field.name() == "loc.file" {
-            self.file = Some(value.to_string());
+        if field.name() == self.name {
+            self.route = Some(value.to_string());
         }
     }
+}
 
-    fn record_u64(&mut self, field: &Field, value: u64) {
+impl Visit for LocVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        // Tokio records the task name and kind with `%`.
+        match field.name() {
+            // Unnamed tasks have an empty name.
+            "task.name" => {
+                self.task_name = Some(format!("{value:?}")).filter(|name| !name.is_empty())
+            }
+            "kind" => self.kind = Some(format!("{value:?}")),
+            _ => {}
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
         match field.name() {
-            "loc.line" => self.line = Some(value as u32),
-            "loc.col" => self.column = Some(value as u32),
+            name if self.names.file.contains(&name) => {
+                self.file.get_or_insert_with(|| value.to_string());
+            }
+            "task.name" => self.task_name = Some(value.to_string()).filter(|n| !n.is_empty()),
+            "concrete_type" => self.concrete_type = Some(value.to_string()),
+            "kind" => self.kind = Some(value.to_string()),
+            "source" => self.source = Some(value.to_string()),
             _ => {}
         }
     }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "task.id" => self.task_id = Some(value),
+            _ => self.record_position(field, value as u32),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if let Ok(value) = u32::try_from(value) {
+            self.record_position(field, value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "is_internal" {
+            self.is_internal = value;
+        }
+    }
+}
+
+fn emit_request_total(id: &span::Id, meta: &'static Metadata<'static>, busy: &RequestBusy) {
+    tracing::event!(
+        target: "tokio_blocked::request_total",
+        parent: id.clone(),
+        Level::INFO,
+        busy_ns = busy.busy_ns.load(Ordering::Relaxed),
+        blocked_ns = busy.blocked_ns.load(Ordering::Relaxed),
+        request.name = meta.name(),
+        request.target = meta.target(),
+        "time spent on behalf of the request",
+    );
+}
+
+/// See [`TokioBlockedLayer::recommended_filter`].
+fn is_needed_span(meta: &Metadata<'_>) -> bool {
+    meta.is_span()
+        && (matches_tokio_poll(meta)
+            || meta.name() == "runtime.resource"
+            || (meta.target() == REQUEST_TARGET && meta.name() == REQUEST_NAME)
+            || (meta.target() == "tokio_blocked::scope" && meta.name() == "tokio_blocked.scope"))
 }
 
 fn matches_tokio_poll(meta: &Metadata<'_>) -> bool {
     match (meta.name(), meta.target()) {
         // Task spans (tokio::task or runtime.spawn)
         ("runtime.spawn", "tokio::task") => true,
+        // Task spans of `tokio_blocked::spawn`
+        ("runtime.spawn", "tokio_blocked::task") => true,
+        // Blocking pool tasks (spawn_blocking, tokio::fs)
+        ("runtime.spawn", "tokio::task::blocking") => true,
+        // Deliberately blocking sections, see `block_zone!`
+        ("tokio_blocked.block_zone", "tokio_blocked::block_zone") => true,
         // Async op spans
         ("runtime.resource.async_op", _) => true,
         // Per-poll spans for async ops
@@ -341,3 +5420,135 @@ fn matches_tokio_poll(meta: &Metadata<'_>) -> bool {
         _ => false,
     }
 }
+
+/// Maximum number of selectors focused at once, see
+/// [`BlockedStatsHandle::focus`].
+const MAX_FOCUSED: usize = 4;
+
+/// Polls kept per focused selector.
+const FOCUS_RECENT_POLLS: usize = 64;
+
+/// See [`BlockedStatsHandle::focus`].
+struct Focus {
+    selector: CallsiteSelector,
+    recent: RecentPolls,
+}
+
+/// See [`TokioBlockedLayer::with_busy_budget`].
+#[derive(Debug, Clone)]
+struct BusyBudget {
+    selector: CallsiteSelector,
+    budget: Duration,
+    per: Duration,
+}
+
+/// Busy time of a callsite in the current period of a busy budget.
+#[derive(Debug)]
+struct BudgetWindow {
+    start: Instant,
+    busy: Duration,
+    polls: u64,
+    warned: bool,
+}
+
+impl BudgetWindow {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            busy: Duration::ZERO,
+            polls: 0,
+            warned: false,
+        }
+    }
+}
+
+/// See [`TokioBlockedLayer::with_anomaly_threshold`].
+#[derive(Debug, Clone, Copy)]
+struct AnomalyThreshold {
+    multiplier: f64,
+    percentile: f64,
+    min_samples: u64,
+}
+
+impl AnomalyThreshold {
+    /// The percentile of the polls in `histogram`, once there are enough.
+    fn baseline(&self, histogram: &AtomicHistogram) -> Option<Duration> {
+        let histogram = histogram.load();
+        if histogram.count() < self.min_samples {
+            return None;
+        }
+        histogram.percentile(self.percentile)
+    }
+}
+
+/// Records the busy time of a closing span onto it if it declares the
+/// `tokio_blocked.*` fields, as the spans of [`spawn`](fn@crate::spawn) do, for
+/// other layers to pick up.
+///
+/// Layers can't record values themselves, so this goes through the current
+/// dispatcher.
+fn record_span_fields(id: &span::Id, meta: &'static Metadata<'static>, ext: &SpanBusyExt) {
+    let fields = meta.fields();
+    let (Some(busy), Some(polls), Some(max_poll)) = (
+        fields.field("tokio_blocked.busy_ns"),
+        fields.field("tokio_blocked.polls"),
+        fields.field("tokio_blocked.max_poll_ns"),
+    ) else {
+        return;
+    };
+    let busy_ns = ext.total_busy().as_nanos() as u64;
+    let max_poll_ns = ext.max_poll().as_nanos() as u64;
+    let values = [
+        (&busy, Some(&busy_ns as &dyn tracing::Value)),
+        (&polls, Some(&ext.polls as &dyn tracing::Value)),
+        (&max_poll, Some(&max_poll_ns as &dyn tracing::Value)),
+    ];
+    let values = fields.value_set(&values);
+    tracing::dispatcher::get_default(|dispatch| dispatch.record(id, &span::Record::new(&values)));
+}
+
+/// Sorts severity tiers by threshold, keeping the first of equal thresholds.
+fn sorted_tiers(tiers: &[(Duration, Level)]) -> Vec<(Duration, Level)> {
+    let mut tiers = tiers.to_vec();
+    tiers.sort_by_key(|&(threshold, _)| threshold);
+    tiers.dedup_by_key(|&mut (threshold, _)| threshold);
+    tiers
+}
+
+/// The highest tier `duration` reached, or the lowest one if the threshold
+/// was lowered below it by adaptive thresholds.
+fn severity_tier(tiers: &[(Duration, Level)], duration: Duration) -> Option<(Duration, Level)> {
+    tiers
+        .iter()
+        .rev()
+        .find(|&&(threshold, _)| duration >= threshold)
+        .or(tiers.first())
+        .copied()
+}
+
+/// Whether `file:line` matches an entry of
+/// [`with_allow_blocking_at`](TokioBlockedLayer::with_allow_blocking_at).
+fn is_allowed_at(allowed: &[(String, Option<u32>)], file: &str, line: u32) -> bool {
+    allowed
+        .iter()
+        .any(|(pattern, l)| file.contains(pattern.as_str()) && l.is_none_or(|l| l == line))
+}
+
+/// Formats the spans entered on this thread, see
+/// [`BlockedPollInfo::span_trace`].
+///
+/// Called with the [`SpanBusyExt`] taken out by [`TakenExt`], so no
+/// extensions are locked while `tracing-error` reads those of the entered
+/// spans.
+#[cfg(feature = "span-trace")]
+fn span_trace() -> Option<String> {
+    use tracing_error::{SpanTrace, SpanTraceStatus};
+
+    let trace = SpanTrace::capture();
+    (trace.status() == SpanTraceStatus::CAPTURED).then(|| trace.to_string())
+}
+
+#[cfg(not(feature = "span-trace"))]
+fn span_trace() -> Option<String> {
+    None
+}
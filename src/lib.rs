@@ -51,7 +51,103 @@
 //!     .unwrap();
 //! }
 //! ```
+//!
+//! With the `init` feature, [`init_with`] sets up the same subscriber in one
+//! call.
 
+mod adaptive;
+mod breadcrumbs;
+mod builder;
+mod chrome_trace;
+mod clock;
+mod cpu_time;
+mod dedup;
+pub mod event_log;
+pub mod events;
+mod global;
+mod health;
+mod histogram;
+#[cfg(feature = "init")]
+mod init;
+#[cfg(any(feature = "serde", feature = "sqlite"))]
+mod interval;
 mod layer;
+#[cfg(feature = "log")]
+mod log_fallback;
+mod observer;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod pattern;
+mod recent;
+mod report;
+mod saturation;
+mod scope;
+mod selector;
+#[cfg(feature = "sentry")]
+mod sentry;
+mod snapshot;
+#[cfg(feature = "spawn")]
+mod spawn;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "statsd")]
+mod statsd;
+#[cfg(feature = "tokio-metrics")]
+mod task_metrics;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod time;
+#[cfg(feature = "serde")]
+mod timeseries;
+#[cfg(feature = "tower")]
+mod tower;
+mod zone;
+
+pub use self::adaptive::AdaptiveThresholds;
+pub use self::breadcrumbs::Breadcrumb;
+pub use self::builder::{Builder, ConfigError};
+pub use self::clock::{Clock, ManualClock, SystemClock};
+pub use self::global::{reset, snapshot, stats, GlobalError};
+pub use self::health::{BlockedHealth, HealthThresholds};
+pub use self::histogram::Histogram;
+#[cfg(feature = "init")]
+pub use self::init::{init, init_with, subscriber_with};
+pub use self::layer::{
+    BlockedStatsHandle, BlockedSummary, Diagnostics, EvictionPolicy, Granularity, GroupBy,
+    LiveSpans, LocFieldNames, MemoryStats, TokioBlockedLayer, Violation, ViolationMode, WorstPoll,
+};
+#[cfg(feature = "log")]
+pub use self::log_fallback::LogObserver;
+pub use self::observer::{BlockedObserver, MetricNaming, TracingObserver};
+#[cfg(feature = "otlp")]
+pub use self::otlp::{spawn_otlp_exporter, OtlpExporter};
+pub use self::pattern::{BlockingPattern, PatternCounts, PatternThresholds};
+pub use self::recent::PollSample;
+pub use self::report::{MarkdownOptions, SortBy, TableOptions};
+pub use self::saturation::SaturationThresholds;
+pub use self::scope::scope;
+pub use self::selector::CallsiteSelector;
+#[cfg(feature = "sentry")]
+pub use self::sentry::SentryBlockedObserver;
+pub use self::snapshot::{
+    CallsiteId, CallsiteStatsSnapshot, Category, GroupKey, GroupStats, OwnedCallsiteStats,
+    ParentTask, Regression, RegressionKind, Snapshot, TaskOps, Tolerances,
+};
+#[cfg(feature = "spawn")]
+pub use self::spawn::spawn;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteSink;
+#[cfg(feature = "statsd")]
+pub use self::statsd::{StatsdExporter, StatsdTags};
+#[cfg(feature = "tokio-metrics")]
+pub use self::task_metrics::CombinedTaskReport;
+#[cfg(feature = "serde")]
+pub use self::timeseries::TimeseriesWriter;
+#[cfg(feature = "tower")]
+pub use self::tower::{BlockedTimeLayer, BlockedTimeService, ResponseFuture};
+pub use self::zone::BlockZoneGuard;
 
-pub use self::layer::TokioBlockedLayer;
+#[doc(hidden)]
+pub mod __private {
+    pub use tracing;
+}
@@ -0,0 +1,95 @@
+use tracing::Level;
+
+use crate::{
+    breadcrumbs,
+    events::{BlockedPollInfo, BlockedTotalInfo},
+    BlockedObserver,
+};
+
+/// Emits blocked polls and spans as `log` records, for binaries that log with
+/// the `log` crate and have no tracing subscriber printing the warnings.
+///
+/// Records use the targets and levels of the [`TracingObserver`](crate::TracingObserver)
+/// events, with the durations, location and task name in the message, and
+/// as key-values for loggers that support them.
+///
+/// Register it with
+/// [`with_log_fallback`](crate::TokioBlockedLayer::with_log_fallback) to log
+/// in addition to the tracing events, or as the only observer with
+/// [`with_observers`](crate::TokioBlockedLayer::with_observers) to log
+/// instead of them. Don't combine it with a `tracing-log` bridge forwarding
+/// tracing events to `log`, which would log every warning twice.
+///
+/// Requires the `log` feature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogObserver;
+
+impl BlockedObserver for LogObserver {
+    fn on_poll_blocked(&self, info: &BlockedPollInfo<'_>) {
+        let (file, line, col) = info.location();
+        let (target, what) = if info.is_first_task_poll() {
+            (
+                "tokio_blocked::task_first_poll_blocked",
+                "on its first poll",
+            )
+        } else {
+            ("tokio_blocked::task_poll_blocked", "in a poll")
+        };
+        let task_name = info.owning_task_name();
+        let breadcrumbs = breadcrumbs::join(&info.breadcrumbs);
+        log::log!(
+            target: target,
+            level(info.level),
+            "poll_duration_ns" = info.poll_duration.as_nanos() as u64,
+            "threshold_ns" = info.threshold.as_nanos() as u64,
+            "callsite.name" = info.callsite_name,
+            "callsite.file" = file,
+            "callsite.line" = line,
+            "callsite.col" = col,
+            "task.name" = task_name,
+            "breadcrumbs" = breadcrumbs.as_deref();
+            "tokio task blocked {what} for {:?} (threshold {:?}) at {file}:{line}:{col}{}",
+            info.poll_duration,
+            info.threshold,
+            task_suffix(task_name),
+        );
+    }
+
+    fn on_span_closed(&self, info: &BlockedTotalInfo<'_>) {
+        let (file, line, col) = info.location();
+        let task_name = info.owning_task_name();
+        log::log!(
+            target: "tokio_blocked::task_blocked_total",
+            level(info.level),
+            "busy_ns" = info.busy.as_nanos() as u64,
+            "duration_ns" = info.lifetime.as_nanos() as u64,
+            "polls_total" = info.polls,
+            "polls_over_threshold" = info.polls_over_threshold,
+            "pattern" = info.pattern.map(|p| p.as_str()),
+            "callsite.name" = info.callsite_name,
+            "callsite.file" = file,
+            "callsite.line" = line,
+            "callsite.col" = col,
+            "task.name" = task_name;
+            "tokio task blocked for {:?} in total over {} polls ({} over threshold) at {file}:{line}:{col}{}",
+            info.busy,
+            info.polls,
+            info.polls_over_threshold,
+            task_suffix(task_name),
+        );
+    }
+}
+
+fn task_suffix(task_name: Option<&str>) -> String {
+    task_name.map_or_else(String::new, |name| format!(" in task {name}"))
+}
+
+fn level(level: Level) -> log::Level {
+    match level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
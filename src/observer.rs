@@ -0,0 +1,296 @@
+use std::time::Duration;
+
+use tracing::Level;
+
+use crate::{
+    breadcrumbs,
+    events::{BlockedPollInfo, BlockedTotalInfo, SustainedBlockingInfo},
+    snapshot::CallsiteStatsSnapshot,
+};
+
+/// Like [`tracing::event!`], but with a level that is only known at runtime.
+macro_rules! dyn_event {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => {
+        match $level {
+            Level::ERROR => tracing::event!(target: $target, Level::ERROR, $($arg)+),
+            Level::WARN => tracing::event!(target: $target, Level::WARN, $($arg)+),
+            Level::INFO => tracing::event!(target: $target, Level::INFO, $($arg)+),
+            Level::DEBUG => tracing::event!(target: $target, Level::DEBUG, $($arg)+),
+            Level::TRACE => tracing::event!(target: $target, Level::TRACE, $($arg)+),
+        }
+    };
+}
+
+/// Receives blocked polls, blocked spans and periodic snapshots from a
+/// [`TokioBlockedLayer`](crate::TokioBlockedLayer).
+///
+/// Register observers with
+/// [`with_observer`](crate::TokioBlockedLayer::with_observer). By default
+/// the layer only has a [`TracingObserver`], which emits the warning events.
+///
+/// Observers receive what would otherwise be emitted as warnings, i.e. after
+/// muting, deduplication and
+/// [`set_warnings_enabled`](crate::BlockedStatsHandle::set_warnings_enabled)
+/// have been applied, and only with [`ViolationMode::Warn`](crate::ViolationMode::Warn).
+/// The poll and span hooks run inline on the worker thread and must be fast.
+pub trait BlockedObserver: Send + Sync {
+    /// Called for a poll that exceeded the single-poll threshold.
+    fn on_poll_blocked(&self, info: &BlockedPollInfo<'_>) {
+        let _ = info;
+    }
+
+    /// Called for a closed span whose total busy time exceeded the total
+    /// threshold.
+    fn on_span_closed(&self, info: &BlockedTotalInfo<'_>) {
+        let _ = info;
+    }
+
+    /// Called with the current statistics by
+    /// [`BlockedStatsHandle::report`](crate::BlockedStatsHandle::report) and
+    /// the periodic reporter, see
+    /// [`with_report_interval`](crate::TokioBlockedLayer::with_report_interval).
+    fn on_snapshot(&self, callsites: &[CallsiteStatsSnapshot]) {
+        let _ = callsites;
+    }
+}
+
+/// The default observer: emits `tokio_blocked::task_poll_blocked` and
+/// `tokio_blocked::task_blocked_total` tracing events.
+///
+/// Blocked first polls of tasks are emitted as
+/// `tokio_blocked::task_first_poll_blocked` instead, with the same fields.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingObserver;
+
+impl BlockedObserver for TracingObserver {
+    fn on_poll_blocked(&self, info: &BlockedPollInfo<'_>) {
+        let (file, line, col) = info.location();
+        let rt = RuntimeMetricsContext::capture();
+        macro_rules! poll_blocked_event {
+            ($target:literal, $($message:tt)*) => {
+                dyn_event!(
+                    target: $target,
+                    info.level,
+                    seq = info.seq,
+                    poll_duration_ns = info.poll_duration.as_nanos() as u64,
+                    threshold_ns = info.threshold.as_nanos() as u64,
+                    effective_threshold_ns =
+                        info.load.map(|_| info.effective_threshold.as_nanos() as u64),
+                    load = info.load,
+                    poll_seq = info.poll_seq,
+                    exceed_count = info.exceed_count,
+                    span_busy_ns_so_far = info.span_busy.as_nanos() as u64,
+                    span_lifetime_ns_so_far = info.span_lifetime.as_nanos() as u64,
+                    span_busy_ratio = info.span_busy_ratio,
+                    cpu_ns = info.cpu.map(|d| d.as_nanos() as u64),
+                    on_cpu_percent = info.on_cpu_percent,
+                    tier_threshold_ns = info.tier_threshold.map(|t| t.as_nanos() as u64),
+                    escalated = info.escalated,
+                    callsite.name = info.callsite_name,
+                    callsite.target = info.callsite_target,
+                    callsite.file = file,
+                    callsite.line = line,
+                    callsite.col = col,
+                    resource.concrete_type = info.resource_concrete_type,
+                    resource.kind = info.resource_kind,
+                    resource.source = info.resource_source,
+                    parent_task.file = info.parent_task_file,
+                    parent_task.line = info.parent_task_line,
+                    parent_task.name = info.parent_task_name,
+                    task.file = info.parent_task_file,
+                    task.line = info.parent_task_line,
+                    task.name = info.owning_task_name(),
+                    task.id = info.task_id,
+                    span.id = info.span_id,
+                    span.parent_id = info.span_parent_id,
+                    runtime = info.runtime,
+                    runtime.num_workers = rt.num_workers,
+                    runtime.num_alive_tasks = rt.num_alive_tasks,
+                    runtime.global_queue_depth = rt.global_queue_depth,
+                    span_trace = info.span_trace.as_deref(),
+                    breadcrumbs = breadcrumbs::join(&info.breadcrumbs),
+                    $($message)*
+                )
+            };
+        }
+        if info.is_first_task_poll() {
+            poll_blocked_event!(
+                "tokio_blocked::task_first_poll_blocked",
+                "tokio task blocked on its first poll, move synchronous initialization \
+                 before spawning or into spawn_blocking",
+            );
+        } else {
+            poll_blocked_event!("tokio_blocked::task_poll_blocked",);
+        }
+    }
+
+    fn on_span_closed(&self, info: &BlockedTotalInfo<'_>) {
+        let (file, line, col) = info.location();
+        dyn_event!(
+            target: "tokio_blocked::task_blocked_total",
+            info.level,
+            seq = info.seq,
+            busy_ns = info.busy.as_nanos() as u64,
+            duration_ns = info.lifetime.as_nanos() as u64,
+            threshold_ns = info.threshold.map(|t| t.as_nanos() as u64),
+            tier_threshold_ns = info.tier_threshold.map(|t| t.as_nanos() as u64),
+            blocked_percent = info.blocked_percent,
+            max_poll_ns = info.max_poll.as_nanos() as u64,
+            max_poll_seq = info.max_poll_seq,
+            polls_over_threshold = info.polls_over_threshold,
+            polls_total = info.polls,
+            mean_poll_ns = info.mean_poll().map(|d| d.as_nanos() as u64),
+            pattern = info.pattern.map(|p| p.as_str()),
+            cpu_ns = info.cpu.map(|d| d.as_nanos() as u64),
+            on_cpu_percent = info.on_cpu_percent,
+            migrations = info.migrations,
+            span_still_open = info.span_still_open,
+            callsite.name = info.callsite_name,
+            callsite.target = info.callsite_target,
+            callsite.file = file,
+            callsite.line = line,
+            callsite.col = col,
+            resource.concrete_type = info.resource_concrete_type,
+            resource.kind = info.resource_kind,
+            resource.source = info.resource_source,
+            parent_task.file = info.parent_task_file,
+            parent_task.line = info.parent_task_line,
+            parent_task.name = info.parent_task_name,
+            task.file = info.parent_task_file,
+            task.line = info.parent_task_line,
+            task.name = info.owning_task_name(),
+            task.id = info.task_id,
+            span.id = info.span_id,
+            span.parent_id = info.span_parent_id,
+            runtime = info.runtime,
+            "tokio task blocked for too long",
+        );
+    }
+}
+
+/// Field names of the metric events enabled with
+/// [`with_metric_events`](crate::TokioBlockedLayer::with_metric_events).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetricNaming {
+    /// Prefixed with the instrument, e.g.
+    /// `histogram.tokio_blocked.poll_duration_ms`, which the
+    /// `tracing-opentelemetry` metrics layer turns into real metrics.
+    #[default]
+    OpenTelemetry,
+    /// Without the instrument prefix, e.g. `tokio_blocked.poll_duration_ms`,
+    /// for readable fmt output and collectors that key on the name alone.
+    Plain,
+}
+
+/// Emits a `tokio_blocked::metrics` event for a blocked poll.
+pub(crate) fn emit_poll_metrics(naming: MetricNaming, info: &BlockedPollInfo<'_>) {
+    let (file, line, _) = info.location();
+    let ms = info.poll_duration.as_secs_f64() * 1000.0;
+    let task_name = info.task_name.as_deref();
+    match naming {
+        MetricNaming::OpenTelemetry => tracing::event!(
+            target: "tokio_blocked::metrics",
+            Level::INFO,
+            histogram.tokio_blocked.poll_duration_ms = ms,
+            monotonic_counter.tokio_blocked.blocked_polls = 1u64,
+            callsite.file = file,
+            callsite.line = line,
+            callsite.name = info.callsite_name,
+            task.name = task_name,
+        ),
+        MetricNaming::Plain => tracing::event!(
+            target: "tokio_blocked::metrics",
+            Level::INFO,
+            tokio_blocked.poll_duration_ms = ms,
+            tokio_blocked.blocked_polls = 1u64,
+            callsite.file = file,
+            callsite.line = line,
+            callsite.name = info.callsite_name,
+            task.name = task_name,
+        ),
+    }
+}
+
+/// Emits a `tokio_blocked::metrics` event for a blocked span.
+pub(crate) fn emit_total_metrics(naming: MetricNaming, info: &BlockedTotalInfo<'_>) {
+    let (file, line, _) = info.location();
+    let ms = info.busy.as_secs_f64() * 1000.0;
+    let task_name = info.task_name.as_deref();
+    match naming {
+        MetricNaming::OpenTelemetry => tracing::event!(
+            target: "tokio_blocked::metrics",
+            Level::INFO,
+            histogram.tokio_blocked.blocked_total_ms = ms,
+            monotonic_counter.tokio_blocked.blocked_spans = 1u64,
+            callsite.file = file,
+            callsite.line = line,
+            callsite.name = info.callsite_name,
+            task.name = task_name,
+        ),
+        MetricNaming::Plain => tracing::event!(
+            target: "tokio_blocked::metrics",
+            Level::INFO,
+            tokio_blocked.blocked_total_ms = ms,
+            tokio_blocked.blocked_spans = 1u64,
+            callsite.file = file,
+            callsite.line = line,
+            callsite.name = info.callsite_name,
+            task.name = task_name,
+        ),
+    }
+}
+
+/// Emits a `tokio_blocked::metrics` event with the blocked fraction of the
+/// last `window`.
+pub(crate) fn emit_fraction_metrics(naming: MetricNaming, fraction: f64, window: Duration) {
+    let window_ms = window.as_secs_f64() * 1000.0;
+    match naming {
+        MetricNaming::OpenTelemetry => tracing::event!(
+            target: "tokio_blocked::metrics",
+            Level::INFO,
+            gauge.tokio_blocked.blocked_fraction = fraction,
+            window_ms,
+        ),
+        MetricNaming::Plain => tracing::event!(
+            target: "tokio_blocked::metrics",
+            Level::INFO,
+            tokio_blocked.blocked_fraction = fraction,
+            window_ms,
+        ),
+    }
+}
+
+pub(crate) type OnBlocked = dyn Fn(&BlockedPollInfo<'_>) + Send + Sync;
+pub(crate) type OnBlockedTotal = dyn Fn(&BlockedTotalInfo<'_>) + Send + Sync;
+pub(crate) type OnSustained = dyn Fn(&SustainedBlockingInfo<'_>) + Send + Sync;
+
+/// Runtime metrics attached to `task_poll_blocked` events.
+///
+/// All fields are `None` (and thus omitted from events) without the
+/// `runtime-metrics` feature, or when there is no current tokio runtime.
+#[derive(Default)]
+struct RuntimeMetricsContext {
+    num_workers: Option<u64>,
+    num_alive_tasks: Option<u64>,
+    global_queue_depth: Option<u64>,
+}
+
+impl RuntimeMetricsContext {
+    #[cfg(feature = "runtime-metrics")]
+    fn capture() -> Self {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return Self::default();
+        };
+        let metrics = handle.metrics();
+        Self {
+            num_workers: Some(metrics.num_workers() as u64),
+            num_alive_tasks: Some(metrics.num_alive_tasks() as u64),
+            global_queue_depth: Some(metrics.global_queue_depth() as u64),
+        }
+    }
+
+    #[cfg(not(feature = "runtime-metrics"))]
+    fn capture() -> Self {
+        Self::default()
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    io::{self, BufRead as _, BufReader, Write as _},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    snapshot::{OwnedCallsiteStats, Snapshot},
+    time::SystemTime,
+    BlockedStatsHandle,
+};
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE`
+const CUMULATIVE: u64 = 2;
+
+/// Sends the callsite statistics to an OpenTelemetry collector as OTLP
+/// metrics, over HTTP with protobuf encoding.
+///
+/// Every export sends the cumulative statistics since the layer was created
+/// or last [reset](BlockedStatsHandle::reset), including spans that are
+/// still open with
+/// [`with_live_snapshots`](crate::TokioBlockedLayer::with_live_snapshots):
+///
+/// - `tokio_blocked.busy`, a sum of the busy seconds.
+/// - `tokio_blocked.polls` and `tokio_blocked.exceeded`, sums of the polls
+///   and the polls that exceeded the single-poll threshold.
+/// - `tokio_blocked.max_poll`, a gauge of the longest poll in seconds.
+/// - `tokio_blocked.poll_duration`, a histogram of the poll durations in
+///   seconds, for callsites with a
+///   [poll histogram](crate::TokioBlockedLayer::with_poll_histogram).
+/// - `tokio_blocked.polls_over_threshold`, a sum of the polls that took at
+///   least each [SLO threshold](crate::TokioBlockedLayer::with_slo_thresholds),
+///   in seconds as the `threshold` attribute.
+///
+/// Data points carry the `callsite.file`, `callsite.line`, `callsite.name`
+/// and `callsite.target` attributes, plus `task.name`, `resource`,
+/// `parent_task` and `runtime` where set.
+///
+/// Only plain `http` endpoints are supported, e.g. a collector sidecar.
+/// Exports that fail with a connection error or a `429`, `502`, `503` or
+/// `504` response are retried with exponential backoff, see
+/// [`with_retry`](Self::with_retry). Exports that still fail are counted in
+/// [`failed`](Self::failed).
+///
+/// Requires the `otlp` feature.
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use tokio_blocked::{OtlpExporter, TokioBlockedLayer};
+///
+/// let layer = TokioBlockedLayer::new().with_poll_histogram(true);
+/// let exporter = OtlpExporter::new("http://localhost:4318")?
+///     .with_service_name("my-service")
+///     .spawn(Duration::from_secs(30), &layer.handle());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct OtlpExporter {
+    // `host:port`, also sent as the `Host` header.
+    authority: String,
+    path: String,
+    resource: Vec<(String, String)>,
+    attempts: u32,
+    backoff: Duration,
+    timeout: Duration,
+    failed: AtomicU64,
+}
+
+impl OtlpExporter {
+    /// Exports to the collector at `endpoint`, e.g. `http://localhost:4318`.
+    ///
+    /// Metrics are posted to `/v1/metrics` unless `endpoint` has a path of
+    /// its own.
+    pub fn new(endpoint: &str) -> io::Result<Self> {
+        let rest = endpoint.strip_prefix("http://").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only http:// OTLP endpoints are supported",
+            )
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) if i + 1 < rest.len() => (&rest[..i], &rest[i..]),
+            Some(i) => (&rest[..i], "/v1/metrics"),
+            None => (rest, "/v1/metrics"),
+        };
+        if authority.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "OTLP endpoint without host",
+            ));
+        }
+        let authority = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{authority}:80")
+        };
+        Ok(Self {
+            authority,
+            path: path.to_string(),
+            resource: vec![("service.name".to_string(), "unknown_service".to_string())],
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    /// Sets the `service.name` resource attribute, `unknown_service` by
+    /// default.
+    pub fn with_service_name(self, name: impl Into<String>) -> Self {
+        self.with_resource_attribute("service.name", name)
+    }
+
+    /// Sets a resource attribute of the exported metrics, e.g.
+    /// `deployment.environment`.
+    pub fn with_resource_attribute(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        let (key, value) = (key.into(), value.into());
+        match self.resource.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.resource.push((key, value)),
+        }
+        self
+    }
+
+    /// Tries every export up to `attempts` times, waiting `backoff` before
+    /// the first retry and twice as long before every further one. 3 attempts
+    /// with 1s of backoff by default.
+    pub fn with_retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    /// Timeout for connecting to the collector and for each read and write,
+    /// 10s by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        if !timeout.is_zero() {
+            self.timeout = timeout;
+        }
+        self
+    }
+
+    /// Number of exports that failed after all attempts.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Exports the current statistics of `handle` once, retrying as
+    /// configured.
+    pub fn export(&self, handle: &BlockedStatsHandle) -> io::Result<()> {
+        let summary = handle.summary();
+        let now = SystemTime::now();
+        let start = now
+            .checked_sub(summary.until.saturating_duration_since(summary.since))
+            .unwrap_or(now);
+        let snapshot = Snapshot::from(handle.snapshot_with_live(true));
+        let body = self.encode(&snapshot.callsites, start, now);
+        let result = self.send(&body);
+        if result.is_err() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Exports the statistics of `handle` every `interval` on a background
+    /// thread, until the layer and all its handles are dropped. Intervals
+    /// below 1ms are raised to 1ms.
+    pub fn spawn(self, interval: Duration, handle: &BlockedStatsHandle) -> Arc<Self> {
+        let interval = interval.max(Duration::from_millis(1));
+        let exporter = Arc::new(self);
+        let weak = handle.downgrade();
+        let thread_exporter = exporter.clone();
+        std::thread::Builder::new()
+            .name("tokio-blocked-otlp".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                let Some(handle) = weak.upgrade() else {
+                    return;
+                };
+                // Failures are counted, and the next export sends everything again.
+                let _ = thread_exporter.export(&handle);
+            })
+            .expect("failed to spawn OTLP exporter thread");
+        exporter
+    }
+
+    fn send(&self, body: &[u8]) -> io::Result<()> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            let error = match self.post(body) {
+                Ok(status) if (200..300).contains(&status) => return Ok(()),
+                Ok(status @ (429 | 502 | 503 | 504)) => {
+                    io::Error::other(format!("OTLP collector responded with {status}"))
+                }
+                Ok(status) => {
+                    return Err(io::Error::other(format!(
+                        "OTLP collector responded with {status}"
+                    )))
+                }
+                Err(error) => error,
+            };
+            if attempt >= self.attempts {
+                return Err(error);
+            }
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Posts `body` and returns the response status.
+    fn post(&self, body: &[u8]) -> io::Result<u16> {
+        let addr = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-protobuf\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len(),
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))
+    }
+
+    /// Encodes an `ExportMetricsServiceRequest`.
+    fn encode(&self, rows: &[OwnedCallsiteStats], start: SystemTime, now: SystemTime) -> Vec<u8> {
+        let times = (unix_nanos(start), unix_nanos(now));
+        let seconds = |d: Duration| NumberValue::Double(d.as_secs_f64());
+
+        let mut request = Proto::default();
+        request.message(1, |p| {
+            p.message(1, |p| {
+                for (key, value) in &self.resource {
+                    p.message(1, |p| key_value(p, key, AnyValue::String(value)));
+                }
+            });
+            p.message(2, |p| {
+                p.message(1, |p| {
+                    p.string(1, "tokio-blocked");
+                    p.string(2, env!("CARGO_PKG_VERSION"));
+                });
+                sum(
+                    p,
+                    ("tokio_blocked.busy", "s"),
+                    "Time spent polling the spans of the callsite.",
+                    rows,
+                    times,
+                    |row| seconds(row.total_busy),
+                );
+                sum(
+                    p,
+                    ("tokio_blocked.polls", "{poll}"),
+                    "Number of polls of the spans of the callsite.",
+                    rows,
+                    times,
+                    |row| NumberValue::Int(row.polls),
+                );
+                sum(
+                    p,
+                    ("tokio_blocked.exceeded", "{poll}"),
+                    "Number of polls that exceeded the single-poll threshold.",
+                    rows,
+                    times,
+                    |row| NumberValue::Int(row.exceed_count),
+                );
+                p.message(2, |p| {
+                    p.string(1, "tokio_blocked.max_poll");
+                    p.string(2, "Longest poll of the spans of the callsite.");
+                    p.string(3, "s");
+                    p.message(5, |p| {
+                        number_points(p, rows, times, |row| seconds(row.max_poll))
+                    });
+                });
+                if rows.iter().any(|row| !row.over_threshold.is_empty()) {
+                    p.message(2, |p| {
+                        p.string(1, "tokio_blocked.polls_over_threshold");
+                        p.string(2, "Number of polls that took at least the SLO threshold.");
+                        p.string(3, "{poll}");
+                        p.message(7, |p| {
+                            over_threshold_points(p, rows, times);
+                            p.varint(2, CUMULATIVE);
+                            p.varint(3, 1);
+                        });
+                    });
+                }
+                if rows.iter().any(|row| row.poll_histogram.is_some()) {
+                    p.message(2, |p| {
+                        p.string(1, "tokio_blocked.poll_duration");
+                        p.string(2, "Poll durations of the callsite.");
+                        p.string(3, "s");
+                        p.message(9, |p| {
+                            histogram_points(p, rows, times);
+                            p.varint(2, CUMULATIVE);
+                        });
+                    });
+                }
+            });
+        });
+        request.0
+    }
+}
+
+/// Exports the statistics of `handle` to the OTLP collector at `endpoint`
+/// every `interval`, with the default settings of [`OtlpExporter`].
+///
+/// Requires the `otlp` feature.
+pub fn spawn_otlp_exporter(
+    endpoint: &str,
+    interval: Duration,
+    handle: &BlockedStatsHandle,
+) -> io::Result<Arc<OtlpExporter>> {
+    Ok(OtlpExporter::new(endpoint)?.spawn(interval, handle))
+}
+
+/// Encodes a cumulative, monotonic `Sum` metric.
+fn sum(
+    p: &mut Proto,
+    (name, unit): (&str, &str),
+    description: &str,
+    rows: &[OwnedCallsiteStats],
+    times: (u64, u64),
+    value: impl Fn(&OwnedCallsiteStats) -> NumberValue,
+) {
+    p.message(2, |p| {
+        p.string(1, name);
+        p.string(2, description);
+        p.string(3, unit);
+        p.message(7, |p| {
+            number_points(p, rows, times, value);
+            p.varint(2, CUMULATIVE);
+            p.varint(3, 1);
+        });
+    });
+}
+
+/// Encodes a `NumberDataPoint` per callsite.
+fn number_points(
+    p: &mut Proto,
+    rows: &[OwnedCallsiteStats],
+    (start, now): (u64, u64),
+    value: impl Fn(&OwnedCallsiteStats) -> NumberValue,
+) {
+    for row in rows {
+        p.message(1, |p| {
+            p.fixed64(2, start);
+            p.fixed64(3, now);
+            match value(row) {
+                NumberValue::Double(v) => p.double(4, v),
+                NumberValue::Int(v) => p.fixed64(6, v),
+            }
+            attributes(p, 7, row);
+        });
+    }
+}
+
+/// Encodes a `NumberDataPoint` per callsite and SLO threshold, with the
+/// threshold in seconds as the `threshold` attribute.
+fn over_threshold_points(p: &mut Proto, rows: &[OwnedCallsiteStats], (start, now): (u64, u64)) {
+    for row in rows {
+        for &(threshold, polls) in &row.over_threshold {
+            p.message(1, |p| {
+                p.fixed64(2, start);
+                p.fixed64(3, now);
+                p.fixed64(6, polls);
+                attributes(p, 7, row);
+                p.message(7, |p| {
+                    key_value(p, "threshold", AnyValue::Double(threshold.as_secs_f64()))
+                });
+            });
+        }
+    }
+}
+
+/// Encodes a `HistogramDataPoint` per callsite with a poll histogram.
+fn histogram_points(p: &mut Proto, rows: &[OwnedCallsiteStats], (start, now): (u64, u64)) {
+    for row in rows {
+        let Some(histogram) = &row.poll_histogram else {
+            continue;
+        };
+        p.message(1, |p| {
+            p.fixed64(2, start);
+            p.fixed64(3, now);
+            p.fixed64(4, histogram.count());
+            p.double(5, row.total_busy.as_secs_f64());
+            p.packed_fixed64(6, histogram.counts().iter().copied());
+            p.packed_double(7, histogram.bounds().iter().map(Duration::as_secs_f64));
+            attributes(p, 9, row);
+        });
+    }
+}
+
+enum NumberValue {
+    Double(f64),
+    Int(u64),
+}
+
+enum AnyValue<'a> {
+    String(&'a str),
+    Int(u64),
+    Double(f64),
+}
+
+/// The attributes of a callsite's data points, as field `field`.
+fn attributes(p: &mut Proto, field: u32, row: &OwnedCallsiteStats) {
+    let id = &row.id;
+    let mut attribute = |key: &str, value: AnyValue<'_>| {
+        p.message(field, |p| key_value(p, key, value));
+    };
+    if let Some(file) = &id.file {
+        attribute("callsite.file", AnyValue::String(file));
+    }
+    if let Some(line) = id.line {
+        attribute("callsite.line", AnyValue::Int(line.into()));
+    }
+    attribute("callsite.name", AnyValue::String(&id.name));
+    attribute("callsite.target", AnyValue::String(&id.target));
+    if let Some(task) = &id.task_name {
+        attribute("task.name", AnyValue::String(task));
+    }
+    if let Some(resource) = id.async_op_source.as_ref().or(id.resource_type.as_ref()) {
+        attribute("resource", AnyValue::String(resource));
+    }
+    if let Some(file) = &id.parent_task_file {
+        let parent = format!("{file}:{}", id.parent_task_line.unwrap_or(0));
+        attribute("parent_task", AnyValue::String(&parent));
+    }
+    if let Some(runtime) = &id.runtime {
+        attribute("runtime", AnyValue::String(runtime));
+    }
+}
+
+/// Encodes a `KeyValue`.
+fn key_value(p: &mut Proto, key: &str, value: AnyValue<'_>) {
+    p.string(1, key);
+    p.message(2, |p| match value {
+        AnyValue::String(s) => p.string(1, s),
+        AnyValue::Int(i) => p.varint(3, i),
+        AnyValue::Double(d) => p.double(4, d),
+    });
+}
+
+fn unix_nanos(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// A minimal protobuf encoder, covering the wire types of the OTLP messages.
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.raw_varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn varint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.raw_varint(value);
+    }
+
+    fn fixed64(&mut self, field: u32, value: u64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn double(&mut self, field: u32, value: f64) {
+        self.fixed64(field, value.to_bits());
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, 2);
+        self.raw_varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, encode: impl FnOnce(&mut Proto)) {
+        let mut inner = Proto::default();
+        encode(&mut inner);
+        self.bytes(field, &inner.0);
+    }
+
+    fn packed_fixed64(&mut self, field: u32, values: impl Iterator<Item = u64>) {
+        let bytes: Vec<u8> = values.flat_map(u64::to_le_bytes).collect();
+        self.bytes(field, &bytes);
+    }
+
+    fn packed_double(&mut self, field: u32, values: impl Iterator<Item = f64>) {
+        self.packed_fixed64(field, values.map(f64::to_bits));
+    }
+}
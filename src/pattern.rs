@@ -0,0 +1,201 @@
+use std::{fmt, time::Duration};
+
+/// How the busy time of a blocked span is spread over its polls, reported as
+/// the `pattern` field of `tokio_blocked::task_blocked_total`.
+///
+/// The pattern hints at the cause: a single long poll is usually a
+/// synchronous I/O call or lock, many moderate polls CPU work in a loop that
+/// yields regularly but does too much in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[non_exhaustive]
+pub enum BlockingPattern {
+    /// One poll took most of the busy time and was long by itself.
+    SingleLongPoll,
+    /// The mean poll is slow, without a single poll dominating.
+    UniformlySlow,
+    /// The busy time adds up from many polls that are fast on average.
+    ManyModeratePolls,
+    /// None of the above, e.g. a few polls of mixed durations.
+    Mixed,
+}
+
+impl BlockingPattern {
+    /// The `snake_case` label, e.g. `single_long_poll`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SingleLongPoll => "single_long_poll",
+            Self::UniformlySlow => "uniformly_slow",
+            Self::ManyModeratePolls => "many_moderate_polls",
+            Self::Mixed => "mixed",
+        }
+    }
+}
+
+impl fmt::Display for BlockingPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Thresholds of the [`BlockingPattern`] classification, see
+/// [`with_blocking_patterns`](crate::TokioBlockedLayer::with_blocking_patterns).
+///
+/// Unset thresholds are derived from the warning thresholds of the layer,
+/// the single-poll threshold `P` and the total threshold `T`:
+///
+/// - [`SingleLongPoll`](BlockingPattern::SingleLongPoll): the longest poll
+///   is at least [`long_poll`](Self::long_poll) (`P`, or `T` without one) and
+///   at least [`dominant_share`](Self::dominant_share) of the busy time.
+/// - [`UniformlySlow`](BlockingPattern::UniformlySlow): otherwise, the mean
+///   poll is at least [`slow_mean`](Self::slow_mean) (the long poll
+///   threshold).
+/// - [`ManyModeratePolls`](BlockingPattern::ManyModeratePolls): otherwise,
+///   the span had at least [`many_polls`](Self::many_polls) polls (`T`
+///   divided by the slow mean threshold, at least 2).
+/// - [`Mixed`](BlockingPattern::Mixed) for the rest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatternThresholds {
+    long_poll: Option<Duration>,
+    slow_mean: Option<Duration>,
+    many_polls: Option<u64>,
+    dominant_share: f64,
+}
+
+impl Default for PatternThresholds {
+    fn default() -> Self {
+        Self {
+            long_poll: None,
+            slow_mean: None,
+            many_polls: None,
+            dominant_share: 0.5,
+        }
+    }
+}
+
+impl PatternThresholds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Minimum duration of the poll of a single long poll span.
+    pub fn long_poll(mut self, duration: Duration) -> Self {
+        self.long_poll = Some(duration);
+        self
+    }
+
+    /// Minimum mean poll of a uniformly slow span.
+    pub fn slow_mean(mut self, duration: Duration) -> Self {
+        self.slow_mean = Some(duration);
+        self
+    }
+
+    /// Minimum number of polls of a span with many moderate polls.
+    pub fn many_polls(mut self, polls: u64) -> Self {
+        self.many_polls = Some(polls);
+        self
+    }
+
+    /// Minimum share of the busy time, between `0.0` and `1.0`, the longest
+    /// poll of a single long poll span takes. Defaults to `0.5`.
+    pub fn dominant_share(mut self, share: f64) -> Self {
+        self.dominant_share = share.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Classifies a span from its busy time, polls and longest poll, or
+    /// returns `None` if it was never polled or there is no threshold to
+    /// derive the classification from.
+    pub(crate) fn classify(
+        &self,
+        busy: Duration,
+        polls: u64,
+        max_poll: Duration,
+        poll_threshold: Option<Duration>,
+        total_threshold: Option<Duration>,
+    ) -> Option<BlockingPattern> {
+        if polls == 0 || busy.is_zero() {
+            return None;
+        }
+        let long_poll = self.long_poll.or(poll_threshold).or(total_threshold)?;
+        let slow_mean = self.slow_mean.unwrap_or(long_poll);
+        let many_polls = self.many_polls.unwrap_or_else(|| {
+            total_threshold
+                .filter(|_| !slow_mean.is_zero())
+                .map_or(2, |total| (total.as_nanos() / slow_mean.as_nanos()) as u64)
+                .max(2)
+        });
+        let share = max_poll.as_secs_f64() / busy.as_secs_f64();
+        let mean = busy / polls.min(u32::MAX as u64) as u32;
+        Some(if max_poll >= long_poll && share >= self.dominant_share {
+            BlockingPattern::SingleLongPoll
+        } else if mean >= slow_mean {
+            BlockingPattern::UniformlySlow
+        } else if polls >= many_polls {
+            BlockingPattern::ManyModeratePolls
+        } else {
+            BlockingPattern::Mixed
+        })
+    }
+}
+
+/// Number of blocked spans of a callsite per [`BlockingPattern`].
+///
+/// Spans count as blocked if they reached the total threshold or had a poll
+/// over the single-poll threshold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PatternCounts {
+    pub single_long_poll: u64,
+    pub uniformly_slow: u64,
+    pub many_moderate_polls: u64,
+    pub mixed: u64,
+}
+
+impl PatternCounts {
+    /// Spans classified as `pattern`.
+    pub fn get(&self, pattern: BlockingPattern) -> u64 {
+        match pattern {
+            BlockingPattern::SingleLongPoll => self.single_long_poll,
+            BlockingPattern::UniformlySlow => self.uniformly_slow,
+            BlockingPattern::ManyModeratePolls => self.many_moderate_polls,
+            BlockingPattern::Mixed => self.mixed,
+        }
+    }
+
+    /// Spans of all patterns.
+    pub fn total(&self) -> u64 {
+        self.single_long_poll + self.uniformly_slow + self.many_moderate_polls + self.mixed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+
+    /// The most frequent pattern, or `None` without classified spans.
+    pub fn dominant(&self) -> Option<BlockingPattern> {
+        [
+            BlockingPattern::SingleLongPoll,
+            BlockingPattern::UniformlySlow,
+            BlockingPattern::ManyModeratePolls,
+            BlockingPattern::Mixed,
+        ]
+        .into_iter()
+        .filter(|&p| self.get(p) > 0)
+        .max_by_key(|&p| self.get(p))
+    }
+
+    pub(crate) fn add(&mut self, pattern: BlockingPattern) {
+        let count = match pattern {
+            BlockingPattern::SingleLongPoll => &mut self.single_long_poll,
+            BlockingPattern::UniformlySlow => &mut self.uniformly_slow,
+            BlockingPattern::ManyModeratePolls => &mut self.many_moderate_polls,
+            BlockingPattern::Mixed => &mut self.mixed,
+        };
+        *count += 1;
+    }
+}
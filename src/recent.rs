@@ -0,0 +1,79 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use crate::{events::HistoricalWarning, time::Instant};
+
+/// The newest warnings, oldest first, see
+/// [`with_warning_history`](crate::TokioBlockedLayer::with_warning_history).
+#[derive(Debug, Default)]
+pub(crate) struct WarningHistory {
+    pub(crate) capacity: usize,
+    warnings: VecDeque<HistoricalWarning>,
+}
+
+impl WarningHistory {
+    pub(crate) fn push(&mut self, warning: HistoricalWarning) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.warnings.len() >= self.capacity {
+            self.warnings.pop_front();
+        }
+        self.warnings.push_back(warning);
+    }
+
+    /// Newest first.
+    pub(crate) fn warnings(&self) -> Vec<HistoricalWarning> {
+        self.warnings.iter().rev().cloned().collect()
+    }
+}
+
+/// A poll kept by
+/// [`with_recent_polls`](crate::TokioBlockedLayer::with_recent_polls).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollSample {
+    /// When the poll started, according to the layer's [`Clock`](crate::Clock).
+    pub start: Instant,
+    pub duration: Duration,
+}
+
+/// The newest polls of a callsite, oldest first. Older samples are dropped
+/// once `capacity` is reached.
+#[derive(Debug)]
+pub(crate) struct RecentPolls {
+    capacity: usize,
+    samples: Mutex<VecDeque<PollSample>>,
+}
+
+impl RecentPolls {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(crate) fn push(&self, sample: PollSample) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    pub(crate) fn samples(&self) -> Vec<PollSample> {
+        self.samples.lock().unwrap().iter().copied().collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.samples.lock().unwrap().len()
+    }
+
+    /// Heap memory of the ring, allocated up front.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.samples.lock().unwrap().capacity() * std::mem::size_of::<PollSample>()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.samples.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,617 @@
+use std::{collections::HashSet, fmt::Write as _, str::FromStr, time::Duration};
+
+use crate::{
+    snapshot::{
+        CallsiteId, GroupKey, GroupStats, OwnedCallsiteStats, RegressionKind, Snapshot, Tolerances,
+        Totals,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+    WorstPoll,
+};
+
+/// Options for [`Snapshot::render_markdown`].
+#[derive(Debug, Clone, Default)]
+pub struct MarkdownOptions {
+    /// Single-poll threshold shown in the header.
+    pub warn_busy_single_poll: Option<Duration>,
+    /// Total busy time threshold shown in the header.
+    pub warn_busy_total: Option<Duration>,
+    /// Capture time shown in the header.
+    pub captured_at: Option<SystemTime>,
+    /// Longest task poll shown in the header.
+    pub worst_poll: Option<WorstPoll>,
+    /// Adds a section with the poll duration
+    /// [percentiles](crate::CallsiteStatsSnapshot::percentiles) of all
+    /// callsites that have them.
+    pub details: bool,
+    /// Adds a section with the totals per [group](Snapshot::grouped).
+    pub summary: Option<GroupKey>,
+}
+
+/// Options for [`Snapshot::render_table`] and [`Snapshot::render_diff`].
+#[derive(Debug, Clone, Default)]
+pub struct TableOptions {
+    /// Only shows the first `top` rows after sorting.
+    pub top: Option<usize>,
+    pub sort: SortBy,
+    /// Only shows callsites whose location, name or target contain this
+    /// string.
+    pub filter: Option<String>,
+    /// Adds a second table with the totals per [group](Snapshot::grouped) of
+    /// the filtered callsites, before `top` is applied.
+    pub summary: Option<GroupKey>,
+}
+
+/// Column a table is sorted by, largest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortBy {
+    #[default]
+    TotalBusy,
+    MaxPoll,
+    Polls,
+    Spans,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    /// Parses `total_busy`, `max_poll`, `polls` or `spans`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "total_busy" => Ok(Self::TotalBusy),
+            "max_poll" => Ok(Self::MaxPoll),
+            "polls" => Ok(Self::Polls),
+            "spans" => Ok(Self::Spans),
+            _ => Err(format!(
+                "unknown sort key `{s}`, expected total_busy, max_poll, polls or spans"
+            )),
+        }
+    }
+}
+
+impl SortBy {
+    fn key(self, total_busy: Duration, max_poll: Duration, polls: u64, spans: u64) -> u128 {
+        match self {
+            Self::TotalBusy => total_busy.as_nanos(),
+            Self::MaxPoll => max_poll.as_nanos(),
+            Self::Polls => polls.into(),
+            Self::Spans => spans.into(),
+        }
+    }
+}
+
+impl TableOptions {
+    fn matches(&self, id: &CallsiteId) -> bool {
+        self.filter.as_deref().is_none_or(|filter| {
+            [plain_location(id), name(id), id.target.clone()]
+                .iter()
+                .any(|s| s.contains(filter))
+        })
+    }
+}
+
+impl Snapshot {
+    /// Renders the callsites as a plain text table for terminals, sorted and
+    /// filtered according to `opts`.
+    pub fn render_table(&self, opts: &TableOptions) -> String {
+        let mut rows: Vec<&OwnedCallsiteStats> = self
+            .callsites
+            .iter()
+            .filter(|row| opts.matches(&row.id))
+            .collect();
+        rows.sort_by(|a, b| {
+            let key =
+                |r: &OwnedCallsiteStats| opts.sort.key(r.total_busy, r.max_poll, r.polls, r.count);
+            key(b).cmp(&key(a)).then(a.id.cmp(&b.id))
+        });
+        let groups = opts
+            .summary
+            .map(|key| (key, GroupStats::group(rows.iter().copied(), key)));
+        rows.truncate(opts.top.unwrap_or(usize::MAX));
+
+        // A column per quantile of any row.
+        let mut quantiles: Vec<f64> = rows
+            .iter()
+            .flat_map(|row| row.percentiles.iter().map(|(q, _)| *q))
+            .collect();
+        quantiles.sort_by(f64::total_cmp);
+        quantiles.dedup();
+
+        let rows = rows
+            .iter()
+            .map(|row| {
+                let mut cells = vec![
+                    plain_location(&row.id),
+                    name(&row.id),
+                    row.count.to_string(),
+                    row.polls.to_string(),
+                    human(row.total_busy),
+                    human(row.max_poll),
+                ];
+                cells.extend(quantiles.iter().map(|q| {
+                    let value = row.percentiles.iter().find(|(p, _)| p == q);
+                    human_or_dash(value.map(|(_, v)| *v))
+                }));
+                cells
+            })
+            .collect();
+        let mut header: Vec<String> = [
+            "LOCATION",
+            "NAME",
+            "SPANS",
+            "POLLS",
+            "TOTAL BUSY",
+            "MAX POLL",
+        ]
+        .map(String::from)
+        .to_vec();
+        header.extend(quantiles.iter().map(|q| quantile_label(*q).to_uppercase()));
+        let mut out = text_table(&header.iter().map(String::as_str).collect::<Vec<_>>(), rows);
+
+        if let Some((key, groups)) = groups {
+            let rows = groups
+                .iter()
+                .map(|g| {
+                    vec![
+                        g.key.clone(),
+                        format!("{} {}", plain_location(&g.top), name(&g.top)),
+                        g.spans.to_string(),
+                        g.polls.to_string(),
+                        human(g.total_busy),
+                        human(g.max_poll),
+                    ]
+                })
+                .collect();
+            let group = key.label().to_uppercase();
+            out.push('\n');
+            out.push_str(&text_table(
+                &[
+                    &group,
+                    "TOP OFFENDER",
+                    "SPANS",
+                    "POLLS",
+                    "TOTAL BUSY",
+                    "MAX POLL",
+                ],
+                rows,
+            ));
+        }
+        out
+    }
+
+    /// Renders how the callsites changed compared to `baseline` as a plain
+    /// text table, sorted by the growth of the `opts.sort` column.
+    ///
+    /// Rows are matched by [`CallsiteId`] like in [`compare`](Self::compare),
+    /// whose regressions beyond `tolerances` are marked as `regressed`.
+    pub fn render_diff(
+        &self,
+        baseline: &Snapshot,
+        tolerances: Tolerances,
+        opts: &TableOptions,
+    ) -> String {
+        let mut regressed = HashSet::new();
+        for regression in self.compare(baseline, tolerances) {
+            if !matches!(regression.kind, RegressionKind::NewCallsite { .. }) {
+                regressed.insert(regression.id);
+            }
+        }
+        let before = baseline.by_id();
+        let after = self.by_id();
+        let mut ids: Vec<&CallsiteId> = before
+            .keys()
+            .chain(after.keys().filter(|id| !before.contains_key(*id)))
+            .filter(|id| opts.matches(id))
+            .collect();
+        let growth = |id: &CallsiteId| {
+            let key = |totals: Option<&Totals>| {
+                totals.map_or(0, |t| {
+                    opts.sort.key(t.total_busy, t.max_poll, t.polls, t.count) as i128
+                })
+            };
+            key(after.get(id)) - key(before.get(id))
+        };
+        ids.sort_by(|a, b| growth(b).cmp(&growth(a)).then(a.cmp(b)));
+        ids.truncate(opts.top.unwrap_or(usize::MAX));
+
+        let rows = ids
+            .into_iter()
+            .map(|id| {
+                let (old, new) = (before.get(id), after.get(id));
+                let busy = |t: Option<&Totals>| t.map(|t| t.total_busy);
+                let max = |t: Option<&Totals>| t.map(|t| t.max_poll);
+                let status = match (old, new) {
+                    (None, _) => "new",
+                    (_, None) => "removed",
+                    _ if regressed.contains(id) => "regressed",
+                    _ => "",
+                };
+                vec![
+                    plain_location(id),
+                    name(id),
+                    human_or_dash(busy(old)),
+                    human_or_dash(busy(new)),
+                    delta(busy(old), busy(new)),
+                    human_or_dash(max(old)),
+                    human_or_dash(max(new)),
+                    status.to_string(),
+                ]
+            })
+            .collect();
+        text_table(
+            &[
+                "LOCATION",
+                "NAME",
+                "BUSY BEFORE",
+                "BUSY AFTER",
+                "BUSY DELTA",
+                "MAX BEFORE",
+                "MAX AFTER",
+                "STATUS",
+            ],
+            rows,
+        )
+    }
+
+    /// Renders the callsites as a Markdown table, sorted by total busy time,
+    /// e.g. for pasting into an issue.
+    pub fn render_markdown(&self, opts: &MarkdownOptions) -> String {
+        let mut rows: Vec<&OwnedCallsiteStats> = self.callsites.iter().collect();
+        rows.sort_by(|a, b| b.total_busy.cmp(&a.total_busy).then(a.id.cmp(&b.id)));
+
+        let mut out = String::new();
+        out.push_str("**Blocking report**");
+        if let Some(at) = opts.captured_at {
+            let _ = write!(out, " captured at {}", Utc(at));
+        }
+        let _ = write!(
+            out,
+            " · single poll threshold: {} · total threshold: {}",
+            human_opt(opts.warn_busy_single_poll),
+            human_opt(opts.warn_busy_total),
+        );
+        if let Some(worst) = &opts.worst_poll {
+            let _ = write!(
+                out,
+                " · worst poll: {} at `{}:{}:{}`",
+                human(worst.duration),
+                worst.file,
+                worst.line,
+                worst.col,
+            );
+        }
+        out.push('\n');
+        out.push('\n');
+
+        out.push_str("| Location | Name | Spans | Polls | Total busy | Max poll |\n");
+        out.push_str("| --- | --- | ---: | ---: | ---: | ---: |\n");
+        for row in &rows {
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} | {} | {} | {} |",
+                location(&row.id),
+                escape(&name(&row.id)),
+                row.count,
+                row.polls,
+                human(row.total_busy),
+                human(row.max_poll),
+            );
+        }
+
+        if let Some(key) = opts.summary {
+            let groups = GroupStats::group(rows.iter().copied(), key);
+            let label = key.label();
+            let _ = writeln!(out, "\n### By {label}\n");
+            let mut title = label.to_string();
+            title[..1].make_ascii_uppercase();
+            let _ = writeln!(
+                out,
+                "| {title} | Top offender | Spans | Polls | Total busy | Max poll |"
+            );
+            out.push_str("| --- | --- | ---: | ---: | ---: | ---: |\n");
+            for g in &groups {
+                let _ = writeln!(
+                    out,
+                    "| {} | {} {} | {} | {} | {} | {} |",
+                    escape(&g.key),
+                    location(&g.top),
+                    escape(&name(&g.top)),
+                    g.spans,
+                    g.polls,
+                    human(g.total_busy),
+                    human(g.max_poll),
+                );
+            }
+        }
+
+        let with_percentiles: Vec<_> = rows
+            .iter()
+            .filter(|row| !row.percentiles.is_empty())
+            .collect();
+        if opts.details && !with_percentiles.is_empty() {
+            out.push_str("\n### Details\n\n");
+            for row in with_percentiles {
+                let _ = write!(out, "- {} {}:", location(&row.id), escape(&name(&row.id)));
+                for &(q, value) in &row.percentiles {
+                    let _ = write!(out, " {} {},", quantile_label(q), human(value));
+                }
+                let _ = writeln!(out, " max {}", human(row.max_poll));
+            }
+        }
+        out
+    }
+
+    /// Renders the callsites in the Prometheus text exposition format, e.g.
+    /// to serve from an existing `/metrics` endpoint.
+    ///
+    /// Series are labeled with the callsite `file`, `line` and `name`, plus
+    /// `task_name`, `resource`, `parent_task` and `runtime` where set, so every
+    /// callsite gets its own series. Callsites with a
+    /// [poll histogram](crate::TokioBlockedLayer::with_poll_histogram) are
+    /// also exported as the `tokio_blocked_poll_duration_seconds` histogram,
+    /// and callsites with
+    /// [SLO thresholds](crate::TokioBlockedLayer::with_slo_thresholds) as the
+    /// `tokio_blocked_polls_over_threshold_total` counter, with a
+    /// `threshold_seconds` label.
+    pub fn render_prometheus(&self) -> String {
+        type Value = fn(&OwnedCallsiteStats) -> String;
+        const COUNTERS: [(&str, &str, Value); 3] = [
+            (
+                "tokio_blocked_busy_seconds_total",
+                "Time spent polling the spans of the callsite.",
+                |row| row.total_busy.as_secs_f64().to_string(),
+            ),
+            (
+                "tokio_blocked_polls_total",
+                "Number of polls of the spans of the callsite.",
+                |row| row.polls.to_string(),
+            ),
+            (
+                "tokio_blocked_exceeded_total",
+                "Number of polls that exceeded the single-poll threshold.",
+                |row| row.exceed_count.to_string(),
+            ),
+        ];
+
+        let mut rows: Vec<&OwnedCallsiteStats> = self.callsites.iter().collect();
+        rows.sort_by(|a, b| a.id.cmp(&b.id));
+        let rows: Vec<_> = rows
+            .into_iter()
+            .map(|row| (row, prometheus_labels(&row.id)))
+            .collect();
+
+        let mut out = String::new();
+        for (metric, help, value) in COUNTERS {
+            let _ = writeln!(out, "# HELP {metric} {help}");
+            let _ = writeln!(out, "# TYPE {metric} counter");
+            for (row, labels) in &rows {
+                let _ = writeln!(out, "{metric}{{{labels}}} {}", value(row));
+            }
+        }
+
+        let histograms: Vec<_> = rows
+            .iter()
+            .filter_map(|(row, labels)| Some((row, labels, row.poll_histogram.as_ref()?)))
+            .collect();
+        if !histograms.is_empty() {
+            let metric = "tokio_blocked_poll_duration_seconds";
+            let _ = writeln!(out, "# HELP {metric} Poll durations of the callsite.");
+            let _ = writeln!(out, "# TYPE {metric} histogram");
+            for (row, labels, histogram) in histograms {
+                let mut cumulative = 0;
+                for (bound, count) in histogram.bounds().iter().zip(histogram.counts()) {
+                    cumulative += count;
+                    let le = bound.as_secs_f64();
+                    let _ = writeln!(out, "{metric}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+                }
+                let count = histogram.count();
+                let _ = writeln!(out, "{metric}_bucket{{{labels},le=\"+Inf\"}} {count}");
+                let sum = row.total_busy.as_secs_f64();
+                let _ = writeln!(out, "{metric}_sum{{{labels}}} {sum}");
+                let _ = writeln!(out, "{metric}_count{{{labels}}} {count}");
+            }
+        }
+
+        if rows.iter().any(|(row, _)| !row.over_threshold.is_empty()) {
+            let metric = "tokio_blocked_polls_over_threshold_total";
+            let _ = writeln!(
+                out,
+                "# HELP {metric} Number of polls that took at least the SLO threshold."
+            );
+            let _ = writeln!(out, "# TYPE {metric} counter");
+            for (row, labels) in &rows {
+                for &(threshold, polls) in &row.over_threshold {
+                    let threshold = threshold.as_secs_f64();
+                    let _ = writeln!(
+                        out,
+                        "{metric}{{{labels},threshold_seconds=\"{threshold}\"}} {polls}"
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+/// The labels of a callsite's series, without the braces.
+fn prometheus_labels(id: &CallsiteId) -> String {
+    let mut labels = Vec::new();
+    if let Some(file) = &id.file {
+        labels.push(("file", file.clone()));
+    }
+    if let Some(line) = id.line {
+        labels.push(("line", line.to_string()));
+    }
+    labels.push(("name", id.name.clone()));
+    if let Some(task) = &id.task_name {
+        labels.push(("task_name", task.clone()));
+    }
+    if let Some(resource) = id.async_op_source.as_ref().or(id.resource_type.as_ref()) {
+        labels.push(("resource", resource.clone()));
+    }
+    if let Some(file) = &id.parent_task_file {
+        let line = id.parent_task_line.unwrap_or(0);
+        labels.push(("parent_task", format!("{file}:{line}")));
+    }
+    if let Some(runtime) = &id.runtime {
+        labels.push(("runtime", runtime.clone()));
+    }
+    labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Escapes a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn location(id: &CallsiteId) -> String {
+    if let Some(task) = &id.task_name {
+        return format!("task `{}`", escape_code(task));
+    }
+    let file = id.file.as_deref().unwrap_or("<unknown>");
+    format!("`{}:{}`", escape_code(file), id.line.unwrap_or(0))
+}
+
+fn plain_location(id: &CallsiteId) -> String {
+    match &id.task_name {
+        Some(task) => format!("task {task}"),
+        None => format!(
+            "{}:{}",
+            id.file.as_deref().unwrap_or("<unknown>"),
+            id.line.unwrap_or(0)
+        ),
+    }
+}
+
+fn name(id: &CallsiteId) -> String {
+    let name = match (&id.resource_type, &id.async_op_source) {
+        (Some(ty), Some(source)) => format!("{} ({ty}, {source})", id.name),
+        (Some(s), None) | (None, Some(s)) => format!("{} ({s})", id.name),
+        (None, None) => id.name.clone(),
+    };
+    match &id.runtime {
+        Some(runtime) => format!("{name} on {runtime}"),
+        None => name,
+    }
+}
+
+/// Aligns `rows` in columns below `header`. The first two columns, location
+/// and name, are left-aligned, the others right-aligned.
+fn text_table(header: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    let header = header.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(header).chain(rows) {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+            if i > 0 {
+                line.push_str("  ");
+            }
+            let pad = " ".repeat(width - cell.chars().count());
+            if i < 2 {
+                line.push_str(cell);
+                line.push_str(&pad);
+            } else {
+                line.push_str(&pad);
+                line.push_str(cell);
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Formats a quantile as a percentile, e.g. `p99.9` for 0.999.
+fn quantile_label(quantile: f64) -> String {
+    format!("p{}", (quantile * 1e8).round() / 1e6)
+}
+
+/// Escapes a value for use inside a table cell.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace(['\n', '\r'], " ")
+}
+
+/// Escapes a value for use inside a code span in a table cell, where
+/// backslashes are literal and a backtick would end the span.
+fn escape_code(value: &str) -> String {
+    value
+        .replace('|', "\\|")
+        .replace('`', "'")
+        .replace(['\n', '\r'], " ")
+}
+
+/// Formats a duration with a unit that keeps the number readable.
+pub(crate) fn human(d: Duration) -> String {
+    let nanos = d.as_nanos();
+    if nanos >= 1_000_000_000 {
+        format!("{:.2} s", d.as_secs_f64())
+    } else if nanos >= 1_000_000 {
+        format!("{:.2} ms", nanos as f64 / 1e6)
+    } else if nanos >= 1_000 {
+        format!("{:.2} µs", nanos as f64 / 1e3)
+    } else {
+        format!("{nanos} ns")
+    }
+}
+
+fn human_opt(d: Option<Duration>) -> String {
+    d.map_or_else(|| "off".to_string(), human)
+}
+
+fn human_or_dash(d: Option<Duration>) -> String {
+    d.map_or_else(|| "-".to_string(), human)
+}
+
+/// Signed change from `before` to `after`, missing values count as zero.
+fn delta(before: Option<Duration>, after: Option<Duration>) -> String {
+    let (before, after) = (before.unwrap_or_default(), after.unwrap_or_default());
+    if after >= before {
+        format!("+{}", human(after - before))
+    } else {
+        format!("-{}", human(before - after))
+    }
+}
+
+/// Formats a [`SystemTime`] as `YYYY-MM-DD HH:MM:SS UTC`.
+struct Utc(SystemTime);
+
+impl std::fmt::Display for Utc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (days, rem) = (secs / 86_400, secs % 86_400);
+        // Civil date from days since the epoch, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+            rem / 3600,
+            rem / 60 % 60,
+            rem % 60,
+        )
+    }
+}
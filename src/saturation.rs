@@ -0,0 +1,201 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::time::Instant;
+
+/// Number of buckets the sliding window is split into.
+const BUCKETS: usize = 10;
+
+/// Warns when task polls occupy too much of the runtime's capacity, see
+/// [`with_saturation_detection`](crate::TokioBlockedLayer::with_saturation_detection).
+///
+/// The blocked fraction is the busy time of all task polls during the last
+/// [`window`](Self::window) divided by the window length times the number of
+/// [`workers`](Self::workers). `0.0` is idle, `1.0` means every worker was
+/// polling tasks the whole window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaturationThresholds {
+    pub(crate) percent: f64,
+    pub(crate) hysteresis: f64,
+    pub(crate) window: Duration,
+    pub(crate) workers: Option<usize>,
+}
+
+impl SaturationThresholds {
+    /// Warns once the blocked fraction reaches `percent` of the capacity,
+    /// e.g. `80.0`.
+    pub fn new(percent: f64) -> Self {
+        Self {
+            percent,
+            hysteresis: 10.0,
+            window: Duration::from_secs(10),
+            workers: None,
+        }
+    }
+
+    /// Length of the sliding window, split into 10 buckets. Defaults to 10s.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Number of threads polling tasks, the capacity the busy time is
+    /// compared against.
+    ///
+    /// Defaults to the workers of the runtime of the first recorded poll with
+    /// the `runtime-metrics` feature, and to
+    /// [`std::thread::available_parallelism`] otherwise.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Percentage points the fraction has to drop below the threshold before
+    /// the next warning, so a fraction hovering around it doesn't warn on
+    /// every bucket. Defaults to 10.
+    pub fn hysteresis(mut self, percent: f64) -> Self {
+        self.hysteresis = percent.max(0.0);
+        self
+    }
+}
+
+/// A saturated window, to be reported as `tokio_blocked::runtime_saturated`.
+pub(crate) struct Saturated {
+    pub(crate) fraction: f64,
+    pub(crate) busy: Duration,
+    pub(crate) capacity: Duration,
+    pub(crate) workers: usize,
+    pub(crate) window: Duration,
+}
+
+/// Busy time of task polls per time bucket, as a ring covering the window
+/// plus the current bucket, updated lock-free on every task poll.
+///
+/// The first poll that ends in a new bucket resets its slot and evaluates the
+/// window of completed buckets before it. Polls racing with the reset may be
+/// lost, like with the health tracker.
+pub(crate) struct SaturationTracker {
+    config: SaturationThresholds,
+    bucket_len: Duration,
+    // End of the first recorded poll, so the tracker doesn't depend on the
+    // clock it was created with.
+    epoch: OnceLock<Instant>,
+    workers: OnceLock<usize>,
+    buckets: Box<[Bucket]>,
+    saturated: AtomicBool,
+}
+
+#[derive(Default)]
+struct Bucket {
+    // Index of the bucket plus one, zero if unused.
+    index: AtomicU64,
+    busy_ns: AtomicU64,
+}
+
+impl SaturationTracker {
+    pub(crate) fn new(config: SaturationThresholds) -> Self {
+        Self {
+            config,
+            bucket_len: (config.window / BUCKETS as u32).max(Duration::from_nanos(1)),
+            epoch: OnceLock::new(),
+            workers: OnceLock::new(),
+            buckets: (0..=BUCKETS).map(|_| Bucket::default()).collect(),
+            saturated: AtomicBool::new(false),
+        }
+    }
+
+    fn index(&self, epoch: Instant, at: Instant) -> u64 {
+        (at.saturating_duration_since(epoch).as_nanos() / self.bucket_len.as_nanos()) as u64
+    }
+
+    fn workers(&self) -> usize {
+        *self.workers.get_or_init(|| {
+            self.config
+                .workers
+                .or_else(runtime_workers)
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+                .max(1)
+        })
+    }
+
+    /// Adds a task poll that ended at `end`, returning a window that newly
+    /// crossed the threshold.
+    pub(crate) fn record(&self, busy: Duration, end: Instant) -> Option<Saturated> {
+        let epoch = *self.epoch.get_or_init(|| end);
+        let workers = self.workers();
+        let index = self.index(epoch, end);
+        let bucket = &self.buckets[index as usize % self.buckets.len()];
+        let seen = bucket.index.load(Ordering::Relaxed);
+        let completed = seen != index + 1
+            && bucket
+                .index
+                .compare_exchange(seen, index + 1, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok();
+        if completed {
+            bucket.busy_ns.store(0, Ordering::Relaxed);
+        }
+        bucket
+            .busy_ns
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+        if !completed {
+            return None;
+        }
+
+        let saturated = self.window(index, workers);
+        let percent = saturated.fraction * 100.0;
+        if percent < self.config.percent - self.config.hysteresis {
+            self.saturated.store(false, Ordering::Relaxed);
+        } else if percent >= self.config.percent && !self.saturated.swap(true, Ordering::Relaxed) {
+            return Some(saturated);
+        }
+        None
+    }
+
+    /// Sums the completed buckets of the window before bucket `current`.
+    fn window(&self, current: u64, workers: usize) -> Saturated {
+        let oldest = current.saturating_sub(BUCKETS as u64);
+        let busy_ns: u64 = self
+            .buckets
+            .iter()
+            .filter(|bucket| {
+                let index = bucket.index.load(Ordering::Relaxed);
+                index != 0 && (oldest..current).contains(&(index - 1))
+            })
+            .map(|bucket| bucket.busy_ns.load(Ordering::Relaxed))
+            .sum();
+        let window = self.bucket_len * BUCKETS as u32;
+        let capacity = window * workers as u32;
+        Saturated {
+            fraction: (busy_ns as f64 / capacity.as_nanos() as f64).min(1.0),
+            busy: Duration::from_nanos(busy_ns),
+            capacity,
+            workers,
+            window,
+        }
+    }
+
+    /// The blocked fraction of the window before `now`.
+    pub(crate) fn fraction(&self, now: Instant) -> f64 {
+        let (Some(&epoch), Some(&workers)) = (self.epoch.get(), self.workers.get()) else {
+            return 0.0;
+        };
+        self.window(self.index(epoch, now), workers).fraction
+    }
+}
+
+#[cfg(feature = "runtime-metrics")]
+fn runtime_workers() -> Option<usize> {
+    tokio::runtime::Handle::try_current()
+        .ok()
+        .map(|handle| handle.metrics().num_workers())
+}
+
+#[cfg(not(feature = "runtime-metrics"))]
+fn runtime_workers() -> Option<usize> {
+    None
+}
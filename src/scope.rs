@@ -0,0 +1,27 @@
+use std::future::Future;
+
+use tracing::{instrument::Instrumented, Instrument as _};
+
+/// Runs `future` inside a `tokio_blocked.scope` span, for
+/// [`with_scope_span`](crate::TokioBlockedLayer::with_scope_span) with
+/// [`CallsiteSelector::scope`](crate::CallsiteSelector::scope).
+///
+/// Tokio's task spans have no parent, so the layer also counts tasks spawned
+/// while polling the future, and the tasks spawned by those in turn, as part
+/// of the scope.
+///
+/// ```rust
+/// # async fn run_load_test() {}
+/// # async fn example() {
+/// tokio_blocked::scope(async {
+///     run_load_test().await;
+/// })
+/// .await;
+/// # }
+/// ```
+pub fn scope<F: Future>(future: F) -> Instrumented<F> {
+    future.instrument(tracing::info_span!(
+        target: "tokio_blocked::scope",
+        "tokio_blocked.scope"
+    ))
+}
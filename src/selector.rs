@@ -0,0 +1,62 @@
+/// Selects callsites, either by location or by span target and name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CallsiteSelector {
+    /// Matches the spawn location, or the span callsite location if the spawn
+    /// location is unknown.
+    ///
+    /// `line: None` matches every line of the file.
+    Location { file: String, line: Option<u32> },
+    /// Matches the span target and name, e.g. `tokio::task` and
+    /// `runtime.spawn`.
+    Name { target: String, name: String },
+}
+
+impl CallsiteSelector {
+    /// Selects the callsite at `file:line`.
+    pub fn location(file: impl Into<String>, line: u32) -> Self {
+        Self::Location {
+            file: file.into(),
+            line: Some(line),
+        }
+    }
+
+    /// Selects all callsites in `file`.
+    pub fn file(file: impl Into<String>) -> Self {
+        Self::Location {
+            file: file.into(),
+            line: None,
+        }
+    }
+
+    /// Selects all callsites with the given span target and name.
+    pub fn name(target: impl Into<String>, name: impl Into<String>) -> Self {
+        Self::Name {
+            target: target.into(),
+            name: name.into(),
+        }
+    }
+
+    /// Selects the spans created by [`scope`](fn@crate::scope).
+    pub fn scope() -> Self {
+        Self::name("tokio_blocked::scope", "tokio_blocked.scope")
+    }
+
+    pub(crate) fn matches(
+        &self,
+        target: &str,
+        name: &str,
+        file: Option<&str>,
+        line: Option<u32>,
+    ) -> bool {
+        match self {
+            Self::Location {
+                file: sel_file,
+                line: sel_line,
+            } => file == Some(sel_file.as_str()) && sel_line.is_none_or(|l| line == Some(l)),
+            Self::Name {
+                target: sel_target,
+                name: sel_name,
+            } => target == sel_target && name == sel_name,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::{borrow::Cow, sync::Mutex, time::Duration};
+
+use sentry_core::{
+    protocol::{self, Event, Frame, Stacktrace},
+    Hub,
+};
+use tracing::Level;
+
+use crate::{
+    events::{BlockedPollInfo, BlockedTotalInfo},
+    time::Instant,
+    BlockedObserver,
+};
+
+/// Reports blocked polls and spans as Sentry events, captured with the
+/// active [`Hub`]. Nothing is done without a bound Sentry client.
+///
+/// Events carry the spawn location as their culprit and only stack frame,
+/// the location and task name as tags, the durations as extra data, and the
+/// [breadcrumbs](crate::TokioBlockedLayer::with_breadcrumbs) of blocked
+/// polls as Sentry breadcrumbs.
+/// Events of the same location and kind share a fingerprint, so Sentry
+/// groups them into one issue.
+///
+/// Requires the `sentry` feature.
+///
+/// ```rust
+/// use std::{sync::Arc, time::Duration};
+/// use tokio_blocked::{SentryBlockedObserver, TokioBlockedLayer};
+///
+/// let sentry = SentryBlockedObserver::new(
+///     Some(Duration::from_millis(50)),
+///     Some(Duration::from_secs(1)),
+/// )
+/// .with_rate_limit(5, Duration::from_secs(60));
+/// let layer = TokioBlockedLayer::new().with_observer(Arc::new(sentry));
+/// ```
+pub struct SentryBlockedObserver {
+    poll_threshold: Option<Duration>,
+    total_threshold: Option<Duration>,
+    max_events: u32,
+    rate_window: Duration,
+    // Start of the current rate limit window and the events sent in it.
+    window: Mutex<Option<(Instant, u32)>>,
+}
+
+impl SentryBlockedObserver {
+    /// Reports polls of at least `poll_threshold` and spans busy for at
+    /// least `total_threshold`, `None` disables the respective events.
+    ///
+    /// The thresholds apply on top of the layer's own, so they only need to
+    /// be set to report fewer events to Sentry than are logged.
+    ///
+    /// At most 10 events per minute are sent, see
+    /// [`with_rate_limit`](Self::with_rate_limit).
+    pub fn new(poll_threshold: Option<Duration>, total_threshold: Option<Duration>) -> Self {
+        Self {
+            poll_threshold,
+            total_threshold,
+            max_events: 10,
+            rate_window: Duration::from_secs(60),
+            window: Mutex::new(None),
+        }
+    }
+
+    /// Sends at most `max_events` events per `window`, dropping the rest.
+    pub fn with_rate_limit(mut self, max_events: u32, window: Duration) -> Self {
+        self.max_events = max_events;
+        self.rate_window = window;
+        self
+    }
+
+    fn acquire(&self) -> bool {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        match &mut *window {
+            Some((start, sent)) if now.duration_since(*start) < self.rate_window => {
+                if *sent >= self.max_events {
+                    return false;
+                }
+                *sent += 1;
+            }
+            _ => *window = Some((now, 1)),
+        }
+        self.max_events > 0
+    }
+}
+
+impl BlockedObserver for SentryBlockedObserver {
+    fn on_poll_blocked(&self, info: &BlockedPollInfo<'_>) {
+        if self.poll_threshold.is_none_or(|t| info.poll_duration < t) {
+            return;
+        }
+        Hub::with_active(|hub| {
+            if !self.acquire() {
+                return;
+            }
+            let kind = if info.is_first_task_poll() {
+                "task_first_poll_blocked"
+            } else {
+                "task_poll_blocked"
+            };
+            let mut event = event(
+                kind,
+                info.location(),
+                info.callsite_name,
+                info.owning_task_name(),
+            );
+            event.message = Some(format!(
+                "tokio task poll blocked for {:?}",
+                info.poll_duration
+            ));
+            event.level = level(info.level);
+            event.timestamp = info.timestamp;
+            extra(&mut event, "poll_duration_ns", info.poll_duration);
+            extra(&mut event, "threshold_ns", info.threshold);
+            extra(
+                &mut event,
+                "effective_threshold_ns",
+                info.effective_threshold,
+            );
+            if let Some(trace) = &info.span_trace {
+                event
+                    .extra
+                    .insert("span_trace".into(), trace.as_str().into());
+            }
+            event.extra.insert("seq".into(), info.seq.into());
+            event.extra.insert("poll_seq".into(), info.poll_seq.into());
+            event
+                .extra
+                .insert("exceed_count".into(), info.exceed_count.into());
+            event
+                .extra
+                .insert("span_busy_ratio".into(), info.span_busy_ratio.into());
+            if let Some(id) = info.task_id {
+                event.extra.insert("task.id".into(), id.into());
+            }
+            event.breadcrumbs = info
+                .breadcrumbs
+                .iter()
+                .map(|crumb| protocol::Breadcrumb {
+                    timestamp: crumb.timestamp,
+                    category: Some(crumb.target.into()),
+                    level: level(crumb.level),
+                    message: Some(crumb.message.clone()),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
+                .into();
+            hub.capture_event(event);
+        })
+    }
+
+    fn on_span_closed(&self, info: &BlockedTotalInfo<'_>) {
+        if self.total_threshold.is_none_or(|t| info.busy < t) {
+            return;
+        }
+        Hub::with_active(|hub| {
+            if !self.acquire() {
+                return;
+            }
+            let mut event = event(
+                "task_blocked_total",
+                info.location(),
+                info.callsite_name,
+                info.owning_task_name(),
+            );
+            event.message = Some(format!("tokio task blocked for {:?} in total", info.busy));
+            event.level = level(info.level);
+            event.timestamp = info.timestamp;
+            extra(&mut event, "busy_ns", info.busy);
+            extra(&mut event, "duration_ns", info.lifetime);
+            extra(&mut event, "max_poll_ns", info.max_poll);
+            if let Some(mean) = info.mean_poll() {
+                extra(&mut event, "mean_poll_ns", mean);
+            }
+            if let Some(threshold) = info.threshold {
+                extra(&mut event, "threshold_ns", threshold);
+            }
+            event
+                .extra
+                .insert("blocked_percent".into(), info.blocked_percent.into());
+            event.extra.insert("seq".into(), info.seq.into());
+            event.extra.insert("polls_total".into(), info.polls.into());
+            event.extra.insert(
+                "polls_over_threshold".into(),
+                info.polls_over_threshold.into(),
+            );
+            if let Some(pattern) = info.pattern {
+                event.tags.insert("pattern".into(), pattern.as_str().into());
+            }
+            event
+                .extra
+                .insert("migrations".into(), info.migrations.into());
+            event
+                .extra
+                .insert("span_still_open".into(), info.span_still_open.into());
+            if let Some(id) = info.task_id {
+                event.extra.insert("task.id".into(), id.into());
+            }
+            hub.capture_event(event);
+        })
+    }
+}
+
+fn event(
+    kind: &'static str,
+    (file, line, col): (&'static str, u32, u32),
+    callsite_name: &'static str,
+    task_name: Option<&str>,
+) -> Event<'static> {
+    let location = format!("{file}:{line}:{col}");
+    let mut event = Event {
+        logger: Some(format!("tokio_blocked::{kind}")),
+        culprit: Some(location.clone()),
+        fingerprint: Cow::Owned(vec!["tokio-blocked".into(), kind.into(), location.into()]),
+        stacktrace: Some(Stacktrace {
+            frames: vec![Frame {
+                function: Some(callsite_name.to_string()),
+                filename: Some(file.to_string()),
+                lineno: Some(line.into()),
+                colno: (col > 0).then_some(col.into()),
+                in_app: Some(true),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    event.tags.insert("callsite.file".into(), file.into());
+    event.tags.insert("callsite.line".into(), line.to_string());
+    if let Some(name) = task_name {
+        event.tags.insert("task.name".into(), name.into());
+    }
+    event
+}
+
+fn extra(event: &mut Event<'_>, key: &str, duration: Duration) {
+    event
+        .extra
+        .insert(key.into(), (duration.as_nanos() as u64).into());
+}
+
+fn level(level: Level) -> protocol::Level {
+    match level {
+        Level::ERROR => protocol::Level::Error,
+        Level::WARN => protocol::Level::Warning,
+        Level::INFO => protocol::Level::Info,
+        Level::DEBUG | Level::TRACE => protocol::Level::Debug,
+    }
+}
@@ -0,0 +1,1029 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use crate::{histogram::Histogram, pattern::PatternCounts, time::SystemTime};
+
+/// A serializable snapshot of per-callsite totals.
+///
+/// Borrows static strings from the callsite metadata, so it can be serialized
+/// but not deserialized; use [`OwnedCallsiteStats`] for that.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CallsiteStatsSnapshot {
+    pub name: &'static str,
+    pub target: &'static str,
+    pub file: Option<&'static str>,
+    pub line: Option<u32>,
+    /// `concrete_type` of the tokio resource of an async op span, e.g. `Sleep`.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub resource_type: Option<&'static str>,
+    /// `source` of an async op span, e.g. `Sleep::new_timeout`.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub async_op_source: Option<&'static str>,
+    /// Whether the row holds acquisitions of a tokio sync primitive, e.g.
+    /// `Mutex::lock`. The lifetime statistics are the lock wait times, see
+    /// [`with_warn_lock_wait`](crate::TokioBlockedLayer::with_warn_lock_wait).
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "is_false"))]
+    pub lock_wait: bool,
+    /// Whether the location is exempt from warnings, see
+    /// [`with_allow_blocking_at`](crate::TokioBlockedLayer::with_allow_blocking_at).
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "is_false"))]
+    pub allowed: bool,
+    /// What kind of work the row describes.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Category::is_async"))]
+    pub category: Category,
+    /// The task name of rows grouped by name, see
+    /// [`with_group_by`](crate::TokioBlockedLayer::with_group_by).
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub task_name: Option<&'static str>,
+    /// The label of the runtime the spans were polled on, see
+    /// [`with_runtime_label`](crate::TokioBlockedLayer::with_runtime_label).
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub runtime: Option<&'static str>,
+    /// The task async op rows were created in. Async ops of the same
+    /// location get a row per task.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub parent_task: Option<ParentTask>,
+    #[cfg_attr(feature = "serde", serde(rename = "total_busy_ns", with = "nanos"))]
+    pub total_busy: Duration,
+    /// Thread CPU time of the polls of closed spans, zero without
+    /// [`with_cpu_time`](crate::TokioBlockedLayer::with_cpu_time).
+    ///
+    /// See [`on_cpu_percent`](Self::on_cpu_percent).
+    #[cfg_attr(feature = "serde", serde(rename = "total_cpu_ns", with = "nanos"))]
+    pub total_cpu: Duration,
+    pub count: u64,
+    /// Number of polls that exceeded the single-poll threshold.
+    pub exceed_count: u64,
+    /// Number of [`exceed_count`](Self::exceed_count) polls that were the
+    /// first poll of a task, typically due to synchronous initialization.
+    /// They are reported as `tokio_blocked::task_first_poll_blocked`.
+    pub first_poll_blocked: u64,
+    /// Longest single poll.
+    #[cfg_attr(feature = "serde", serde(rename = "max_poll_ns", with = "nanos"))]
+    pub max_poll: Duration,
+    /// Exponentially weighted moving average of poll durations.
+    ///
+    /// Only available with
+    /// [`with_ewma_alpha`](crate::TokioBlockedLayer::with_ewma_alpha).
+    #[cfg_attr(feature = "serde", serde(rename = "ewma_poll_ns", with = "opt_nanos"))]
+    pub ewma_poll: Option<Duration>,
+    /// Distribution of poll durations.
+    ///
+    /// Only available with
+    /// [`with_poll_histogram`](crate::TokioBlockedLayer::with_poll_histogram).
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub poll_histogram: Option<Histogram>,
+    /// `(quantile, poll duration)` pairs estimated from
+    /// [`poll_histogram`](Self::poll_histogram), for the quantiles set with
+    /// [`with_report_percentiles`](crate::TokioBlockedLayer::with_report_percentiles).
+    ///
+    /// Empty without a histogram or polls.
+    #[cfg_attr(
+        feature = "serde",
+        serde(skip_serializing_if = "Vec::is_empty", with = "quantiles")
+    )]
+    pub percentiles: Vec<(f64, Duration)>,
+    /// Number of polls.
+    pub polls: u64,
+    /// Sum of the squared poll durations in nanoseconds.
+    ///
+    /// See [`stddev_poll`](Self::stddev_poll).
+    pub poll_sum_squares_ns: u128,
+    /// Sum of the delays between span creation (task spawn) and the first
+    /// poll, over all spans that were polled.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "spawn_to_first_poll_ns", with = "nanos")
+    )]
+    pub spawn_to_first_poll: Duration,
+    /// Number of spans that were closed without ever being polled.
+    pub never_polled: u64,
+    /// Sum of the thread migrations of closed spans, i.e. polls on a
+    /// different thread than the previous poll of the span.
+    ///
+    /// Only counted with
+    /// [`with_warn_migrations`](crate::TokioBlockedLayer::with_warn_migrations).
+    pub migrations: u64,
+    /// Shortest time from span creation to close.
+    #[cfg_attr(feature = "serde", serde(rename = "min_lifetime_ns", with = "nanos"))]
+    pub min_lifetime: Duration,
+    /// Sum of the times from span creation to close.
+    ///
+    /// See [`mean_lifetime`](Self::mean_lifetime) and
+    /// [`busy_percent`](Self::busy_percent).
+    #[cfg_attr(feature = "serde", serde(rename = "total_lifetime_ns", with = "nanos"))]
+    pub total_lifetime: Duration,
+    /// Longest time from span creation to close.
+    #[cfg_attr(feature = "serde", serde(rename = "max_lifetime_ns", with = "nanos"))]
+    pub max_lifetime: Duration,
+    /// Wall-clock time of the first poll that exceeded the single-poll
+    /// threshold, whether or not it was warned about.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "first_exceeded_at_unix_ns", with = "opt_unix_nanos")
+    )]
+    pub first_exceeded_at: Option<SystemTime>,
+    /// Blocked spans per [`BlockingPattern`](crate::BlockingPattern), see
+    /// [`with_blocking_patterns`](crate::TokioBlockedLayer::with_blocking_patterns).
+    #[cfg_attr(
+        feature = "serde",
+        serde(skip_serializing_if = "PatternCounts::is_empty")
+    )]
+    pub patterns: PatternCounts,
+    /// `(threshold, polls)` pairs, the polls of closed spans that took at
+    /// least each threshold set with
+    /// [`with_slo_thresholds`](crate::TokioBlockedLayer::with_slo_thresholds),
+    /// by increasing threshold.
+    ///
+    /// Empty without thresholds, or before the first span closed.
+    #[cfg_attr(
+        feature = "serde",
+        serde(skip_serializing_if = "Vec::is_empty", with = "over_threshold")
+    )]
+    pub over_threshold: Vec<(Duration, u64)>,
+}
+
+/// What kind of work a statistics row describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[non_exhaustive]
+pub enum Category {
+    /// Tasks and async ops, polled on the runtime's worker threads.
+    #[default]
+    Async,
+    /// Closures on tokio's blocking pool, i.e. `spawn_blocking` and the
+    /// `tokio::fs` operations built on it. They don't block the workers, so
+    /// they are only reported with
+    /// [`with_warn_blocking_pool_op`](crate::TokioBlockedLayer::with_warn_blocking_pool_op).
+    BlockingPool,
+    /// `runtime.resource` spans, e.g. timers and sockets, with
+    /// [`with_track_resources`](crate::TokioBlockedLayer::with_track_resources).
+    /// Only the lifetimes are tracked, the busy time and polls stay zero.
+    Resource,
+    /// Deliberately blocking sections marked with
+    /// [`block_zone!`](crate::block_zone). They never warn, and their time is
+    /// subtracted from the polls they are part of.
+    Acknowledged,
+}
+
+impl Category {
+    #[cfg(feature = "serde")]
+    fn is_async(&self) -> bool {
+        *self == Self::Async
+    }
+}
+
+/// The task an async op was created in: the nearest enclosing
+/// `runtime.spawn` span, or the task being polled at the time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ParentTask {
+    /// Spawn location of the task, or its span callsite if unknown.
+    pub file: Option<&'static str>,
+    pub line: Option<u32>,
+    /// `task.name` of the task.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub name: Option<&'static str>,
+}
+
+/// A task with the async ops created inside it, see
+/// [`BlockedStatsHandle::snapshot_by_task`](crate::BlockedStatsHandle::snapshot_by_task).
+#[derive(Debug, Clone)]
+pub struct TaskOps {
+    pub task: ParentTask,
+    /// Statistics of the task row, once one of its spans has closed.
+    pub stats: Option<CallsiteStatsSnapshot>,
+    /// Async op rows, by decreasing total busy time.
+    pub ops: Vec<CallsiteStatsSnapshot>,
+}
+
+impl TaskOps {
+    /// Groups the op rows under their task rows, sorted by the busy time of
+    /// the task, or of its ops if the task row is missing.
+    ///
+    /// Rows of tasks grouped by name and of ops outside of any task are
+    /// omitted.
+    pub(crate) fn group(rows: Vec<CallsiteStatsSnapshot>) -> Vec<Self> {
+        let mut groups: HashMap<(Option<&str>, Option<u32>), TaskOps> = HashMap::new();
+        for row in rows {
+            let (task, is_task) = match row.parent_task {
+                Some(task) => (task, false),
+                None if row.name == "runtime.spawn" && row.task_name.is_none() => {
+                    let task = ParentTask {
+                        file: row.file,
+                        line: row.line,
+                        name: None,
+                    };
+                    (task, true)
+                }
+                None => continue,
+            };
+            let group = groups
+                .entry((task.file, task.line))
+                .or_insert_with(|| TaskOps {
+                    task,
+                    stats: None,
+                    ops: Vec::new(),
+                });
+            group.task.name = group.task.name.or(task.name);
+            if is_task {
+                group.stats = Some(row);
+            } else {
+                group.ops.push(row);
+            }
+        }
+
+        let mut groups: Vec<_> = groups.into_values().collect();
+        for group in &mut groups {
+            group.ops.sort_by_key(|op| std::cmp::Reverse(op.total_busy));
+        }
+        groups.sort_by_cached_key(|g| {
+            let busy = match &g.stats {
+                Some(stats) => stats.total_busy,
+                None => g.ops.iter().map(|op| op.total_busy).sum(),
+            };
+            (std::cmp::Reverse(busy), g.task.file, g.task.line)
+        });
+        groups
+    }
+}
+
+impl CallsiteStatsSnapshot {
+    /// Mean poll duration.
+    pub fn mean_poll(&self) -> Option<Duration> {
+        mean(self.polls, self.total_busy)
+    }
+
+    /// Mean delay between spawn and first poll, excluding spans that were
+    /// never polled.
+    pub fn mean_first_poll_latency(&self) -> Option<Duration> {
+        mean(
+            self.count.saturating_sub(self.never_polled),
+            self.spawn_to_first_poll,
+        )
+    }
+
+    /// Mean time from span creation to close.
+    pub fn mean_lifetime(&self) -> Option<Duration> {
+        mean(self.count, self.total_lifetime)
+    }
+
+    /// Share of the total lifetime that was spent in polls, in percent.
+    pub fn busy_percent(&self) -> Option<f64> {
+        busy_percent(self.total_busy, self.total_lifetime)
+    }
+
+    /// Share of the busy time the polling threads spent on the CPU, in
+    /// percent, or `None` without CPU time.
+    pub fn on_cpu_percent(&self) -> Option<f64> {
+        on_cpu_percent(self.total_cpu, self.total_busy)
+    }
+
+    /// Population standard deviation of poll durations.
+    pub fn stddev_poll(&self) -> Option<Duration> {
+        stddev(self.polls, self.total_busy, self.poll_sum_squares_ns)
+    }
+}
+
+/// Identity of a callsite that is stable across runs.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CallsiteId {
+    pub target: String,
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub resource_type: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub async_op_source: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub task_name: Option<String>,
+    /// Location of the [parent task](ParentTask) of async op rows.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub parent_task_file: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub parent_task_line: Option<u32>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub runtime: Option<String>,
+}
+
+impl std::fmt::Display for CallsiteId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.task_name {
+            Some(task) => write!(f, "{} {} for task {task}", self.target, self.name)?,
+            None => write!(
+                f,
+                "{} {} at {}:{}",
+                self.target,
+                self.name,
+                self.file.as_deref().unwrap_or("<unknown>"),
+                self.line.unwrap_or(0),
+            )?,
+        }
+        match (&self.resource_type, &self.async_op_source) {
+            (Some(ty), Some(source)) => write!(f, " ({ty}, {source})")?,
+            (Some(s), None) | (None, Some(s)) => write!(f, " ({s})")?,
+            (None, None) => {}
+        }
+        if let Some(file) = &self.parent_task_file {
+            write!(
+                f,
+                " in task at {file}:{}",
+                self.parent_task_line.unwrap_or(0)
+            )?;
+        }
+        match &self.runtime {
+            Some(runtime) => write!(f, " on runtime {runtime}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Owned counterpart of [`CallsiteStatsSnapshot`], as stored in a
+/// [`Snapshot`].
+///
+/// Serializes to the same field names as [`CallsiteStatsSnapshot`], with
+/// durations as integer nanoseconds (`*_ns`). Fields added in later versions
+/// default when reading older data.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedCallsiteStats {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub id: CallsiteId,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_false"))]
+    pub lock_wait: bool,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_false"))]
+    pub allowed: bool,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Category::is_async")
+    )]
+    pub category: Category,
+    #[cfg_attr(feature = "serde", serde(rename = "total_busy_ns", with = "nanos"))]
+    pub total_busy: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "total_cpu_ns", with = "nanos", default)
+    )]
+    pub total_cpu: Duration,
+    pub count: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub exceed_count: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub first_poll_blocked: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "max_poll_ns", with = "nanos", default)
+    )]
+    pub max_poll: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "ewma_poll_ns", with = "opt_nanos", default)
+    )]
+    pub ewma_poll: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub poll_histogram: Option<Histogram>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty", with = "quantiles")
+    )]
+    pub percentiles: Vec<(f64, Duration)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub polls: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub poll_sum_squares_ns: u128,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "spawn_to_first_poll_ns", with = "nanos", default)
+    )]
+    pub spawn_to_first_poll: Duration,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub never_polled: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub migrations: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "min_lifetime_ns", with = "nanos", default)
+    )]
+    pub min_lifetime: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "total_lifetime_ns", with = "nanos", default)
+    )]
+    pub total_lifetime: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "max_lifetime_ns", with = "nanos", default)
+    )]
+    pub max_lifetime: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "first_exceeded_at_unix_ns", with = "opt_unix_nanos", default)
+    )]
+    pub first_exceeded_at: Option<SystemTime>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "PatternCounts::is_empty")
+    )]
+    pub patterns: PatternCounts,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            default,
+            skip_serializing_if = "Vec::is_empty",
+            with = "over_threshold"
+        )
+    )]
+    pub over_threshold: Vec<(Duration, u64)>,
+}
+
+impl OwnedCallsiteStats {
+    /// Mean poll duration.
+    pub fn mean_poll(&self) -> Option<Duration> {
+        mean(self.polls, self.total_busy)
+    }
+
+    /// Mean delay between spawn and first poll, excluding spans that were
+    /// never polled.
+    pub fn mean_first_poll_latency(&self) -> Option<Duration> {
+        mean(
+            self.count.saturating_sub(self.never_polled),
+            self.spawn_to_first_poll,
+        )
+    }
+
+    /// Mean time from span creation to close.
+    pub fn mean_lifetime(&self) -> Option<Duration> {
+        mean(self.count, self.total_lifetime)
+    }
+
+    /// Share of the total lifetime that was spent in polls, in percent.
+    pub fn busy_percent(&self) -> Option<f64> {
+        busy_percent(self.total_busy, self.total_lifetime)
+    }
+
+    /// Share of the busy time the polling threads spent on the CPU, in
+    /// percent, or `None` without CPU time.
+    pub fn on_cpu_percent(&self) -> Option<f64> {
+        on_cpu_percent(self.total_cpu, self.total_busy)
+    }
+
+    /// Population standard deviation of poll durations.
+    pub fn stddev_poll(&self) -> Option<Duration> {
+        stddev(self.polls, self.total_busy, self.poll_sum_squares_ns)
+    }
+}
+
+impl From<CallsiteStatsSnapshot> for OwnedCallsiteStats {
+    fn from(s: CallsiteStatsSnapshot) -> Self {
+        Self::from(&s)
+    }
+}
+
+impl From<&CallsiteStatsSnapshot> for OwnedCallsiteStats {
+    fn from(s: &CallsiteStatsSnapshot) -> Self {
+        Self {
+            id: CallsiteId {
+                target: s.target.to_string(),
+                name: s.name.to_string(),
+                file: s.file.map(|f| f.to_string()),
+                line: s.line,
+                resource_type: s.resource_type.map(|t| t.to_string()),
+                async_op_source: s.async_op_source.map(|s| s.to_string()),
+                task_name: s.task_name.map(|n| n.to_string()),
+                parent_task_file: s.parent_task.and_then(|p| p.file).map(|f| f.to_string()),
+                parent_task_line: s.parent_task.and_then(|p| p.line),
+                runtime: s.runtime.map(|r| r.to_string()),
+            },
+            lock_wait: s.lock_wait,
+            allowed: s.allowed,
+            category: s.category,
+            total_busy: s.total_busy,
+            total_cpu: s.total_cpu,
+            count: s.count,
+            exceed_count: s.exceed_count,
+            first_poll_blocked: s.first_poll_blocked,
+            max_poll: s.max_poll,
+            ewma_poll: s.ewma_poll,
+            poll_histogram: s.poll_histogram.clone(),
+            percentiles: s.percentiles.clone(),
+            polls: s.polls,
+            poll_sum_squares_ns: s.poll_sum_squares_ns,
+            spawn_to_first_poll: s.spawn_to_first_poll,
+            never_polled: s.never_polled,
+            migrations: s.migrations,
+            min_lifetime: s.min_lifetime,
+            total_lifetime: s.total_lifetime,
+            max_lifetime: s.max_lifetime,
+            first_exceeded_at: s.first_exceeded_at,
+            patterns: s.patterns,
+            over_threshold: s.over_threshold.clone(),
+        }
+    }
+}
+
+/// An owned snapshot of all callsites, e.g. a baseline for regression
+/// detection.
+///
+/// With the `serde` feature, snapshots can be persisted with
+/// [`save`](Self::save) and [`load`](Self::load), or converted with
+/// [`to_json`](Self::to_json) and [`from_json`](Self::from_json).
+///
+/// The serialized form carries a `version` field ([`Snapshot::FORMAT_VERSION`]),
+/// which is only bumped for incompatible changes. Readers reject snapshots
+/// with a newer version.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub version: u32,
+    /// How long statistics were accumulated, excluding paused periods.
+    ///
+    /// See [`BlockedStatsHandle::stats_active_duration`](crate::BlockedStatsHandle::stats_active_duration).
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "active_duration_ns", with = "nanos", default)
+    )]
+    pub active_duration: Duration,
+    pub callsites: Vec<OwnedCallsiteStats>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl From<&[CallsiteStatsSnapshot]> for Snapshot {
+    fn from(stats: &[CallsiteStatsSnapshot]) -> Self {
+        Self::new(stats.iter().map(OwnedCallsiteStats::from).collect())
+    }
+}
+
+impl From<Vec<CallsiteStatsSnapshot>> for Snapshot {
+    fn from(stats: Vec<CallsiteStatsSnapshot>) -> Self {
+        Self::from(&stats[..])
+    }
+}
+
+/// How [`Snapshot::grouped`] rolls up callsites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GroupKey {
+    /// The first segment of the target, e.g. `hyper` for `hyper::proto::h1`.
+    Crate,
+    /// The whole target, i.e. the module path of the span.
+    Module,
+    /// The directory of the file, e.g. `src/db` for `src/db/pool.rs`.
+    ///
+    /// Task spans all share the `tokio::task` target, so this is the key
+    /// that tells apart the components spawning them.
+    Directory,
+}
+
+impl GroupKey {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Self::Crate => "crate",
+            Self::Module => "module",
+            Self::Directory => "directory",
+        }
+    }
+
+    fn of(self, id: &CallsiteId) -> String {
+        match self {
+            Self::Crate => id.target.split("::").next().unwrap_or_default().to_string(),
+            Self::Module => id.target.clone(),
+            Self::Directory => match id.file.as_deref() {
+                Some(file) => file
+                    .rsplit_once(['/', '\\'])
+                    .map_or(".", |(dir, _)| dir)
+                    .to_string(),
+                None => "<unknown>".to_string(),
+            },
+        }
+    }
+}
+
+/// Totals of the callsites sharing a [`GroupKey`], see [`Snapshot::grouped`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupStats {
+    pub key: String,
+    pub total_busy: Duration,
+    pub polls: u64,
+    /// Closed spans.
+    pub spans: u64,
+    pub max_poll: Duration,
+    /// The callsite with the most busy time.
+    pub top: CallsiteId,
+    pub top_busy: Duration,
+}
+
+impl GroupStats {
+    /// Rolls up `rows`, by decreasing total busy time.
+    pub(crate) fn group<'a>(
+        rows: impl IntoIterator<Item = &'a OwnedCallsiteStats>,
+        key: GroupKey,
+    ) -> Vec<Self> {
+        let mut groups: BTreeMap<String, GroupStats> = BTreeMap::new();
+        for row in rows {
+            let name = key.of(&row.id);
+            let group = groups.entry(name.clone()).or_insert_with(|| GroupStats {
+                key: name,
+                total_busy: Duration::ZERO,
+                polls: 0,
+                spans: 0,
+                max_poll: Duration::ZERO,
+                top: row.id.clone(),
+                top_busy: row.total_busy,
+            });
+            group.total_busy += row.total_busy;
+            group.polls += row.polls;
+            group.spans += row.count;
+            group.max_poll = group.max_poll.max(row.max_poll);
+            if row.total_busy > group.top_busy {
+                group.top = row.id.clone();
+                group.top_busy = row.total_busy;
+            }
+        }
+        let mut groups: Vec<_> = groups.into_values().collect();
+        groups.sort_by_key(|g| std::cmp::Reverse(g.total_busy));
+        groups
+    }
+}
+
+/// Allowed slack when comparing a [`Snapshot`] against a baseline.
+///
+/// A value regresses if it exceeds the baseline by more than
+/// `abs + rel * baseline`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tolerances {
+    pub total_busy_abs: Duration,
+    pub total_busy_rel: f64,
+    pub max_poll_abs: Duration,
+    pub max_poll_rel: f64,
+}
+
+/// A callsite that got worse compared to the baseline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Regression {
+    pub id: CallsiteId,
+    pub kind: RegressionKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegressionKind {
+    /// Total busy time exceeds the baseline.
+    TotalBusy {
+        baseline: Duration,
+        current: Duration,
+    },
+    /// Longest single poll exceeds the baseline.
+    MaxPoll {
+        baseline: Duration,
+        current: Duration,
+    },
+    /// The callsite does not exist in the baseline.
+    NewCallsite {
+        total_busy: Duration,
+        max_poll: Duration,
+    },
+}
+
+impl Snapshot {
+    /// The current version of the serialized format.
+    pub const FORMAT_VERSION: u32 = 1;
+
+    pub fn new(callsites: Vec<OwnedCallsiteStats>) -> Self {
+        Self {
+            version: Self::FORMAT_VERSION,
+            active_duration: Duration::ZERO,
+            callsites,
+        }
+    }
+
+    /// Compares this snapshot against `baseline`.
+    ///
+    /// Rows are matched by [`CallsiteId`]; rows that share an id are merged.
+    /// Callsites missing from the baseline are reported as
+    /// [`RegressionKind::NewCallsite`], after all other regressions.
+    pub fn compare(&self, baseline: &Snapshot, tolerances: Tolerances) -> Vec<Regression> {
+        let baseline = baseline.by_id();
+        let mut regressions = Vec::new();
+        let mut new_callsites = Vec::new();
+
+        for (id, current) in self.by_id() {
+            let Some(base) = baseline.get(&id) else {
+                new_callsites.push(Regression {
+                    id,
+                    kind: RegressionKind::NewCallsite {
+                        total_busy: current.total_busy,
+                        max_poll: current.max_poll,
+                    },
+                });
+                continue;
+            };
+            if exceeds(
+                base.total_busy,
+                current.total_busy,
+                tolerances.total_busy_abs,
+                tolerances.total_busy_rel,
+            ) {
+                regressions.push(Regression {
+                    id: id.clone(),
+                    kind: RegressionKind::TotalBusy {
+                        baseline: base.total_busy,
+                        current: current.total_busy,
+                    },
+                });
+            }
+            if exceeds(
+                base.max_poll,
+                current.max_poll,
+                tolerances.max_poll_abs,
+                tolerances.max_poll_rel,
+            ) {
+                regressions.push(Regression {
+                    id,
+                    kind: RegressionKind::MaxPoll {
+                        baseline: base.max_poll,
+                        current: current.max_poll,
+                    },
+                });
+            }
+        }
+
+        regressions.extend(new_callsites);
+        regressions
+    }
+
+    /// Rolls up the callsites by crate, module or directory, by decreasing
+    /// total busy time, e.g. to see which component owns the blocking.
+    pub fn grouped(&self, key: GroupKey) -> Vec<GroupStats> {
+        GroupStats::group(&self.callsites, key)
+    }
+
+    /// Rows merged by id, in a deterministic order.
+    pub(crate) fn by_id(&self) -> BTreeMap<CallsiteId, Totals> {
+        let mut map = BTreeMap::<CallsiteId, Totals>::new();
+        for row in &self.callsites {
+            let totals = map.entry(row.id.clone()).or_default();
+            totals.total_busy += row.total_busy;
+            totals.max_poll = totals.max_poll.max(row.max_poll);
+            totals.polls += row.polls;
+            totals.count += row.count;
+        }
+        map
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Snapshot {
+    /// Writes the snapshot as JSON to `path`.
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        use std::io::Write as _;
+
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, self).map_err(std::io::Error::other)?;
+        file.flush()
+    }
+
+    /// Reads a snapshot previously written with [`save`](Self::save).
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let snapshot: Self = serde_json::from_reader(file).map_err(std::io::Error::other)?;
+        snapshot.check_version()
+    }
+
+    /// Serializes the snapshot to pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("snapshot serialization cannot fail")
+    }
+
+    /// Parses a snapshot from JSON.
+    pub fn from_json(json: &str) -> std::io::Result<Self> {
+        let snapshot: Self = serde_json::from_str(json).map_err(std::io::Error::other)?;
+        snapshot.check_version()
+    }
+
+    fn check_version(self) -> std::io::Result<Self> {
+        if self.version > Self::FORMAT_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported snapshot format version {} (supported: {})",
+                    self.version,
+                    Self::FORMAT_VERSION
+                ),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Totals {
+    pub(crate) total_busy: Duration,
+    pub(crate) max_poll: Duration,
+    pub(crate) polls: u64,
+    pub(crate) count: u64,
+}
+
+fn mean(polls: u64, total: Duration) -> Option<Duration> {
+    (polls > 0).then(|| Duration::from_nanos((total.as_nanos() / polls as u128) as u64))
+}
+
+fn busy_percent(busy: Duration, lifetime: Duration) -> Option<f64> {
+    (!lifetime.is_zero()).then(|| busy.as_secs_f64() / lifetime.as_secs_f64() * 100.0)
+}
+
+fn on_cpu_percent(cpu: Duration, busy: Duration) -> Option<f64> {
+    (!cpu.is_zero())
+        .then(|| crate::cpu_time::on_cpu_percent(cpu, busy))
+        .flatten()
+}
+
+fn stddev(polls: u64, total: Duration, sum_squares_ns: u128) -> Option<Duration> {
+    if polls == 0 {
+        return None;
+    }
+    let n = polls as u128;
+    let sum = total.as_nanos();
+    // n² · variance = n · Σx² - (Σx)², exact in integers unless it overflows.
+    let variance = match (n.checked_mul(sum_squares_ns), sum.checked_mul(sum)) {
+        (Some(a), Some(b)) => a.saturating_sub(b) as f64 / (n as f64 * n as f64),
+        _ => {
+            let mean = sum as f64 / n as f64;
+            (sum_squares_ns as f64 / n as f64 - mean * mean).max(0.0)
+        }
+    };
+    Some(Duration::from_nanos(variance.sqrt().round() as u64))
+}
+
+fn exceeds(baseline: Duration, current: Duration, abs: Duration, rel: f64) -> bool {
+    let allowed = baseline + abs + baseline.mul_f64(rel.max(0.0));
+    current > allowed
+}
+
+/// Serializes a [`Duration`] as integer nanoseconds.
+#[cfg(feature = "serde")]
+mod nanos {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_nanos() as u64)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_nanos)
+    }
+}
+
+#[cfg(feature = "serde")]
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Serializes an optional [`Duration`] as integer nanoseconds or `null`.
+#[cfg(feature = "serde")]
+mod opt_nanos {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => s.serialize_some(&(d.as_nanos() as u64)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(d).map(|d| d.map(Duration::from_nanos))
+    }
+}
+
+/// Serializes an optional [`SystemTime`] as integer nanoseconds since the
+/// Unix epoch or `null`.
+#[cfg(feature = "serde")]
+mod opt_unix_nanos {
+    use std::time::Duration;
+
+    use crate::time::{SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        t: &Option<SystemTime>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match t {
+            Some(t) => {
+                let nanos = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+                s.serialize_some(&(nanos as u64))
+            }
+            None => s.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        Option::<u64>::deserialize(d).map(|t| t.map(|t| UNIX_EPOCH + Duration::from_nanos(t)))
+    }
+}
+
+/// Serializes `(threshold, polls)` pairs as
+/// `[{"threshold_ns": 1000000, "polls": 3}, ...]`.
+#[cfg(feature = "serde")]
+mod over_threshold {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Counter {
+        threshold_ns: u64,
+        polls: u64,
+    }
+
+    pub(super) fn serialize<S: Serializer>(c: &[(Duration, u64)], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(c.iter().map(|&(threshold, polls)| Counter {
+            threshold_ns: threshold.as_nanos() as u64,
+            polls,
+        }))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(Duration, u64)>, D::Error> {
+        Vec::<Counter>::deserialize(d).map(|v| {
+            v.into_iter()
+                .map(|c| (Duration::from_nanos(c.threshold_ns), c.polls))
+                .collect()
+        })
+    }
+}
+
+/// Serializes `(quantile, duration)` pairs as
+/// `[{"quantile": 0.5, "value_ns": 1000}, ...]`.
+#[cfg(feature = "serde")]
+mod quantiles {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Quantile {
+        quantile: f64,
+        value_ns: u64,
+    }
+
+    pub(super) fn serialize<S: Serializer>(q: &[(f64, Duration)], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(q.iter().map(|&(quantile, value)| Quantile {
+            quantile,
+            value_ns: value.as_nanos() as u64,
+        }))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(f64, Duration)>, D::Error> {
+        Vec::<Quantile>::deserialize(d).map(|v| {
+            v.into_iter()
+                .map(|q| (q.quantile, Duration::from_nanos(q.value_ns)))
+                .collect()
+        })
+    }
+}
@@ -0,0 +1,44 @@
+use std::{future::Future, panic::Location};
+
+use tokio::task::JoinHandle;
+use tracing::{field, Instrument as _};
+
+/// Spawns `future` on the current tokio runtime inside a task span that the
+/// layer tracks, with the caller as the spawn location.
+///
+/// Unlike tokio's own task spans, this needs no `--cfg tokio_unstable`. The
+/// span declares `tokio_blocked.busy_ns`, `tokio_blocked.polls` and
+/// `tokio_blocked.max_poll_ns`, which the layer records when the span
+/// closes, so layers like a JSON fmt layer or `tracing-opentelemetry` pick
+/// them up. Tokio's spans don't declare them, so they don't get the fields.
+/// With `tokio_unstable`, the task is tracked through both spans.
+///
+/// Requires the `spawn` feature.
+///
+/// ```rust,no_run
+/// # async fn example() {
+/// let handle = tokio_blocked::spawn(async {
+///     // ...
+/// });
+/// handle.await.unwrap();
+/// # }
+/// ```
+#[track_caller]
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let location = Location::caller();
+    let span = tracing::info_span!(
+        target: "tokio_blocked::task",
+        "runtime.spawn",
+        loc.file = location.file(),
+        loc.line = location.line(),
+        loc.col = location.column(),
+        tokio_blocked.busy_ns = field::Empty,
+        tokio_blocked.polls = field::Empty,
+        tokio_blocked.max_poll_ns = field::Empty,
+    );
+    tokio::spawn(future.instrument(span))
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, path::Path, sync::Mutex, time::Duration};
+
+use rusqlite::{params, Connection, OptionalExtension as _};
+
+use crate::{
+    interval::{key, RowKey, Totals},
+    observer::BlockedObserver,
+    snapshot::{CallsiteStatsSnapshot, OwnedCallsiteStats},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Schema migrations, applied in order. The index of the last applied one
+/// plus one is kept as the `user_version` of the database.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE callsites (
+        id INTEGER PRIMARY KEY,
+        key TEXT NOT NULL UNIQUE,
+        target TEXT NOT NULL,
+        name TEXT NOT NULL,
+        file TEXT,
+        line INTEGER,
+        task_name TEXT,
+        runtime TEXT
+    );
+    CREATE TABLE samples (
+        timestamp_unix_ns INTEGER NOT NULL,
+        callsite_id INTEGER NOT NULL REFERENCES callsites (id),
+        busy_ns INTEGER NOT NULL,
+        polls INTEGER NOT NULL,
+        spans INTEGER NOT NULL,
+        max_poll_ns INTEGER NOT NULL
+    );
+    CREATE INDEX samples_by_callsite ON samples (callsite_id, timestamp_unix_ns);
+    CREATE INDEX samples_by_time ON samples (timestamp_unix_ns);
+"];
+
+/// Stores one sample per callsite and interval in a SQLite database, for
+/// historical analysis, e.g. the trend of a spawn location over weeks.
+///
+/// Like the [`TimeseriesWriter`](crate::TimeseriesWriter), the sink is an
+/// [observer](BlockedObserver) and every
+/// [`on_snapshot`](BlockedObserver::on_snapshot) call ends an interval. Use
+/// [`with_sqlite_sink`](crate::TokioBlockedLayer::with_sqlite_sink), so the
+/// reporter thread writes the samples and the runtime workers never wait on
+/// the database.
+///
+/// The database has two tables:
+///
+/// - `callsites`: `id`, `key`, `target`, `name`, `file`, `line`,
+///   `task_name` and `runtime`. `key` is the [`CallsiteId`](crate::CallsiteId)
+///   as text, which is stable across runs, so the same callsite keeps its
+///   `id`. Every [labeled runtime](crate::TokioBlockedLayer::with_runtime_label)
+///   has its own callsites.
+/// - `samples`: `timestamp_unix_ns`, `callsite_id`, and the `busy_ns`,
+///   `polls` and `spans` of the interval, plus `max_poll_ns`, the longest
+///   poll of the callsite so far. Callsites without activity are skipped.
+///
+/// ```sql
+/// SELECT date(s.timestamp_unix_ns / 1000000000, 'unixepoch') AS day, sum(s.busy_ns)
+/// FROM samples s JOIN callsites c ON c.id = s.callsite_id
+/// WHERE c.file = 'src/handlers.rs' AND c.line = 88
+/// GROUP BY day;
+/// ```
+///
+/// The schema is created or migrated when the database is opened. Requires
+/// the `sqlite` feature.
+pub struct SqliteSink {
+    state: Mutex<State>,
+}
+
+struct State {
+    conn: Connection,
+    // Ids of the callsites seen so far.
+    ids: HashMap<RowKey, i64>,
+    previous: HashMap<RowKey, Totals>,
+}
+
+impl SqliteSink {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Uses an already opened database, e.g. an in-memory one.
+    pub fn from_connection(mut conn: Connection) -> rusqlite::Result<Self> {
+        migrate(&mut conn)?;
+        Ok(Self {
+            state: Mutex::new(State {
+                conn,
+                ids: HashMap::new(),
+                previous: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Deletes the samples older than `older_than`, and the callsites left
+    /// without samples. Returns the number of deleted samples.
+    pub fn prune(&self, older_than: Duration) -> rusqlite::Result<usize> {
+        let cutoff = unix_nanos(SystemTime::now()).saturating_sub(older_than.as_nanos() as u64);
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let tx = state.conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM samples WHERE timestamp_unix_ns < ?1",
+            params![cutoff as i64],
+        )?;
+        tx.execute(
+            "DELETE FROM callsites WHERE id NOT IN (SELECT DISTINCT callsite_id FROM samples)",
+            [],
+        )?;
+        tx.commit()?;
+        // Deleted ids are looked up again, and recreated, on the next sample.
+        state.ids.clear();
+        Ok(deleted)
+    }
+
+    fn write(&self, callsites: &[CallsiteStatsSnapshot]) -> rusqlite::Result<()> {
+        let timestamp = unix_nanos(SystemTime::now()) as i64;
+        let mut state = self.state.lock().unwrap();
+        let State {
+            conn,
+            ids,
+            previous,
+        } = &mut *state;
+        let tx = conn.transaction()?;
+        for row in callsites {
+            let totals = Totals::of(row);
+            let delta = totals.since(previous.insert(key(row), totals).unwrap_or_default());
+            if delta.is_empty() {
+                continue;
+            }
+            let id = match ids.get(&key(row)) {
+                Some(&id) => id,
+                None => {
+                    let id = callsite_id(&tx, row)?;
+                    ids.insert(key(row), id);
+                    id
+                }
+            };
+            tx.prepare_cached(
+                "INSERT INTO samples (timestamp_unix_ns, callsite_id, busy_ns, polls, spans, max_poll_ns)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![
+                timestamp,
+                id,
+                delta.busy.as_nanos() as i64,
+                delta.polls as i64,
+                delta.spans as i64,
+                row.max_poll.as_nanos() as i64,
+            ])?;
+        }
+        tx.commit()
+    }
+}
+
+impl BlockedObserver for SqliteSink {
+    fn on_snapshot(&self, callsites: &[CallsiteStatsSnapshot]) {
+        if let Err(error) = self.write(callsites) {
+            tracing::warn!(
+                target: "tokio_blocked::sqlite",
+                %error,
+                "failed to write samples to the database",
+            );
+        }
+    }
+}
+
+/// Applies the migrations the database hasn't seen yet.
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= MIGRATIONS.len() {
+        return Ok(());
+    }
+    let tx = conn.transaction()?;
+    for migration in &MIGRATIONS[version..] {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()
+}
+
+/// The id of the callsite of `row`, inserting it if it is new.
+fn callsite_id(conn: &Connection, row: &CallsiteStatsSnapshot) -> rusqlite::Result<i64> {
+    let key = OwnedCallsiteStats::from(row).id.to_string();
+    let existing = conn
+        .prepare_cached("SELECT id FROM callsites WHERE key = ?1")?
+        .query_row([&key], |row| row.get(0))
+        .optional()?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    conn.prepare_cached(
+        "INSERT INTO callsites (key, target, name, file, line, task_name, runtime)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?
+    .execute(params![
+        key,
+        row.target,
+        row.name,
+        row.file,
+        row.line,
+        row.task_name,
+        row.runtime,
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    events::{BlockedPollInfo, BlockedTotalInfo},
+    snapshot::{CallsiteId, CallsiteStatsSnapshot, OwnedCallsiteStats},
+    BlockedObserver,
+};
+
+/// Maximum size of a datagram, so that it fits into the MTU of most networks.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends blocked polls and per-interval callsite statistics to a StatsD or
+/// DogStatsD server over UDP.
+///
+/// Register it with [`with_observer`](crate::TokioBlockedLayer::with_observer).
+/// It sends:
+///
+/// - `<prefix>.poll:<ms>|ms` for every poll that exceeded the single-poll
+///   threshold, as it completes.
+/// - `<prefix>.blocked_total:<ms>|ms` for every span whose total busy time
+///   exceeded the total threshold.
+/// - `<prefix>.busy:<ms>|c`, `<prefix>.polls:<n>|c` and
+///   `<prefix>.exceeded:<n>|c` with the growth of each callsite since the
+///   previous snapshot, and `<prefix>.max_poll:<ms>|g`, on every
+///   [report](crate::TokioBlockedLayer::with_report_interval).
+///
+/// Metrics carry DogStatsD tags as configured with
+/// [`with_tags`](Self::with_tags). The socket is non-blocking and send
+/// errors are only counted in [`dropped`](Self::dropped), so the exporter
+/// never blocks or fails the polled task.
+///
+/// Requires the `statsd` feature.
+///
+/// ```rust,no_run
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use tokio_blocked::{StatsdExporter, TokioBlockedLayer};
+///
+/// let statsd = StatsdExporter::connect("127.0.0.1:8125")?.with_prefix("myapp.blocked");
+/// let layer = TokioBlockedLayer::new()
+///     .with_observer(Arc::new(statsd))
+///     .with_report_interval(Duration::from_secs(10));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    prefix: String,
+    tags: StatsdTags,
+    // Totals of the previous snapshot, to send the growth as counters.
+    previous: Mutex<HashMap<CallsiteId, Totals>>,
+    dropped: AtomicU64,
+}
+
+/// Tag names of the callsite fields sent by [`StatsdExporter`]. `None`
+/// omits the field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsdTags {
+    pub file: Option<String>,
+    pub line: Option<String>,
+    pub name: Option<String>,
+    pub target: Option<String>,
+    /// Only set for callsites of tasks with a `task.name`.
+    pub task_name: Option<String>,
+}
+
+impl Default for StatsdTags {
+    /// `file`, `line`, `name` and `task_name`, without the target.
+    fn default() -> Self {
+        Self {
+            file: Some("file".to_string()),
+            line: Some("line".to_string()),
+            name: Some("name".to_string()),
+            target: None,
+            task_name: Some("task_name".to_string()),
+        }
+    }
+}
+
+impl StatsdTags {
+    /// No tags at all, e.g. for plain StatsD servers.
+    pub fn none() -> Self {
+        Self {
+            file: None,
+            line: None,
+            name: None,
+            target: None,
+            task_name: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    busy: Duration,
+    polls: u64,
+    exceeded: u64,
+}
+
+impl StatsdExporter {
+    /// Sends to the address `socket` is connected to.
+    ///
+    /// The socket is switched to non-blocking mode.
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            prefix: "tokio_blocked".to_string(),
+            tags: StatsdTags::default(),
+            previous: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Sends to `addr` from an ephemeral local port.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Self::new(socket)
+    }
+
+    /// Prefix of the metric names, `tokio_blocked` by default.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Sets the tags sent with every metric.
+    pub fn with_tags(mut self, tags: StatsdTags) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Number of datagrams that could not be sent.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn tags(
+        &self,
+        file: Option<&str>,
+        line: Option<u32>,
+        name: &str,
+        target: &str,
+        task_name: Option<&str>,
+    ) -> String {
+        let line = line.map(|l| l.to_string());
+        let fields = [
+            (&self.tags.file, file),
+            (&self.tags.line, line.as_deref()),
+            (&self.tags.name, Some(name)),
+            (&self.tags.target, Some(target)),
+            (&self.tags.task_name, task_name),
+        ];
+        let mut tags = String::new();
+        for (tag, value) in fields {
+            let (Some(tag), Some(value)) = (tag, value) else {
+                continue;
+            };
+            tags.push(if tags.is_empty() { '#' } else { ',' });
+            let _ = write!(tags, "{}:{}", sanitize(tag), sanitize(value));
+        }
+        tags
+    }
+
+    fn metric(
+        &self,
+        out: &mut String,
+        name: &str,
+        value: impl std::fmt::Display,
+        kind: &str,
+        tags: &str,
+    ) {
+        let _ = write!(out, "{}.{name}:{value}|{kind}", self.prefix);
+        if !tags.is_empty() {
+            let _ = write!(out, "|{tags}");
+        }
+    }
+
+    fn send(&self, datagram: &str) {
+        if self.socket.send(datagram.as_bytes()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl BlockedObserver for StatsdExporter {
+    fn on_poll_blocked(&self, info: &BlockedPollInfo<'_>) {
+        let (file, line, _) = info.location();
+        let tags = self.tags(
+            Some(file),
+            Some(line),
+            info.callsite_name,
+            info.callsite_target,
+            info.task_name.as_deref(),
+        );
+        let mut out = String::new();
+        self.metric(&mut out, "poll", millis(info.poll_duration), "ms", &tags);
+        self.send(&out);
+    }
+
+    fn on_span_closed(&self, info: &BlockedTotalInfo<'_>) {
+        let (file, line, _) = info.location();
+        let tags = self.tags(
+            Some(file),
+            Some(line),
+            info.callsite_name,
+            info.callsite_target,
+            info.task_name.as_deref(),
+        );
+        let mut out = String::new();
+        self.metric(&mut out, "blocked_total", millis(info.busy), "ms", &tags);
+        self.send(&out);
+    }
+
+    fn on_snapshot(&self, callsites: &[CallsiteStatsSnapshot]) {
+        let mut previous = self.previous.lock().unwrap();
+        let mut datagram = String::new();
+        let mut current = HashMap::with_capacity(callsites.len());
+        for row in callsites {
+            let id = OwnedCallsiteStats::from(row).id;
+            let totals = Totals {
+                busy: row.total_busy,
+                polls: row.polls,
+                exceeded: row.exceed_count,
+            };
+            // Totals shrink after a reset, then everything is new.
+            let before = previous
+                .get(&id)
+                .copied()
+                .filter(|b| b.polls <= totals.polls && b.busy <= totals.busy)
+                .unwrap_or_default();
+            let tags = self.tags(row.file, row.line, row.name, row.target, row.task_name);
+            let mut lines = String::new();
+            if totals.polls > before.polls {
+                self.metric(
+                    &mut lines,
+                    "busy",
+                    millis(totals.busy - before.busy),
+                    "c",
+                    &tags,
+                );
+                lines.push('\n');
+                self.metric(&mut lines, "polls", totals.polls - before.polls, "c", &tags);
+                lines.push('\n');
+                let exceeded = totals.exceeded.saturating_sub(before.exceeded);
+                self.metric(&mut lines, "exceeded", exceeded, "c", &tags);
+                lines.push('\n');
+            }
+            self.metric(&mut lines, "max_poll", millis(row.max_poll), "g", &tags);
+            current.insert(id, totals);
+
+            if !datagram.is_empty() && datagram.len() + 1 + lines.len() > MAX_DATAGRAM {
+                self.send(&datagram);
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&lines);
+        }
+        if !datagram.is_empty() {
+            self.send(&datagram);
+        }
+        *previous = current;
+    }
+}
+
+/// Milliseconds with microsecond precision.
+fn millis(d: Duration) -> f64 {
+    d.as_micros() as f64 / 1000.0
+}
+
+/// Replaces the characters that delimit metrics and tags.
+fn sanitize(value: &str) -> String {
+    value.replace(['|', ',', '#', '\n', '\r'], "_")
+}
@@ -0,0 +1,88 @@
+use std::{fmt, time::Duration};
+
+use tokio_metrics::TaskMetrics;
+
+use crate::{report::human, snapshot::CallsiteStatsSnapshot};
+
+/// Blocking statistics of the tasks named `task_name`, merged with the
+/// scheduling metrics of the [`TaskMonitor`](tokio_metrics::TaskMonitor)
+/// instrumenting them, see
+/// [`BlockedStatsHandle::combined_report`](crate::BlockedStatsHandle::combined_report).
+///
+/// `spans` to `polls_over_threshold` come from this crate: they cover the
+/// tracked task spans with the `task.name`, including spans that are still
+/// open. They are only counted with
+/// [`GroupBy::TaskName`](crate::GroupBy::TaskName) or
+/// [`GroupBy::Both`](crate::GroupBy::Both), and are zero otherwise.
+/// `scheduling` comes from tokio-metrics and covers the tasks the monitor
+/// instrumented. The two only describe the same tasks if the monitor
+/// instruments exactly the tasks of that name.
+///
+/// The [`Display`](fmt::Display) implementation renders both as text.
+///
+/// Requires the `tokio-metrics` feature.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CombinedTaskReport {
+    pub task_name: String,
+    /// Number of task spans.
+    pub spans: u64,
+    pub polls: u64,
+    pub total_busy: Duration,
+    pub max_poll: Duration,
+    /// Polls that reached the single-poll threshold.
+    pub polls_over_threshold: u64,
+    /// Cumulative metrics of the monitor, see
+    /// [`TaskMonitor::cumulative`](tokio_metrics::TaskMonitor::cumulative).
+    pub scheduling: TaskMetrics,
+}
+
+impl CombinedTaskReport {
+    pub(crate) fn new(
+        task_name: &str,
+        rows: &[CallsiteStatsSnapshot],
+        scheduling: TaskMetrics,
+    ) -> Self {
+        // Several rows if the tasks run on differently labeled runtimes.
+        let rows: Vec<_> = rows
+            .iter()
+            .filter(|row| row.name == "runtime.spawn" && row.task_name == Some(task_name))
+            .collect();
+        Self {
+            task_name: task_name.to_string(),
+            spans: rows.iter().map(|r| r.count).sum(),
+            polls: rows.iter().map(|r| r.polls).sum(),
+            total_busy: rows.iter().map(|r| r.total_busy).sum(),
+            max_poll: rows.iter().map(|r| r.max_poll).max().unwrap_or_default(),
+            polls_over_threshold: rows.iter().map(|r| r.exceed_count).sum(),
+            scheduling,
+        }
+    }
+}
+
+impl fmt::Display for CombinedTaskReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = &self.scheduling;
+        writeln!(f, "task {}", self.task_name)?;
+        writeln!(
+            f,
+            "tokio-blocked: {} spans, {} polls, {} busy, max poll {}, {} polls over threshold",
+            self.spans,
+            self.polls,
+            human(self.total_busy),
+            human(self.max_poll),
+            self.polls_over_threshold,
+        )?;
+        writeln!(
+            f,
+            "tokio-metrics: {} tasks, {} polls ({} slow), {} polling, {} scheduled, {} idle, mean first poll delay {}",
+            m.instrumented_count,
+            m.total_poll_count,
+            m.total_slow_poll_count,
+            human(m.total_poll_duration),
+            human(m.total_scheduled_duration),
+            human(m.total_idle_duration),
+            human(m.mean_first_poll_delay()),
+        )
+    }
+}
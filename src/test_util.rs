@@ -0,0 +1,239 @@
+//! Helpers for testing code against [`TokioBlockedLayer`](crate::TokioBlockedLayer).
+//!
+//! Requires the `test-util` feature.
+//!
+//! ```
+//! use tokio_blocked::{test_util::BlockedEventCollector, TokioBlockedLayer};
+//! use tracing_subscriber::layer::SubscriberExt as _;
+//!
+//! let collector = BlockedEventCollector::new();
+//! let subscriber = tracing_subscriber::registry()
+//!     .with(TokioBlockedLayer::new())
+//!     .with(collector.clone());
+//! let _guard = tracing::subscriber::set_default(subscriber);
+//!
+//! // ... run the code under test ...
+//!
+//! collector.assert_no_blocking();
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{field::Visit, Level};
+use tracing_core::{Event, Field, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// The kind of a captured event, derived from its target.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum EventKind {
+    /// `tokio_blocked::task_poll_blocked`
+    PollBlocked,
+    /// `tokio_blocked::task_first_poll_blocked`
+    FirstPollBlocked,
+    /// `tokio_blocked::task_poll_blocked_summary`
+    PollBlockedSummary,
+    /// `tokio_blocked::task_blocked_total`
+    BlockedTotal,
+    /// `tokio_blocked::task_poll_storm`
+    PollStorm,
+    /// `tokio_blocked::task_first_poll_delayed`
+    FirstPollDelayed,
+    /// `tokio_blocked::lock_wait`
+    LockWait,
+    /// `tokio_blocked::blocking_pool_op`
+    BlockingPoolOp,
+    /// `tokio_blocked::callsite_recovered`
+    CallsiteRecovered,
+    /// `tokio_blocked::task_migrations`
+    TaskMigrations,
+    /// `tokio_blocked::metrics`
+    Metrics,
+    /// `tokio_blocked::request_blocked`
+    RequestBlocked,
+    /// `tokio_blocked::request_total`
+    RequestTotal,
+    /// `tokio_blocked::poll_anomaly`
+    PollAnomaly,
+    /// `tokio_blocked::focus_poll`
+    FocusPoll,
+    /// `tokio_blocked::budget_exceeded`
+    BudgetExceeded,
+    /// `tokio_blocked::runtime_saturated`
+    RuntimeSaturated,
+    /// `tokio_blocked::sustained_blocking`
+    SustainedBlocking,
+    /// Any other `tokio_blocked` event.
+    Other,
+}
+
+impl EventKind {
+    fn from_target(target: &str) -> Self {
+        match target {
+            "tokio_blocked::task_poll_blocked" => Self::PollBlocked,
+            "tokio_blocked::task_first_poll_blocked" => Self::FirstPollBlocked,
+            "tokio_blocked::task_poll_blocked_summary" => Self::PollBlockedSummary,
+            "tokio_blocked::task_blocked_total" => Self::BlockedTotal,
+            "tokio_blocked::task_poll_storm" => Self::PollStorm,
+            "tokio_blocked::task_first_poll_delayed" => Self::FirstPollDelayed,
+            "tokio_blocked::lock_wait" => Self::LockWait,
+            "tokio_blocked::blocking_pool_op" => Self::BlockingPoolOp,
+            "tokio_blocked::callsite_recovered" => Self::CallsiteRecovered,
+            "tokio_blocked::task_migrations" => Self::TaskMigrations,
+            "tokio_blocked::metrics" => Self::Metrics,
+            "tokio_blocked::request_blocked" => Self::RequestBlocked,
+            "tokio_blocked::request_total" => Self::RequestTotal,
+            "tokio_blocked::poll_anomaly" => Self::PollAnomaly,
+            "tokio_blocked::focus_poll" => Self::FocusPoll,
+            "tokio_blocked::budget_exceeded" => Self::BudgetExceeded,
+            "tokio_blocked::runtime_saturated" => Self::RuntimeSaturated,
+            "tokio_blocked::sustained_blocking" => Self::SustainedBlocking,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// A structured copy of an event emitted by this crate.
+#[derive(Debug, Clone)]
+pub struct CapturedEvent {
+    pub kind: EventKind,
+    pub target: String,
+    pub level: Level,
+    /// The main duration of the event: the poll duration, total busy time,
+    /// first poll delay, lock wait or the longest deduplicated poll.
+    pub duration: Option<Duration>,
+    /// `callsite.file`
+    pub file: Option<String>,
+    /// `callsite.line`
+    pub line: Option<u32>,
+    /// All fields, formatted as strings.
+    pub fields: HashMap<String, String>,
+}
+
+/// A [`Layer`] that records the events of this crate, for assertions in
+/// tests.
+///
+/// Clones share the recorded events.
+#[derive(Clone, Default, Debug)]
+pub struct BlockedEventCollector {
+    events: Arc<Mutex<Vec<CapturedEvent>>>,
+}
+
+impl BlockedEventCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All events recorded so far.
+    pub fn events(&self) -> Vec<CapturedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Events with the given target.
+    pub fn with_target(&self, target: &str) -> Vec<CapturedEvent> {
+        self.filter(|e| e.target == target)
+    }
+
+    /// Events of the given kind.
+    pub fn of_kind(&self, kind: EventKind) -> Vec<CapturedEvent> {
+        self.filter(|e| e.kind == kind)
+    }
+
+    /// `tokio_blocked::task_poll_blocked` and
+    /// `tokio_blocked::task_first_poll_blocked` events.
+    pub fn poll_blocked_events(&self) -> Vec<CapturedEvent> {
+        self.filter(|e| matches!(e.kind, EventKind::PollBlocked | EventKind::FirstPollBlocked))
+    }
+
+    /// Removes all recorded events.
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    /// Panics if any blocked poll or blocked total event was recorded,
+    /// listing the offending locations.
+    #[track_caller]
+    pub fn assert_no_blocking(&self) {
+        let blocked = self.filter(|e| {
+            matches!(
+                e.kind,
+                EventKind::PollBlocked
+                    | EventKind::FirstPollBlocked
+                    | EventKind::PollBlockedSummary
+                    | EventKind::BlockedTotal
+            )
+        });
+        if blocked.is_empty() {
+            return;
+        }
+        let mut msg = format!("expected no blocking, got {} event(s):", blocked.len());
+        for e in &blocked {
+            msg.push_str(&format!(
+                "\n  {} at {}:{} ({:?})",
+                e.target,
+                e.file.as_deref().unwrap_or("<unknown>"),
+                e.line.unwrap_or(0),
+                e.duration.unwrap_or_default(),
+            ));
+        }
+        panic!("{msg}");
+    }
+
+    fn filter(&self, pred: impl Fn(&CapturedEvent) -> bool) -> Vec<CapturedEvent> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| pred(e))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<S: Subscriber> Layer<S> for BlockedEventCollector {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        if !meta.target().starts_with("tokio_blocked") {
+            return;
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let fields = visitor.0;
+        let duration = [
+            "poll_duration_ns",
+            "busy_ns",
+            "delay_ns",
+            "wait_ns",
+            "max_duration_ns",
+        ]
+        .iter()
+        .find_map(|name| fields.get(*name)?.parse().ok())
+        .map(Duration::from_nanos);
+        self.events.lock().unwrap().push(CapturedEvent {
+            kind: EventKind::from_target(meta.target()),
+            target: meta.target().to_string(),
+            level: *meta.level(),
+            duration,
+            file: fields.get("callsite.file").cloned(),
+            line: fields.get("callsite.line").and_then(|l| l.parse().ok()),
+            fields,
+        });
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor(HashMap<String, String>);
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+}
@@ -0,0 +1,22 @@
+//! The time types used throughout the crate.
+//!
+//! These are the types of [`std::time`], except on wasm targets without an
+//! operating system such as `wasm32-unknown-unknown`, where the clocks of
+//! `std` panic. There they are the types of the
+//! [`web-time`](https://docs.rs/web-time) crate, which reads the clocks of
+//! the JavaScript host. WASI targets use `std`, which reads the WASI clocks.
+//!
+//! [`Clock`](crate::Clock) implementations return the [`Instant`] of this
+//! module, and timestamps such as
+//! [`BlockedPollInfo::timestamp`](crate::events::BlockedPollInfo::timestamp)
+//! are its [`SystemTime`].
+//!
+//! Tracking works on all of these targets. Settings that start a background
+//! thread, such as
+//! [`with_report_interval`](crate::TokioBlockedLayer::with_report_interval),
+//! need a target with thread support.
+
+#[cfg(not(all(target_family = "wasm", target_os = "unknown")))]
+pub use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(all(target_family = "wasm", target_os = "unknown"))]
+pub use web_time::{Instant, SystemTime, UNIX_EPOCH};
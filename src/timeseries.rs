@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    interval::{key, RowKey, Totals},
+    observer::BlockedObserver,
+    snapshot::{CallsiteStatsSnapshot, ParentTask},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Appends one JSON object per callsite and interval to a file, as
+/// newline-delimited JSON, e.g. for Grafana's JSON or Infinity datasource.
+///
+/// The writer is an [observer](BlockedObserver): every
+/// [`on_snapshot`](BlockedObserver::on_snapshot) call ends an interval. Use
+/// [`with_timeseries_file`](crate::TokioBlockedLayer::with_timeseries_file),
+/// or register it with
+/// [`with_observer`](crate::TokioBlockedLayer::with_observer) and drive it
+/// with the reporter or
+/// [`BlockedStatsHandle::report`](crate::BlockedStatsHandle::report).
+///
+/// Each line describes what happened at a callsite since the previous
+/// snapshot, callsites without activity are skipped:
+///
+/// ```json
+/// {"timestamp_unix_ns":1700000000000000000,"target":"tokio::task","name":"runtime.spawn","file":"src/main.rs","line":12,"busy_ns":4000000,"polls":3,"spans":1,"max_poll_ns":2000000}
+/// ```
+///
+/// `busy_ns`, `polls` and `spans` are deltas, counted when spans close (or
+/// are [live flushed](crate::TokioBlockedLayer::with_live_flush)).
+/// `max_poll_ns` is the longest poll of the callsite so far. Rows of
+/// [labeled runtimes](crate::TokioBlockedLayer::with_runtime_label) carry
+/// the label as `runtime`.
+///
+/// Lines are flushed at the end of every interval. Requires the `serde`
+/// feature.
+pub struct TimeseriesWriter {
+    path: PathBuf,
+    max_file_size: Option<u64>,
+    state: Mutex<State>,
+}
+
+struct State {
+    out: BufWriter<File>,
+    // Bytes in the current file.
+    written: u64,
+    previous: HashMap<RowKey, Totals>,
+}
+
+#[derive(serde::Serialize)]
+struct Sample<'a> {
+    timestamp_unix_ns: u64,
+    target: &'a str,
+    name: &'a str,
+    file: Option<&'a str>,
+    line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource_type: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    async_op_source: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    lock_wait: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_task: Option<ParentTask>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    runtime: Option<&'a str>,
+    busy_ns: u64,
+    polls: u64,
+    spans: u64,
+    max_poll_ns: u64,
+}
+
+impl TimeseriesWriter {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (out, written) = open(&path)?;
+        Ok(Self {
+            path,
+            max_file_size: None,
+            state: Mutex::new(State {
+                out,
+                written,
+                previous: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Rotates the file once it exceeds `bytes`: it is renamed to
+    /// `<path>.1`, replacing an older rotated file, and a new file is
+    /// started. Rotation happens between intervals, so lines are never split.
+    pub fn with_max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Flushes buffered lines to the file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.state.lock().unwrap().out.flush()
+    }
+
+    fn write(&self, callsites: &[CallsiteStatsSnapshot]) -> io::Result<()> {
+        let timestamp_unix_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut line = Vec::new();
+        for row in callsites {
+            let totals = Totals::of(row);
+            let previous = state.previous.insert(key(row), totals).unwrap_or_default();
+            let delta = totals.since(previous);
+            if delta.is_empty() {
+                continue;
+            }
+            let sample = Sample {
+                timestamp_unix_ns,
+                target: row.target,
+                name: row.name,
+                file: row.file,
+                line: row.line,
+                resource_type: row.resource_type,
+                async_op_source: row.async_op_source,
+                task_name: row.task_name,
+                lock_wait: row.lock_wait,
+                parent_task: row.parent_task,
+                runtime: row.runtime,
+                busy_ns: delta.busy.as_nanos() as u64,
+                polls: delta.polls,
+                spans: delta.spans,
+                max_poll_ns: row.max_poll.as_nanos() as u64,
+            };
+            line.clear();
+            serde_json::to_writer(&mut line, &sample)?;
+            line.push(b'\n');
+            state.out.write_all(&line)?;
+            state.written += line.len() as u64;
+        }
+        state.out.flush()?;
+
+        if self.max_file_size.is_some_and(|max| state.written > max) {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+            (state.out, state.written) = open(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockedObserver for TimeseriesWriter {
+    fn on_snapshot(&self, callsites: &[CallsiteStatsSnapshot]) {
+        if let Err(error) = self.write(callsites) {
+            tracing::warn!(
+                target: "tokio_blocked::timeseries",
+                path = %self.path.display(),
+                %error,
+                "failed to write time series samples",
+            );
+        }
+    }
+}
+
+impl Drop for TimeseriesWriter {
+    fn drop(&mut self) {
+        if let Ok(state) = self.state.get_mut() {
+            let _ = state.out.flush();
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+    Ok((BufWriter::new(file), written))
+}
@@ -0,0 +1,151 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tracing::{field, Level, Span};
+
+use crate::BlockedStatsHandle;
+
+/// Tower middleware attributing blocked time to the requests it serves.
+///
+/// Every request is handled inside a `tokio_blocked.request` span, which the
+/// [`TokioBlockedLayer`](crate::TokioBlockedLayer) of `handle` recognizes.
+/// The span accumulates the time the response future was polled, and the
+/// polls of the tasks spawned while handling the request or spawned by those
+/// tasks in turn. Task polls are only seen with tokio's tracing instrumentation
+/// (`--cfg tokio_unstable`).
+///
+/// Once the response is ready, the span records
+/// `tokio_blocked.request_busy_ns` and `tokio_blocked.request_blocked_ns`,
+/// the part of polls that reached the layer's single-poll threshold. With
+/// [`with_warn_blocked`](Self::with_warn_blocked), requests blocked for
+/// longer emit a `tokio_blocked::request_blocked` warning.
+///
+/// Requires the `tower` feature.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use tokio_blocked::{BlockedTimeLayer, TokioBlockedLayer};
+///
+/// let layer = TokioBlockedLayer::new();
+/// let middleware =
+///     BlockedTimeLayer::new(layer.handle()).with_warn_blocked(Duration::from_millis(100));
+/// ```
+#[derive(Clone)]
+pub struct BlockedTimeLayer {
+    handle: BlockedStatsHandle,
+    warn_blocked: Option<Duration>,
+}
+
+impl BlockedTimeLayer {
+    /// Attributes blocked time with the layer of `handle`.
+    pub fn new(handle: BlockedStatsHandle) -> Self {
+        Self {
+            handle,
+            warn_blocked: None,
+        }
+    }
+
+    /// Warns about requests whose blocked time reached `threshold`.
+    pub fn with_warn_blocked(mut self, threshold: Duration) -> Self {
+        self.warn_blocked = Some(threshold);
+        self
+    }
+}
+
+impl<S> tower_layer::Layer<S> for BlockedTimeLayer {
+    type Service = BlockedTimeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BlockedTimeService {
+            inner,
+            handle: self.handle.clone(),
+            warn_blocked: self.warn_blocked,
+        }
+    }
+}
+
+/// Service created by [`BlockedTimeLayer`].
+#[derive(Clone)]
+pub struct BlockedTimeService<S> {
+    inner: S,
+    handle: BlockedStatsHandle,
+    warn_blocked: Option<Duration>,
+}
+
+impl<S, R> tower_service::Service<R> for BlockedTimeService<S>
+where
+    S: tower_service::Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let span = tracing::info_span!(
+            target: "tokio_blocked::request",
+            "tokio_blocked.request",
+            tokio_blocked.request_busy_ns = field::Empty,
+            tokio_blocked.request_blocked_ns = field::Empty,
+        );
+        let inner = span.in_scope(|| self.inner.call(request));
+        ResponseFuture {
+            inner,
+            span,
+            handle: self.handle.clone(),
+            warn_blocked: self.warn_blocked,
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`BlockedTimeService`].
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        span: Span,
+        handle: BlockedStatsHandle,
+        warn_blocked: Option<Duration>,
+    }
+}
+
+impl<F: Future> Future for ResponseFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let span: &Span = this.span;
+        let output = {
+            let _enter = span.enter();
+            ready!(this.inner.poll(cx))
+        };
+        let busy = span.id().and_then(|id| this.handle.request_busy(&id));
+        if let Some((busy, blocked)) = busy {
+            let busy_ns = busy.as_nanos() as u64;
+            let blocked_ns = blocked.as_nanos() as u64;
+            span.record("tokio_blocked.request_busy_ns", busy_ns);
+            span.record("tokio_blocked.request_blocked_ns", blocked_ns);
+            if let Some(threshold) = *this.warn_blocked {
+                if blocked >= threshold && this.handle.warnings_enabled() {
+                    tracing::event!(
+                        target: "tokio_blocked::request_blocked",
+                        parent: span,
+                        Level::WARN,
+                        busy_ns,
+                        blocked_ns,
+                        threshold_ns = threshold.as_nanos() as u64,
+                        "request blocked the runtime for too long",
+                    );
+                }
+            }
+        }
+        Poll::Ready(output)
+    }
+}
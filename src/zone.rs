@@ -0,0 +1,44 @@
+use tracing::{span::EnteredSpan, Span};
+
+/// Marks a section that blocks deliberately, e.g. a small file read at
+/// startup, until the returned [`BlockZoneGuard`] is dropped.
+///
+/// The zone is measured in its own row with
+/// [`Category::Acknowledged`](crate::Category::Acknowledged), located at the
+/// macro invocation, and its time is subtracted from the enclosing polls, so
+/// it neither warns nor counts towards the busy time of its task.
+///
+/// The guard must not be held across an `.await`.
+///
+/// ```rust
+/// fn load_config() -> String {
+///     let _zone = tokio_blocked::block_zone!("read config at startup");
+///     std::fs::read_to_string("config.toml").unwrap_or_default()
+/// }
+/// ```
+#[macro_export]
+macro_rules! block_zone {
+    ($reason:expr) => {
+        $crate::BlockZoneGuard::__enter($crate::__private::tracing::trace_span!(
+            target: "tokio_blocked::block_zone",
+            "tokio_blocked.block_zone",
+            reason = $reason,
+        ))
+    };
+}
+
+/// Ends the zone entered by [`block_zone!`](crate::block_zone) when dropped.
+#[derive(Debug)]
+#[must_use = "the zone ends when the guard is dropped"]
+pub struct BlockZoneGuard {
+    _span: EnteredSpan,
+}
+
+impl BlockZoneGuard {
+    #[doc(hidden)]
+    pub fn __enter(span: Span) -> Self {
+        Self {
+            _span: span.entered(),
+        }
+    }
+}
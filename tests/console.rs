@@ -0,0 +1,91 @@
+//! Runs the layer next to console-subscriber.
+//!
+//! A separate test binary, as the console panics once it sees more than a
+//! handful of `runtime.spawn` callsites, which the main tests create plenty
+//! of. Needs the `console-test` feature and `--cfg tokio_unstable`, which the
+//! console only works with, as in CI.
+#![cfg(all(feature = "console-test", tokio_unstable))]
+
+use std::{collections::BTreeSet, time::Duration};
+
+use tokio_blocked::{
+    test_util::BlockedEventCollector, BlockedStatsHandle, Diagnostics, TokioBlockedLayer,
+};
+use tracing_subscriber::layer::SubscriberExt as _;
+
+const THRESHOLD: Duration = Duration::from_millis(5);
+const BLOCK: Duration = Duration::from_millis(20);
+
+#[test]
+fn coexists_with_console_subscriber() {
+    fn run(subscriber: impl tracing::Subscriber + Send + Sync) {
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            tokio::spawn(async {
+                std::thread::sleep(BLOCK);
+                tokio::task::yield_now().await;
+            })
+            .await
+            .unwrap();
+        });
+    }
+
+    /// The task rows, with the counts that must not depend on the console.
+    fn tasks(handle: &BlockedStatsHandle) -> BTreeSet<(Option<u32>, u64, u64, u64)> {
+        handle
+            .snapshot()
+            .iter()
+            .filter(|row| row.name == "runtime.spawn")
+            .map(|row| (row.line, row.count, row.polls, row.exceed_count))
+            .collect()
+    }
+
+    fn assert_tracked(
+        handle: &BlockedStatsHandle,
+        collector: &BlockedEventCollector,
+        expected: &BTreeSet<(Option<u32>, u64, u64, u64)>,
+    ) {
+        let blocked = collector.poll_blocked_events();
+        assert_eq!(blocked.len(), 1, "{blocked:?}");
+        assert_eq!(blocked[0].file.as_deref(), Some(file!()));
+        assert_eq!(&tasks(handle), expected);
+        assert_eq!(handle.diagnostics(), Diagnostics::default());
+    }
+
+    // Without the console, the spawned task and `block_on`.
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let handle = layer.handle();
+    let collector = BlockedEventCollector::new();
+    run(tracing_subscriber::registry()
+        .with(layer)
+        .with(collector.clone()));
+    let expected = tasks(&handle);
+    assert_eq!(expected.len(), 2, "{expected:?}");
+    assert!(expected.iter().any(|&(_, _, _, exceeded)| exceeded == 1));
+    assert_tracked(&handle, &collector, &expected);
+
+    // Console first, then this layer.
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let handle = layer.handle();
+    let collector = BlockedEventCollector::new();
+    let (console, _server) = console_subscriber::ConsoleLayer::builder().build();
+    run(tracing_subscriber::registry()
+        .with(console)
+        .with(layer)
+        .with(collector.clone()));
+    assert_tracked(&handle, &collector, &expected);
+
+    // This layer first, then the console.
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let handle = layer.handle();
+    let collector = BlockedEventCollector::new();
+    let (console, _server) = console_subscriber::ConsoleLayer::builder().build();
+    run(tracing_subscriber::registry()
+        .with(layer)
+        .with(console)
+        .with(collector.clone()));
+    assert_tracked(&handle, &collector, &expected);
+}
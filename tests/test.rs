@@ -1,31 +1,5938 @@
-// use std::time::Duration;
-//
-// use tokio_blocked::TokioBlockedLayer;
-// use tracing_subscriber::{
-//     layer::SubscriberExt as _, util::SubscriberInitExt as _, EnvFilter, Layer,
-// };
-//
-// #[test]
-// fn main() {
-//     tracing_subscriber::registry()
-//         .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
-//         .with(TokioBlockedLayer::new())
-//         .init();
-//
-//     tracing::info!("Tokio Blocked Layer initialized");
-//
-//     let rt = tokio::runtime::Builder::new_multi_thread()
-//         .enable_all()
-//         .build()
-//         .expect("Failed to create Tokio runtime");
-//
-//     rt.block_on(async {
-//         tokio::task::spawn(async {
-//             eprintln!("task start");
-//             std::thread::sleep(Duration::from_secs(1));
-//             eprintln!("task end");
-//         })
-//         .await
-//     })
-//     .unwrap();
-// }
+use std::{panic::AssertUnwindSafe, time::Duration};
+
+use tokio_blocked::{
+    events::{BlockedPollInfo, BlockedTotalInfo},
+    test_util::{BlockedEventCollector, CapturedEvent, EventKind},
+    AdaptiveThresholds, BlockedObserver, CallsiteId, CallsiteSelector, CallsiteStatsSnapshot,
+    Category, ConfigError, Diagnostics, EvictionPolicy, GlobalError, Granularity, GroupBy,
+    GroupKey, HealthThresholds, LiveSpans, LocFieldNames, ManualClock, MarkdownOptions,
+    MetricNaming, OwnedCallsiteStats, PatternThresholds, RegressionKind, SaturationThresholds,
+    Snapshot, TokioBlockedLayer, Tolerances, ViolationMode,
+};
+use tracing::{Level, Span};
+use tracing_subscriber::layer::SubscriberExt as _;
+
+/// Creates a span that looks like a tokio task span to the layer.
+///
+/// A macro so that every use gets its own callsite.
+macro_rules! task_span {
+    () => {
+        tracing::trace_span!(
+            target: "tokio::task",
+            "runtime.spawn",
+            loc.file = file!(),
+            loc.line = line!(),
+            loc.col = column!(),
+        )
+    };
+}
+
+/// Simulates a single poll of `span` that blocks for `duration`.
+fn poll(span: &Span, duration: Duration) {
+    let _enter = span.enter();
+    std::thread::sleep(duration);
+}
+
+/// Simulates a single poll of `span` that takes `duration` on `clock`.
+fn poll_manual(span: &Span, clock: &ManualClock, duration: Duration) {
+    let _enter = span.enter();
+    clock.advance(duration);
+}
+
+/// Installs `layer` plus a [`BlockedEventCollector`] as the default subscriber
+/// for the current thread.
+fn install(layer: TokioBlockedLayer) -> (BlockedEventCollector, tracing::subscriber::DefaultGuard) {
+    let collector = BlockedEventCollector::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(layer)
+        .with(collector.clone());
+    let guard = tracing::subscriber::set_default(subscriber);
+    (collector, guard)
+}
+
+const THRESHOLD: Duration = Duration::from_millis(5);
+const BLOCK: Duration = Duration::from_millis(20);
+
+#[test]
+fn warns_on_blocked_poll() {
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll(&span, BLOCK);
+    poll(&span, Duration::ZERO);
+    drop(span);
+
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].level, Level::WARN);
+    assert_eq!(events[0].fields["callsite.file"], file!());
+}
+
+#[test]
+fn strict_mode_panics_with_location_and_duration() {
+    let layer = TokioBlockedLayer::strict().with_warn_busy_single_poll(Some(THRESHOLD));
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    let line = line!() - 1;
+    let err = std::panic::catch_unwind(AssertUnwindSafe(|| poll(&span, BLOCK))).unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains(&format!("{}:{}", file!(), line)), "{msg}");
+    assert!(msg.contains("ms"), "{msg}");
+
+    // The span is still usable after the panic.
+    poll(&span, Duration::ZERO);
+    drop(span);
+    assert!(collector.events().is_empty());
+}
+
+#[test]
+fn strict_mode_ignores_fast_polls() {
+    let layer = TokioBlockedLayer::strict().with_warn_busy_single_poll(Some(THRESHOLD));
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for _ in 0..10 {
+        poll(&span, Duration::ZERO);
+    }
+}
+
+#[test]
+fn record_mode_collects_violations() {
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_on_violation(ViolationMode::Record);
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let fast = task_span!();
+    poll(&fast, Duration::ZERO);
+    assert!(handle.take_violations().is_empty());
+
+    let slow = task_span!();
+    let line = line!() - 1;
+    poll(&slow, BLOCK);
+
+    let violations = handle.take_violations();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].file, file!());
+    assert_eq!(violations[0].line, line);
+    assert!(violations[0].poll_duration >= BLOCK);
+    assert_eq!(violations[0].threshold, THRESHOLD);
+    assert!(handle.take_violations().is_empty());
+    assert!(collector.events().is_empty());
+}
+
+#[cfg(feature = "runtime-metrics")]
+#[test]
+fn warning_includes_runtime_metrics() {
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let (collector, _guard) = install(layer);
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let line = rt.block_on(async {
+        let span = task_span!();
+        poll(&span, BLOCK);
+        line!() - 2
+    });
+
+    // With `tokio_unstable`, the `block_on` task itself is reported as well.
+    let events: Vec<_> = collector
+        .poll_blocked_events()
+        .into_iter()
+        .filter(|e| e.line == Some(line))
+        .collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].fields["runtime.num_workers"], "1");
+    assert!(events[0].fields.contains_key("runtime.num_alive_tasks"));
+    assert!(events[0].fields.contains_key("runtime.global_queue_depth"));
+}
+
+#[test]
+fn warning_without_runtime_omits_runtime_metrics() {
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll(&span, BLOCK);
+
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert!(!events[0].fields.contains_key("runtime.num_workers"));
+}
+
+#[test]
+fn chrome_trace_export() {
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_trace_recorder(2, THRESHOLD);
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    // Below the minimum duration, not recorded.
+    poll(&span, Duration::ZERO);
+    poll(&span, BLOCK);
+    std::thread::scope(|s| {
+        s.spawn(|| poll(&span, BLOCK));
+    });
+    // Exceeds the limit, so the first slice is evicted.
+    poll(&span, BLOCK);
+
+    let mut out = Vec::new();
+    handle.export_chrome_trace(&mut out).unwrap();
+    let trace: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    let slices: Vec<_> = trace
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|e| e["ph"] == "X")
+        .collect();
+    assert_eq!(slices.len(), 2);
+    assert_ne!(slices[0]["tid"], slices[1]["tid"]);
+    assert!(slices[0]["ts"].as_f64().unwrap() < slices[1]["ts"].as_f64().unwrap());
+    assert!(slices[1]["dur"].as_f64().unwrap() >= BLOCK.as_micros() as f64);
+    assert_eq!(slices[1]["args"]["callsite.file"], file!());
+}
+
+#[test]
+fn chrome_trace_export_without_recorder() {
+    let layer = TokioBlockedLayer::new();
+    let mut out = Vec::new();
+    layer.export_chrome_trace(&mut out).unwrap();
+    assert_eq!(out, b"[]");
+}
+
+#[test]
+fn escalation_flips_level_at_configured_count() {
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_escalation(3, Level::ERROR);
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for _ in 0..4 {
+        poll(&span, BLOCK);
+    }
+    let levels: Vec<_> = collector
+        .poll_blocked_events()
+        .iter()
+        .map(|e| e.level)
+        .collect();
+    assert_eq!(
+        levels,
+        [Level::WARN, Level::WARN, Level::ERROR, Level::ERROR]
+    );
+    let events = collector.poll_blocked_events();
+    assert_eq!(events[1].fields["escalated"], "false");
+    assert_eq!(events[2].fields["escalated"], "true");
+    assert_eq!(events[2].fields["exceed_count"], "3");
+
+    // Resetting de-escalates.
+    handle.reset();
+    poll(&span, BLOCK);
+    let events = collector.poll_blocked_events();
+    assert_eq!(events[4].level, Level::WARN);
+    assert_eq!(events[4].fields["exceed_count"], "1");
+}
+
+#[test]
+fn escalation_quiet_period_deescalates() {
+    let quiet = Duration::from_millis(50);
+    // Independent of the order of the two calls.
+    for layer in [
+        TokioBlockedLayer::new()
+            .with_escalation(2, Level::ERROR)
+            .with_escalation_quiet_period(quiet),
+        TokioBlockedLayer::new()
+            .with_escalation_quiet_period(quiet)
+            .with_escalation(2, Level::ERROR),
+    ] {
+        let layer = layer.with_warn_busy_single_poll(Some(THRESHOLD));
+        let (collector, _guard) = install(layer);
+
+        let span = task_span!();
+        poll(&span, BLOCK);
+        poll(&span, BLOCK);
+        std::thread::sleep(Duration::from_millis(80));
+        poll(&span, BLOCK);
+
+        let events = collector.poll_blocked_events();
+        let levels: Vec<_> = events.iter().map(|e| e.level).collect();
+        assert_eq!(levels, [Level::WARN, Level::ERROR, Level::WARN]);
+        // The all-time count is not affected by de-escalation.
+        assert_eq!(events[2].fields["exceed_count"], "3");
+    }
+}
+
+#[test]
+fn dedup_window_groups_by_location() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_dedup_window(Duration::from_secs(1))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let spans: Vec<_> = (0..3).map(|_| task_span!()).collect();
+    let other = task_span!();
+    for span in &spans {
+        poll_manual(span, &clock, BLOCK);
+    }
+    poll_manual(&other, &clock, BLOCK);
+    assert_eq!(collector.poll_blocked_events().len(), 2);
+    assert!(collector.of_kind(EventKind::PollBlockedSummary).is_empty());
+
+    // Still inside the window.
+    clock.advance(Duration::from_millis(500));
+    poll_manual(&spans[0], &clock, BLOCK * 2);
+    assert_eq!(collector.poll_blocked_events().len(), 2);
+
+    // The window has closed, so the next warning flushes the summary and is
+    // emitted again.
+    clock.advance(Duration::from_millis(500));
+    poll_manual(&spans[0], &clock, BLOCK);
+    assert_eq!(collector.poll_blocked_events().len(), 3);
+    let summaries = collector.of_kind(EventKind::PollBlockedSummary);
+    assert_eq!(summaries.len(), 1);
+    let summary = &summaries[0].fields;
+    assert_eq!(summary["occurrences"], "4");
+    assert_eq!(
+        summary["max_duration_ns"],
+        (BLOCK * 2).as_nanos().to_string()
+    );
+    assert_eq!(
+        summary["total_duration_ns"],
+        (BLOCK * 5).as_nanos().to_string()
+    );
+
+    // `other` only warned once, so no summary; the new window of `spans`
+    // has nothing suppressed either.
+    handle.flush();
+    assert_eq!(collector.of_kind(EventKind::PollBlockedSummary).len(), 1);
+
+    // A report summarizes a closed window without another warning, but
+    // leaves open ones alone.
+    poll_manual(&other, &clock, BLOCK);
+    poll_manual(&other, &clock, BLOCK);
+    handle.report();
+    assert_eq!(collector.of_kind(EventKind::PollBlockedSummary).len(), 1);
+    clock.advance(Duration::from_secs(1));
+    handle.report();
+    let summaries = collector.of_kind(EventKind::PollBlockedSummary);
+    assert_eq!(summaries.len(), 2);
+    assert_eq!(summaries[1].fields["occurrences"], "2");
+}
+
+#[test]
+fn dedup_window_groups_warnings_by_file() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_dedup_window(Duration::from_secs(1))
+        .with_group_warnings_by(Granularity::File)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let line = line!();
+    let first = task_span!();
+    let second = task_span!();
+    poll_manual(&first, &clock, BLOCK);
+    poll_manual(&second, &clock, BLOCK);
+    poll_manual(&first, &clock, BLOCK);
+    let warnings = collector.poll_blocked_events();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].line, Some(line + 1));
+
+    handle.flush();
+    let summaries = collector.of_kind(EventKind::PollBlockedSummary);
+    assert_eq!(summaries.len(), 1);
+    let summary = &summaries[0].fields;
+    assert_eq!(summary["occurrences"], "3");
+    assert_eq!(summary["callsite.file"], file!());
+    assert!(!summary.contains_key("callsite.line"));
+    assert_eq!(summary["lines"], format!("{},{}", line + 1, line + 2));
+
+    // Statistics are still per callsite.
+    assert_eq!(handle.snapshot().len(), 2);
+}
+
+#[test]
+fn dedup_window_flushes_on_drop() {
+    let outer = BlockedEventCollector::new();
+    let _outer_guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(outer.clone()));
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_dedup_window(Duration::from_secs(1))
+        .with_clock(clock.clone());
+    let (_collector, guard) = install(layer);
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    // Dropping the subscriber drops the layer, which emits the summary to
+    // the now current subscriber.
+    drop(guard);
+    let summaries = outer.of_kind(EventKind::PollBlockedSummary);
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].fields["occurrences"], "2");
+}
+
+#[test]
+fn adaptive_threshold_tightens_under_load() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_adaptive_thresholds(
+            AdaptiveThresholds::linear(0.2, 0.8, 0.5)
+                .window(Duration::from_millis(100))
+                .workers(1),
+        )
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+    let span = task_span!();
+    let short = Duration::from_millis(1);
+    let poll = Duration::from_millis(4);
+
+    // Low load: mostly idle between polls.
+    for _ in 0..12 {
+        poll_manual(&span, &clock, short);
+        clock.advance(Duration::from_millis(9));
+    }
+    poll_manual(&span, &clock, poll);
+    assert!(collector.poll_blocked_events().is_empty());
+
+    // High load: back to back polls for more than two windows.
+    for _ in 0..250 {
+        poll_manual(&span, &clock, short);
+    }
+    poll_manual(&span, &clock, poll);
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].duration, Some(poll));
+    assert_eq!(
+        events[0].fields["threshold_ns"],
+        THRESHOLD.as_nanos().to_string()
+    );
+    assert_eq!(
+        events[0].fields["effective_threshold_ns"],
+        (THRESHOLD / 2).as_nanos().to_string()
+    );
+    assert_eq!(events[0].fields["load"], "1.0");
+}
+
+fn row(line: u32, total_busy_ms: u64, max_poll_ms: u64) -> OwnedCallsiteStats {
+    OwnedCallsiteStats {
+        lock_wait: false,
+        allowed: false,
+        category: Category::Async,
+        id: CallsiteId {
+            target: "tokio::task".to_string(),
+            name: "runtime.spawn".to_string(),
+            file: Some("src/main.rs".to_string()),
+            line: Some(line),
+            resource_type: None,
+            async_op_source: None,
+            task_name: None,
+            parent_task_file: None,
+            parent_task_line: None,
+            runtime: None,
+        },
+        total_busy: Duration::from_millis(total_busy_ms),
+        total_cpu: Duration::ZERO,
+        count: 1,
+        exceed_count: 0,
+        first_poll_blocked: 0,
+        max_poll: Duration::from_millis(max_poll_ms),
+        ewma_poll: None,
+        poll_histogram: None,
+        percentiles: Vec::new(),
+        polls: 1,
+        poll_sum_squares_ns: 0,
+        spawn_to_first_poll: Duration::ZERO,
+        never_polled: 0,
+        migrations: 0,
+        min_lifetime: Duration::ZERO,
+        total_lifetime: Duration::ZERO,
+        max_lifetime: Duration::ZERO,
+        first_exceeded_at: None,
+        patterns: Default::default(),
+        over_threshold: Vec::new(),
+    }
+}
+
+#[test]
+fn snapshot_compare_detects_regressions() {
+    let baseline = Snapshot::new(vec![row(1, 100, 10), row(2, 100, 10)]);
+    let tolerances = Tolerances {
+        total_busy_abs: Duration::from_millis(5),
+        total_busy_rel: 0.1,
+        max_poll_abs: Duration::from_millis(1),
+        max_poll_rel: 0.0,
+    };
+
+    // Within tolerance.
+    let current = Snapshot::new(vec![row(1, 115, 11), row(2, 50, 5)]);
+    assert!(current.compare(&baseline, tolerances).is_empty());
+
+    // Injected regression on line 2, plus a new callsite.
+    let current = Snapshot::new(vec![row(1, 100, 10), row(2, 116, 12), row(3, 1, 1)]);
+    let regressions = current.compare(&baseline, tolerances);
+    assert_eq!(regressions.len(), 3);
+    assert_eq!(regressions[0].id.line, Some(2));
+    assert_eq!(
+        regressions[0].kind,
+        RegressionKind::TotalBusy {
+            baseline: Duration::from_millis(100),
+            current: Duration::from_millis(116),
+        }
+    );
+    assert!(matches!(
+        regressions[1].kind,
+        RegressionKind::MaxPoll { .. }
+    ));
+    assert_eq!(regressions[2].id.line, Some(3));
+    assert!(matches!(
+        regressions[2].kind,
+        RegressionKind::NewCallsite { .. }
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn snapshot_save_load_roundtrip() {
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll(&span, BLOCK);
+    drop(span);
+
+    let snapshot = handle.owned_snapshot();
+    assert_eq!(snapshot.callsites.len(), 1);
+    assert!(snapshot.callsites[0].max_poll >= BLOCK);
+
+    let path = std::env::temp_dir().join(format!(
+        "tokio-blocked-snapshot-{}.json",
+        std::process::id()
+    ));
+    snapshot.save(&path).unwrap();
+    let loaded = Snapshot::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded, snapshot);
+    assert!(loaded.compare(&snapshot, Tolerances::default()).is_empty());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn owned_callsite_stats_serde_roundtrip() {
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll(&span, BLOCK);
+    drop(span);
+
+    let stats = handle.snapshot().pop().unwrap();
+    let owned = OwnedCallsiteStats::from(stats.clone());
+    assert_eq!(owned.id.name, stats.name);
+    assert_eq!(owned.id.file.as_deref(), stats.file);
+    assert_eq!(owned.total_busy, stats.total_busy);
+
+    // The borrowed and the owned form serialize identically.
+    let json = serde_json::to_value(&stats).unwrap();
+    assert_eq!(json, serde_json::to_value(&owned).unwrap());
+    assert!(json["total_busy_ns"].is_u64());
+    assert!(json["first_exceeded_at_unix_ns"].is_u64());
+    let back: OwnedCallsiteStats = serde_json::from_value(json).unwrap();
+    assert_eq!(back, owned);
+
+    let snapshot = Snapshot::new(vec![owned]);
+    let json = snapshot.to_json();
+    assert_eq!(Snapshot::from_json(&json).unwrap(), snapshot);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn snapshot_format_versioning() {
+    // Fields added after the initial format default when missing.
+    let json = r#"{
+        "version": 1,
+        "callsites": [{
+            "target": "tokio::task",
+            "name": "runtime.spawn",
+            "file": "src/main.rs",
+            "line": 3,
+            "total_busy_ns": 1000,
+            "count": 2,
+            "some_future_field": true
+        }]
+    }"#;
+    let snapshot = Snapshot::from_json(json).unwrap();
+    assert_eq!(snapshot.callsites[0].total_busy, Duration::from_micros(1));
+    assert_eq!(snapshot.callsites[0].max_poll, Duration::ZERO);
+
+    let newer = json.replace(r#""version": 1"#, r#""version": 2"#);
+    let err = Snapshot::from_json(&newer).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "serde")]
+fn fixture(name: &str) -> Snapshot {
+    Snapshot::load(format!(
+        "{}/tests/fixtures/{name}",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap()
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn report_table_from_snapshot_file() {
+    use tokio_blocked::{SortBy, TableOptions};
+
+    let snapshot = fixture("before.json");
+    assert_eq!(
+        snapshot.render_table(&TableOptions::default()),
+        "\
+LOCATION        NAME           SPANS  POLLS  TOTAL BUSY   MAX POLL
+src/api.rs:12   runtime.spawn     10    200    40.00 ms    2.00 ms
+src/db.rs:40    runtime.spawn      2     20     5.00 ms    1.00 ms
+src/cache.rs:7  runtime.spawn      1    300     3.00 ms  500.00 µs
+"
+    );
+
+    let opts = TableOptions {
+        top: Some(1),
+        sort: "polls".parse().unwrap(),
+        ..Default::default()
+    };
+    let table = snapshot.render_table(&opts);
+    assert_eq!(table.lines().count(), 2);
+    assert!(table.lines().nth(1).unwrap().starts_with("src/cache.rs:7 "));
+
+    let opts = TableOptions {
+        filter: Some("db.rs".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(snapshot.render_table(&opts).lines().count(), 2);
+    assert!("nope".parse::<SortBy>().is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn report_diff_between_snapshot_files() {
+    use tokio_blocked::TableOptions;
+
+    let (before, after) = (fixture("before.json"), fixture("after.json"));
+    let diff = after.render_diff(&before, Tolerances::default(), &TableOptions::default());
+    let lines: Vec<_> = diff.lines().collect();
+    assert_eq!(lines.len(), 5, "{diff}");
+    assert!(lines[0].starts_with("LOCATION "));
+    assert!(lines[0].ends_with(" STATUS"));
+    // Sorted by the growth of the total busy time.
+    assert!(lines[1].starts_with("src/db.rs:40 "));
+    assert!(lines[1].contains("+20.00 ms"));
+    assert!(lines[1].ends_with(" regressed"));
+    assert!(lines[2].contains("(Mutex, Mutex::lock)"));
+    assert!(lines[2].ends_with(" new"));
+    assert!(lines[3].starts_with("src/cache.rs:7 "));
+    assert!(lines[3].ends_with(" removed"));
+    assert!(lines[4].starts_with("src/api.rs:12 "));
+    assert!(lines[4].ends_with("-10.00 ms     2.00 ms    2.00 ms"));
+
+    let opts = TableOptions {
+        filter: Some("Mutex".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        after
+            .render_diff(&before, Tolerances::default(), &opts)
+            .lines()
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn ewma_of_poll_durations() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_ewma_alpha(0.25)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let samples = [10u64, 20, 5, 40, 40, 1];
+    let mut expected: Option<f64> = None;
+    let span = task_span!();
+    for ms in samples {
+        poll_manual(&span, &clock, Duration::from_millis(ms));
+        let x = ms as f64;
+        expected = Some(match expected {
+            None => x,
+            Some(e) => 0.25 * x + 0.75 * e,
+        });
+    }
+    drop(span);
+
+    let stats = handle.snapshot().pop().unwrap();
+    let ewma_ms = stats.ewma_poll.unwrap().as_secs_f64() * 1000.0;
+    assert!((ewma_ms - expected.unwrap()).abs() < 1e-6, "{ewma_ms}");
+
+    handle.reset();
+    let span = task_span!();
+    drop(span);
+    assert!(handle.snapshot().iter().all(|s| s.ewma_poll.is_none()));
+}
+
+#[test]
+fn ewma_alpha_of_nan_follows_the_last_poll() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_ewma_alpha(f64::NAN)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, THRESHOLD);
+    drop(span);
+    assert_eq!(handle.snapshot()[0].ewma_poll, Some(THRESHOLD));
+}
+
+#[test]
+fn ewma_disabled_by_default() {
+    let layer = TokioBlockedLayer::new();
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let span = task_span!();
+    poll(&span, Duration::ZERO);
+    drop(span);
+    assert_eq!(handle.snapshot()[0].ewma_poll, None);
+}
+
+#[test]
+fn stddev_of_poll_durations() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    // Two spans of the same callsite, merged at close.
+    let samples = [[2u64, 4, 4, 4], [5, 5, 7, 9]];
+    for chunk in samples {
+        let span = task_span!();
+        for ms in chunk {
+            poll_manual(&span, &clock, Duration::from_millis(ms));
+        }
+    }
+
+    let stats = handle.snapshot().pop().unwrap();
+    assert_eq!(stats.polls, 8);
+    assert_eq!(stats.mean_poll(), Some(Duration::from_millis(5)));
+    // Population stddev of the sequence is exactly 2.
+    assert_eq!(stats.stddev_poll(), Some(Duration::from_millis(2)));
+}
+
+#[test]
+fn stddev_with_large_counts() {
+    // A billion polls alternating between 1ms and 3ms.
+    let polls = 1_000_000_000u64;
+    let mut stats = row(1, 0, 3);
+    stats.polls = polls;
+    stats.total_busy = Duration::from_millis(2) * polls as u32;
+    stats.poll_sum_squares_ns = (polls as u128 / 2) * (1_000_000u128.pow(2) + 3_000_000u128.pow(2));
+    assert_eq!(stats.mean_poll(), Some(Duration::from_millis(2)));
+    assert_eq!(stats.stddev_poll(), Some(Duration::from_millis(1)));
+
+    // Constant durations have no spread.
+    stats.poll_sum_squares_ns = polls as u128 * 2_000_000u128.pow(2);
+    assert_eq!(stats.stddev_poll(), Some(Duration::ZERO));
+
+    let mut empty = row(1, 0, 0);
+    empty.polls = 0;
+    assert_eq!(empty.stddev_poll(), None);
+}
+
+#[test]
+fn warnings_toggle_at_runtime() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_warnings_enabled(false)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    assert!(!handle.warnings_enabled());
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    assert!(collector.events().is_empty());
+
+    handle.set_warnings_enabled(true);
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    assert_eq!(collector.poll_blocked_events().len(), 2);
+    assert_eq!(collector.of_kind(EventKind::BlockedTotal).len(), 1);
+
+    handle.set_warnings_enabled(false);
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    assert_eq!(collector.events().len(), 3);
+
+    // Statistics were collected the whole time.
+    let polls: u64 = handle.snapshot().iter().map(|s| s.polls).sum();
+    assert_eq!(polls, 4);
+    let exceeded: u64 = handle.snapshot().iter().map(|s| s.exceed_count).sum();
+    assert_eq!(exceeded, 4);
+}
+
+#[test]
+fn pause_and_resume_stats() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    drop(span);
+
+    // A blocking burst while paused.
+    handle.pause_stats();
+    let span = task_span!();
+    let paused_line = line!() - 1;
+    poll_manual(&span, &clock, Duration::from_secs(1));
+    drop(span);
+    handle.resume_stats();
+
+    let span = task_span!();
+    poll_manual(&span, &clock, Duration::from_millis(2));
+    drop(span);
+
+    let total_busy: Duration = handle.snapshot().iter().map(|s| s.total_busy).sum();
+    let count: u64 = handle.snapshot().iter().map(|s| s.count).sum();
+    assert_eq!(total_busy, Duration::from_millis(3));
+    assert_eq!(count, 2);
+    // Warnings are not affected by pausing, but the callsite only seen while
+    // paused has no row.
+    assert_eq!(collector.poll_blocked_events().len(), 1);
+    assert_eq!(handle.snapshot().len(), 2);
+    assert!(handle
+        .snapshot()
+        .iter()
+        .all(|row| row.line != Some(paused_line)));
+
+    // Only the active periods count.
+    assert_eq!(handle.stats_active_duration(), Duration::from_millis(3));
+    assert_eq!(
+        handle.owned_snapshot().active_duration,
+        Duration::from_millis(3)
+    );
+
+    handle.reset();
+    assert_eq!(handle.stats_active_duration(), Duration::ZERO);
+}
+
+#[test]
+fn clear_single_callsite() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new().with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let a = task_span!();
+    let a_line = line!() - 1;
+    let b = task_span!();
+    let b_line = line!() - 1;
+    poll_manual(&a, &clock, BLOCK);
+    poll_manual(&b, &clock, BLOCK);
+    drop((a, b));
+    assert_eq!(handle.snapshot().len(), 2);
+
+    assert_eq!(
+        handle.clear_callsite(&CallsiteSelector::location(file!(), a_line)),
+        1
+    );
+    let remaining = handle.snapshot();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].file, Some(file!()));
+    assert_eq!(remaining[0].line, Some(b_line));
+
+    assert_eq!(
+        handle.clear_callsite(&CallsiteSelector::location(file!(), a_line)),
+        0
+    );
+    assert_eq!(
+        handle.clear_callsite(&CallsiteSelector::name("tokio::task", "runtime.spawn")),
+        1
+    );
+    assert!(handle.snapshot().is_empty());
+}
+
+#[test]
+fn mute_callsite_suppresses_warnings() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let muted = task_span!();
+    let muted_line = line!() - 1;
+    let other = task_span!();
+    let selector = CallsiteSelector::location(file!(), muted_line);
+    handle.mute_callsite(selector.clone());
+
+    poll_manual(&muted, &clock, BLOCK);
+    poll_manual(&other, &clock, BLOCK);
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert_ne!(events[0].fields["callsite.line"], muted_line.to_string());
+
+    assert!(handle.unmute_callsite(&selector));
+    assert!(!handle.unmute_callsite(&selector));
+    poll_manual(&muted, &clock, BLOCK);
+    assert_eq!(collector.poll_blocked_events().len(), 2);
+
+    // Muted polls were still counted.
+    drop((muted, other));
+    let stats = handle.snapshot();
+    let muted_stats = stats.iter().find(|s| s.line == Some(muted_line)).unwrap();
+    assert_eq!(muted_stats.exceed_count, 2);
+}
+
+#[test]
+fn reload_keeps_stats_and_in_flight_spans() {
+    use tracing_subscriber::reload;
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(Duration::from_secs(1)))
+        .with_clock(clock.clone());
+    let stats = layer.handle();
+    let (layer, reload_handle) = reload::Layer::new(layer);
+    let collector = BlockedEventCollector::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(layer)
+        .with(collector.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+
+    // Enter before the swap, exit after it.
+    let entered = span.clone().entered();
+    clock.advance(BLOCK);
+    reload_handle
+        .reload(TokioBlockedLayer::from_handle(&stats).with_warn_busy_single_poll(Some(THRESHOLD)))
+        .unwrap();
+    drop(entered);
+    assert_eq!(collector.poll_blocked_events().len(), 1);
+
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].polls, 3);
+    assert_eq!(snapshot[0].total_busy, BLOCK * 3);
+    assert_eq!(snapshot[0].exceed_count, 2);
+}
+
+/// A future that wakes itself immediately on every poll, until `until`.
+struct SelfWaking {
+    until: std::time::Instant,
+}
+
+impl std::future::Future for SelfWaking {
+    type Output = ();
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        if std::time::Instant::now() >= self.until {
+            return std::task::Poll::Ready(());
+        }
+        cx.waker().wake_by_ref();
+        std::task::Poll::Pending
+    }
+}
+
+#[test]
+fn poll_storm_warning() {
+    use tracing::Instrument as _;
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_poll_rate(1000.0);
+    let (collector, _guard) = install(layer);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let storm = task_span!();
+    let storm_line = line!() - 1;
+    rt.block_on(
+        SelfWaking {
+            until: std::time::Instant::now() + Duration::from_millis(250),
+        }
+        .instrument(storm),
+    );
+
+    // A few slow polls stay below the rate.
+    let calm = task_span!();
+    for _ in 0..5 {
+        poll(&calm, Duration::from_millis(30));
+    }
+    drop(calm);
+
+    // With `tokio_unstable`, the `block_on` task is reported as well.
+    let events: Vec<_> = collector
+        .of_kind(EventKind::PollStorm)
+        .into_iter()
+        .filter(|e| e.line == Some(storm_line))
+        .collect();
+    assert!(!events.is_empty());
+    for event in &events {
+        let rate: f64 = event.fields["polls_per_sec"].parse().unwrap();
+        assert!(rate >= 1000.0, "rate {rate}");
+        let window_polls: u64 = event.fields["window_polls"].parse().unwrap();
+        let total_polls: u64 = event.fields["total_polls"].parse().unwrap();
+        assert!(window_polls >= 100);
+        assert!(total_polls >= window_polls);
+    }
+}
+
+#[test]
+fn first_poll_latency_on_saturated_runtime() {
+    use tracing::Instrument as _;
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_first_poll_latency(Some(Duration::from_millis(30)));
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        // Occupies the only worker, so the next task waits.
+        let blocker = tokio::spawn(async { std::thread::sleep(Duration::from_millis(60)) });
+        let span = tracing::trace_span!(
+            target: "tokio::task",
+            "runtime.spawn",
+            task.name = %"delayed",
+            loc.file = file!(),
+            loc.line = line!(),
+            loc.col = column!(),
+        );
+        let delayed = tokio::spawn(async {}.instrument(span));
+        blocker.await.unwrap();
+        delayed.await.unwrap();
+    });
+    let delayed_line = line!() - 7;
+
+    // Closed without being polled.
+    drop(task_span!());
+    let unpolled_line = line!() - 1;
+
+    // With `tokio_unstable`, the spawned tasks are reported as well.
+    let events: Vec<_> = collector
+        .of_kind(EventKind::FirstPollDelayed)
+        .into_iter()
+        .filter(|e| e.line == Some(delayed_line))
+        .collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].fields["task.name"], "delayed");
+    let delay: u64 = events[0].fields["delay_ns"].parse().unwrap();
+    assert!(delay >= Duration::from_millis(60).as_nanos() as u64);
+
+    let stats = handle.snapshot();
+    let delayed = stats.iter().find(|s| s.line == Some(delayed_line)).unwrap();
+    assert!(delayed.spawn_to_first_poll >= Duration::from_millis(60));
+    assert_eq!(
+        delayed.mean_first_poll_latency(),
+        Some(delayed.spawn_to_first_poll)
+    );
+    let unpolled = stats
+        .iter()
+        .find(|s| s.line == Some(unpolled_line))
+        .unwrap();
+    assert_eq!(unpolled.never_polled, 1);
+    assert_eq!(unpolled.spawn_to_first_poll, Duration::ZERO);
+    assert_eq!(unpolled.mean_first_poll_latency(), None);
+}
+
+#[test]
+fn lifetime_per_callsite() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let ms = Duration::from_millis;
+    let lifetimes = [ms(10), ms(30)];
+    let mut short_line = 0;
+    for lifetime in lifetimes {
+        let span = task_span!();
+        short_line = line!() - 1;
+        poll_manual(&span, &clock, ms(5));
+        clock.advance(lifetime - ms(5));
+        drop(span);
+    }
+    let long = task_span!();
+    let long_line = line!() - 1;
+    poll_manual(&long, &clock, ms(100));
+    clock.advance(ms(900));
+    drop(long);
+
+    let stats = handle.snapshot();
+    let short = stats.iter().find(|s| s.line == Some(short_line)).unwrap();
+    assert_eq!(short.min_lifetime, ms(10));
+    assert_eq!(short.max_lifetime, ms(30));
+    assert_eq!(short.total_lifetime, ms(40));
+    assert_eq!(short.mean_lifetime(), Some(ms(20)));
+    assert_eq!(short.busy_percent(), Some(25.0));
+
+    let long = stats.iter().find(|s| s.line == Some(long_line)).unwrap();
+    assert_eq!(long.min_lifetime, ms(1000));
+    assert_eq!(long.max_lifetime, ms(1000));
+    assert_eq!(long.mean_lifetime(), Some(ms(1000)));
+    assert_eq!(long.busy_percent(), Some(10.0));
+}
+
+/// Simulates an async op on a tokio resource, like `tokio::time::sleep` does:
+/// a `runtime.resource` span with the async op span created inside it.
+fn async_op_span(concrete_type: &str, kind: &str, source: &str) -> Span {
+    let resource = tracing::trace_span!(
+        target: "tokio::resource",
+        "runtime.resource",
+        concrete_type,
+        kind,
+    );
+    resource.in_scope(|| {
+        tracing::trace_span!(
+            target: "tokio::resource",
+            "runtime.resource.async_op",
+            source,
+        )
+    })
+}
+
+#[test]
+fn async_ops_grouped_by_resource() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let sleep = async_op_span("Sleep", "timer", "Sleep::new_timeout");
+    let read = async_op_span("TcpStream", "io", "TcpStream::read");
+    poll_manual(&sleep, &clock, BLOCK);
+    poll_manual(&read, &clock, Duration::from_millis(1));
+    drop((sleep, read));
+
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].fields["resource.concrete_type"], "Sleep");
+    assert_eq!(events[0].fields["resource.kind"], "timer");
+    assert_eq!(events[0].fields["resource.source"], "Sleep::new_timeout");
+
+    let mut stats = handle.snapshot();
+    stats.sort_by_key(|s| s.resource_type);
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].resource_type, Some("Sleep"));
+    assert_eq!(stats[0].async_op_source, Some("Sleep::new_timeout"));
+    assert_eq!(stats[0].total_busy, BLOCK);
+    assert_eq!(stats[1].resource_type, Some("TcpStream"));
+    assert_eq!(stats[1].async_op_source, Some("TcpStream::read"));
+    assert_eq!(stats[1].total_busy, Duration::from_millis(1));
+
+    let id = OwnedCallsiteStats::from(&stats[0]).id;
+    assert!(id.to_string().ends_with(" (Sleep, Sleep::new_timeout)"));
+}
+
+#[test]
+fn interned_spawn_locations_resolve_in_warnings() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    // Interleaved spans of a few shared spawn files, open at the same time.
+    let spans: Vec<_> = (0..6u32)
+        .map(|i| {
+            let file = ["src/a.rs", "src/b.rs"][i as usize % 2];
+            match i {
+                5 => tracing::trace_span!(
+                    target: "tokio::task",
+                    "runtime.spawn",
+                    loc.file = file,
+                    loc.line = i,
+                ),
+                _ => tracing::trace_span!(
+                    target: "tokio::task",
+                    "runtime.spawn",
+                    loc.file = file,
+                    loc.line = i,
+                    loc.col = i + 10,
+                ),
+            }
+        })
+        .collect();
+    for span in &spans {
+        poll_manual(span, &clock, BLOCK);
+    }
+    drop(spans);
+
+    let expected: Vec<_> = (0..6u32)
+        .map(|i| {
+            let file = ["src/a.rs", "src/b.rs"][i as usize % 2].to_string();
+            let col = if i == 5 { 0 } else { i + 10 };
+            (file, i, col.to_string())
+        })
+        .collect();
+    let polls = collector.poll_blocked_events();
+    let totals = collector.of_kind(EventKind::BlockedTotal);
+    for events in [polls, totals] {
+        let locations: Vec<_> = events
+            .into_iter()
+            .map(|e| {
+                (
+                    e.file.unwrap(),
+                    e.line.unwrap(),
+                    e.fields["callsite.col"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(locations, expected);
+    }
+}
+
+#[test]
+fn time_types_are_std_on_native_targets() {
+    use tokio_blocked::{time, Clock as _, SystemClock};
+
+    // Only wasm targets without an OS use `web-time`.
+    let before: std::time::Instant = time::Instant::now();
+    let now: std::time::Instant = SystemClock.now();
+    assert!(now >= before);
+    let _: std::time::SystemTime = time::SystemTime::now();
+}
+
+#[test]
+fn lru_callsite_eviction() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_callsite_eviction(EvictionPolicy::Lru { max_entries: 2 })
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let run = |span: Span| {
+        poll_manual(&span, &clock, Duration::from_millis(1));
+        drop(span);
+    };
+    let hot = || task_span!();
+    let hot_line = line!() - 1;
+    run(hot());
+    run(task_span!());
+    run(hot());
+    // Exceeds the cap, evicting the cold callsite that was updated least
+    // recently.
+    let warm = task_span!();
+    let warm_line = line!() - 1;
+    run(warm);
+    run(hot());
+    run(task_span!());
+
+    assert_eq!(handle.evicted_callsites(), 2);
+    let stats = handle.snapshot();
+    assert_eq!(stats.len(), 2);
+    let hot = stats.iter().find(|s| s.line == Some(hot_line)).unwrap();
+    assert_eq!(hot.count, 3);
+    assert!(stats.iter().all(|s| s.line != Some(warm_line)));
+}
+
+/// Spawns a task from within the layer's callbacks.
+struct SpawningObserver;
+
+impl BlockedObserver for SpawningObserver {
+    fn on_poll_blocked(&self, _info: &BlockedPollInfo<'_>) {
+        drop(task_span!());
+    }
+}
+
+/// Panics once when armed.
+#[derive(Clone, Default)]
+struct PanickingClock {
+    clock: ManualClock,
+    armed: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl tokio_blocked::Clock for PanickingClock {
+    fn now(&self) -> std::time::Instant {
+        if self.armed.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            panic!("clock failed");
+        }
+        self.clock.now()
+    }
+}
+
+#[test]
+fn diagnostics_count_dropped_data() {
+    use std::sync::{atomic::Ordering, Arc};
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_callsite_eviction(EvictionPolicy::Lru { max_entries: 1 })
+        .with_observer(Arc::new(SpawningObserver))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    assert_eq!(handle.diagnostics(), Diagnostics::default());
+
+    // The second callsite evicts the first, the observer's task span is not
+    // tracked.
+    poll_manual(&task_span!(), &clock, BLOCK);
+    poll_manual(&task_span!(), &clock, Duration::from_millis(1));
+    let diagnostics = handle.diagnostics();
+    assert_eq!(diagnostics.evicted_callsites, 1);
+    assert_eq!(diagnostics.reentrant_spans, 1);
+    assert_eq!(diagnostics.uninterned_strings, 0);
+
+    // Distinct spawn locations beyond the interning cap are dropped.
+    for i in 0..4100 {
+        drop(tracing::trace_span!(
+            target: "tokio::task",
+            "runtime.spawn",
+            loc.file = format!("src/generated_{i}.rs").as_str(),
+            loc.line = 1u32,
+        ));
+    }
+    assert!(handle.diagnostics().uninterned_strings >= 4);
+
+    // A panicking clock poisons the shared clock, which is recovered.
+    let clock = PanickingClock::default();
+    let layer = TokioBlockedLayer::new().with_clock(clock.clone());
+    let handle = layer.handle();
+    clock.armed.store(true, Ordering::Relaxed);
+    let err = std::panic::catch_unwind(AssertUnwindSafe(|| handle.reset())).unwrap_err();
+    assert_eq!(err.downcast_ref::<&str>(), Some(&"clock failed"));
+    handle.reset();
+    assert_eq!(handle.diagnostics().poisoned_locks, 1);
+    handle.reset();
+    assert_eq!(handle.diagnostics().poisoned_locks, 1);
+    drop(layer);
+}
+
+#[test]
+fn live_span_gauge() {
+    use std::sync::{Arc, Barrier};
+
+    const TASKS: usize = 4;
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(None);
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    // Each task holds its span open on the barrier until the gauge was read.
+    // The threads need the dispatcher as well, to close parent spans.
+    let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+    let barrier = Arc::new(Barrier::new(TASKS + 1));
+    let threads: Vec<_> = (0..TASKS)
+        .map(|_| {
+            let span = task_span!();
+            let op = span.in_scope(|| async_op_span("Sleep", "timer", "Sleep::new_timeout"));
+            let (dispatch, barrier) = (dispatch.clone(), barrier.clone());
+            std::thread::spawn(move || {
+                let _default = tracing::dispatcher::set_default(&dispatch);
+                let _enter = span.enter();
+                barrier.wait();
+                barrier.wait();
+                drop(op);
+            })
+        })
+        .collect();
+    barrier.wait();
+    assert_eq!(handle.live_spans(), TASKS * 2);
+    assert_eq!(
+        handle.live_spans_by_kind(),
+        LiveSpans {
+            tasks: TASKS,
+            async_ops: TASKS,
+            resources: 0,
+        }
+    );
+    barrier.wait();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(handle.live_spans(), 0);
+    assert_eq!(handle.live_spans_high_water(), TASKS * 2);
+}
+
+#[test]
+fn memory_stats_count_entries() {
+    let clock = ManualClock::new();
+    let layer = |recent_polls| {
+        TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(None)
+            .with_poll_histogram(true)
+            .with_recent_polls(recent_polls)
+            .with_clock(clock.clone())
+    };
+    let populate = |layer: TokioBlockedLayer| {
+        let handle = layer.handle();
+        let (_collector, _guard) = install(layer);
+        for span in [task_span!(), task_span!(), task_span!()] {
+            poll_manual(&span, &clock, THRESHOLD);
+            poll_manual(&span, &clock, THRESHOLD);
+        }
+        let open = [task_span!(), task_span!()];
+        poll_manual(&open[0], &clock, THRESHOLD);
+        let stats = handle.memory_stats();
+        drop(open);
+        stats
+    };
+
+    let stats = populate(layer(64));
+    assert_eq!(stats.callsites, 3);
+    assert_eq!(stats.callsite_cells, 5);
+    assert_eq!(stats.live_spans, 2);
+    assert_eq!(stats.recent_poll_samples, 7);
+    assert_eq!(stats.trace_slices, 0);
+    // At least the rings and histograms of the cells, but not wildly more.
+    assert!(stats.estimated_bytes > 5 * 64 * 16, "{stats:?}");
+    assert!(stats.estimated_bytes < 1 << 20, "{stats:?}");
+
+    let without_rings = populate(layer(0));
+    assert_eq!(without_rings.recent_poll_samples, 0);
+    assert!(without_rings.estimated_bytes < stats.estimated_bytes);
+}
+
+#[test]
+fn health_recovers_after_window() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_health_window(Duration::from_secs(60))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let window = Duration::from_secs(10);
+    let thresholds = HealthThresholds {
+        blocked_time: Some(BLOCK),
+        ..Default::default()
+    };
+
+    let span = task_span!();
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    assert!(handle.health(window).is_healthy(&thresholds));
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, BLOCK);
+
+    let health = handle.health(window);
+    assert_eq!(health.window, window);
+    assert_eq!(health.blocked_time, BLOCK * 2);
+    assert_eq!(health.blocked_polls, 2);
+    assert_eq!(health.max_poll, BLOCK);
+    assert!(!health.is_healthy(&thresholds));
+
+    // The blocking ages out of the window, but is still within the retention.
+    clock.advance(window + Duration::from_secs(1));
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    let health = handle.health(window);
+    assert_eq!(health.blocked_time, Duration::ZERO);
+    assert_eq!(health.max_poll, Duration::from_millis(1));
+    assert!(health.is_healthy(&thresholds));
+    assert_eq!(
+        handle.health(Duration::from_secs(3600)).blocked_time,
+        BLOCK * 2
+    );
+}
+
+#[test]
+fn blocked_fraction_of_bursts() {
+    let clock = ManualClock::new();
+    let window = Duration::from_secs(1);
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_blocked_fraction_gauge(window)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    assert_eq!(handle.blocked_fraction(window), 0.0);
+
+    let span = task_span!();
+    for _ in 0..5 {
+        poll_manual(&span, &clock, BLOCK);
+        poll_manual(&span, &clock, Duration::from_millis(1));
+    }
+    // 100ms of blocking in the last second, the clean polls don't count.
+    let fraction = handle.blocked_fraction(window);
+    assert!((fraction - 0.1).abs() < 1e-3, "{fraction}");
+    let half = handle.blocked_fraction(window / 2);
+    // Rounded up to whole buckets of the health window, so slightly less.
+    assert!((half - 0.2).abs() < 0.01, "{half}");
+
+    let text = handle.render_prometheus();
+    let gauge = text
+        .lines()
+        .find_map(|l| l.strip_prefix("tokio_blocked_blocked_fraction{window_seconds=\"1\"} "))
+        .unwrap_or_else(|| panic!("no gauge in\n{text}"));
+    assert!((gauge.parse::<f64>().unwrap() - 0.1).abs() < 1e-3);
+
+    handle.report();
+    let metrics = collector.of_kind(EventKind::Metrics);
+    assert_eq!(metrics.len(), 1, "{metrics:?}");
+    let reported: f64 = metrics[0].fields["gauge.tokio_blocked.blocked_fraction"]
+        .parse()
+        .unwrap();
+    assert!((reported - 0.1).abs() < 1e-3);
+
+    // The bursts age out of the window.
+    clock.advance(window * 2);
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    assert_eq!(handle.blocked_fraction(window), 0.0);
+
+    let handle = TokioBlockedLayer::new().handle();
+    assert_eq!(handle.blocked_fraction(window), 0.0);
+}
+
+#[test]
+fn runtime_saturation_warns_with_hysteresis() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_saturation_detection(
+            SaturationThresholds::new(40.0)
+                .window(Duration::from_secs(1))
+                .workers(2),
+        )
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    let bucket = Duration::from_millis(100);
+    let span = task_span!();
+    let saturated = || collector.of_kind(EventKind::RuntimeSaturated);
+
+    assert_eq!(handle.blocked_capacity_fraction(), 0.0);
+    // One worker busy all the time is half of the capacity of two.
+    for _ in 0..20 {
+        poll_manual(&span, &clock, bucket);
+    }
+    assert_eq!(handle.blocked_capacity_fraction(), 0.5);
+    let events = saturated();
+    assert_eq!(events.len(), 1, "{events:?}");
+    assert_eq!(events[0].level, Level::WARN);
+    assert_eq!(events[0].fields["workers"], "2");
+    assert_eq!(events[0].fields["window_ns"], "1000000000");
+    assert_eq!(events[0].fields["capacity_ns"], "2000000000");
+
+    // Dropping to 35% stays within the hysteresis, so going back up doesn't
+    // warn again.
+    for _ in 0..10 {
+        clock.advance(Duration::from_millis(30));
+        poll_manual(&span, &clock, Duration::from_millis(70));
+    }
+    let fraction = handle.blocked_capacity_fraction();
+    assert!((0.3..0.4).contains(&fraction), "{fraction}");
+    for _ in 0..10 {
+        poll_manual(&span, &clock, bucket);
+    }
+    assert_eq!(saturated().len(), 1);
+
+    // An idle window re-arms the warning.
+    clock.advance(Duration::from_secs(2));
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    assert!(handle.blocked_capacity_fraction() < 0.01);
+    for _ in 0..10 {
+        poll_manual(&span, &clock, bucket);
+    }
+    assert_eq!(saturated().len(), 2);
+}
+
+#[test]
+fn live_flush_of_long_lived_span() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_live_flush(Duration::from_millis(100))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let server = task_span!();
+    poll_manual(&server, &clock, BLOCK);
+    // Not due yet.
+    assert!(handle.snapshot().is_empty());
+
+    clock.advance(Duration::from_millis(100));
+    poll_manual(&server, &clock, BLOCK);
+    let stats = handle.snapshot();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].total_busy, BLOCK * 2);
+    assert_eq!(stats[0].polls, 2);
+    assert_eq!(stats[0].max_poll, BLOCK);
+    assert_eq!(stats[0].count, 0);
+
+    // Closing only merges what was not flushed yet.
+    poll_manual(&server, &clock, BLOCK);
+    drop(server);
+    let stats = handle.snapshot();
+    assert_eq!(stats[0].total_busy, BLOCK * 3);
+    assert_eq!(stats[0].polls, 3);
+    assert_eq!(stats[0].count, 1);
+    assert_eq!(
+        stats[0].poll_sum_squares_ns,
+        3 * BLOCK.as_nanos() * BLOCK.as_nanos()
+    );
+}
+
+#[test]
+fn live_snapshots_are_off_by_default() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let running = task_span!();
+    poll_manual(&running, &clock, BLOCK);
+    assert!(handle.snapshot_with_live(true).is_empty());
+    assert_eq!(handle.live_spans(), 1);
+    assert_eq!(handle.memory_stats().live_spans, 1);
+}
+
+#[test]
+fn snapshot_includes_live_spans() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_live_snapshots(true)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let finished = task_span!();
+    poll_manual(&finished, &clock, BLOCK);
+    drop(finished);
+    let running = task_span!();
+    poll_manual(&running, &clock, BLOCK);
+    poll_manual(&running, &clock, BLOCK);
+
+    assert_eq!(handle.snapshot_with_live(false).len(), 1);
+    let live = handle.snapshot_with_live(true);
+    assert_eq!(live.len(), 2);
+    let running_stats = live.iter().find(|s| s.count == 0).unwrap();
+    assert_eq!(running_stats.total_busy, BLOCK * 2);
+    assert_eq!(running_stats.polls, 2);
+
+    // Once closed, the span is counted exactly once.
+    drop(running);
+    let stats = handle.snapshot_with_live(true);
+    assert_eq!(stats.len(), 2);
+    assert!(stats
+        .iter()
+        .all(|s| s.count == 1 && s.total_busy == BLOCK * s.polls as u32));
+}
+
+#[test]
+fn recent_polls_keep_newest_samples() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_recent_polls(3)
+        .with_recent_polls_floor(Duration::from_millis(2))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    let line = line!() - 1;
+    let other = task_span!();
+    for ms in 1..=6 {
+        poll_manual(&span, &clock, Duration::from_millis(ms));
+        poll_manual(&other, &clock, BLOCK);
+    }
+
+    let samples = handle.recent_polls(&CallsiteSelector::location(file!(), line));
+    let durations: Vec<_> = samples.iter().map(|s| s.duration).collect();
+    assert_eq!(
+        durations,
+        [4, 5, 6].map(Duration::from_millis),
+        "only the newest polls above the floor remain"
+    );
+    assert!(samples.windows(2).all(|w| w[0].start < w[1].start));
+    // Both callsites, merged in start order.
+    let all = handle.recent_polls(&CallsiteSelector::name("tokio::task", "runtime.spawn"));
+    assert_eq!(all.len(), 6);
+    assert!(all.windows(2).all(|w| w[0].start < w[1].start));
+
+    handle.reset();
+    assert!(handle
+        .recent_polls(&CallsiteSelector::file(file!()))
+        .is_empty());
+}
+
+#[test]
+fn poll_histogram_percentiles() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_poll_histogram(true)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for _ in 0..9 {
+        poll_manual(&span, &clock, Duration::from_millis(1));
+    }
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let stats = handle.snapshot();
+    let histogram = stats[0].poll_histogram.as_ref().unwrap();
+    assert_eq!(histogram.count(), 10);
+    assert_eq!(histogram.counts().len(), histogram.bounds().len() + 1);
+    // Interpolated within the power-of-two buckets: the 5th of 9 polls in
+    // (512µs, 1024µs].
+    assert_eq!(
+        histogram.percentile(50.0),
+        Some(Duration::from_nanos(796_444))
+    );
+    assert_eq!(
+        histogram.percentile(90.0),
+        Some(Duration::from_micros(1024))
+    );
+    assert_eq!(
+        histogram.percentile(99.0),
+        Some(Duration::from_micros(32768))
+    );
+
+    handle.reset();
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    assert!(handle
+        .snapshot()
+        .iter()
+        .all(|s| s.poll_histogram.as_ref().unwrap().count() <= 1));
+}
+
+#[test]
+fn custom_histogram_buckets() {
+    let ms = Duration::from_millis;
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_poll_histogram(true)
+        .with_histogram_buckets(&[ms(10), ms(1), ms(5), ms(5)])
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for poll in [
+        ms(1),
+        ms(1) + Duration::from_nanos(1),
+        ms(3),
+        ms(5),
+        ms(10),
+        ms(11),
+    ] {
+        poll_manual(&span, &clock, poll);
+    }
+    drop(span);
+
+    let histogram = handle.snapshot()[0].poll_histogram.clone().unwrap();
+    assert_eq!(histogram.bounds(), [ms(1), ms(5), ms(10)]);
+    // Bounds are inclusive, longer polls go to the next bucket.
+    assert_eq!(histogram.counts(), [1, 3, 1, 1]);
+    assert_eq!(histogram.percentile(0.0), Some(ms(1)));
+    // The 3rd of 6 polls is the 2nd of 3 in (1ms, 5ms].
+    assert_eq!(
+        histogram.percentile(50.0),
+        Some(ms(1) + Duration::from_nanos(2_666_667))
+    );
+    assert_eq!(histogram.percentile(66.0), Some(ms(5)));
+    assert_eq!(histogram.percentile(80.0), Some(ms(10)));
+    // Polls past the last bound.
+    assert_eq!(histogram.percentile(100.0), Some(ms(10)));
+}
+
+#[test]
+fn custom_report_percentiles() {
+    let ms = Duration::from_millis;
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_poll_histogram(true)
+        .with_histogram_buckets(&[ms(1), ms(10), ms(100)])
+        .with_report_percentiles(&[0.999, 0.1])
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, Duration::from_micros(500));
+    for _ in 0..8 {
+        poll_manual(&span, &clock, ms(5));
+    }
+    poll_manual(&span, &clock, ms(50));
+    drop(span);
+
+    let stats = handle.snapshot();
+    assert_eq!(stats[0].percentiles, [(0.1, ms(1)), (0.999, ms(100))]);
+
+    let snapshot = handle.owned_snapshot();
+    let table = snapshot.render_table(&Default::default());
+    let mut lines = table.lines();
+    let header: Vec<_> = lines.next().unwrap().split_whitespace().collect();
+    assert_eq!(header[header.len() - 2..], ["P10", "P99.9"]);
+    assert!(
+        lines.next().unwrap().ends_with("1.00 ms  100.00 ms"),
+        "{table}"
+    );
+
+    let markdown = handle.render_markdown(&MarkdownOptions {
+        details: true,
+        ..Default::default()
+    });
+    assert!(
+        markdown.contains(": p10 1.00 ms, p99.9 100.00 ms, max 50.00 ms"),
+        "{markdown}"
+    );
+
+    #[cfg(feature = "serde")]
+    {
+        let json = snapshot.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value["callsites"][0]["percentiles"],
+            serde_json::json!([
+                { "quantile": 0.1, "value_ns": 1_000_000 },
+                { "quantile": 0.999, "value_ns": 100_000_000 },
+            ])
+        );
+        assert_eq!(Snapshot::from_json(&json).unwrap(), snapshot);
+    }
+}
+
+type PromSample = (String, Vec<(String, String)>, f64);
+
+/// Parses the Prometheus text exposition format, panicking on malformed lines
+/// or samples without a preceding `# TYPE`.
+fn parse_prometheus(text: &str) -> Vec<PromSample> {
+    let is_name = |s: &str| {
+        !s.is_empty()
+            && !s.starts_with(|c: char| c.is_ascii_digit())
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    };
+    let mut types = std::collections::HashMap::new();
+    let mut samples = Vec::new();
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# ") {
+            let mut parts = rest.splitn(3, ' ');
+            let (kind, name, value) = (parts.next(), parts.next(), parts.next());
+            assert!(name.is_some_and(is_name), "{line}");
+            match kind {
+                Some("HELP") => {}
+                Some("TYPE") => {
+                    let value = value.unwrap();
+                    assert!(["counter", "gauge", "histogram", "untyped"].contains(&value));
+                    types.insert(name.unwrap().to_string(), value.to_string());
+                }
+                _ => panic!("unexpected comment: {line}"),
+            }
+            continue;
+        }
+
+        let end = line.find(['{', ' ']).unwrap();
+        let name = &line[..end];
+        assert!(is_name(name), "{line}");
+        let family = ["_bucket", "_sum", "_count"]
+            .iter()
+            .find_map(|suffix| {
+                let base = name.strip_suffix(suffix)?;
+                (types.get(base)? == "histogram").then_some(base)
+            })
+            .unwrap_or(name);
+        assert!(types.contains_key(family), "no TYPE for {line}");
+
+        let mut labels = Vec::new();
+        let chars: Vec<char> = line[end..].chars().collect();
+        let mut i = 0;
+        if chars[0] == '{' {
+            loop {
+                i += 1;
+                let eq = i + chars[i..].iter().position(|c| *c == '=').unwrap();
+                let key: String = chars[i..eq].iter().collect();
+                assert!(is_name(&key), "{line}");
+                assert_eq!(chars[eq + 1], '"', "{line}");
+                i = eq + 2;
+                let mut value = String::new();
+                loop {
+                    match chars[i] {
+                        '\\' => {
+                            i += 1;
+                            value.push(match chars[i] {
+                                '\\' => '\\',
+                                '"' => '"',
+                                'n' => '\n',
+                                c => panic!("invalid escape \\{c} in {line}"),
+                            });
+                        }
+                        '"' => break,
+                        c => value.push(c),
+                    }
+                    i += 1;
+                }
+                labels.push((key, value));
+                i += 1;
+                match chars[i] {
+                    ',' => continue,
+                    '}' => break,
+                    c => panic!("unexpected {c} in {line}"),
+                }
+            }
+            i += 1;
+        }
+        let rest: String = chars[i..].iter().collect();
+        let value = rest.strip_prefix(' ').unwrap_or_else(|| panic!("{line}"));
+        let value = match value {
+            "+Inf" => f64::INFINITY,
+            v => v
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid value in {line}")),
+        };
+        samples.push((name.to_string(), labels, value));
+    }
+    samples
+}
+
+#[test]
+fn prometheus_exposition() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_group_by(GroupBy::TaskName)
+        .with_poll_histogram(true)
+        .with_histogram_buckets(&[THRESHOLD, BLOCK])
+        .with_live_snapshots(true)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let name = "say \"hi\"\\\nbye";
+    let span = named_task_span(Some(name));
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    // Still open, so only included as a live span.
+    let live = task_span!();
+    let line = line!() - 1;
+    poll_manual(&live, &clock, Duration::from_millis(2));
+
+    let text = handle.render_prometheus();
+    let samples = parse_prometheus(&text);
+    let value = |metric: &str, label: (&str, &str)| {
+        samples
+            .iter()
+            .find(|(n, labels, _)| {
+                n == metric
+                    && labels
+                        .iter()
+                        .any(|(k, v)| (k.as_str(), v.as_str()) == label)
+            })
+            .unwrap_or_else(|| panic!("no {metric} with {label:?} in\n{text}"))
+            .2
+    };
+    assert_eq!(value("tokio_blocked_polls_total", ("task_name", name)), 2.0);
+    assert_eq!(
+        value("tokio_blocked_exceeded_total", ("task_name", name)),
+        1.0
+    );
+    assert_eq!(
+        value("tokio_blocked_busy_seconds_total", ("task_name", name)),
+        0.021
+    );
+    assert_eq!(
+        value(
+            "tokio_blocked_busy_seconds_total",
+            ("line", &line.to_string())
+        ),
+        0.002
+    );
+    let escaped = r#"task_name="say \"hi\"\\\nbye""#;
+    assert!(
+        text.contains(&format!(
+            "tokio_blocked_poll_duration_seconds_bucket{{name=\"runtime.spawn\",{escaped},le=\"0.005\"}} 1\n"
+        )),
+        "{text}"
+    );
+    assert!(text.contains(&format!(
+        "tokio_blocked_poll_duration_seconds_bucket{{name=\"runtime.spawn\",{escaped},le=\"+Inf\"}} 2\n"
+    )));
+    assert!(text.contains("# TYPE tokio_blocked_polls_total counter\n"));
+    assert!(text.contains("# TYPE tokio_blocked_poll_duration_seconds histogram\n"));
+}
+
+#[cfg(feature = "statsd")]
+#[test]
+fn statsd_exporter_sends_datagrams() {
+    use std::{net::UdpSocket, sync::Arc};
+
+    use tokio_blocked::{StatsdExporter, StatsdTags};
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let recv = || {
+        let mut buf = [0; 2048];
+        let len = server.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    };
+    let exporter = Arc::new(
+        StatsdExporter::connect(server.local_addr().unwrap())
+            .unwrap()
+            .with_prefix("app.blocked")
+            .with_tags(StatsdTags {
+                file: Some("src".to_string()),
+                target: Some("target".to_string()),
+                ..StatsdTags::default()
+            }),
+    );
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_observer(exporter.clone())
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    let line = line!() - 1;
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    let tags = format!("#src:tests/test.rs,line:{line},name:runtime.spawn,target:tokio::task");
+    assert_eq!(recv(), format!("app.blocked.poll:20|ms|{tags}"));
+
+    handle.report();
+    assert_eq!(
+        recv(),
+        [
+            format!("app.blocked.busy:21|c|{tags}"),
+            format!("app.blocked.polls:2|c|{tags}"),
+            format!("app.blocked.exceeded:1|c|{tags}"),
+            format!("app.blocked.max_poll:20|g|{tags}"),
+        ]
+        .join("\n")
+    );
+    // Counters only carry the growth since the last report.
+    handle.report();
+    assert_eq!(recv(), format!("app.blocked.max_poll:20|g|{tags}"));
+    assert_eq!(exporter.dropped(), 0);
+
+    // Sending to a closed port fails without affecting the layer.
+    drop(server);
+    for _ in 0..3 {
+        handle.report();
+    }
+    assert!(exporter.dropped() > 0);
+}
+
+#[test]
+fn markdown_report_table() {
+    let mut pipe = row(7, 2, 2);
+    pipe.id.file = Some("src\\a|b.rs".to_string());
+    let snapshot = Snapshot::new(vec![row(3, 5, 1), pipe, row(9, 1500, 1000)]);
+    let opts = MarkdownOptions {
+        warn_busy_single_poll: Some(Duration::from_micros(150)),
+        captured_at: Some(std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        snapshot.render_markdown(&opts),
+        "\
+**Blocking report** captured at 2023-11-14 22:13:20 UTC · single poll threshold: 150.00 µs · total threshold: off
+
+| Location | Name | Spans | Polls | Total busy | Max poll |
+| --- | --- | ---: | ---: | ---: | ---: |
+| `src/main.rs:9` | runtime.spawn | 1 | 1 | 1.50 s | 1.00 s |
+| `src/main.rs:3` | runtime.spawn | 1 | 1 | 5.00 ms | 1.00 ms |
+| `src\\a\\|b.rs:7` | runtime.spawn | 1 | 1 | 2.00 ms | 2.00 ms |
+"
+    );
+}
+
+#[test]
+fn markdown_report_from_handle_with_details() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_poll_histogram(true)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    let line = line!() - 1;
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let report = handle.render_markdown(&MarkdownOptions {
+        details: true,
+        ..Default::default()
+    });
+    let lines: Vec<_> = report.lines().collect();
+    assert!(lines[0].starts_with("**Blocking report** captured at "));
+    assert!(lines[0].contains(" · single poll threshold: 5.00 ms · total threshold: off"));
+    assert!(lines[0].contains(&format!(" · worst poll: 20.00 ms at `{}:{line}:", file!())));
+    assert_eq!(lines[2].matches('|').count(), 7);
+    assert!(lines[4].starts_with(&format!("| `{}:{line}` |", file!())));
+    assert_eq!(lines[6], "### Details");
+    assert_eq!(
+        lines[8],
+        format!(
+            "- `{}:{line}` runtime.spawn: p50 32.77 ms, p95 32.77 ms, p99 32.77 ms, max 20.00 ms",
+            file!()
+        )
+    );
+}
+
+#[test]
+fn event_collector_captures_structured_events() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    let line = line!() - 1;
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    collector.assert_no_blocking();
+
+    poll_manual(&span, &clock, BLOCK);
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, EventKind::PollBlocked);
+    assert_eq!(events[0].level, Level::WARN);
+    assert_eq!(events[0].duration, Some(BLOCK));
+    assert_eq!(events[0].file.as_deref(), Some(file!()));
+    assert_eq!(events[0].line, Some(line));
+
+    let err = std::panic::catch_unwind(|| collector.assert_no_blocking()).unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains(&format!("{}:{line}", file!())), "{msg}");
+
+    collector.clear();
+    collector.assert_no_blocking();
+}
+
+#[test]
+fn builder_accepts_valid_config() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::builder()
+        .warn_busy_single_poll(Some(THRESHOLD))
+        .warn_busy_total(Some(BLOCK))
+        // Before `escalation`, still applied.
+        .escalation_quiet_period(Duration::from_secs(1))
+        .escalation(1, Level::ERROR)
+        .ewma_alpha(1.0)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].level, Level::ERROR);
+}
+
+#[test]
+fn builder_rejects_invalid_config() {
+    let err = |builder: tokio_blocked::Builder| builder.build().err().unwrap();
+
+    assert_eq!(
+        err(TokioBlockedLayer::builder().warn_busy_single_poll(Some(Duration::ZERO))),
+        ConfigError::ZeroDuration {
+            option: "warn_busy_single_poll"
+        }
+    );
+    assert_eq!(
+        err(TokioBlockedLayer::builder().dedup_window(Duration::ZERO)),
+        ConfigError::ZeroDuration {
+            option: "dedup_window"
+        }
+    );
+    assert_eq!(
+        err(TokioBlockedLayer::builder().ewma_alpha(7.0)),
+        ConfigError::InvalidRate {
+            option: "ewma_alpha",
+            value: 7.0
+        }
+    );
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().warn_poll_rate(-1.0)),
+        ConfigError::InvalidRate {
+            option: "warn_poll_rate",
+            ..
+        }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().recovery_events(0)),
+        ConfigError::InvalidRate {
+            option: "recovery_events",
+            ..
+        }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder()
+            .warn_busy_single_poll(Some(BLOCK))
+            .warn_busy_total(Some(THRESHOLD))),
+        ConfigError::ConflictingOptions { .. }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().escalation_quiet_period(BLOCK)),
+        ConfigError::ConflictingOptions { .. }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().callsite_eviction(EvictionPolicy::Lru { max_entries: 0 })),
+        ConfigError::ConflictingOptions { .. }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder()
+            .adaptive_thresholds(AdaptiveThresholds::linear(0.5, 0.9, 1.5))),
+        ConfigError::InvalidRate {
+            option: "adaptive_thresholds.min_factor",
+            ..
+        }
+    ));
+
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().anomaly_threshold(f64::INFINITY, 95.0, 1)),
+        ConfigError::InvalidRate {
+            option: "anomaly_threshold.multiplier",
+            ..
+        }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().report_percentiles(&[0.5, 0.0])),
+        ConfigError::InvalidRate {
+            option: "report_percentiles",
+            ..
+        }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().histogram_buckets(&[])),
+        ConfigError::InvalidBuckets { .. }
+    ));
+    assert!(matches!(
+        err(TokioBlockedLayer::builder().histogram_buckets(&[BLOCK, THRESHOLD])),
+        ConfigError::InvalidBuckets { .. }
+    ));
+
+    let error: Box<dyn std::error::Error> =
+        Box::new(err(TokioBlockedLayer::builder().live_flush(Duration::ZERO)));
+    assert_eq!(error.to_string(), "live_flush must not be zero");
+}
+
+#[test]
+fn on_blocked_callbacks() {
+    use std::sync::{Arc, Mutex};
+
+    let polls = Arc::new(Mutex::new(Vec::new()));
+    let totals = Arc::new(Mutex::new(Vec::new()));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_warnings_enabled(false)
+        .with_on_blocked({
+            let polls = polls.clone();
+            move |info| {
+                polls.lock().unwrap().push((
+                    info.poll_duration,
+                    info.threshold,
+                    info.exceed_count,
+                    info.callsite_name,
+                    info.origin_file,
+                    info.origin_line,
+                    info.task_name.as_deref().map(|n| n.to_string()),
+                    info.task_id,
+                    info.thread_id,
+                ));
+            }
+        })
+        .with_on_blocked_total({
+            let totals = totals.clone();
+            move |info| {
+                totals
+                    .lock()
+                    .unwrap()
+                    .push((info.busy, info.lifetime, info.origin_line));
+            }
+        })
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = tracing::trace_span!(
+        target: "tokio::task",
+        "runtime.spawn",
+        task.name = %"worker",
+        task.id = 42u64,
+        loc.file = file!(),
+        loc.line = line!(),
+        loc.col = column!(),
+    );
+    let line = line!() - 3;
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    poll_manual(&span, &clock, BLOCK);
+    clock.advance(BLOCK);
+    drop(span);
+
+    let polls = polls.lock().unwrap();
+    assert_eq!(polls.len(), 1);
+    let (duration, threshold, exceed_count, name, file, origin_line, task_name, task_id, thread) =
+        polls[0].clone();
+    assert_eq!(duration, BLOCK);
+    assert_eq!(threshold, THRESHOLD);
+    assert_eq!(exceed_count, 1);
+    assert_eq!(name, "runtime.spawn");
+    assert_eq!(file, Some(file!()));
+    assert_eq!(origin_line, Some(line));
+    assert_eq!(task_name.as_deref(), Some("worker"));
+    assert_eq!(task_id, Some(42));
+    assert_eq!(thread, std::thread::current().id());
+
+    let totals = totals.lock().unwrap();
+    assert_eq!(
+        *totals,
+        [(
+            BLOCK + Duration::from_millis(1),
+            BLOCK * 2 + Duration::from_millis(1),
+            Some(line)
+        )]
+    );
+    // Callbacks run even with warnings disabled.
+    assert!(collector.events().is_empty());
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    polls: std::sync::Mutex<Vec<(Duration, Option<u32>)>>,
+    closed: std::sync::Mutex<Vec<Duration>>,
+    snapshots: std::sync::Mutex<Vec<usize>>,
+}
+
+impl BlockedObserver for RecordingObserver {
+    fn on_poll_blocked(&self, info: &BlockedPollInfo<'_>) {
+        self.polls
+            .lock()
+            .unwrap()
+            .push((info.poll_duration, info.origin_line));
+    }
+
+    fn on_span_closed(&self, info: &BlockedTotalInfo<'_>) {
+        self.closed.lock().unwrap().push(info.busy);
+    }
+
+    fn on_snapshot(&self, callsites: &[CallsiteStatsSnapshot]) {
+        self.snapshots.lock().unwrap().push(callsites.len());
+    }
+}
+
+#[test]
+fn observers_receive_all_hooks() {
+    use std::sync::Arc;
+
+    let observer = Arc::new(RecordingObserver::default());
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_observer(observer.clone())
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    let line = line!() - 1;
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    assert_eq!(handle.report().len(), 1);
+
+    assert_eq!(*observer.polls.lock().unwrap(), [(BLOCK, Some(line))]);
+    assert_eq!(
+        *observer.closed.lock().unwrap(),
+        [BLOCK + Duration::from_millis(1)]
+    );
+    assert_eq!(*observer.snapshots.lock().unwrap(), [1]);
+    // The default tracing observer still emits events.
+    assert_eq!(collector.poll_blocked_events().len(), 1);
+    assert_eq!(collector.of_kind(EventKind::BlockedTotal).len(), 1);
+}
+
+#[test]
+fn observers_replace_tracing_events() {
+    use std::sync::Arc;
+
+    let observer = Arc::new(RecordingObserver::default());
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_observers([observer.clone() as Arc<dyn BlockedObserver>])
+        .with_report_interval(Duration::from_millis(10))
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    assert_eq!(observer.polls.lock().unwrap().len(), 1);
+    assert_eq!(observer.closed.lock().unwrap().len(), 1);
+    assert!(collector.events().is_empty());
+
+    // The periodic reporter passes snapshots to the observers.
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while observer.snapshots.lock().unwrap().is_empty() {
+        assert!(std::time::Instant::now() < deadline, "no snapshot reported");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[cfg(feature = "sentry")]
+#[test]
+fn sentry_observer_captures_events() {
+    use std::sync::Arc;
+
+    use sentry_core::protocol::{Level as SentryLevel, Value};
+    use tokio_blocked::SentryBlockedObserver;
+
+    let observer = SentryBlockedObserver::new(Some(BLOCK), Some(BLOCK))
+        .with_rate_limit(2, Duration::from_secs(3600));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_observer(Arc::new(observer))
+        .with_clock(clock.clone());
+    let (_collector, _guard) = install(layer);
+
+    let mut line = 0;
+    let events = sentry_core::test::with_captured_events(|| {
+        let span = task_span!();
+        line = line!() - 1;
+        // Below the poll threshold of the observer.
+        poll_manual(&span, &clock, THRESHOLD * 2);
+        poll_manual(&span, &clock, BLOCK);
+        drop(span);
+        // Dropped by the rate limit.
+        let span = task_span!();
+        poll_manual(&span, &clock, BLOCK);
+    });
+
+    assert_eq!(events.len(), 2);
+    let poll = &events[0];
+    let frame = &poll.stacktrace.as_ref().unwrap().frames[0];
+    let culprit = poll.culprit.as_deref().unwrap();
+    assert_eq!(poll.level, SentryLevel::Warning);
+    assert_eq!(
+        culprit,
+        format!("tests/test.rs:{line}:{}", frame.colno.unwrap())
+    );
+    assert_eq!(poll.tags["callsite.file"], "tests/test.rs");
+    assert_eq!(poll.tags["callsite.line"], line.to_string());
+    assert_eq!(
+        poll.extra["poll_duration_ns"],
+        Value::from(BLOCK.as_nanos() as u64)
+    );
+    assert_eq!(frame.filename.as_deref(), Some("tests/test.rs"));
+    assert_eq!(frame.lineno, Some(line.into()));
+
+    let total = &events[1];
+    assert_eq!(total.culprit, poll.culprit);
+    assert_eq!(
+        total.extra["busy_ns"],
+        Value::from((BLOCK + THRESHOLD * 2).as_nanos() as u64)
+    );
+    assert_ne!(total.fingerprint, poll.fingerprint);
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn log_fallback_emits_records() {
+    use std::{cell::RefCell, collections::HashMap};
+
+    type Record = (log::Level, String, String, HashMap<String, String>);
+
+    thread_local! {
+        static RECORDS: RefCell<Vec<Record>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Keeps the records of the logging thread, so that parallel tests
+    /// don't see each other's.
+    struct ThreadLogger;
+
+    impl log::Log for ThreadLogger {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            struct Collect(HashMap<String, String>);
+            impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+                fn visit_pair(
+                    &mut self,
+                    key: log::kv::Key<'kvs>,
+                    value: log::kv::Value<'kvs>,
+                ) -> Result<(), log::kv::Error> {
+                    self.0.insert(key.to_string(), value.to_string());
+                    Ok(())
+                }
+            }
+            let mut kvs = Collect(HashMap::new());
+            record.key_values().visit(&mut kvs).unwrap();
+            RECORDS.with(|r| {
+                r.borrow_mut().push((
+                    record.level(),
+                    record.target().to_string(),
+                    record.args().to_string(),
+                    kvs.0,
+                ))
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    log::set_logger(&ThreadLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_log_fallback(true)
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = named_task_span(Some("worker"));
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    // The tracing events are still emitted.
+    assert_eq!(collector.poll_blocked_events().len(), 1);
+    assert_eq!(collector.of_kind(EventKind::BlockedTotal).len(), 1);
+    let records = RECORDS.with(|r| r.take());
+    assert_eq!(records.len(), 2, "{records:?}");
+
+    let (level, target, message, kvs) = &records[0];
+    assert_eq!(*level, log::Level::Warn);
+    assert_eq!(target, "tokio_blocked::task_poll_blocked");
+    assert_eq!(
+        message,
+        &format!("tokio task blocked in a poll for {BLOCK:?} (threshold {THRESHOLD:?}) at {}:1:1 in task worker", file!())
+    );
+    assert_eq!(kvs["poll_duration_ns"], BLOCK.as_nanos().to_string());
+    assert_eq!(kvs["callsite.file"], file!());
+    assert_eq!(kvs["task.name"], "worker");
+
+    let (level, target, message, kvs) = &records[1];
+    assert_eq!(*level, log::Level::Warn);
+    assert_eq!(target, "tokio_blocked::task_blocked_total");
+    assert!(
+        message.contains("over 2 polls (1 over threshold)"),
+        "{message}"
+    );
+    assert_eq!(kvs["polls_total"], "2");
+}
+
+#[cfg(feature = "span-trace")]
+#[test]
+fn span_trace_lists_entered_spans() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let collector = BlockedEventCollector::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_error::ErrorLayer::default())
+        .with(layer)
+        .with(collector.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let task = task_span!();
+    {
+        let _task = task.enter();
+        let _outer = tracing::info_span!("handle_request").entered();
+        let _inner = tracing::info_span!("load_user", user = 7).entered();
+        let op = async_op_span("tokio::sync::mpsc::Receiver", "Sync", "Receiver::recv");
+        poll_manual(&op, &clock, BLOCK);
+    }
+
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 2);
+    let trace = &events[0].fields["span_trace"];
+    let names = ["load_user", "handle_request", "runtime.spawn"];
+    let positions: Vec<_> = names.iter().map(|n| trace.find(n).unwrap()).collect();
+    assert!(positions.is_sorted(), "{trace}");
+    assert!(trace.contains("user=7"), "{trace}");
+    // Tokio polls tasks outside of any span.
+    assert!(!events[1].fields.contains_key("span_trace"));
+}
+
+/// Records the fields that the tracing events also carry.
+#[derive(Default)]
+struct FieldsObserver {
+    polls: std::sync::Mutex<Vec<Vec<(&'static str, String)>>>,
+    closed: std::sync::Mutex<Vec<Vec<(&'static str, String)>>>,
+}
+
+impl BlockedObserver for FieldsObserver {
+    fn on_poll_blocked(&self, info: &BlockedPollInfo<'_>) {
+        self.polls.lock().unwrap().push(vec![
+            (
+                "poll_duration_ns",
+                info.poll_duration.as_nanos().to_string(),
+            ),
+            ("threshold_ns", info.threshold.as_nanos().to_string()),
+            ("poll_seq", info.poll_seq.to_string()),
+            ("exceed_count", info.exceed_count.to_string()),
+            ("callsite.name", info.callsite_name.to_string()),
+            ("callsite.line", info.origin_line.unwrap().to_string()),
+            ("task.name", info.task_name.as_deref().unwrap().to_string()),
+            ("task.id", info.task_id.unwrap().to_string()),
+        ]);
+    }
+
+    fn on_span_closed(&self, info: &BlockedTotalInfo<'_>) {
+        self.closed.lock().unwrap().push(vec![
+            ("busy_ns", info.busy.as_nanos().to_string()),
+            ("duration_ns", info.lifetime.as_nanos().to_string()),
+            (
+                "threshold_ns",
+                info.threshold.unwrap().as_nanos().to_string(),
+            ),
+            ("blocked_percent", info.blocked_percent.to_string()),
+            ("max_poll_ns", info.max_poll.as_nanos().to_string()),
+            (
+                "polls_over_threshold",
+                info.polls_over_threshold.to_string(),
+            ),
+            ("polls_total", info.polls.to_string()),
+            (
+                "mean_poll_ns",
+                info.mean_poll().unwrap().as_nanos().to_string(),
+            ),
+            ("pattern", info.pattern.unwrap().to_string()),
+            ("callsite.line", info.origin_line.unwrap().to_string()),
+            ("task.id", info.task_id.unwrap().to_string()),
+        ]);
+    }
+}
+
+#[test]
+fn event_info_matches_tracing_events() {
+    use std::sync::Arc;
+
+    let observer = Arc::new(FieldsObserver::default());
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_observer(observer.clone())
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = tracing::trace_span!(
+        target: "tokio::task",
+        "runtime.spawn",
+        task.name = %"worker",
+        task.id = 7u64,
+        loc.file = file!(),
+        loc.line = line!(),
+        loc.col = column!(),
+    );
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    poll_manual(&span, &clock, BLOCK);
+    clock.advance(BLOCK);
+    drop(span);
+
+    let check = |expected: &[Vec<(&str, String)>], kind| {
+        let events = collector.of_kind(kind);
+        assert_eq!(events.len(), expected.len());
+        for (fields, event) in expected.iter().zip(&events) {
+            for (name, value) in fields {
+                assert_eq!(event.fields.get(*name), Some(value), "field {name}");
+            }
+        }
+    };
+    let polls = observer.polls.lock().unwrap();
+    assert_eq!(polls[0][2], ("poll_seq", "2".to_string()));
+    check(&polls, EventKind::PollBlocked);
+    check(&observer.closed.lock().unwrap(), EventKind::BlockedTotal);
+}
+
+#[cfg(any(feature = "serde", feature = "sqlite"))]
+thread_local! {
+    static RUNTIME_LABEL: std::cell::Cell<Option<&'static str>> = const { std::cell::Cell::new(None) };
+}
+
+/// Runtime label of the current thread, set with [`RUNTIME_LABEL`], for
+/// `with_runtime_label` without real runtimes.
+#[cfg(any(feature = "serde", feature = "sqlite"))]
+fn thread_runtime_label() -> Option<&'static str> {
+    RUNTIME_LABEL.with(|label| label.get())
+}
+
+/// Polls one span per runtime label at the same location, `polls` times for
+/// `BLOCK / 2` each.
+#[cfg(any(feature = "serde", feature = "sqlite"))]
+fn poll_per_runtime(clock: &ManualClock, runtimes: &[&'static str], polls: usize) {
+    for &runtime in runtimes {
+        RUNTIME_LABEL.with(|label| label.set(Some(runtime)));
+        let span = named_task_span(None);
+        for _ in 0..polls {
+            poll_manual(&span, clock, BLOCK / 2);
+        }
+    }
+    RUNTIME_LABEL.with(|label| label.set(None));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn timeseries_file_writes_interval_deltas() {
+    let path = std::env::temp_dir().join(format!(
+        "tokio-blocked-timeseries-{}.ndjson",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_clock(clock.clone())
+        .with_timeseries_file(&path, Duration::from_secs(3600))
+        .unwrap();
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let line = line!() + 2;
+    for (interval, duration) in [BLOCK, THRESHOLD].into_iter().enumerate() {
+        let span = task_span!();
+        poll_manual(&span, &clock, duration);
+        poll_manual(&span, &clock, duration);
+        drop(span);
+        if interval == 1 {
+            let other = task_span!();
+            poll_manual(&other, &clock, BLOCK);
+        }
+        handle.report();
+    }
+    // Nothing happened since the last report, so nothing is written.
+    handle.report();
+
+    let samples: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(samples.len(), 3, "{samples:?}");
+    let nanos = |d: Duration| serde_json::json!(d.as_nanos() as u64);
+
+    assert_eq!(samples[0]["line"], line);
+    assert_eq!(samples[0]["busy_ns"], nanos(BLOCK * 2));
+    assert_eq!(samples[0]["polls"], 2);
+    assert_eq!(samples[0]["spans"], 1);
+    assert_eq!(samples[0]["max_poll_ns"], nanos(BLOCK));
+
+    let second = samples[1..].iter().find(|s| s["line"] == line).unwrap();
+    assert_eq!(second["busy_ns"], nanos(THRESHOLD * 2));
+    assert_eq!(second["polls"], 2);
+    assert_eq!(second["spans"], 1);
+    assert_eq!(second["max_poll_ns"], nanos(BLOCK));
+    assert!(second["timestamp_unix_ns"].as_u64() >= samples[0]["timestamp_unix_ns"].as_u64());
+    let other = samples[1..].iter().find(|s| s["line"] != line).unwrap();
+    assert_eq!(other["busy_ns"], nanos(BLOCK));
+    assert_eq!(other["polls"], 1);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn timeseries_rows_of_runtimes_keep_their_own_deltas() {
+    let path = std::env::temp_dir().join(format!(
+        "tokio-blocked-timeseries-runtimes-{}.ndjson",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_clock(clock.clone())
+        .with_runtime_label(thread_runtime_label)
+        .with_timeseries_file(&path, Duration::from_secs(3600))
+        .unwrap();
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    for _ in 0..3 {
+        poll_per_runtime(&clock, &["a", "b"], 5);
+        handle.report();
+    }
+
+    let samples: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(samples.len(), 6, "{samples:?}");
+    for runtime in ["a", "b"] {
+        let rows: Vec<_> = samples.iter().filter(|s| s["runtime"] == runtime).collect();
+        assert_eq!(rows.len(), 3, "{samples:?}");
+        for row in rows {
+            assert_eq!(row["polls"], 5, "{samples:?}");
+            assert_eq!(row["busy_ns"], (BLOCK / 2 * 5).as_nanos() as u64);
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_sink_keeps_runtimes_apart() {
+    let path = std::env::temp_dir().join(format!(
+        "tokio-blocked-runtimes-{}.sqlite",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_clock(clock.clone())
+        .with_runtime_label(thread_runtime_label)
+        .with_sqlite_sink(&path, Duration::from_secs(3600))
+        .unwrap();
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    for _ in 0..3 {
+        poll_per_runtime(&clock, &["a", "b"], 5);
+        handle.report();
+    }
+
+    let db = rusqlite::Connection::open(&path).unwrap();
+    let mut samples: Vec<(String, i64, i64)> = db
+        .prepare(
+            "SELECT c.runtime, s.busy_ns, s.polls
+             FROM samples s JOIN callsites c ON c.id = s.callsite_id",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+    samples.sort();
+    let busy = (BLOCK / 2 * 5).as_nanos() as i64;
+    let sample = |runtime: &str| (runtime.to_string(), busy, 5);
+    assert_eq!(samples, ["a", "a", "a", "b", "b", "b"].map(sample));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_sink_stores_interval_samples() {
+    use tokio_blocked::SqliteSink;
+
+    let path = std::env::temp_dir().join(format!(
+        "tokio-blocked-samples-{}.sqlite",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_clock(clock.clone())
+        .with_sqlite_sink(&path, Duration::from_secs(3600))
+        .unwrap();
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let line = line!() + 3;
+    for (spans, duration) in [(2, BLOCK), (1, THRESHOLD)] {
+        for _ in 0..spans {
+            let span = task_span!();
+            poll_manual(&span, &clock, duration);
+        }
+        handle.report();
+    }
+    // Nothing happened since the last report, so nothing is written.
+    handle.report();
+
+    let db = rusqlite::Connection::open(&path).unwrap();
+    let callsites: Vec<(i64, String, Option<String>, Option<u32>)> = db
+        .prepare("SELECT id, name, file, line FROM callsites")
+        .unwrap()
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(callsites.len(), 1, "{callsites:?}");
+    let (id, name, file, callsite_line) = &callsites[0];
+    assert_eq!(name, "runtime.spawn");
+    assert_eq!(file.as_deref(), Some(file!()));
+    assert_eq!(*callsite_line, Some(line));
+
+    let samples: Vec<(i64, i64, i64, i64, i64, i64)> = db
+        .prepare(
+            "SELECT timestamp_unix_ns, callsite_id, busy_ns, polls, spans, max_poll_ns
+             FROM samples ORDER BY rowid",
+        )
+        .unwrap()
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let nanos = |d: Duration| d.as_nanos() as i64;
+    assert_eq!(samples.len(), 2, "{samples:?}");
+    // The longest poll so far, not of the interval.
+    assert_eq!(
+        (
+            samples[0].1,
+            samples[0].2,
+            samples[0].3,
+            samples[0].4,
+            samples[0].5
+        ),
+        (*id, nanos(BLOCK * 2), 2, 2, nanos(BLOCK))
+    );
+    assert_eq!(
+        (
+            samples[1].1,
+            samples[1].2,
+            samples[1].3,
+            samples[1].4,
+            samples[1].5
+        ),
+        (*id, nanos(THRESHOLD), 1, 1, nanos(BLOCK))
+    );
+    assert!(samples[1].0 >= samples[0].0);
+
+    // Reopening keeps the schema and data, pruning removes old samples.
+    let sink = SqliteSink::open(&path).unwrap();
+    assert_eq!(sink.prune(Duration::from_secs(3600)).unwrap(), 0);
+    assert_eq!(sink.prune(Duration::ZERO).unwrap(), 2);
+    let count = |table: &str| -> i64 {
+        db.query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    };
+    assert_eq!(count("samples"), 0);
+    assert_eq!(count("callsites"), 0);
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn event_info_serializes() {
+    use std::sync::{Arc, Mutex};
+
+    let json = Arc::new(Mutex::new(Vec::new()));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_on_blocked({
+            let json = json.clone();
+            move |info| {
+                json.lock()
+                    .unwrap()
+                    .push(serde_json::to_value(info).unwrap())
+            }
+        })
+        .with_clock(clock.clone());
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let json = json.lock().unwrap();
+    let value = &json[0];
+    assert_eq!(value["poll_duration_ns"], BLOCK.as_nanos() as u64);
+    assert_eq!(value["threshold_ns"], THRESHOLD.as_nanos() as u64);
+    assert_eq!(value["poll_seq"], 1);
+    assert_eq!(value["level"], "WARN");
+    assert_eq!(value["callsite_name"], "runtime.spawn");
+    assert!(value["thread_id"].as_str().unwrap().starts_with("ThreadId"));
+    assert!(value["timestamp_unix_ns"].as_u64().unwrap() > 0);
+}
+
+#[cfg(feature = "channel")]
+#[test]
+fn subscribe_receives_blocked_events() {
+    use tokio_blocked::events::BlockedEvent;
+    use tracing::Instrument as _;
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(THRESHOLD));
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let mut first = handle.subscribe();
+    let mut second = handle.subscribe();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let line = rt.block_on(async {
+        let span = task_span!();
+        tokio::spawn(async { std::thread::sleep(BLOCK) }.instrument(span))
+            .await
+            .unwrap();
+        line!() - 4
+    });
+    drop(rt);
+
+    // With `tokio_unstable`, the real task spans are reported as well.
+    for rx in [&mut first, &mut second] {
+        let events: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        let polls: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                BlockedEvent::PollBlocked(info) if info.origin_line == Some(line) => Some(info),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(polls.len(), 1, "{events:?}");
+        assert!(polls[0].poll_duration >= BLOCK);
+        assert!(events.iter().any(|e| matches!(
+            e,
+            BlockedEvent::TotalBlocked(info)
+                if info.origin_line == Some(line) && info.busy >= BLOCK
+        )));
+    }
+    assert_eq!(handle.dropped_events(), 0);
+
+    // A closed subscriber is removed, a full one drops new events.
+    drop(first);
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::from_handle(&handle)
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let (_collector, _guard) = install(layer);
+    for _ in 0..1030 {
+        let span = task_span!();
+        poll_manual(&span, &clock, BLOCK);
+    }
+    assert_eq!(handle.dropped_events(), 6);
+    assert_eq!(handle.diagnostics().dropped_events, 6);
+}
+
+#[cfg(feature = "channel")]
+#[test]
+fn watch_snapshot_after_blocking_burst() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    assert!(!handle.publish_snapshot(), "published without receivers");
+
+    let mut rx = handle.watch_snapshot();
+    assert!(rx.borrow_and_update().is_empty());
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    for _ in 0..3 {
+        let span = task_span!();
+        poll_manual(&span, &clock, BLOCK);
+    }
+    let watcher = rt.spawn(async move {
+        rx.changed().await.unwrap();
+        let snapshot = rx.borrow_and_update().clone();
+        snapshot
+    });
+    assert!(handle.publish_snapshot());
+
+    let snapshot = rt.block_on(watcher).unwrap();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].count, 3);
+    assert_eq!(snapshot[0].total_busy, BLOCK * 3);
+}
+
+#[test]
+fn per_task_summary_replaces_poll_warnings() {
+    let run = |summary: bool| {
+        let clock = ManualClock::new();
+        let layer = TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_per_task_summary(summary)
+            .with_clock(clock.clone());
+        let (collector, _guard) = install(layer);
+        for _ in 0..2 {
+            let span = task_span!();
+            for _ in 0..5 {
+                poll_manual(&span, &clock, BLOCK);
+                poll_manual(&span, &clock, Duration::from_millis(1));
+            }
+        }
+        // Never exceeds the single-poll threshold.
+        let calm = task_span!();
+        poll_manual(&calm, &clock, Duration::from_millis(1));
+        drop(calm);
+        collector
+    };
+
+    let per_poll = run(false);
+    assert_eq!(per_poll.poll_blocked_events().len(), 10);
+    assert!(per_poll.of_kind(EventKind::BlockedTotal).is_empty());
+
+    let summary = run(true);
+    assert!(summary.poll_blocked_events().is_empty());
+    let totals = summary.of_kind(EventKind::BlockedTotal);
+    assert_eq!(totals.len(), 2);
+    for event in &totals {
+        assert_eq!(event.fields["max_poll_ns"], BLOCK.as_nanos().to_string());
+        assert_eq!(event.fields["max_poll_seq"], "1");
+        assert_eq!(event.fields["polls_over_threshold"], "5");
+        assert_eq!(event.fields["polls_total"], "10");
+        assert_eq!(event.duration, Some(BLOCK * 5 + Duration::from_millis(5)));
+        assert!(!event.fields.contains_key("threshold_ns"));
+    }
+}
+
+#[test]
+fn total_warning_tells_one_long_poll_from_many_short_ones() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let huge = named_task_span(Some("huge"));
+    poll_manual(&huge, &clock, BLOCK * 5);
+    drop(huge);
+    // Each poll stays under the single-poll threshold.
+    let cuts = named_task_span(Some("cuts"));
+    for _ in 0..50 {
+        poll_manual(&cuts, &clock, Duration::from_millis(2));
+    }
+    drop(cuts);
+
+    let totals = collector.of_kind(EventKind::BlockedTotal);
+    let total = |name: &str| {
+        totals
+            .iter()
+            .find(|e| e.fields.get("task.name").map(String::as_str) == Some(name))
+            .unwrap_or_else(|| panic!("no {name} in {totals:?}"))
+            .fields
+            .clone()
+    };
+    let huge = total("huge");
+    assert_eq!(huge["polls_total"], "1");
+    assert_eq!(huge["polls_over_threshold"], "1");
+    assert_eq!(huge["mean_poll_ns"], (BLOCK * 5).as_nanos().to_string());
+    let cuts = total("cuts");
+    assert_eq!(cuts["polls_total"], "50");
+    assert_eq!(cuts["polls_over_threshold"], "0");
+    assert_eq!(cuts["mean_poll_ns"], "2000000");
+}
+
+/// Closes spans named after the patterns they should be classified as,
+/// returning the `pattern` field of their total events.
+fn blocking_patterns(layer: TokioBlockedLayer) -> Vec<(String, String)> {
+    let clock = ManualClock::new();
+    let layer = layer
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_per_task_summary(true)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let ms = Duration::from_millis;
+    for (name, polls) in [
+        ("single_long_poll", vec![ms(1), ms(100), ms(1)]),
+        ("uniformly_slow", vec![ms(10); 8]),
+        ("many_moderate_polls", vec![ms(2); 50]),
+        // Under the total threshold, reported as a per-task summary.
+        ("mixed", vec![ms(2), ms(6), ms(6)]),
+    ] {
+        let span = named_task_span(Some(name));
+        for poll in polls {
+            poll_manual(&span, &clock, poll);
+        }
+    }
+    // Spans that didn't block aren't counted.
+    let span = named_task_span(Some("fine"));
+    poll_manual(&span, &clock, ms(1));
+    drop(span);
+
+    let patterns = handle.snapshot().pop().unwrap().patterns;
+    assert_eq!(patterns.total(), 4, "{patterns:?}");
+    collector
+        .of_kind(EventKind::BlockedTotal)
+        .iter()
+        .map(|e| (e.fields["task.name"].clone(), e.fields["pattern"].clone()))
+        .collect()
+}
+
+#[test]
+fn total_events_classify_the_blocking_pattern() {
+    let patterns = blocking_patterns(TokioBlockedLayer::new());
+    assert_eq!(patterns.len(), 4, "{patterns:?}");
+    for (name, pattern) in &patterns {
+        assert_eq!(name, pattern);
+    }
+}
+
+#[test]
+fn blocking_pattern_thresholds_can_be_overridden() {
+    // Polls of 10ms no longer count as slow, but as moderate.
+    let patterns = blocking_patterns(
+        TokioBlockedLayer::new().with_blocking_patterns(PatternThresholds::new().slow_mean(BLOCK)),
+    );
+    let pattern = |name: &str| {
+        let (_, pattern) = patterns.iter().find(|(n, _)| n == name).unwrap();
+        pattern.as_str()
+    };
+    assert_eq!(pattern("uniformly_slow"), "many_moderate_polls");
+    assert_eq!(pattern("single_long_poll"), "single_long_poll");
+}
+
+#[test]
+fn cpu_time_tells_waiting_from_computing() {
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_cpu_time(true);
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let sleeping = named_task_span(Some("sleeping"));
+    sleeping.in_scope(|| std::thread::sleep(BLOCK * 2));
+    drop(sleeping);
+    let spinning = named_task_span(Some("spinning"));
+    spinning.in_scope(|| {
+        let start = std::time::Instant::now();
+        while start.elapsed() < BLOCK * 2 {
+            std::hint::spin_loop();
+        }
+    });
+    drop(spinning);
+
+    let on_cpu = |events: Vec<CapturedEvent>, name: &str| -> f64 {
+        let event = events
+            .into_iter()
+            .find(|e| e.fields["task.name"] == name)
+            .unwrap();
+        assert!(event.fields.contains_key("cpu_ns"), "{event:?}");
+        event.fields["on_cpu_percent"].parse().unwrap()
+    };
+    for events in [
+        collector.poll_blocked_events(),
+        collector.of_kind(EventKind::BlockedTotal),
+    ] {
+        assert!(on_cpu(events.clone(), "sleeping") < 50.0, "{events:?}");
+        assert!(on_cpu(events.clone(), "spinning") > 50.0, "{events:?}");
+    }
+    let row = handle.snapshot().pop().unwrap();
+    assert!(row.total_cpu >= BLOCK, "{row:?}");
+    assert!(row.on_cpu_percent().unwrap() < 90.0, "{row:?}");
+}
+
+#[test]
+fn cpu_time_is_off_by_default() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let events = collector.poll_blocked_events();
+    assert!(!events[0].fields.contains_key("cpu_ns"), "{events:?}");
+    assert_eq!(handle.snapshot()[0].total_cpu, Duration::ZERO);
+    assert_eq!(handle.snapshot()[0].on_cpu_percent(), None);
+}
+
+#[test]
+fn slo_thresholds_count_polls_per_callsite() {
+    let clock = ManualClock::new();
+    let ms = Duration::from_millis;
+    let ns = Duration::from_nanos;
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_slo_thresholds(&[ms(10), ms(1), ms(100), ms(10)])
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let line = line!() + 1;
+    let span = task_span!();
+    for poll in [
+        ms(1) - ns(1),
+        ms(1),
+        ms(10) - ns(1),
+        ms(10),
+        ms(10) + ns(1),
+        ms(100) - ns(1),
+        ms(100),
+        ms(250),
+    ] {
+        poll_manual(&span, &clock, poll);
+    }
+    // Counted when the span closes.
+    assert!(handle
+        .snapshot()
+        .iter()
+        .all(|row| row.over_threshold.is_empty()));
+    drop(span);
+    let other_line = line!() + 1;
+    let other = task_span!();
+    poll_manual(&other, &clock, ms(1));
+    drop(other);
+
+    let counters = |line: u32| {
+        let snapshot = handle.snapshot();
+        let row = snapshot.iter().find(|row| row.line == Some(line)).unwrap();
+        row.over_threshold.clone()
+    };
+    assert_eq!(counters(line), [(ms(1), 7), (ms(10), 5), (ms(100), 2)]);
+    assert_eq!(
+        counters(other_line),
+        [(ms(1), 1), (ms(10), 0), (ms(100), 0)]
+    );
+
+    let prometheus = handle.render_prometheus();
+    let series = format!(
+        "tokio_blocked_polls_over_threshold_total{{file=\"{}\",line=\"{line}\",\
+         name=\"runtime.spawn\",threshold_seconds=\"0.01\"}} 5",
+        file!()
+    );
+    assert!(prometheus.contains(&series), "{prometheus}");
+
+    #[cfg(feature = "serde")]
+    {
+        let snapshot = Snapshot::from(handle.snapshot());
+        let json = snapshot.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(value["callsites"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|c| c["over_threshold"][1]
+                == serde_json::json!({ "threshold_ns": 10_000_000, "polls": 5 })));
+        assert_eq!(Snapshot::from_json(&json).unwrap(), snapshot);
+    }
+}
+
+#[test]
+fn consecutive_blocked_spans_alert_once_per_streak() {
+    use std::sync::{Arc, Mutex};
+
+    let streaks = Arc::new(Mutex::new(Vec::new()));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_busy_total(Some(BLOCK))
+        .with_consecutive_alert(3, {
+            let streaks = streaks.clone();
+            move |info| streaks.lock().unwrap().push((info.streak, info.busy))
+        })
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+    let run = |busy: Duration| {
+        let span = task_span!();
+        poll_manual(&span, &clock, busy);
+    };
+    let clean = Duration::from_millis(1);
+
+    // Broken before reaching the count.
+    run(BLOCK);
+    run(BLOCK);
+    run(clean);
+    assert!(streaks.lock().unwrap().is_empty());
+    // Alerts on the third span, and not again while the streak continues.
+    for _ in 0..5 {
+        run(BLOCK);
+    }
+    assert_eq!(*streaks.lock().unwrap(), [(3, BLOCK)]);
+    // A clean span re-arms the alert.
+    run(clean);
+    for _ in 0..3 {
+        run(BLOCK * 2);
+    }
+    assert_eq!(*streaks.lock().unwrap(), [(3, BLOCK), (3, BLOCK * 2)]);
+
+    let events = collector.of_kind(EventKind::SustainedBlocking);
+    assert_eq!(events.len(), 2, "{events:?}");
+    assert_eq!(events[0].level, Level::ERROR);
+    assert_eq!(events[0].fields["streak"], "3");
+    assert_eq!(events[0].file.as_deref(), Some(file!()));
+}
+
+#[test]
+fn total_warning_reports_longest_poll() {
+    use std::sync::{Arc, Mutex};
+
+    let totals = Arc::new(Mutex::new(Vec::new()));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_busy_total(Some(BLOCK))
+        .with_on_blocked_total({
+            let totals = totals.clone();
+            move |info| {
+                totals
+                    .lock()
+                    .unwrap()
+                    .push((info.max_poll, info.max_poll_seq))
+            }
+        })
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for duration in [2, 3, 15, 3, 15] {
+        poll_manual(&span, &clock, Duration::from_millis(duration));
+    }
+    drop(span);
+
+    let events = collector.of_kind(EventKind::BlockedTotal);
+    assert_eq!(events.len(), 1);
+    let max_poll = Duration::from_millis(15);
+    assert_eq!(
+        events[0].fields["max_poll_ns"],
+        max_poll.as_nanos().to_string()
+    );
+    // The first of equally long polls.
+    assert_eq!(events[0].fields["max_poll_seq"], "3");
+    assert_eq!(*totals.lock().unwrap(), [(max_poll, 3)]);
+}
+
+#[test]
+fn busy_total_eager_warns_while_open() {
+    for final_warning in [false, true] {
+        let clock = ManualClock::new();
+        let layer = TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(None)
+            .with_warn_busy_total(Some(BLOCK))
+            .with_warn_busy_total_eager(true)
+            .with_warn_busy_total_final(final_warning)
+            .with_clock(clock.clone());
+        let (collector, _guard) = install(layer);
+
+        let span = task_span!();
+        for _ in 0..3 {
+            poll_manual(&span, &clock, Duration::from_millis(8));
+        }
+        let events = collector.of_kind(EventKind::BlockedTotal);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].fields["span_still_open"], "true");
+        assert_eq!(events[0].fields["polls_total"], "3");
+
+        // Crossing the threshold is only reported once.
+        poll_manual(&span, &clock, Duration::from_millis(8));
+        assert_eq!(collector.of_kind(EventKind::BlockedTotal).len(), 1);
+
+        drop(span);
+        let events = collector.of_kind(EventKind::BlockedTotal);
+        if final_warning {
+            assert_eq!(events.len(), 2);
+            assert_eq!(events[1].fields["span_still_open"], "false");
+            assert_eq!(events[1].fields["polls_total"], "4");
+        } else {
+            assert_eq!(events.len(), 1);
+        }
+    }
+}
+
+#[test]
+fn summary_matches_snapshot_totals() {
+    use tokio_blocked::Clock as _;
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let start = clock.now();
+
+    for duration in [2, 3] {
+        let span = task_span!();
+        poll_manual(&span, &clock, Duration::from_millis(duration));
+    }
+    let span = task_span!();
+    for duration in [1, 9, 4] {
+        poll_manual(&span, &clock, Duration::from_millis(duration));
+    }
+    let worst_line = line!() - 4;
+    drop(span);
+
+    let summary = handle.summary();
+    let rows = handle.snapshot();
+    assert_eq!(summary.callsites, rows.len());
+    assert_eq!(summary.callsites, 2);
+    assert_eq!(
+        summary.total_busy,
+        rows.iter().map(|r| r.total_busy).sum::<Duration>()
+    );
+    assert_eq!(summary.polls, rows.iter().map(|r| r.polls).sum::<u64>());
+    assert_eq!(summary.spans, rows.iter().map(|r| r.count).sum::<u64>());
+    assert_eq!((summary.polls, summary.spans), (5, 3));
+    let worst = summary.worst_poll.unwrap();
+    assert_eq!(worst.duration, Duration::from_millis(9));
+    assert_eq!(worst.callsite.line, Some(worst_line));
+    assert_eq!((summary.since, summary.until), (start, clock.now()));
+
+    handle.reset();
+    let summary = handle.summary();
+    assert_eq!((summary.polls, summary.callsites), (0, 0));
+    assert_eq!(summary.worst_poll, None);
+    assert_eq!(summary.since, clock.now());
+}
+
+#[test]
+fn worst_poll_tracks_longest_task_poll() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    assert_eq!(handle.worst_poll(), None);
+
+    let first = task_span!();
+    let named = named_task_span(Some("worst"));
+    for (span, duration) in [(&first, 4), (&named, 12), (&first, 7), (&named, 3)] {
+        poll_manual(span, &clock, Duration::from_millis(duration));
+    }
+
+    // Open tasks are included.
+    let worst = handle.worst_poll().unwrap();
+    assert_eq!(worst.duration, Duration::from_millis(12));
+    assert_eq!(
+        (worst.file.as_str(), worst.line, worst.col),
+        (file!(), 1, 1)
+    );
+    assert_eq!(worst.callsite.line, Some(1));
+    assert_eq!(worst.task_name.as_deref(), Some("worst"));
+    assert_eq!(worst.thread_id, std::thread::current().id());
+    assert_eq!(handle.summary().worst_poll, Some(worst));
+    let report = handle.render_markdown(&MarkdownOptions::default());
+    assert!(report.contains(&format!("worst poll: 12.00 ms at `{}:1:1`", file!())));
+
+    handle.reset();
+    assert_eq!(handle.worst_poll(), None);
+    poll_manual(&first, &clock, Duration::from_millis(2));
+    assert_eq!(
+        handle.worst_poll().map(|w| w.duration),
+        Some(Duration::from_millis(2))
+    );
+}
+
+#[test]
+fn first_exceeded_at_is_set_once() {
+    use std::time::SystemTime;
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_dedup_window(Duration::from_secs(60))
+        .with_live_snapshots(true)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    // Measured even while warnings are suppressed.
+    handle.set_warnings_enabled(false);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    assert_eq!(handle.snapshot_with_live(true)[0].first_exceeded_at, None);
+
+    let before = SystemTime::now();
+    poll_manual(&span, &clock, BLOCK);
+    let after = SystemTime::now();
+    let first = handle.snapshot()[0].first_exceeded_at.unwrap();
+    assert!(before <= first && first <= after);
+
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    let rows = handle.snapshot();
+    assert_eq!(rows[0].exceed_count, 2);
+    assert_eq!(rows[0].first_exceeded_at, Some(first));
+    assert!(collector.poll_blocked_events().is_empty());
+}
+
+#[test]
+fn warning_history_keeps_newest_warnings() {
+    use tokio_blocked::events::WarningInfo;
+
+    for suppressed in [false, true] {
+        let clock = ManualClock::new();
+        let layer = TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_warn_busy_total(Some(BLOCK * 3))
+            .with_warning_history(3)
+            .with_warning_history_suppressed(suppressed)
+            .with_clock(clock.clone());
+        let handle = layer.handle();
+        let (_collector, _guard) = install(layer);
+
+        let span = task_span!();
+        for ms in [10, 11, 12, 13] {
+            poll_manual(&span, &clock, Duration::from_millis(ms));
+        }
+        handle.set_warnings_enabled(false);
+        poll_manual(&span, &clock, Duration::from_millis(14));
+        handle.set_warnings_enabled(true);
+        drop(span);
+
+        let warnings = handle.recent_warnings();
+        assert_eq!(warnings.len(), 3);
+        let polls: Vec<_> = warnings
+            .iter()
+            .filter_map(|w| match &w.warning {
+                WarningInfo::PollBlocked(info) => Some((w.suppressed, info.poll_duration)),
+                _ => None,
+            })
+            .collect();
+        let ms = Duration::from_millis;
+        if suppressed {
+            assert_eq!(polls, [(true, ms(14)), (false, ms(13))]);
+        } else {
+            assert_eq!(polls, [(false, ms(13)), (false, ms(12))]);
+        }
+        let WarningInfo::TotalBlocked(total) = &warnings[0].warning else {
+            panic!("expected the total warning first");
+        };
+        assert_eq!(total.polls, 5);
+        assert!(!warnings[0].suppressed);
+        assert!(warnings
+            .windows(2)
+            .all(|w| w[0].timestamp() >= w[1].timestamp()));
+    }
+}
+
+/// A task span spawned from line 1 of this file, like `tokio::task::Builder`
+/// creates for named tasks.
+fn named_task_span(name: Option<&str>) -> Span {
+    tracing::trace_span!(
+        target: "tokio::task",
+        "runtime.spawn",
+        task.name = name.map(tracing::field::display),
+        loc.file = file!(),
+        loc.line = 1u32,
+        loc.col = 1u32,
+    )
+}
+
+#[test]
+fn group_by_task_name() {
+    let run = |group_by| {
+        let clock = ManualClock::new();
+        let layer = TokioBlockedLayer::new()
+            .with_group_by(group_by)
+            .with_clock(clock.clone());
+        let handle = layer.handle();
+        let (_collector, _guard) = install(layer);
+        for (name, busy) in [
+            (Some("db-writer"), 10),
+            (Some("http-conn"), 3),
+            (Some("db-writer"), 5),
+            (None, 1),
+            (Some(""), 1),
+        ] {
+            let span = named_task_span(name);
+            poll_manual(&span, &clock, Duration::from_millis(busy));
+        }
+
+        let mut rows: Vec<_> = handle
+            .snapshot()
+            .iter()
+            .map(|s| (s.task_name, s.line, s.count, s.total_busy.as_millis()))
+            .collect();
+        rows.sort();
+        rows
+    };
+
+    assert_eq!(run(GroupBy::Location), [(None, Some(1), 5, 20)]);
+    assert_eq!(
+        run(GroupBy::TaskName),
+        [
+            (None, Some(1), 2, 2),
+            (Some("db-writer"), None, 2, 15),
+            (Some("http-conn"), None, 1, 3),
+        ]
+    );
+    assert_eq!(
+        run(GroupBy::Both),
+        [
+            (None, Some(1), 5, 20),
+            (Some("db-writer"), None, 2, 15),
+            (Some("http-conn"), None, 1, 3),
+        ]
+    );
+}
+
+#[test]
+fn lock_wait_on_contended_mutex() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_warn_lock_wait(THRESHOLD)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    // The holder runs a slow critical section while the waiter's
+    // `Mutex::lock` is pending.
+    let holder = task_span!();
+    let waiter = task_span!();
+    let contended = waiter.in_scope(|| async_op_span("Mutex", "Sync", "Mutex::lock"));
+    poll_manual(&contended, &clock, Duration::ZERO);
+    poll_manual(&holder, &clock, BLOCK);
+    poll_manual(&contended, &clock, Duration::ZERO);
+    drop((contended, holder, waiter));
+    // Uncontended acquisition, a long sleep and a long channel receive.
+    let uncontended = async_op_span("Mutex", "Sync", "Mutex::lock");
+    poll_manual(&uncontended, &clock, Duration::ZERO);
+    drop(uncontended);
+    let sleep = async_op_span("Sleep", "timer", "Sleep::new_timeout");
+    poll_manual(&sleep, &clock, Duration::ZERO);
+    clock.advance(BLOCK);
+    drop(sleep);
+    let recv = async_op_span("Sender|Receiver", "Sync", "Receiver::await");
+    poll_manual(&recv, &clock, Duration::ZERO);
+    clock.advance(BLOCK);
+    drop(recv);
+
+    let waits = collector.of_kind(EventKind::LockWait);
+    assert_eq!(waits.len(), 1);
+    assert_eq!(waits[0].duration, Some(BLOCK));
+    assert_eq!(waits[0].fields["resource.concrete_type"], "Mutex");
+    assert_eq!(waits[0].fields["resource.source"], "Mutex::lock");
+    // The holder still gets the generic warning for its busy time.
+    let totals = collector.of_kind(EventKind::BlockedTotal);
+    assert_eq!(totals.len(), 1);
+    assert_eq!(totals[0].fields["callsite.name"], "runtime.spawn");
+
+    let locks: Vec<_> = handle
+        .snapshot()
+        .into_iter()
+        .filter(|s| s.lock_wait)
+        .collect();
+    // One row per parent task; the uncontended lock has none.
+    assert_eq!(locks.len(), 2);
+    let contended = locks.iter().find(|s| s.parent_task.is_some()).unwrap();
+    assert_eq!(contended.resource_type, Some("Mutex"));
+    assert_eq!(contended.count, 1);
+    assert_eq!(contended.max_lifetime, BLOCK);
+}
+
+/// Real tokio resource spans need `--cfg tokio_unstable`, as in CI.
+#[cfg(tokio_unstable)]
+#[test]
+fn lock_wait_with_tokio_mutex() {
+    use std::sync::Arc;
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_lock_wait(THRESHOLD);
+    let (collector, _guard) = install(layer);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let mutex = Arc::new(tokio::sync::Mutex::new(()));
+        let guard = mutex.lock().await;
+        let waiter = tokio::spawn({
+            let mutex = mutex.clone();
+            async move {
+                let _guard = mutex.lock().await;
+            }
+        });
+        tokio::task::yield_now().await;
+        // Slow critical section while the waiter is queued.
+        std::thread::sleep(BLOCK);
+        drop(guard);
+        waiter.await.unwrap();
+    });
+
+    let waits = collector.of_kind(EventKind::LockWait);
+    assert_eq!(waits.len(), 1, "{waits:?}");
+    assert!(waits[0].duration.unwrap() >= BLOCK);
+    assert_eq!(waits[0].fields["resource.concrete_type"], "Mutex");
+}
+
+#[test]
+fn blocking_pool_ops_are_categorized() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_warn_blocking_pool_op(BLOCK)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let blocking = |duration| {
+        let span = tracing::trace_span!(
+            target: "tokio::task::blocking",
+            "runtime.spawn",
+            kind = %"blocking",
+            loc.file = "src/fs.rs",
+            loc.line = 1u32,
+        );
+        poll_manual(&span, &clock, duration);
+    };
+    blocking(BLOCK);
+    blocking(THRESHOLD);
+
+    assert!(collector.poll_blocked_events().is_empty());
+    assert!(collector.of_kind(EventKind::BlockedTotal).is_empty());
+    let ops = collector.of_kind(EventKind::BlockingPoolOp);
+    assert_eq!(ops.len(), 1);
+    assert_eq!(ops[0].duration, Some(BLOCK));
+    assert_eq!(ops[0].file.as_deref(), Some("src/fs.rs"));
+
+    let stats = handle.snapshot();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].category, Category::BlockingPool);
+    assert_eq!(stats[0].count, 2);
+    assert_eq!(stats[0].total_busy, BLOCK + THRESHOLD);
+    assert_eq!(
+        OwnedCallsiteStats::from(&stats[0]).category,
+        Category::BlockingPool
+    );
+}
+
+#[test]
+fn resource_lifetimes_are_tracked() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_track_resources(true)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let timer = || {
+        tracing::trace_span!(
+            target: "tokio::resource",
+            "runtime.resource",
+            concrete_type = "Sleep",
+            kind = "timer",
+            loc.file = "src/retry.rs",
+            loc.line = 3u32,
+        )
+    };
+    let timers: Vec<_> = (0..3).map(|_| timer()).collect();
+    assert_eq!(handle.live_spans_by_kind().resources, 3);
+    // Entering a resource to create an async op is not a poll.
+    poll_manual(&timers[0], &clock, BLOCK);
+    drop(timers);
+    let timer = timer();
+    clock.advance(BLOCK);
+    drop(timer);
+
+    assert!(collector.poll_blocked_events().is_empty());
+    assert!(collector.of_kind(EventKind::BlockedTotal).is_empty());
+    let stats = handle.snapshot();
+    assert_eq!(stats.len(), 1);
+    let row = &stats[0];
+    assert_eq!(row.category, Category::Resource);
+    assert_eq!(row.resource_type, Some("Sleep"));
+    assert_eq!((row.file, row.line), (Some("src/retry.rs"), Some(3)));
+    assert_eq!(row.count, 4);
+    assert_eq!(row.polls, 0);
+    assert_eq!(row.total_busy, Duration::ZERO);
+    assert_eq!(row.max_lifetime, BLOCK);
+    assert_eq!(row.total_lifetime, BLOCK * 4);
+    assert_eq!(handle.live_spans_by_kind().resources, 0);
+}
+
+/// Untracked unless enabled, as every resource adds a span.
+#[test]
+fn resources_are_not_tracked_by_default() {
+    let layer = TokioBlockedLayer::new();
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    drop(tracing::trace_span!(
+        target: "tokio::resource",
+        "runtime.resource",
+        concrete_type = "Sleep",
+        kind = "timer",
+    ));
+    assert!(handle.snapshot().is_empty());
+}
+
+/// Real tokio async op spans need `--cfg tokio_unstable`, as in CI. Tokio
+/// instruments timers and sync primitives, but not sockets.
+#[cfg(tokio_unstable)]
+#[test]
+fn async_op_sources_of_tokio_timer_and_channel() {
+    use std::collections::BTreeSet;
+
+    // The polls of real async ops are short, so warn about all of them.
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(Duration::ZERO));
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let sender = tokio::spawn(async move { tx.send(()).unwrap() });
+        rx.await.unwrap();
+        sender.await.unwrap();
+    });
+
+    let ops: BTreeSet<_> = handle
+        .snapshot()
+        .iter()
+        .filter(|row| row.name == "runtime.resource.async_op")
+        .map(|row| (row.resource_type.unwrap(), row.async_op_source.unwrap()))
+        .collect();
+    assert_eq!(
+        ops,
+        BTreeSet::from([
+            ("Sender|Receiver", "Receiver::await"),
+            ("Sleep", "Sleep::new_timeout"),
+        ])
+    );
+    let sources: BTreeSet<_> = collector
+        .poll_blocked_events()
+        .iter()
+        .filter_map(|e| e.fields.get("resource.source").cloned())
+        .collect();
+    assert!(sources.contains("Sleep::new_timeout"), "{sources:?}");
+    assert!(sources.contains("Receiver::await"), "{sources:?}");
+}
+
+/// Real tokio resource spans need `--cfg tokio_unstable`, as in CI.
+#[cfg(tokio_unstable)]
+#[test]
+fn resource_lifetimes_of_tokio_timers() {
+    let layer = TokioBlockedLayer::new().with_track_resources(true);
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    });
+
+    let stats = handle.snapshot();
+    let timers: Vec<_> = stats
+        .iter()
+        .filter(|row| row.category == Category::Resource && row.resource_type == Some("Sleep"))
+        .collect();
+    assert_eq!(timers.len(), 1, "{stats:?}");
+    assert_eq!(timers[0].count, 5);
+    assert_eq!(timers[0].file, Some(file!()));
+}
+
+/// Tokio only instruments the blocking pool with `--cfg tokio_unstable`.
+#[cfg(tokio_unstable)]
+#[test]
+fn tokio_fs_read_on_blocking_pool() {
+    let path = std::env::temp_dir().join(format!("tokio-blocked-fs-{}", std::process::id()));
+    std::fs::write(&path, vec![7u8; 32 << 20]).unwrap();
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_live_snapshots(true);
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let data = rt.block_on(tokio::fs::read(&path)).unwrap();
+    drop(rt);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(data.len(), 32 << 20);
+
+    // The blocking pool thread lacks the thread-local dispatcher, so the
+    // task spans are never closed; read their live statistics.
+    let stats = handle.snapshot_with_live(true);
+    let blocking: Vec<_> = stats
+        .iter()
+        .filter(|s| s.category == Category::BlockingPool)
+        .collect();
+    assert_eq!(blocking.len(), 1, "{stats:?}");
+    assert_eq!(blocking[0].name, "runtime.spawn");
+    assert!(blocking[0].total_busy > Duration::ZERO);
+    // The `block_on` task is a regular one.
+    assert!(stats.iter().any(|s| s.category == Category::Async));
+}
+
+#[test]
+fn async_op_attributed_to_parent_task() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let task = named_task_span(Some("handler"));
+    let read = task.in_scope(|| async_op_span("File", "io", "File::read"));
+    task.in_scope(|| poll_manual(&read, &clock, BLOCK));
+    drop(read);
+    drop(task);
+    // Not created inside a task.
+    let orphan = async_op_span("Sleep", "timer", "Sleep::new_timeout");
+    poll_manual(&orphan, &clock, BLOCK);
+    drop(orphan);
+
+    let is_op = |e: &&tokio_blocked::test_util::CapturedEvent| {
+        e.fields["callsite.name"] == "runtime.resource.async_op"
+    };
+    for kind in [EventKind::PollBlocked, EventKind::BlockedTotal] {
+        let events = collector.of_kind(kind);
+        let ops: Vec<_> = events.iter().filter(is_op).collect();
+        assert_eq!(ops.len(), 2, "{kind:?}");
+        let read = ops
+            .iter()
+            .find(|e| e.fields["resource.source"] == "File::read")
+            .unwrap();
+        assert_eq!(read.fields["parent_task.name"], "handler");
+        assert_eq!(read.fields["parent_task.file"], file!());
+        assert_eq!(read.fields["parent_task.line"], "1");
+        // The owning task, as for the task's own warnings.
+        assert_eq!(read.fields["task.name"], "handler");
+        assert_eq!(read.fields["task.file"], file!());
+        assert_eq!(read.fields["task.line"], "1");
+        let orphan = ops
+            .iter()
+            .find(|e| e.fields["resource.source"] == "Sleep::new_timeout")
+            .unwrap();
+        assert!(!orphan.fields.contains_key("parent_task.file"));
+        assert!(!orphan.fields.contains_key("task.name"));
+        // Task events have no parent.
+        let task = events.iter().find(|e| !is_op(e)).unwrap();
+        assert!(!task.fields.contains_key("parent_task.name"));
+        assert!(!task.fields.contains_key("task.file"));
+        assert_eq!(task.fields["task.name"], "handler");
+    }
+
+    let groups = handle.snapshot_by_task();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].task.file, Some(file!()));
+    assert_eq!(groups[0].task.line, Some(1));
+    assert_eq!(groups[0].task.name, Some("handler"));
+    assert!(groups[0].stats.as_ref().unwrap().total_busy >= BLOCK);
+    assert_eq!(groups[0].ops.len(), 1);
+    assert_eq!(groups[0].ops[0].async_op_source, Some("File::read"));
+    assert_eq!(groups[0].ops[0].parent_task, Some(groups[0].task));
+
+    let id = OwnedCallsiteStats::from(&groups[0].ops[0]).id;
+    assert_eq!(id.parent_task_line, Some(1));
+    assert!(id
+        .to_string()
+        .ends_with(&format!(" in task at {}:1", file!())));
+}
+
+#[test]
+fn loc_fields_with_both_conventions() {
+    let run = |names: Option<LocFieldNames>| {
+        let clock = ManualClock::new();
+        let mut layer = TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_clock(clock.clone());
+        if let Some(names) = names {
+            layer = layer.with_loc_fields(names);
+        }
+        let (collector, _guard) = install(layer);
+
+        let tokio = tracing::trace_span!(
+            target: "tokio::task",
+            "runtime.spawn",
+            loc.file = "src/tokio.rs",
+            loc.line = 10u32,
+            loc.col = 5u32,
+        );
+        poll_manual(&tokio, &clock, BLOCK);
+        let otel = tracing::trace_span!(
+            target: "tokio::task",
+            "runtime.spawn",
+            code.filepath = "src/otel.rs",
+            code.lineno = 20i64,
+            code.column = 7i64,
+        );
+        poll_manual(&otel, &clock, BLOCK);
+
+        collector
+            .poll_blocked_events()
+            .into_iter()
+            .map(|e| {
+                (
+                    e.file.unwrap(),
+                    e.line.unwrap(),
+                    e.fields["callsite.col"].clone(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let tokio = ("src/tokio.rs".to_string(), 10, "5".to_string());
+    let otel = ("src/otel.rs".to_string(), 20, "7".to_string());
+    // Without the OTel names, the span callsite is reported instead.
+    let defaults = run(None);
+    assert_eq!(defaults[0], tokio);
+    assert_eq!(defaults[1].0, file!());
+
+    assert_eq!(run(Some(LocFieldNames::otel()))[1], otel);
+    assert_eq!(
+        run(Some(LocFieldNames::tokio().and(LocFieldNames::otel()))),
+        [tokio, otel]
+    );
+}
+
+#[test]
+fn loc_fields_recorded_after_creation() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_loc_fields(LocFieldNames::otel())
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let span = tracing::trace_span!(
+        target: "tokio::task",
+        "runtime.spawn",
+        code.filepath = tracing::field::Empty,
+        code.lineno = tracing::field::Empty,
+        code.column = tracing::field::Empty,
+    );
+    span.record("code.filepath", "src/otel.rs");
+    span.record("code.lineno", 20i64);
+    span.record("code.column", 7i64);
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let events = collector.poll_blocked_events();
+    assert_eq!(events[0].file.as_deref(), Some("src/otel.rs"));
+    assert_eq!(events[0].line, Some(20));
+    assert_eq!(events[0].fields["callsite.col"], "7");
+    let snapshot = handle.snapshot();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].file, Some("src/otel.rs"));
+    assert_eq!(snapshot[0].line, Some(20));
+}
+
+#[test]
+fn metric_events() {
+    for (naming, prefix) in [
+        (
+            MetricNaming::OpenTelemetry,
+            ["histogram.", "monotonic_counter."],
+        ),
+        (MetricNaming::Plain, ["", ""]),
+    ] {
+        let clock = ManualClock::new();
+        let layer = TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_warn_busy_total(Some(BLOCK))
+            .with_metric_naming(naming)
+            .with_observers([])
+            .with_clock(clock.clone());
+        let (collector, _guard) = install(layer);
+
+        let span = named_task_span(Some("worker"));
+        poll_manual(&span, &clock, BLOCK);
+        poll_manual(&span, &clock, Duration::from_millis(1));
+        drop(span);
+
+        assert!(collector.poll_blocked_events().is_empty());
+        let metrics = collector.of_kind(EventKind::Metrics);
+        assert_eq!(metrics.len(), 2, "{metrics:?}");
+        let number =
+            |event: &CapturedEvent, field: &str| -> f64 { event.fields[field].parse().unwrap() };
+
+        let [hist, counter] = prefix;
+        let poll = &metrics[0];
+        assert_eq!(
+            number(poll, &format!("{hist}tokio_blocked.poll_duration_ms")),
+            20.0
+        );
+        assert_eq!(
+            number(poll, &format!("{counter}tokio_blocked.blocked_polls")),
+            1.0
+        );
+        assert_eq!(poll.fields["task.name"], "worker");
+        assert!(poll.file.is_some() && poll.line.is_some());
+
+        let total = &metrics[1];
+        assert_eq!(
+            number(total, &format!("{hist}tokio_blocked.blocked_total_ms")),
+            21.0
+        );
+        assert_eq!(
+            number(total, &format!("{counter}tokio_blocked.blocked_spans")),
+            1.0
+        );
+    }
+}
+
+#[test]
+fn snapshot_grouped_rollups() {
+    use tokio_blocked::TableOptions;
+
+    let with = |target: &str, file: &str, line, busy, max| {
+        let mut row = row(line, busy, max);
+        row.id.target = target.to_string();
+        row.id.file = Some(file.to_string());
+        row
+    };
+    let snapshot = Snapshot::new(vec![
+        with("hyper::proto", "src/http/conn.rs", 1, 10, 4),
+        with("hyper::client", "src/http/client.rs", 2, 30, 3),
+        with("app::db", "src/db/pool.rs", 3, 25, 20),
+    ]);
+
+    let crates = snapshot.grouped(GroupKey::Crate);
+    assert_eq!(crates.len(), 2);
+    assert_eq!(crates[0].key, "hyper");
+    assert_eq!(crates[0].total_busy, Duration::from_millis(40));
+    assert_eq!(crates[0].polls, 2);
+    assert_eq!(crates[0].spans, 2);
+    assert_eq!(crates[0].max_poll, Duration::from_millis(4));
+    assert_eq!(crates[0].top.line, Some(2));
+    assert_eq!(crates[0].top_busy, Duration::from_millis(30));
+    assert_eq!(crates[1].key, "app");
+    assert_eq!(crates[1].total_busy, Duration::from_millis(25));
+
+    assert_eq!(snapshot.grouped(GroupKey::Module).len(), 3);
+    let dirs = snapshot.grouped(GroupKey::Directory);
+    assert_eq!(
+        dirs.iter().map(|g| g.key.as_str()).collect::<Vec<_>>(),
+        ["src/http", "src/db"]
+    );
+
+    let opts = TableOptions {
+        summary: Some(GroupKey::Crate),
+        top: Some(1),
+        ..Default::default()
+    };
+    let table = snapshot.render_table(&opts);
+    let summary = table.split("\n\n").nth(1).unwrap();
+    let lines: Vec<_> = summary.lines().collect();
+    assert_eq!(lines.len(), 3, "{table}");
+    assert!(lines[0].starts_with("CRATE "));
+    assert!(lines[1].starts_with("hyper  src/http/client.rs:2 runtime.spawn "));
+    assert!(lines[1].ends_with(" 40.00 ms   4.00 ms"), "{table}");
+
+    let markdown = snapshot.render_markdown(&MarkdownOptions {
+        summary: Some(GroupKey::Directory),
+        ..Default::default()
+    });
+    assert!(markdown.contains(
+        "### By directory\n\n\
+         | Directory | Top offender | Spans | Polls | Total busy | Max poll |\n\
+         | --- | --- | ---: | ---: | ---: | ---: |\n\
+         | src/http | `src/http/client.rs:2` runtime.spawn | 2 | 2 | 40.00 ms | 4.00 ms |\n\
+         | src/db | `src/db/pool.rs:3` runtime.spawn | 1 | 1 | 25.00 ms | 20.00 ms |\n"
+    ));
+}
+
+#[test]
+fn task_migrations_are_counted() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_warn_migrations(1)
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+    let span = task_span!();
+    let line = line!() - 1;
+    poll_manual(&span, &clock, BLOCK);
+    let span = std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let _default = tracing::dispatcher::set_default(&dispatch);
+                poll_manual(&span, &clock, BLOCK);
+                poll_manual(&span, &clock, BLOCK);
+                span
+            })
+            .join()
+            .unwrap()
+    });
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let events = collector.of_kind(EventKind::TaskMigrations);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].fields["migrations"], "2");
+    assert_eq!(events[0].fields["polls_total"], "4");
+    assert_eq!(events[0].line, Some(line));
+    let total = collector.of_kind(EventKind::BlockedTotal);
+    assert_eq!(total[0].fields["migrations"], "2");
+    assert_eq!(handle.snapshot()[0].migrations, 2);
+}
+
+#[test]
+fn task_migrations_on_multi_thread_runtime() {
+    use tracing::Instrument as _;
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_warn_migrations(u64::MAX);
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .on_thread_start(move || {
+            // Leaked, as the workers outlive the test's default.
+            std::mem::forget(tracing::dispatcher::set_default(&dispatch));
+        })
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let span = named_task_span(Some("yielding"));
+                tokio::spawn(
+                    async {
+                        for _ in 0..50 {
+                            tokio::task::yield_now().await;
+                            std::thread::sleep(Duration::from_micros(100));
+                        }
+                    }
+                    .instrument(span),
+                )
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+    drop(rt);
+
+    let migrations: u64 = handle
+        .snapshot()
+        .iter()
+        .filter(|row| row.line == Some(1))
+        .map(|row| row.migrations)
+        .sum();
+    assert!(migrations > 0);
+}
+
+#[test]
+fn runtime_labels_split_stats() {
+    use tracing::Instrument as _;
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_runtime_label_from_thread_prefixes(&[("ingest-", "ingest"), ("compute-", "compute")]);
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+    let runtime = |prefix: &str| {
+        let dispatch = dispatch.clone();
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name(format!("{prefix}worker"))
+            .on_thread_start(move || {
+                // Leaked, as the workers outlive the test's default.
+                std::mem::forget(tracing::dispatcher::set_default(&dispatch));
+            })
+            .build()
+            .unwrap()
+    };
+
+    for (prefix, polls) in [("ingest-", 1), ("compute-", 2)] {
+        let rt = runtime(prefix);
+        rt.block_on(async {
+            for _ in 0..polls {
+                let span = named_task_span(None);
+                let task = async { std::thread::sleep(BLOCK) }.instrument(span);
+                tokio::spawn(task).await.unwrap();
+            }
+        });
+        drop(rt);
+    }
+
+    let mut rows: Vec<_> = handle
+        .snapshot()
+        .into_iter()
+        .filter(|row| row.line == Some(1))
+        .map(|row| (row.runtime, row.count))
+        .collect();
+    rows.sort();
+    assert_eq!(rows, [(Some("compute"), 2), (Some("ingest"), 1)]);
+    let mut runtimes: Vec<_> = collector
+        .poll_blocked_events()
+        .iter()
+        .filter(|e| e.line == Some(1))
+        .map(|e| e.fields["runtime"].clone())
+        .collect();
+    runtimes.sort();
+    assert_eq!(runtimes, ["compute", "compute", "ingest"]);
+}
+
+#[test]
+fn scope_span_limits_tracking() {
+    use tracing::Instrument as _;
+
+    // Like tokio's task spans, which have no parent.
+    fn root_task_span(name: &str) -> Span {
+        tracing::trace_span!(
+            target: "tokio::task",
+            parent: None,
+            "runtime.spawn",
+            task.name = name,
+            loc.file = file!(),
+            loc.line = 1u32,
+            loc.col = 1u32,
+        )
+    }
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_scope_span(CallsiteSelector::name(module_path!(), "load_test"))
+        .with_scope_span(CallsiteSelector::scope());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    // Beneath the scope span through the span ancestry.
+    let load_test = tracing::info_span!("load_test");
+    let inside = load_test.in_scope(|| named_task_span(Some("inside")));
+    poll(&inside, BLOCK);
+    drop(inside);
+    let outside = named_task_span(Some("outside"));
+    poll(&outside, BLOCK);
+    drop(outside);
+
+    // Root task spans are scoped through the span current when they are
+    // created, including nested spawns.
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let scoped = tokio_blocked::scope(async {
+            let nested = async { std::thread::sleep(BLOCK) }.instrument(root_task_span("nested"));
+            tokio::spawn(nested).await.unwrap();
+        });
+        let scoped = async {
+            std::thread::sleep(BLOCK);
+            scoped.await
+        };
+        tokio::spawn(scoped.instrument(root_task_span("scoped")))
+            .await
+            .unwrap();
+        let sibling = async { std::thread::sleep(BLOCK) }.instrument(root_task_span("sibling"));
+        tokio::spawn(sibling).await.unwrap();
+    });
+
+    let mut tasks: Vec<_> = collector
+        .poll_blocked_events()
+        .iter()
+        .filter(|e| e.line == Some(1))
+        .map(|e| e.fields["task.name"].clone())
+        .collect();
+    tasks.sort();
+    assert_eq!(tasks, ["inside", "nested"]);
+    let spans: u64 = handle
+        .snapshot()
+        .iter()
+        .filter(|row| row.line == Some(1))
+        .map(|row| row.count)
+        .sum();
+    assert_eq!(spans, 2);
+}
+
+#[test]
+fn first_poll_blocking_has_own_target() {
+    use tracing::Instrument as _;
+
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let span = task_span!();
+    let line = line!() - 1;
+    rt.block_on(
+        async {
+            // Synchronous initialization, then only fast polls.
+            std::thread::sleep(BLOCK);
+            for _ in 0..3 {
+                tokio::task::yield_now().await;
+            }
+        }
+        .instrument(span),
+    );
+
+    // With `tokio_unstable`, the `block_on` task is reported as well.
+    let first: Vec<_> = collector
+        .of_kind(EventKind::FirstPollBlocked)
+        .into_iter()
+        .filter(|e| e.line == Some(line))
+        .collect();
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].fields["poll_seq"], "1");
+    assert!(first[0].fields["message"].contains("first poll"));
+    assert!(collector
+        .of_kind(EventKind::PollBlocked)
+        .iter()
+        .all(|e| e.line != Some(line)));
+
+    let stats = handle.snapshot();
+    let row = stats.iter().find(|s| s.line == Some(line)).unwrap();
+    assert_eq!(row.first_poll_blocked, 1);
+    assert_eq!(row.exceed_count, 1);
+
+    // Later blocked polls are reported as usual.
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, BLOCK);
+    assert_eq!(collector.of_kind(EventKind::FirstPollBlocked).len(), 1);
+    assert_eq!(collector.of_kind(EventKind::PollBlocked).len(), 1);
+    drop(span);
+}
+
+#[test]
+fn recovery_event_after_quiet_polls() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_recovery_events(3)
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+    let fast = Duration::from_millis(1);
+
+    let span = task_span!();
+    let line = line!() - 1;
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, fast);
+    poll_manual(&span, &clock, fast);
+    // A blocked poll restarts the count of clean polls.
+    poll_manual(&span, &clock, BLOCK * 2);
+    for _ in 0..10 {
+        poll_manual(&span, &clock, fast);
+    }
+
+    let recovered = collector.of_kind(EventKind::CallsiteRecovered);
+    assert_eq!(recovered.len(), 1);
+    let event = &recovered[0];
+    assert_eq!(event.line, Some(line));
+    assert_eq!(event.fields["blocked_polls"], "2");
+    assert_eq!(event.fields["clean_polls"], "3");
+    assert_eq!(
+        event.fields["peak_poll_ns"],
+        (BLOCK * 2).as_nanos().to_string()
+    );
+    // From the start of the first blocked poll to the end of the third clean
+    // poll after the last one.
+    let episode = BLOCK * 3 + fast * 5;
+    assert_eq!(event.fields["episode_ns"], episode.as_nanos().to_string());
+
+    // A new regression gets a fresh warning and recovery pair.
+    poll_manual(&span, &clock, BLOCK);
+    for _ in 0..3 {
+        poll_manual(&span, &clock, fast);
+    }
+    assert_eq!(collector.poll_blocked_events().len(), 3);
+    let recovered = collector.of_kind(EventKind::CallsiteRecovered);
+    assert_eq!(recovered.len(), 2);
+    assert_eq!(recovered[1].fields["blocked_polls"], "1");
+}
+
+/// The only test using the global handle, as it is per process.
+#[test]
+fn global_stats_accessors() {
+    assert!(tokio_blocked::stats().is_none());
+    assert!(tokio_blocked::snapshot().is_empty());
+    assert_eq!(tokio_blocked::reset(), Err(GlobalError::NotInstalled));
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_clock(clock.clone())
+        .install_global()
+        .unwrap();
+    let (_collector, _guard) = install(layer);
+    assert_eq!(
+        TokioBlockedLayer::new().install_global().err(),
+        Some(GlobalError::AlreadyInstalled)
+    );
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    let stats = tokio_blocked::snapshot();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].total_busy, BLOCK);
+    assert_eq!(tokio_blocked::stats().unwrap().snapshot().len(), 1);
+
+    tokio_blocked::reset().unwrap();
+    assert!(tokio_blocked::snapshot().is_empty());
+}
+
+#[cfg(feature = "init")]
+#[test]
+fn init_subscriber_collects_stats() {
+    let clock = ManualClock::new();
+    let (subscriber, handle) = tokio_blocked::subscriber_with(
+        TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_clock(clock.clone()),
+    );
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+    let stats = handle.snapshot();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].exceed_count, 1);
+}
+
+#[cfg(feature = "tower")]
+#[test]
+fn tower_middleware_attributes_blocked_time_to_requests() {
+    use tokio_blocked::BlockedTimeLayer;
+    use tower::{Layer as _, ServiceExt as _};
+
+    let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(Some(THRESHOLD));
+    let middleware = BlockedTimeLayer::new(layer.handle()).with_warn_blocked(BLOCK);
+    let (collector, _guard) = install(layer);
+    let service = middleware.layer(tower::service_fn(|fast: bool| async move {
+        if !fast {
+            std::thread::sleep(BLOCK);
+            // Attributed as well, if tokio instruments the task.
+            tokio::spawn(async { std::thread::sleep(BLOCK) })
+                .await
+                .unwrap();
+        }
+        Ok::<_, std::convert::Infallible>(())
+    }));
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(service.clone().oneshot(true)).unwrap();
+    assert!(collector.of_kind(EventKind::RequestBlocked).is_empty());
+
+    rt.block_on(service.oneshot(false)).unwrap();
+    let warnings = collector.of_kind(EventKind::RequestBlocked);
+    assert_eq!(warnings.len(), 1);
+    let blocked: u64 = warnings[0].fields["blocked_ns"].parse().unwrap();
+    let expected = if cfg!(tokio_unstable) {
+        BLOCK * 2
+    } else {
+        BLOCK
+    };
+    assert!(blocked >= expected.as_nanos() as u64, "{blocked}");
+    let busy: u64 = warnings[0].fields["busy_ns"].parse().unwrap();
+    assert!(busy >= blocked);
+}
+
+#[test]
+fn block_zone_is_acknowledged() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    {
+        let _enter = span.enter();
+        let _zone = tokio_blocked::block_zone!("startup config");
+        clock.advance(BLOCK);
+    }
+    {
+        let _enter = span.enter();
+        clock.advance(Duration::from_millis(1));
+        let _zone = tokio_blocked::block_zone!("startup config");
+        clock.advance(BLOCK);
+    }
+    drop(span);
+    assert!(collector.poll_blocked_events().is_empty());
+    assert!(collector.of_kind(EventKind::BlockedTotal).is_empty());
+
+    let stats = handle.snapshot();
+    let task = stats.iter().find(|s| s.name == "runtime.spawn").unwrap();
+    assert_eq!(task.polls, 2);
+    assert_eq!(task.total_busy, Duration::from_millis(1));
+    let zones: Vec<_> = stats
+        .iter()
+        .filter(|s| s.category == Category::Acknowledged)
+        .collect();
+    assert_eq!(zones.len(), 2);
+    assert!(zones.iter().all(|z| z.total_busy == BLOCK && z.polls == 1));
+}
+
+#[test]
+fn allow_blocking_at_exempts_locations() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone())
+        .with_allow_blocking_at(&[("startup.rs", Some(10))]);
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let spawned_at = |line: u32| {
+        tracing::trace_span!(
+            target: "tokio::task",
+            "runtime.spawn",
+            loc.file = "src/startup.rs",
+            loc.line = line,
+            loc.col = 1u32,
+        )
+    };
+    let allowed = spawned_at(10);
+    let other = spawned_at(20);
+    poll_manual(&allowed, &clock, BLOCK);
+    poll_manual(&other, &clock, BLOCK);
+    drop((allowed, other));
+
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].line, Some(20));
+    let mut stats = handle.snapshot();
+    stats.sort_by_key(|s| s.line);
+    assert_eq!(stats.len(), 2);
+    assert!(stats[0].allowed);
+    assert_eq!(stats[0].exceed_count, 1);
+    assert!(!stats[1].allowed);
+}
+
+#[test]
+fn severity_tiers_select_levels() {
+    let tiers = [
+        (Duration::from_micros(200), Level::DEBUG),
+        (Duration::from_millis(2), Level::WARN),
+        (Duration::from_millis(50), Level::ERROR),
+    ];
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_poll_severity_tiers(&tiers)
+        .with_total_severity_tiers(&tiers)
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for ms in [0.1, 1.0, 10.0, 100.0] {
+        poll_manual(&span, &clock, Duration::from_secs_f64(ms / 1000.0));
+    }
+    drop(span);
+    let events = collector.poll_blocked_events();
+    let levels: Vec<_> = events.iter().map(|e| e.level).collect();
+    assert_eq!(levels, [Level::DEBUG, Level::WARN, Level::ERROR]);
+    assert_eq!(events[1].fields["tier_threshold_ns"], "2000000");
+    let total = collector.of_kind(EventKind::BlockedTotal);
+    assert_eq!(total[0].level, Level::ERROR);
+    assert_eq!(total[0].fields["tier_threshold_ns"], "50000000");
+
+    let err = TokioBlockedLayer::builder()
+        .poll_severity_tiers(&[(BLOCK, Level::WARN), (THRESHOLD, Level::ERROR)])
+        .build()
+        .err();
+    assert_eq!(
+        err,
+        Some(ConfigError::InvalidTiers {
+            reason: "severity tiers are not strictly increasing"
+        })
+    );
+    assert!(TokioBlockedLayer::builder()
+        .poll_severity_tiers(&tiers)
+        .total_severity_tiers(&tiers)
+        .build()
+        .is_ok());
+}
+
+#[test]
+fn anomaly_threshold_from_callsite_distribution() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_anomaly_threshold(5.0, 95.0, 20)
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    // Slower than the static threshold, but usual for this callsite.
+    let span = task_span!();
+    let usual = Duration::from_millis(8);
+    for _ in 0..20 {
+        poll_manual(&span, &clock, usual);
+    }
+    assert_eq!(collector.poll_blocked_events().len(), 20);
+    poll_manual(&span, &clock, usual);
+    assert_eq!(collector.poll_blocked_events().len(), 20);
+    assert!(collector.of_kind(EventKind::PollAnomaly).is_empty());
+
+    poll_manual(&span, &clock, Duration::from_millis(100));
+    let anomalies = collector.of_kind(EventKind::PollAnomaly);
+    assert_eq!(anomalies.len(), 1);
+    let baseline: u64 = anomalies[0].fields["baseline_ns"].parse().unwrap();
+    assert!(
+        (usual.as_nanos() as u64 / 2..=usual.as_nanos() as u64 * 2).contains(&baseline),
+        "{baseline}"
+    );
+    assert_eq!(anomalies[0].fields["percentile"], "95.0");
+    assert_eq!(collector.poll_blocked_events().len(), 20);
+}
+
+#[test]
+fn anomaly_threshold_saturates_for_huge_multipliers() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_anomaly_threshold(f64::INFINITY, 95.0, 1)
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for _ in 0..3 {
+        poll_manual(&span, &clock, BLOCK);
+    }
+    drop(span);
+    assert_eq!(collector.poll_blocked_events().len(), 1);
+    assert!(collector.of_kind(EventKind::PollAnomaly).is_empty());
+}
+
+#[test]
+fn busy_budget_warns_once_per_period() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(None)
+        .with_busy_budget(
+            CallsiteSelector::file(file!()),
+            Duration::from_millis(2),
+            Duration::from_secs(1),
+        )
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    let poll = Duration::from_micros(900);
+    // First period: over budget on the third poll, warned once.
+    for _ in 0..5 {
+        poll_manual(&span, &clock, poll);
+    }
+    let warnings = collector.of_kind(EventKind::BudgetExceeded);
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].fields["polls"], "3");
+    assert_eq!(warnings[0].fields["busy_ns"], "2700000");
+    assert_eq!(warnings[0].fields["over_budget_ns"], "700000");
+    assert_eq!(warnings[0].fields["budget_ns"], "2000000");
+
+    // Second period: stays under budget.
+    clock.advance(Duration::from_secs(1));
+    poll_manual(&span, &clock, poll);
+    poll_manual(&span, &clock, poll);
+    assert_eq!(collector.of_kind(EventKind::BudgetExceeded).len(), 1);
+}
+
+#[test]
+fn poll_warnings_carry_span_busy_ratio() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    clock.advance(BLOCK * 3);
+    for _ in 0..3 {
+        poll_manual(&span, &clock, BLOCK);
+    }
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].fields["span_busy_ns_so_far"], "20000000");
+    assert_eq!(events[0].fields["span_lifetime_ns_so_far"], "80000000");
+    let ratios: Vec<f64> = events
+        .iter()
+        .map(|e| e.fields["span_busy_ratio"].parse().unwrap())
+        .collect();
+    assert_eq!(ratios[0], 0.25);
+    assert!(ratios.windows(2).all(|w| w[0] < w[1]), "{ratios:?}");
+}
+
+#[cfg(feature = "spawn")]
+#[test]
+fn spawn_records_busy_fields_onto_its_span() {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Output {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self {
+            self.clone()
+        }
+    }
+
+    let output = Output::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(TokioBlockedLayer::new().with_warn_busy_single_poll(None))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(output.clone()),
+        );
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        tokio_blocked::spawn(async {
+            std::thread::sleep(BLOCK);
+            tokio::task::yield_now().await;
+        })
+        .await
+        .unwrap();
+    });
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let span = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find_map(|line| {
+            let span = line.get("span")?;
+            span.get("tokio_blocked.busy_ns")
+                .is_some()
+                .then(|| span.clone())
+        })
+        .unwrap_or_else(|| panic!("no span with busy fields in {output}"));
+    assert_eq!(span["name"], "runtime.spawn");
+    assert_eq!(span["loc.file"], file!());
+    // Plus the enter to drop the future.
+    assert!(span["tokio_blocked.polls"].as_u64().unwrap() >= 2);
+    assert!(span["tokio_blocked.busy_ns"].as_u64().unwrap() >= BLOCK.as_nanos() as u64);
+    assert!(span["tokio_blocked.max_poll_ns"].as_u64().unwrap() >= BLOCK.as_nanos() as u64);
+}
+
+#[test]
+fn recommended_filter_tracks_under_warn_env_filter() {
+    use tracing_subscriber::{EnvFilter, Layer as _};
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let collector = BlockedEventCollector::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(layer.with_filter(TokioBlockedLayer::recommended_filter()))
+        .with(collector.clone().with_filter(EnvFilter::new("warn")));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    drop(span);
+    let unrelated = tracing::info_span!("unrelated");
+    poll_manual(&unrelated, &clock, BLOCK);
+    tracing::info!("not a warning");
+
+    let rows = handle.snapshot();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].name, "runtime.spawn");
+    assert_eq!(rows[0].polls, 2);
+    assert_eq!(rows[0].total_busy, BLOCK + Duration::from_millis(1));
+    assert_eq!(collector.poll_blocked_events().len(), 1);
+    assert!(collector.events().iter().all(|e| e.level <= Level::WARN));
+}
+
+#[test]
+fn layer_does_not_disable_callsites_for_other_layers() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tracing_subscriber::Layer;
+
+    #[derive(Clone, Default)]
+    struct Counter {
+        events: Arc<AtomicUsize>,
+        spans: Arc<AtomicUsize>,
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for Counter {
+        fn on_event(&self, _: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            self.events.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_new_span(
+            &self,
+            _: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            self.spans.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let run = |filtered: bool| {
+        let counter = Counter::default();
+        let layer = TokioBlockedLayer::new().with_warn_busy_single_poll(None);
+        let handle = layer.handle();
+        let layer = if filtered {
+            layer
+                .with_filter(TokioBlockedLayer::recommended_filter())
+                .boxed()
+        } else {
+            layer.boxed()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(layer)
+            .with(counter.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        for i in 0..100 {
+            tracing::debug!(i, "unrelated");
+        }
+        let _unrelated = tracing::debug_span!("unrelated");
+        let span = task_span!();
+        drop(span.enter());
+        drop(span);
+
+        assert_eq!(counter.events.load(Ordering::Relaxed), 100);
+        assert_eq!(counter.spans.load(Ordering::Relaxed), 2);
+        assert_eq!(handle.snapshot().len(), 1);
+    };
+    run(false);
+    run(true);
+}
+
+#[test]
+fn warnings_carry_the_span_ids_other_layers_see() {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+    type Ids = (u64, Option<u64>);
+
+    /// Records the id and parent id of every new span.
+    #[derive(Clone, Default)]
+    struct SpanIds {
+        spans: Arc<Mutex<Vec<Ids>>>,
+    }
+
+    impl<S> Layer<S> for SpanIds
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            _: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            cx: Context<'_, S>,
+        ) {
+            let span = cx.span(id).unwrap();
+            let parent = span.parent().map(|p| p.id().into_u64());
+            self.spans.lock().unwrap().push((id.into_u64(), parent));
+        }
+    }
+
+    let infos = Arc::new(Mutex::new(Vec::new()));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_on_blocked({
+            let infos = infos.clone();
+            move |info| {
+                infos
+                    .lock()
+                    .unwrap()
+                    .push((info.span_id, info.span_parent_id))
+            }
+        })
+        .with_on_blocked_total({
+            let infos = infos.clone();
+            move |info| {
+                infos
+                    .lock()
+                    .unwrap()
+                    .push((info.span_id, info.span_parent_id))
+            }
+        })
+        .with_clock(clock.clone());
+    let recorder = SpanIds::default();
+    let collector = BlockedEventCollector::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(layer)
+        .with(recorder.clone())
+        .with(collector.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let request = tracing::info_span!("request");
+    let child = request.in_scope(|| task_span!());
+    poll_manual(&child, &clock, BLOCK);
+    drop(child);
+    let orphan = task_span!();
+    poll_manual(&orphan, &clock, BLOCK);
+    drop(orphan);
+
+    let spans = recorder.spans.lock().unwrap().clone();
+    let [(request_id, None), (child_id, Some(parent)), (orphan_id, None)] = spans[..] else {
+        panic!("{spans:?}");
+    };
+    assert_eq!(parent, request_id);
+    let child = (child_id.to_string(), Some(request_id.to_string()));
+    let orphan = (orphan_id.to_string(), None);
+    let ids = |events: Vec<CapturedEvent>| {
+        events
+            .iter()
+            .map(|e| {
+                (
+                    e.fields["span.id"].clone(),
+                    e.fields.get("span.parent_id").cloned(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        ids(collector.poll_blocked_events()),
+        [child.clone(), orphan.clone()]
+    );
+    // Reported while the spans close.
+    assert_eq!(
+        ids(collector.of_kind(EventKind::BlockedTotal)),
+        [child, orphan]
+    );
+    assert_eq!(
+        *infos.lock().unwrap(),
+        [
+            (child_id, Some(request_id)),
+            (child_id, Some(request_id)),
+            (orphan_id, None),
+            (orphan_id, None),
+        ]
+    );
+}
+
+#[test]
+fn warnings_are_emitted_without_holding_span_extensions() {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+    /// Locks the extensions of the task spans from `on_event`, like
+    /// tracing-opentelemetry does for span events.
+    #[derive(Clone, Default)]
+    struct ExtensionsReader {
+        spans: Arc<Mutex<Vec<tracing::span::Id>>>,
+    }
+
+    impl<S> Layer<S> for ExtensionsReader
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            _: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            _: Context<'_, S>,
+        ) {
+            self.spans.lock().unwrap().push(id.clone());
+        }
+
+        fn on_event(&self, _: &tracing::Event<'_>, cx: Context<'_, S>) {
+            for id in self.spans.lock().unwrap().iter() {
+                if let Some(span) = cx.span(id) {
+                    span.extensions_mut().insert(());
+                    span.extensions_mut().remove::<()>();
+                }
+            }
+        }
+    }
+
+    let (done, finished) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let clock = ManualClock::new();
+        let layer = TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_warn_busy_total(Some(BLOCK))
+            .with_warn_first_poll_latency(Some(THRESHOLD))
+            .with_clock(clock.clone());
+        let collector = BlockedEventCollector::new();
+        let subscriber = tracing_subscriber::registry()
+            .with(layer)
+            .with(collector.clone())
+            .with(ExtensionsReader::default());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let span = task_span!();
+        clock.advance(BLOCK);
+        poll_manual(&span, &clock, BLOCK);
+        poll_manual(&span, &clock, BLOCK);
+        drop(span);
+        done.send(collector).unwrap();
+    });
+    let collector = finished
+        .recv_timeout(Duration::from_secs(10))
+        .expect("deadlocked on the span extensions");
+    assert_eq!(collector.of_kind(EventKind::FirstPollDelayed).len(), 1);
+    assert_eq!(collector.poll_blocked_events().len(), 2);
+    assert_eq!(collector.of_kind(EventKind::BlockedTotal).len(), 1);
+}
+
+#[test]
+fn reactions_to_warnings_are_not_tracked() {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::{layer::Context, Layer};
+
+    /// Polls a task-like span for every warning it sees. New spans can't be
+    /// created from `on_event`, as tracing disables nested dispatch.
+    #[derive(Clone, Default)]
+    struct Reflector {
+        span: Arc<Mutex<Option<Span>>>,
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for Reflector {
+        fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+            if event.metadata().target().starts_with("tokio_blocked") {
+                if let Some(span) = &*self.span.lock().unwrap() {
+                    drop(span.enter());
+                }
+            }
+        }
+    }
+
+    let clock = ManualClock::new();
+    let layer = |clock: &ManualClock| {
+        TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_warn_busy_total(Some(BLOCK))
+            .with_clock(clock.clone())
+    };
+    let (first, second) = (layer(&clock), layer(&clock));
+    let handles = [first.handle(), second.handle()];
+    let collector = BlockedEventCollector::new();
+    let reflector = Reflector::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(first)
+        .with(second)
+        .with(reflector.clone())
+        .with(collector.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    *reflector.span.lock().unwrap() = Some(task_span!());
+
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, BLOCK);
+    drop(span);
+
+    let rows = handles[0].snapshot_with_live(true);
+    assert_eq!(rows.iter().map(|r| r.polls).sum::<u64>(), 2);
+    let row = rows.iter().find(|r| r.polls > 0).unwrap();
+    assert_eq!(row.total_busy, 2 * BLOCK);
+    assert_eq!(row.exceed_count, 2);
+    // The spans are already tracked by the first layer.
+    assert!(handles[1].snapshot().is_empty());
+    assert_eq!(collector.poll_blocked_events().len(), 2);
+    assert_eq!(collector.of_kind(EventKind::BlockedTotal).len(), 1);
+}
+
+#[test]
+fn emitted_events_have_increasing_seq() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warn_busy_total(Some(BLOCK))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    for _ in 0..3 {
+        let span = task_span!();
+        poll_manual(&span, &clock, BLOCK);
+        poll_manual(&span, &clock, Duration::from_millis(1));
+    }
+
+    let seqs: Vec<u64> = collector
+        .events()
+        .iter()
+        .map(|e| e.fields["seq"].parse().unwrap())
+        .collect();
+    assert_eq!(seqs, (1..=6).collect::<Vec<_>>());
+    assert_eq!(handle.events_emitted(), 6);
+    let kinds: Vec<EventKind> = collector.events().iter().map(|e| e.kind).collect();
+    assert_eq!(
+        kinds[..2],
+        [EventKind::FirstPollBlocked, EventKind::BlockedTotal]
+    );
+}
+
+#[test]
+fn focus_traces_only_the_focused_callsite() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let focused_line = line!() + 2;
+    let poll_twice = |clock: &ManualClock| {
+        let focused = task_span!();
+        let other = task_span!();
+        for span in [&focused, &other] {
+            poll_manual(span, clock, BLOCK);
+            poll_manual(span, clock, Duration::from_millis(1));
+        }
+    };
+    poll_twice(&clock);
+    assert!(collector.of_kind(EventKind::FocusPoll).is_empty());
+
+    let selector = CallsiteSelector::location(file!(), focused_line);
+    assert!(handle.focus(selector.clone()));
+    poll_twice(&clock);
+
+    let events = collector.of_kind(EventKind::FocusPoll);
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|e| e.line == Some(focused_line)));
+    assert!(events.iter().all(|e| e.level == Level::TRACE));
+    assert_eq!(events[0].duration, Some(BLOCK));
+    assert_eq!(events[1].duration, Some(Duration::from_millis(1)));
+    assert_eq!(events[1].fields["poll_seq"], "2");
+    assert!(events[0].fields.contains_key("thread.id"));
+    assert!(events[0].fields.contains_key("poll_start_unix_ns"));
+    let durations: Vec<_> = handle
+        .recent_polls(&selector)
+        .iter()
+        .map(|p| p.duration)
+        .collect();
+    assert_eq!(durations, [BLOCK, Duration::from_millis(1)]);
+
+    assert!(handle.unfocus(&selector));
+    poll_twice(&clock);
+    assert_eq!(collector.of_kind(EventKind::FocusPoll).len(), 2);
+    assert!(handle.recent_polls(&selector).is_empty());
+
+    for line in 0..4 {
+        assert!(handle.focus(CallsiteSelector::location(file!(), line)));
+    }
+    assert!(!handle.focus(selector));
+}
+
+#[cfg(feature = "otlp")]
+#[test]
+fn otlp_exporter_posts_metrics() {
+    use std::{
+        io::{BufRead as _, BufReader, Read as _, Write as _},
+        net::TcpListener,
+    };
+
+    use opentelemetry_proto::tonic::{
+        collector::metrics::v1::ExportMetricsServiceRequest,
+        common::v1::any_value::Value,
+        metrics::v1::{metric::Data, number_data_point},
+    };
+    use prost::Message as _;
+    use tokio_blocked::OtlpExporter;
+
+    // Answers the first request with 503 and the rest with 200.
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let (requests, received) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for (i, stream) in server.incoming().enumerate() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut head = Vec::new();
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    len = value.trim().parse().unwrap();
+                }
+                head.push(line);
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            let status = if i == 0 {
+                "503 Service Unavailable"
+            } else {
+                "200 OK"
+            };
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").unwrap();
+            if requests.send((head, body)).is_err() {
+                return;
+            }
+        }
+    });
+
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_poll_histogram(true)
+        .with_slo_thresholds(&[THRESHOLD, BLOCK * 2])
+        .with_clock(clock.clone());
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let span = task_span!();
+    poll_manual(&span, &clock, BLOCK);
+    poll_manual(&span, &clock, Duration::from_millis(1));
+    drop(span);
+
+    let exporter = OtlpExporter::new(&format!("http://{addr}"))
+        .unwrap()
+        .with_service_name("test-service")
+        .with_retry(3, Duration::from_millis(10))
+        .spawn(Duration::from_millis(10), &handle);
+
+    let recv = || received.recv_timeout(Duration::from_secs(10)).unwrap();
+    let (head, _) = recv();
+    assert_eq!(head[0], "POST /v1/metrics HTTP/1.1\r\n");
+    assert!(head.contains(&"Content-Type: application/x-protobuf\r\n".to_string()));
+    let (_, body) = recv();
+    assert_eq!(exporter.failed(), 0);
+
+    let request = ExportMetricsServiceRequest::decode(&body[..]).unwrap();
+    let resource_metrics = &request.resource_metrics[0];
+    let service = &resource_metrics.resource.as_ref().unwrap().attributes[0];
+    assert_eq!(service.key, "service.name");
+    assert_eq!(
+        service.value.as_ref().unwrap().value,
+        Some(Value::StringValue("test-service".to_string()))
+    );
+    let scope = &resource_metrics.scope_metrics[0];
+    assert_eq!(scope.scope.as_ref().unwrap().name, "tokio-blocked");
+    let metric = |name: &str| {
+        let metric = scope.metrics.iter().find(|m| m.name == name).unwrap();
+        metric.data.clone().unwrap()
+    };
+
+    let Data::Sum(busy) = metric("tokio_blocked.busy") else {
+        panic!("busy time is not a sum");
+    };
+    assert!(busy.is_monotonic);
+    let point = &busy.data_points[0];
+    assert_eq!(
+        point.value,
+        Some(number_data_point::Value::AsDouble(
+            (BLOCK + Duration::from_millis(1)).as_secs_f64()
+        ))
+    );
+    let attribute = |key: &str| {
+        point
+            .attributes
+            .iter()
+            .find(|a| a.key == key)
+            .unwrap()
+            .value
+            .clone()
+            .unwrap()
+            .value
+    };
+    assert_eq!(
+        attribute("callsite.name"),
+        Some(Value::StringValue("runtime.spawn".to_string()))
+    );
+    assert_eq!(
+        attribute("callsite.file"),
+        Some(Value::StringValue(file!().to_string()))
+    );
+    let Data::Sum(exceeded) = metric("tokio_blocked.exceeded") else {
+        panic!("exceeded polls are not a sum");
+    };
+    assert_eq!(
+        exceeded.data_points[0].value,
+        Some(number_data_point::Value::AsInt(1))
+    );
+    let Data::Histogram(histogram) = metric("tokio_blocked.poll_duration") else {
+        panic!("poll durations are not a histogram");
+    };
+    let point = &histogram.data_points[0];
+    assert_eq!(point.count, 2);
+    assert_eq!(point.bucket_counts.len(), point.explicit_bounds.len() + 1);
+    assert_eq!(point.bucket_counts.iter().sum::<u64>(), 2);
+    let Data::Sum(slo) = metric("tokio_blocked.polls_over_threshold") else {
+        panic!("SLO counters are not a sum");
+    };
+    let counters: Vec<_> = slo
+        .data_points
+        .iter()
+        .map(|point| {
+            let threshold = point.attributes.iter().find(|a| a.key == "threshold");
+            (threshold.unwrap().value.clone().unwrap().value, point.value)
+        })
+        .collect();
+    assert_eq!(
+        counters,
+        [
+            (
+                Some(Value::DoubleValue(THRESHOLD.as_secs_f64())),
+                Some(number_data_point::Value::AsInt(1))
+            ),
+            (
+                Some(Value::DoubleValue((BLOCK * 2).as_secs_f64())),
+                Some(number_data_point::Value::AsInt(0))
+            ),
+        ]
+    );
+}
+
+#[test]
+fn event_log_round_trips_blocked_polls() {
+    let path =
+        std::env::temp_dir().join(format!("tokio-blocked-events-{}.log", std::process::id()));
+    let clock = ManualClock::new();
+    // Polls are logged even without warnings.
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warnings_enabled(false)
+        .with_clock(clock.clone())
+        .with_event_log(&path, 1 << 20)
+        .unwrap();
+    let handle = layer.handle();
+    let (collector, _guard) = install(layer);
+
+    let line = line!();
+    let first = task_span!();
+    let second = task_span!();
+    for i in 0..3000 {
+        let span = if i % 3 == 0 { &second } else { &first };
+        poll_manual(span, &clock, BLOCK + Duration::from_micros(i));
+    }
+    // Not logged, it is under the threshold.
+    poll_manual(&first, &clock, Duration::from_millis(1));
+    handle.flush_event_log().unwrap();
+    assert!(collector.poll_blocked_events().is_empty());
+    assert_eq!(handle.event_log_dropped(), 0);
+
+    let reader = tokio_blocked::event_log::Reader::open(&path).unwrap();
+    let records: Vec<_> = reader.records().map(Result::unwrap).collect();
+    for suffix in ["", ".strings"] {
+        let mut file = path.clone().into_os_string();
+        file.push(suffix);
+        std::fs::remove_file(file).unwrap();
+    }
+    assert_eq!(records.len(), 3000);
+    for (i, record) in records.iter().enumerate() {
+        assert_eq!(record.duration, BLOCK + Duration::from_micros(i as u64));
+        assert_eq!(record.first_poll, i < 2, "{i}");
+    }
+    assert!(records.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+    assert_ne!(records[0].callsite, records[1].callsite);
+    assert_eq!(records[1].callsite, records[2].callsite);
+
+    let callsite = reader.callsite(records[1].callsite).unwrap();
+    assert_eq!(callsite.name, "runtime.spawn");
+    assert_eq!(callsite.file, file!());
+    assert_eq!(callsite.line, line + 1);
+    assert_eq!(reader.callsite(records[0].callsite).unwrap().line, line + 2);
+    assert_eq!(
+        reader.thread_name(records[0].thread),
+        std::thread::current().name()
+    );
+}
+
+#[test]
+fn event_log_rotates_at_max_size() {
+    let path =
+        std::env::temp_dir().join(format!("tokio-blocked-rotate-{}.log", std::process::id()));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_warnings_enabled(false)
+        .with_clock(clock.clone())
+        .with_event_log(&path, 2000)
+        .unwrap();
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let span = task_span!();
+    for i in 0..3000 {
+        poll_manual(&span, &clock, BLOCK + Duration::from_micros(i));
+    }
+    handle.flush_event_log().unwrap();
+
+    let reader = tokio_blocked::event_log::Reader::open(&path).unwrap();
+    let records: Vec<_> = reader.records().map(Result::unwrap).collect();
+    let mut size = 0;
+    for suffix in ["", ".1", ".strings"] {
+        let mut file = path.clone().into_os_string();
+        file.push(suffix);
+        if suffix != ".strings" {
+            size += std::fs::metadata(&file).unwrap().len();
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+    assert!(size <= 4000, "{size}");
+    // The newest records are kept, in order.
+    assert!(
+        records.len() > 50 && records.len() < 3000,
+        "{}",
+        records.len()
+    );
+    let last = records.last().unwrap();
+    assert_eq!(last.duration, BLOCK + Duration::from_micros(2999));
+    assert!(records
+        .windows(2)
+        .all(|w| w[1].duration == w[0].duration + Duration::from_micros(1)));
+    assert!(reader.callsite(last.callsite).is_some());
+}
+
+#[cfg(feature = "tokio-metrics")]
+#[test]
+fn combined_report_merges_task_monitor_metrics() {
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_group_by(GroupBy::TaskName);
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let monitor = tokio_metrics::TaskMonitor::new();
+    for _ in 0..2 {
+        // The task's poll enters its span, as tokio's instrumentation does.
+        let span = named_task_span(Some("worker"));
+        runtime.block_on(monitor.instrument(async { poll(&span, BLOCK) }));
+    }
+    // Not instrumented by the monitor, and not part of the report.
+    poll(&named_task_span(Some("other")), BLOCK);
+
+    let report = handle.combined_report("worker", &monitor);
+    assert_eq!(report.task_name, "worker");
+    assert_eq!(report.spans, 2);
+    assert_eq!(report.polls, 2);
+    assert_eq!(report.polls_over_threshold, 2);
+    assert!(report.total_busy >= BLOCK * 2);
+    assert!(report.max_poll >= BLOCK);
+    assert_eq!(report.scheduling.instrumented_count, 2);
+    assert_eq!(report.scheduling.total_poll_count, 2);
+    assert!(report.scheduling.total_poll_duration >= BLOCK * 2);
+
+    let rendered = report.to_string();
+    assert!(rendered.starts_with("task worker\n"), "{rendered}");
+    assert!(
+        rendered.contains("tokio-blocked: 2 spans, 2 polls,"),
+        "{rendered}"
+    );
+    assert!(
+        rendered.contains("tokio-metrics: 2 tasks, 2 polls"),
+        "{rendered}"
+    );
+}
+
+#[test]
+fn request_span_totals_blocked_time_of_its_tasks() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_request_span("request")
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+
+    let request = tracing::info_span!("request");
+    // Created while the request is current, as tokio's task spans have no
+    // parent; the nested task is attributed through its spawning task.
+    let (task, nested) = request.in_scope(|| {
+        clock.advance(Duration::from_millis(1));
+        let task = task_span!();
+        let nested = task.in_scope(|| task_span!());
+        (task, nested)
+    });
+    poll_manual(&task, &clock, BLOCK);
+    poll_manual(&task, &clock, Duration::from_millis(2));
+    poll_manual(&nested, &clock, BLOCK);
+    // Not beneath the request.
+    poll_manual(&task_span!(), &clock, BLOCK);
+    // Not a request span.
+    let other = tracing::info_span!("other");
+    poll_manual(&other, &clock, BLOCK);
+    drop(other);
+
+    drop(request);
+    // Still open beneath the request, so it is not closed yet.
+    assert!(collector.of_kind(EventKind::RequestTotal).is_empty());
+    drop((task, nested));
+
+    let totals = collector.of_kind(EventKind::RequestTotal);
+    assert_eq!(totals.len(), 1);
+    let fields = &totals[0].fields;
+    assert_eq!(fields["request.name"], "request");
+    let nanos = |d: Duration| (d.as_nanos() as u64).to_string();
+    assert_eq!(fields["blocked_ns"], nanos(BLOCK * 2));
+    assert_eq!(
+        fields["busy_ns"],
+        nanos(BLOCK * 2 + Duration::from_millis(3))
+    );
+    assert_eq!(totals[0].level, Level::INFO);
+}
+
+#[test]
+fn route_snapshot_attributes_blocked_time_to_axum_routes() {
+    use axum::{
+        body::Body,
+        extract::{MatchedPath, Request},
+        middleware::{self, Next},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt as _;
+    use tracing::Instrument as _;
+
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_request_span("request")
+        .with_route_field("http.route");
+    let handle = layer.handle();
+    let (_collector, _guard) = install(layer);
+
+    let app = Router::new()
+        .route("/fast", get(|| async { "fast" }))
+        .route(
+            "/slow/{id}",
+            get(|| async {
+                std::thread::sleep(BLOCK);
+                "slow"
+            }),
+        )
+        .layer(middleware::from_fn(|req: Request, next: Next| async move {
+            let span = tracing::info_span!("request", http.route = tracing::field::Empty);
+            if let Some(route) = req.extensions().get::<MatchedPath>() {
+                span.record("http.route", route.as_str());
+            }
+            next.run(req).instrument(span).await
+        }));
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        for uri in ["/fast", "/slow/1", "/slow/2", "/fast"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert!(response.status().is_success());
+        }
+        // Not routed, so there is no matched path.
+        let request = Request::get("/missing").body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap();
+    });
+
+    let routes = handle.route_snapshot();
+    assert_eq!(routes.len(), 3, "{routes:?}");
+    assert_eq!(routes[0].route, "/slow/{id}");
+    assert_eq!(routes[0].requests, 2);
+    assert!(routes[0].total_blocked >= BLOCK * 2);
+    assert!(routes[0].max_blocked >= BLOCK && routes[0].max_blocked < routes[0].total_blocked);
+    assert!(routes[0].total_busy >= routes[0].total_blocked);
+    assert_eq!(routes[1].route, "/fast");
+    assert_eq!(routes[1].requests, 2);
+    assert_eq!(routes[1].total_blocked, Duration::ZERO);
+    // Requests without the field are counted as `unknown`.
+    assert_eq!(routes[2].route, "unknown");
+    assert_eq!(routes[2].requests, 1);
+}
+
+#[test]
+fn breadcrumbs_attach_the_events_of_blocked_polls() {
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::{
+        filter::{filter_fn, FilterExt as _},
+        layer::Filter,
+        Layer as _, Registry,
+    };
+
+    /// The breadcrumbs of a blocked poll, with the layer behind `filter`.
+    fn breadcrumbs(filter: impl Filter<Registry> + Send + Sync + 'static) -> Vec<CapturedEvent> {
+        let clock = ManualClock::new();
+        let layer = TokioBlockedLayer::new()
+            .with_warn_busy_single_poll(Some(THRESHOLD))
+            .with_clock(clock.clone())
+            .with_breadcrumbs(2);
+        let collector = BlockedEventCollector::new();
+        let subscriber = tracing_subscriber::registry()
+            .with(layer.with_filter(filter))
+            .with(collector.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let span = task_span!();
+        span.in_scope(|| {
+            tracing::info!("connecting");
+            clock.advance(BLOCK);
+        });
+        collector.poll_blocked_events()
+    }
+
+    let levels = Arc::new(Mutex::new(Vec::new()));
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone())
+        .with_breadcrumbs(2)
+        .with_on_blocked({
+            let levels = levels.clone();
+            move |info| {
+                levels
+                    .lock()
+                    .unwrap()
+                    .push(info.breadcrumbs.iter().map(|b| b.level).collect::<Vec<_>>());
+            }
+        });
+    let (collector, _guard) = install(layer);
+
+    let span = task_span!();
+    span.in_scope(|| {
+        tracing::info!("connecting");
+        tracing::debug!(target: "app::config", path = "/etc/app.toml", "reading config");
+        // Counts for the task span, which is the innermost tracked one.
+        tracing::info_span!("load").in_scope(|| tracing::warn!("config is large"));
+        clock.advance(BLOCK);
+    });
+    // Outside of the span.
+    tracing::info!("unrelated");
+    poll_manual(&span, &clock, BLOCK);
+
+    let events = collector.poll_blocked_events();
+    assert_eq!(events.len(), 2, "{events:?}");
+    let expected =
+        r#"DEBUG app::config: reading config path="/etc/app.toml" | WARN test: config is large"#;
+    // The breadcrumbs are kept across polls.
+    for event in &events {
+        assert_eq!(event.fields["breadcrumbs"], expected, "{event:?}");
+    }
+    assert_eq!(levels.lock().unwrap()[0], [Level::DEBUG, Level::WARN]);
+
+    // The recommended filter disables events, so there are no breadcrumbs.
+    let events = breadcrumbs(TokioBlockedLayer::recommended_filter());
+    assert_eq!(events.len(), 1);
+    assert!(!events[0].fields.contains_key("breadcrumbs"), "{events:?}");
+
+    let events =
+        breadcrumbs(TokioBlockedLayer::recommended_filter().or(filter_fn(|meta| meta.is_event())));
+    assert_eq!(events[0].fields["breadcrumbs"], "INFO test: connecting");
+}
+
+#[test]
+fn breadcrumbs_are_off_by_default() {
+    let clock = ManualClock::new();
+    let layer = TokioBlockedLayer::new()
+        .with_warn_busy_single_poll(Some(THRESHOLD))
+        .with_clock(clock.clone());
+    let (collector, _guard) = install(layer);
+    let span = task_span!();
+    span.in_scope(|| {
+        tracing::info!("connecting");
+        clock.advance(BLOCK);
+    });
+
+    let events = collector.poll_blocked_events();
+    assert!(!events[0].fields.contains_key("breadcrumbs"), "{events:?}");
+}